@@ -1,17 +1,54 @@
-use crate::config::AppConfig;
-use anyhow::{anyhow, Context, Result};
+use crate::auth::{self, Secret};
+use crate::config::{AppConfig, Backend};
+use crate::utils::{find_char_boundary, parse_python_version};
+use crate::python_exec::RESULT_MARKER;
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
+use regex::Regex;
 use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::error::Error as _;
+use std::fmt;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
 
 #[derive(Serialize)]
-struct ChatRequest {
+pub struct ChatRequest {
     model: String,
     messages: Vec<Message>,
     #[serde(skip_serializing_if = "Option::is_none")]
     max_tokens: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    /// Number of choices to return, for backends that support it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<u32>,
+}
+
+impl ChatRequest {
+    /// Check the assembled conversation before it is sent: a system prompt first, then at
+    /// least one message, none of them empty. A bad payload (history trimmed to nothing, an
+    /// empty turn) would cost a request and come back as a confusing provider error.
+    pub fn validate(&self) -> Result<(), ApiError> {
+        let invalid = |reason: String| Err(ApiError::InvalidRequest(reason));
+        match self.messages.first() {
+            None => return invalid("the message list is empty".to_string()),
+            Some(first) if first.role != "system" => {
+                return invalid(format!("the first message is a {} message, not the system prompt", first.role))
+            }
+            Some(_) if self.messages.len() == 1 => {
+                return invalid("there is no message after the system prompt".to_string())
+            }
+            Some(_) => {}
+        }
+        if let Some((index, message)) = self.messages.iter().enumerate().find(|(_, m)| m.content.trim().is_empty()) {
+            return invalid(format!("message {} ({}) has empty content", index + 1, message.role));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -28,10 +65,84 @@ struct ChatResponse {
 #[derive(Deserialize)]
 struct Choice {
     message: Message,
+    /// "stop" when the model finished, "length" when it hit `max_tokens`.
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+/// Body for HF Inference "text-generation" endpoints.
+#[derive(Serialize)]
+struct TextGenerationRequest {
+    inputs: String,
+    parameters: TextGenerationParameters,
+}
+
+#[derive(Serialize)]
+struct TextGenerationParameters {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_new_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    return_full_text: bool,
+}
+
+#[derive(Deserialize)]
+struct TextGenerationOutput {
+    generated_text: String,
+    /// Only present when the deployment returns generation details.
+    #[serde(default)]
+    details: Option<TextGenerationDetails>,
+}
+
+#[derive(Deserialize)]
+struct TextGenerationDetails {
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+/// Success bodies we know how to read, tried in order.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SuccessBody {
+    Chat(ChatResponse),
+    /// HF Inference `[{"generated_text": ...}]`.
+    TextGenerationArray(Vec<TextGenerationOutput>),
+    TextGeneration(TextGenerationOutput),
+}
+
+impl From<TextGenerationOutput> for Completion {
+    fn from(output: TextGenerationOutput) -> Self {
+        Completion {
+            content: output.generated_text,
+            finish_reason: output.details.and_then(|d| d.finish_reason),
+            rate: None,
+        }
+    }
 }
 
-/// System prompt used for all code-generation requests.
-const SYSTEM_PROMPT: &str = "You are an expert Python code generator. Generate clean, well-commented, COMPLETE and POLISHED executable Python code based on user requests. \
+#[derive(Deserialize)]
+struct ModelList {
+    data: Vec<ModelEntry>,
+}
+
+#[derive(Deserialize)]
+struct ModelEntry {
+    id: String,
+}
+
+/// Shown by `/models` when the endpoint can't list models.
+pub const CURATED_CODE_MODELS: &[&str] = &[
+    "Qwen/Qwen2.5-Coder-32B-Instruct",
+    "Qwen/Qwen3-Coder-480B-A35B-Instruct",
+    "deepseek-ai/DeepSeek-V3",
+    "meta-llama/Llama-3.3-70B-Instruct",
+    "mistralai/Devstral-Small-2505",
+];
+
+/// Core system prompt, sent with every code-generation request.
+const CORE_PROMPT: &str = "You are an expert Python code generator. Generate clean, well-commented, COMPLETE and POLISHED executable Python code based on user requests. \
 CRITICAL RULES:\n\
 1. Output ONLY valid, executable Python code - NO markdown text, NO explanations outside comments\n\
 2. DO NOT include phrases like 'Here is the code' or 'Step 1:' - these cause syntax errors\n\
@@ -43,16 +154,19 @@ CRITICAL RULES:\n\
 8. If external libraries are needed, import them at the top\n\
 9. Make the code production-ready, feature-complete, and maintainable\n\
 10. The code must run immediately when executed with python3 <file>.py WITHOUT ANY ERRORS\n\
-\n\
-CRITICAL BUG PREVENTION:\n\
+11. Target Python 3.8; if the code needs a newer version, make its first line `# requires-python >= 3.X`";
+
+/// Appended for stateful programs (games, GUIs, classes) where undefined names bite most.
+const BUG_PREVENTION_ADDENDUM: &str = "CRITICAL BUG PREVENTION:\n\
 - DEFINE ALL VARIABLES before using them (e.g., if you use RED, define RED = (255, 0, 0) first)\n\
 - DEFINE ALL COLOR CONSTANTS at the top (WHITE, BLACK, RED, GREEN, BLUE, YELLOW, etc.)\n\
 - CHECK for empty lists before accessing indices: if len(my_list) > 0: my_list[0]\n\
 - INITIALIZE all class attributes in __init__ (e.g., self.passed = False)\n\
 - USE try-except for any operations that could fail\n\
-- TEST all variable references - never use undefined variables\n\
-\n\
-FOR GAMES - CRITICAL PLAYABILITY RULES:\n\
+- TEST all variable references - never use undefined variables";
+
+/// Appended for games and other pygame/tkinter programs.
+const GAME_ADDENDUM: &str = "FOR GAMES - CRITICAL PLAYABILITY RULES:\n\
 - Games MUST be actually playable and FUN - test physics and controls!\n\
 - Define ALL colors at the top: WHITE, BLACK, RED, GREEN, BLUE, YELLOW\n\
 - Use VISIBLE, contrasting colors (bright colors on light/dark backgrounds)\n\
@@ -115,215 +229,2216 @@ TESTING:\n\
 - Controls must work on first try\n\
 - Game must be FUN - not too hard, not too easy";
 
-/// Generate code with conversation history for multi-turn refinement
-pub async fn generate_code_with_history(
-    messages: Vec<Message>,
-    config: &AppConfig,
-) -> Result<String> {
-    let token = std::env::var("HF_TOKEN")
-        .context("HF_TOKEN missing in .env")?;
+/// Words in a prompt that call for the game addendum.
+const GAME_HINTS: &[&str] = &[
+    "game", "pygame", "tkinter", "arcade", "snake", "pong", "flappy", "tetris", "breakout", "sprite",
+];
 
-    // Ensure system message is at the beginning
-    let mut full_messages = vec![Message {
-        role: "system".to_string(),
-        content: SYSTEM_PROMPT.to_string(),
-    }];
+/// Words in a prompt that call for the bug-prevention addendum (on top of the game hints).
+const BUG_PREVENTION_HINTS: &[&str] = &[
+    "class", "gui", "window", "app", "simulation", "interface", "state",
+];
 
-    // Add conversation history
-    full_messages.extend(messages);
+/// Names of the addenda `build_system_prompt` appends, in order.
+/// `profile` "full"/"game" forces all of them, "minimal" none; otherwise they follow the prompt.
+pub fn prompt_addenda(profile: Option<&str>, user_prompt: &str) -> Vec<&'static str> {
+    let prompt = user_prompt.to_lowercase();
+    let words: Vec<&str> = prompt
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect();
+    let mentions = |hints: &[&str]| {
+        hints
+            .iter()
+            .any(|h| words.iter().any(|w| w == h || w.strip_suffix('s') == Some(*h)))
+    };
 
-    let body = ChatRequest {
-        model: config.model.clone(),
-        messages: full_messages,
-        max_tokens: Some(config.max_tokens),
-        temperature: Some(config.temperature),
+    let (bugs, game) = match profile {
+        Some("full") | Some("game") => (true, true),
+        Some("minimal") => (false, false),
+        _ => {
+            let game = mentions(GAME_HINTS);
+            (game || mentions(BUG_PREVENTION_HINTS), game)
+        }
     };
 
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        AUTHORIZATION,
-        HeaderValue::from_str(&format!("Bearer {token}"))
-            .context("Invalid Bearer token format")?,
-    );
-    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    let mut addenda = Vec::new();
+    if bugs {
+        addenda.push("bug-prevention");
+    }
+    if game {
+        addenda.push("game");
+    }
+    addenda
+}
 
-    let client = reqwest::Client::new();
+/// Session style toggles (`/style`), appended to the system prompt as short directives
+/// after the addenda rather than replacing it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PromptStyle {
+    pub type_hints: bool,
+    pub docstrings: bool,
+    /// Oldest Python the code must run on.
+    pub python_version: Option<(u32, u32)>,
+    /// Ask for the final result as JSON between `RESULT_MARKER` lines (`capture_result`).
+    pub json_result: bool,
+}
 
-    // Retry loop with exponential backoff
-    let mut last_err: Option<anyhow::Error> = None;
-    for attempt in 0..=config.max_retries {
-        if attempt > 0 {
-            let base_delay = Duration::from_secs(1u64 << (attempt - 1)); // 1s, 2s, 4s, ...
-            let jitter = Duration::from_millis(rand::random::<u64>() % 500);
-            tokio::time::sleep(base_delay + jitter).await;
+impl PromptStyle {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn directives(&self) -> Vec<String> {
+        let mut directives = Vec::new();
+        if self.type_hints {
+            directives.push("Annotate every function and method signature with type hints (PEP 484).".to_string());
         }
+        if self.docstrings {
+            directives.push("Give every module, class and function a short docstring.".to_string());
+        }
+        if let Some((major, minor)) = self.python_version {
+            directives.push(format!(
+                "Target Python {major}.{minor}: use no syntax or standard-library feature introduced after it."
+            ));
+        }
+        if self.json_result {
+            directives.push(format!(
+                "After any other output, print the program's final result as JSON between two lines reading {RESULT_MARKER} \
+                 (print('{RESULT_MARKER}'); print(json.dumps(result)); print('{RESULT_MARKER}'))."
+            ));
+        }
+        directives
+    }
 
-        let result = client
-            .post(&config.api_url)
-            .headers(headers.clone())
-            .json(&body)
-            .timeout(Duration::from_secs(60))
-            .send()
-            .await;
+    /// Short label for logs and the status line, e.g. `hints,py3.8`.
+    pub fn label(&self) -> String {
+        let mut parts = Vec::new();
+        if self.type_hints {
+            parts.push("hints".to_string());
+        }
+        if self.docstrings {
+            parts.push("docstrings".to_string());
+        }
+        if let Some((major, minor)) = self.python_version {
+            parts.push(format!("py{major}.{minor}"));
+        }
+        if self.json_result {
+            parts.push("result".to_string());
+        }
+        parts.join(",")
+    }
 
-        let resp = match result {
-            Ok(r) => r,
-            Err(e) => {
-                last_err = Some(anyhow!("HTTP error to Hugging Face router: {}", e));
-                continue; // network error → retry
-            }
+    /// Apply `/style` arguments: `hints on|off`, `docstrings on|off`, `pyversion <3.x>|off`,
+    /// `result on|off`, or `reset`.
+    pub fn apply(&mut self, args: &str) -> Result<()> {
+        let words: Vec<&str> = args.split_whitespace().collect();
+        let on_off = |value: &str| match value {
+            "on" => Ok(true),
+            "off" => Ok(false),
+            other => Err(anyhow!("Expected on or off, got '{other}'")),
         };
+        match words.as_slice() {
+            ["reset"] => *self = Self::default(),
+            ["hints", value] => self.type_hints = on_off(value)?,
+            ["docstrings", value] => self.docstrings = on_off(value)?,
+            ["result", value] => self.json_result = on_off(value)?,
+            ["pyversion", "off"] => self.python_version = None,
+            ["pyversion", version] => {
+                let version = parse_python_version(version)
+                    .filter(|&(major, _)| major == 3)
+                    .ok_or_else(|| anyhow!("Expected a Python 3 version such as 3.8, got '{version}'"))?;
+                self.python_version = Some(version);
+            }
+            _ => return Err(anyhow!("Usage: /style hints on|off | docstrings on|off | pyversion <3.x>|off | result on|off | reset")),
+        }
+        Ok(())
+    }
+}
 
-        let status = resp.status();
-        let text_body = resp
-            .text()
-            .await
-            .context("Failed to read Hugging Face response")?;
+/// Core prompt plus the addenda relevant to `user_prompt` (see `prompt_addenda`), then
+/// the `/style` directives.
+pub fn build_system_prompt(profile: Option<&str>, style: &PromptStyle, user_prompt: &str) -> String {
+    let mut prompt = CORE_PROMPT.to_string();
+    for addendum in prompt_addenda(profile, user_prompt) {
+        prompt.push_str("\n\n");
+        prompt.push_str(match addendum {
+            "bug-prevention" => BUG_PREVENTION_ADDENDUM,
+            _ => GAME_ADDENDUM,
+        });
+    }
+    let directives = style.directives();
+    if !directives.is_empty() {
+        prompt.push_str("\n\nSTYLE:");
+        for directive in directives {
+            prompt.push_str("\n- ");
+            prompt.push_str(&directive);
+        }
+    }
+    prompt
+}
+
+/// Every user turn, so a refinement keeps the addenda its original request selected.
+fn user_text(history: &[Message]) -> String {
+    history
+        .iter()
+        .filter(|m| m.role == "user")
+        .map(|m| m.content.as_str())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Short label for logs, e.g. `core+bug-prevention+game+style(hints,py3.8)`.
+pub fn system_prompt_variant(history: &[Message], profile: Option<&str>, style: &PromptStyle) -> String {
+    let mut parts = vec!["core".to_string()];
+    parts.extend(prompt_addenda(profile, &user_text(history)).into_iter().map(str::to_string));
+    if !style.is_empty() {
+        parts.push(format!("style({})", style.label()));
+    }
+    parts.join("+")
+}
 
-        if status.is_success() {
-            let parsed: ChatResponse = serde_json::from_str(&text_body)
-                .context("Failed to parse Hugging Face JSON response")?;
+/// The text of one choice and why the model stopped producing it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Completion {
+    pub content: String,
+    pub finish_reason: Option<String>,
+    /// The quota the provider reported with this response.
+    pub rate: Option<RateInfo>,
+}
+
+impl Completion {
+    /// The model ran out of `max_tokens` before finishing.
+    pub fn truncated(&self) -> bool {
+        self.finish_reason.as_deref() == Some("length")
+    }
+}
+
+/// A successful generation plus how it was obtained.
+#[derive(Debug, Clone)]
+pub struct Generation {
+    pub content: String,
+    /// Number of failed attempts retried before this one succeeded.
+    pub retries: u32,
+    /// True when the output was cut off by the token limit.
+    pub truncated: bool,
+    pub rate: Option<RateInfo>,
+    /// Time held back by the client-side rate limiter before sending.
+    pub paced: Duration,
+}
 
-            let generated = parsed
-                .choices
-                .first()
-                .map(|choice| choice.message.content.clone())
-                .ok_or_else(|| anyhow!("No choices in Hugging Face response"))?;
+/// Request quota reported by the provider's rate-limit response headers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateInfo {
+    pub remaining: Option<u64>,
+    pub limit: Option<u64>,
+    /// When the quota refills.
+    pub reset_at: Option<DateTime<Utc>>,
+}
 
-            return Ok(generated);
+impl RateInfo {
+    /// Read the request quota from response headers. Providers name them differently
+    /// (`x-ratelimit-remaining`, `x-ratelimit-remaining-requests`, `ratelimit-remaining`,
+    /// the combined `ratelimit: limit=…, remaining=…, reset=…`, `retry-after`) and give the
+    /// reset as seconds, a Unix time, a `12m30s` duration or an HTTP date. Token quotas
+    /// are ignored. `None` when no header says anything.
+    pub fn from_headers(headers: &HeaderMap, now: DateTime<Utc>) -> Option<Self> {
+        let mut info = RateInfo { remaining: None, limit: None, reset_at: None };
+        let mut retry_after = None;
+        for (name, value) in headers {
+            let Ok(value) = value.to_str() else { continue };
+            let name = name.as_str().trim_start_matches("x-").replace("rate-limit", "ratelimit");
+            if name.contains("token") {
+                continue;
+            }
+            if name == "ratelimit" {
+                for (key, val) in value.split([',', ';']).filter_map(|part| part.split_once('=')) {
+                    info.set(key.trim(), val.trim(), now);
+                }
+            } else if let Some(key) = name.strip_prefix("ratelimit-") {
+                info.set(key.trim_end_matches("-requests"), value, now);
+            } else if name == "retry-after" {
+                retry_after = parse_reset(value, now);
+            }
         }
+        info.reset_at = info.reset_at.or(retry_after);
+        (info.remaining.is_some() || info.limit.is_some() || info.reset_at.is_some()).then_some(info)
+    }
 
-        // Decide whether to retry based on status code
-        let code = status.as_u16();
-        if code == 429 || (500..600).contains(&code) {
-            last_err = Some(anyhow!("HuggingFace error {}: {}", status, text_body));
-            continue; // rate-limited or server error → retry
+    fn set(&mut self, key: &str, value: &str, now: DateTime<Utc>) {
+        let number = || {
+            let digits: String = value.trim().chars().take_while(char::is_ascii_digit).collect();
+            digits.parse::<u64>().ok()
+        };
+        match key {
+            "remaining" | "r" => self.remaining = self.remaining.or_else(number),
+            "limit" | "l" => self.limit = self.limit.or_else(number),
+            "reset" | "t" => self.reset_at = self.reset_at.or_else(|| parse_reset(value, now)),
+            _ => {}
         }
+    }
 
-        // Client errors (400, 401, 403, etc.) — fail fast
-        return Err(anyhow!("HuggingFace error {}: {}", status, text_body));
+    /// `42 requests remaining, resets in 12m`; empty when nothing useful is known.
+    pub fn summary(&self, now: DateTime<Utc>) -> String {
+        let mut parts = Vec::new();
+        match (self.remaining, self.limit) {
+            (Some(left), Some(limit)) => parts.push(format!("{left}/{limit} requests remaining")),
+            (Some(left), None) => parts.push(format!("{left} requests remaining")),
+            _ => {}
+        }
+        if let Some(wait) = self.reset_at.and_then(|at| (at - now).to_std().ok()) {
+            parts.push(format!("resets in {}", format_wait(wait)));
+        }
+        parts.join(", ")
     }
 
-    Err(last_err.unwrap_or_else(|| anyhow!("All retry attempts exhausted")))
+    /// How long until the quota refills, when it is used up.
+    pub fn exhausted_for(&self, now: DateTime<Utc>) -> Option<Duration> {
+        if self.remaining != Some(0) {
+            return None;
+        }
+        self.reset_at.and_then(|at| (at - now).to_std().ok()).filter(|wait| !wait.is_zero())
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_message_creation() {
-        let msg = Message {
-            role: "user".to_string(),
-            content: "test content".to_string(),
+/// A reset header value as a point in time: seconds from now, a Unix time, a Go-style
+/// duration (`1m30s`, `200ms`) or an HTTP date.
+fn parse_reset(value: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+    if let Ok(number) = value.parse::<f64>() {
+        return match number {
+            n if n > 1e12 => DateTime::from_timestamp_millis(n as i64),
+            n if n > 1e9 => DateTime::from_timestamp(n as i64, 0),
+            n if n >= 0.0 => Some(now + chrono::Duration::milliseconds((n * 1000.0) as i64)),
+            _ => None,
         };
-        assert_eq!(msg.role, "user");
-        assert_eq!(msg.content, "test content");
     }
+    if let Some(caps) = DURATION_RE.captures(value) {
+        let mut millis = 0.0;
+        for part in DURATION_PART_RE.captures_iter(&caps[0]) {
+            let amount: f64 = part[1].parse().ok()?;
+            millis += amount * match &part[2] {
+                "ms" => 1.0,
+                "s" => 1_000.0,
+                "m" => 60_000.0,
+                _ => 3_600_000.0,
+            };
+        }
+        return Some(now + chrono::Duration::milliseconds(millis as i64));
+    }
+    DateTime::parse_from_rfc2822(value).ok().map(|at| at.with_timezone(&Utc))
+}
 
-    #[test]
-    fn test_message_clone() {
-        let msg = Message {
-            role: "assistant".to_string(),
-            content: "response".to_string(),
-        };
-        let cloned = msg.clone();
-        assert_eq!(msg.role, cloned.role);
-        assert_eq!(msg.content, cloned.content);
+static DURATION_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^(\d+(\.\d+)?(ms|s|m|h))+$").unwrap());
+static DURATION_PART_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(\d+(?:\.\d+)?)(ms|s|m|h)").unwrap());
+
+/// `45s`, `12m` or `1h05m`, rounded up.
+fn format_wait(wait: Duration) -> String {
+    let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m", secs.div_ceil(60)),
+        _ => format!("{}h{:02}m", secs / 3600, (secs % 3600).div_ceil(60)),
     }
+}
 
-    #[test]
-    fn test_chat_request_serialization() {
-        let request = ChatRequest {
-            model: "test-model".to_string(),
-            messages: vec![
-                Message {
-                    role: "system".to_string(),
-                    content: "You are helpful".to_string(),
-                },
-                Message {
-                    role: "user".to_string(),
-                    content: "Hello".to_string(),
-                },
-            ],
-            max_tokens: Some(100),
-            temperature: Some(0.5),
-        };
+/// The most recent quota each endpoint and model reported, for `/stats` and for holding
+/// back requests that would only get a 429. Keyed so that switching provider or model
+/// does not carry over a quota that belongs to another one.
+static LAST_RATE: Mutex<BTreeMap<(String, String), RateInfo>> = Mutex::new(BTreeMap::new());
 
-        let json = serde_json::to_string(&request);
-        assert!(json.is_ok());
+fn rate_key(config: &AppConfig) -> (String, String) {
+    (config.api_url.clone(), config.model.clone())
+}
 
-        let json_str = json.unwrap();
-        assert!(json_str.contains("test-model"));
-        assert!(json_str.contains("system"));
-        assert!(json_str.contains("user"));
-        assert!(json_str.contains("Hello"));
+/// The last quota reported for `config`'s endpoint and model.
+pub fn last_rate(config: &AppConfig) -> Option<RateInfo> {
+    LAST_RATE.lock().ok().and_then(|rates| rates.get(&rate_key(config)).cloned())
+}
+
+/// Parse the quota headers of a response and remember them when present.
+fn record_rate(headers: &HeaderMap, config: &AppConfig) -> Option<RateInfo> {
+    let rate = RateInfo::from_headers(headers, Utc::now())?;
+    if let Ok(mut rates) = LAST_RATE.lock() {
+        rates.insert(rate_key(config), rate.clone());
     }
+    Some(rate)
+}
 
-    #[test]
-    fn test_chat_response_deserialization() {
-        let json = r#"{
-            "choices": [
-                {
-                    "message": {
-                        "role": "assistant",
-                        "content": "print('Hello, World!')"
-                    }
+/// Typed failures from the Hugging Face router, so callers can give precise diagnostics.
+#[derive(Debug)]
+pub enum ApiError {
+    /// `HF_TOKEN` is not set.
+    MissingToken,
+    /// `HF_TOKEN` contains characters that can't go in an HTTP header.
+    InvalidToken,
+    /// 401/403: the token was rejected or lacks access to the model.
+    Unauthorized { status: u16, body: String },
+    /// 429: too many requests; `retry_after` is how long the provider asked to wait.
+    RateLimited { body: String, retry_after: Option<Duration> },
+    /// Any other non-success status.
+    Http { status: u16, body: String },
+    /// The request never got a response (DNS, connection, timeout...).
+    Network(String),
+    /// A 2xx response whose body we couldn't use.
+    InvalidResponse(String),
+    /// A 2xx response carrying an `error`/`message` payload instead of choices.
+    Provider { message: String, body_preview: String },
+    /// A 2xx response with an empty `choices` array.
+    EmptyChoices { body_preview: String },
+    /// The connection failed at the proxy or TLS layer (or the client couldn't be configured for it).
+    ProxyOrTls(String),
+    /// The request was not sent: its message list failed `ChatRequest::validate`.
+    InvalidRequest(String),
+    /// A generation request hit one of the configured timeouts.
+    Timeout {
+        /// "connecting" or "waiting for the response".
+        phase: &'static str,
+        secs: u64,
+        /// The config key to raise.
+        setting: &'static str,
+    },
+}
+
+impl ApiError {
+    fn from_status(status: u16, body: String) -> Self {
+        match status {
+            401 | 403 => ApiError::Unauthorized { status, body },
+            429 => ApiError::RateLimited { body, retry_after: None },
+            _ => ApiError::Http { status, body },
+        }
+    }
+
+    /// Attach the wait a 429 response asked for (`Retry-After` or a rate-limit reset).
+    fn with_rate(self, rate: Option<&RateInfo>) -> Self {
+        match self {
+            ApiError::RateLimited { body, .. } => {
+                let retry_after = rate
+                    .and_then(|rate| rate.reset_at)
+                    .map(|reset| (reset - Utc::now()).to_std().unwrap_or_default());
+                ApiError::RateLimited { body, retry_after }
+            }
+            other => other,
+        }
+    }
+
+    /// Network errors, rate limits and server errors are worth retrying.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ApiError::Network(_)
+            | ApiError::RateLimited { .. }
+            | ApiError::EmptyChoices { .. }
+            | ApiError::Timeout { .. } => true,
+            ApiError::Http { status, .. } => (500..600).contains(status),
+            _ => false,
+        }
+    }
+
+    /// The server answered but gave us nothing usable; worth offering a manual retry.
+    pub fn is_soft_failure(&self) -> bool {
+        matches!(self, ApiError::Provider { .. } | ApiError::EmptyChoices { .. })
+    }
+
+    /// Start of the offending response body, for debug logging.
+    pub fn body_preview(&self) -> Option<&str> {
+        match self {
+            ApiError::Provider { body_preview, .. } | ApiError::EmptyChoices { body_preview } => {
+                Some(body_preview)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::MissingToken => write!(f, "No Hugging Face token. {}", auth::missing_token_hint()),
+            ApiError::InvalidToken => write!(f, "Invalid Bearer token format"),
+            ApiError::Unauthorized { status, body } => {
+                let detail = body.trim();
+                let detail = if detail.is_empty() { String::new() } else { format!(" ({})", body_preview(detail)) };
+                if *status == 403 {
+                    write!(
+                        f,
+                        "Hugging Face refused access (403){}: the token lacks permission for this model. Give it the \
+                         \"Make calls to Inference Providers\" permission at https://huggingface.co/settings/tokens \
+                         and accept the model's terms on its Hugging Face page if it is gated",
+                        detail
+                    )
+                } else {
+                    write!(
+                        f,
+                        "Hugging Face rejected the token ({}){}: it is invalid, expired or revoked. Create a new one at \
+                         https://huggingface.co/settings/tokens. {}",
+                        status,
+                        detail,
+                        auth::missing_token_hint()
+                    )
                 }
-            ]
-        }"#;
+            }
+            ApiError::RateLimited { body, .. } => write!(f, "HuggingFace error 429: {}", body),
+            ApiError::Http { status, body } => write!(f, "HuggingFace error {}: {}", status, body),
+            ApiError::Network(e) => write!(f, "HTTP error to Hugging Face router: {}", e),
+            ApiError::InvalidResponse(e) => write!(f, "Invalid Hugging Face response: {}", e),
+            ApiError::Provider { message, .. } => write!(f, "Hugging Face returned an error: {}", message),
+            ApiError::EmptyChoices { .. } => write!(f, "No choices in Hugging Face response"),
+            ApiError::InvalidRequest(reason) => write!(f, "Request not sent, it is invalid: {}", reason),
+            ApiError::Timeout { phase, secs, setting } => write!(
+                f,
+                "Timed out {} after {}s; raise `{}` in pymakebot.toml{}",
+                phase,
+                secs,
+                setting,
+                if *setting == "request_timeout_secs" { " or pass --timeout <secs>" } else { "" }
+            ),
+            ApiError::ProxyOrTls(e) => write!(
+                f,
+                "Proxy/TLS failure reaching the router: {}. Behind a corporate proxy, set HTTPS_PROXY \
+                 or `proxy` in pymakebot.toml; if it re-signs TLS, point `ca_cert` at its CA certificate (PEM)",
+                e
+            ),
+        }
+    }
+}
 
-        let response: Result<ChatResponse, _> = serde_json::from_str(json);
-        assert!(response.is_ok());
+impl std::error::Error for ApiError {}
 
-        let response = response.unwrap();
-        assert_eq!(response.choices.len(), 1);
-        assert_eq!(response.choices[0].message.role, "assistant");
-        assert!(response.choices[0].message.content.contains("print"));
+/// Requests per minute when `BOT_RPM` is unset; generous, it only slows down tight loops.
+const DEFAULT_RPM: u32 = 60;
+/// Requests that may go back to back before the pace applies.
+const RATE_BURST: f64 = 5.0;
+
+/// Client-side token bucket: `rpm` tokens a minute, holding at most `RATE_BURST`.
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    capacity: f64,
+    per_sec: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rpm: u32, now: Instant) -> Self {
+        let capacity = RATE_BURST.min(rpm as f64).max(1.0);
+        Self { tokens: capacity, capacity, per_sec: rpm as f64 / 60.0, updated: now }
     }
 
-    #[test]
-    fn test_message_vector_operations() {
-        let mut messages = vec![
-            Message {
-                role: "user".to_string(),
-                content: "First".to_string(),
-            },
-            Message {
-                role: "assistant".to_string(),
-                content: "Second".to_string(),
-            },
-        ];
+    /// Take a token for a request at `now` and return how long it must wait first.
+    /// Tokens go negative while requests queue, so each waiter gets its own slot.
+    fn acquire(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.capacity);
+        self.updated = now;
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.per_sec)
+        }
+    }
+}
 
-        assert_eq!(messages.len(), 2);
+/// The pace from `BOT_RPM`: `None` when set to 0 (no limit), the default when unset or invalid.
+fn rpm_from(env: Option<String>) -> Option<u32> {
+    match env.and_then(|v| v.trim().parse::<u32>().ok()) {
+        Some(0) => None,
+        Some(rpm) => Some(rpm),
+        None => Some(DEFAULT_RPM),
+    }
+}
 
-        messages.push(Message {
-            role: "user".to_string(),
-            content: "Third".to_string(),
-        });
+/// Shared by every request and retry, so the repair and auto-refine loops keep one global pace.
+static LIMITER: LazyLock<Option<Mutex<TokenBucket>>> = LazyLock::new(|| {
+    rpm_from(std::env::var("BOT_RPM").ok()).map(|rpm| Mutex::new(TokenBucket::new(rpm, Instant::now())))
+});
 
-        assert_eq!(messages.len(), 3);
-        assert_eq!(messages.last().unwrap().content, "Third");
+/// Wait for the client-side rate limiter; returns the time waited.
+async fn pace() -> Duration {
+    let wait = match LIMITER.as_ref() {
+        Some(bucket) => bucket.lock().map(|mut b| b.acquire(Instant::now())).unwrap_or_default(),
+        None => Duration::ZERO,
+    };
+    if !wait.is_zero() {
+        tokio::time::sleep(wait).await;
     }
+    wait
+}
 
-    #[test]
-    fn test_optional_parameters() {
-        let request = ChatRequest {
-            model: "test".to_string(),
-            messages: vec![],
-            max_tokens: None,
-            temperature: None,
-        };
+/// The settings a client is built from; another one is built when they change (`/set`,
+/// `/setup`, a recipe with its own config).
+#[derive(Debug, Clone, PartialEq)]
+struct ClientKey {
+    proxy: Option<String>,
+    ca_cert: Option<String>,
+    danger_accept_invalid_certs: bool,
+    connect_timeout_secs: u64,
+    user_agent: String,
+}
 
-        let json = serde_json::to_string(&request).unwrap();
-        // Optional fields should not appear in JSON when None
-        assert!(!json.contains("max_tokens"));
-        assert!(!json.contains("temperature"));
+impl ClientKey {
+    fn of(config: &AppConfig) -> Self {
+        Self {
+            proxy: config.proxy.clone(),
+            ca_cert: config.ca_cert.clone(),
+            danger_accept_invalid_certs: config.danger_accept_invalid_certs,
+            connect_timeout_secs: config.connect_timeout_secs,
+            user_agent: user_agent(),
+        }
     }
+}
 
-    #[test]
-    fn test_system_prompt_not_empty() {
-        assert!(!SYSTEM_PROMPT.is_empty());
-        assert!(SYSTEM_PROMPT.contains("Python"));
+static CLIENT: Mutex<Option<(ClientKey, reqwest::Client)>> = Mutex::new(None);
+
+/// The HTTP client shared by every request, built on first use from the config and
+/// rebuilt when its settings change. Proxies from HTTPS_PROXY/HTTP_PROXY/NO_PROXY are
+/// honored unless `proxy` overrides them.
+fn client(config: &AppConfig) -> Result<reqwest::Client, ApiError> {
+    let key = ClientKey::of(config);
+    let mut cached = CLIENT.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((built_for, client)) = cached.as_ref() {
+        if *built_for == key {
+            return Ok(client.clone());
+        }
+    }
+    let client = build_client(config)?;
+    *cached = Some((key, client.clone()));
+    Ok(client)
+}
+
+/// The `User-Agent` sent with every request: `BOT_USER_AGENT` when set, otherwise
+/// `python-maker-bot/<version>`.
+fn user_agent() -> String {
+    user_agent_from(std::env::var("BOT_USER_AGENT").ok())
+}
+
+fn user_agent_from(env: Option<String>) -> String {
+    env.map(|ua| ua.trim().to_string())
+        .filter(|ua| !ua.is_empty())
+        .unwrap_or_else(|| format!("python-maker-bot/{}", env!("CARGO_PKG_VERSION")))
+}
+
+fn build_client(config: &AppConfig) -> Result<reqwest::Client, ApiError> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(user_agent())
+        .connect_timeout(Duration::from_secs(config.connect_timeout_secs));
+    if let Some(url) = &config.proxy {
+        let proxy = reqwest::Proxy::all(url)
+            .map_err(|e| ApiError::ProxyOrTls(format!("invalid proxy URL {url}: {e}")))?;
+        builder = builder.proxy(proxy);
+    }
+    if let Some(path) = &config.ca_cert {
+        let pem = std::fs::read(path)
+            .map_err(|e| ApiError::ProxyOrTls(format!("could not read ca_cert {path}: {e}")))?;
+        let cert = reqwest::Certificate::from_pem(&pem)
+            .map_err(|e| ApiError::ProxyOrTls(format!("invalid PEM in ca_cert {path}: {e}")))?;
+        builder = builder.add_root_certificate(cert);
+    }
+    if config.danger_accept_invalid_certs {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    builder
+        .build()
+        .map_err(|e| ApiError::ProxyOrTls(format!("could not build the HTTP client: {e}")))
+}
+
+/// Classify a transport error: proxy and certificate problems, which surface while
+/// connecting, get their own variant.
+fn transport_error(e: reqwest::Error) -> ApiError {
+    let connecting = e.is_connect();
+    let mut chain = e.to_string();
+    let mut source = e.source();
+    while let Some(inner) = source {
+        chain.push_str(": ");
+        chain.push_str(&inner.to_string());
+        source = inner.source();
+    }
+
+    let lower = chain.to_lowercase();
+    if connecting && ["certificate", "tls", "ssl", "proxy", "unknownissuer"].iter().any(|hint| lower.contains(hint)) {
+        ApiError::ProxyOrTls(chain)
+    } else {
+        ApiError::Network(chain)
+    }
+}
+
+/// Apply `request_timeout_secs` (0 = no cap on the whole request).
+fn with_request_timeout(request: reqwest::RequestBuilder, config: &AppConfig) -> reqwest::RequestBuilder {
+    match config.request_timeout_secs {
+        0 => request,
+        secs => request.timeout(Duration::from_secs(secs)),
+    }
+}
+
+/// Like `transport_error`, but timeouts name the setting that fired.
+fn generation_error(e: reqwest::Error, config: &AppConfig) -> ApiError {
+    if !e.is_timeout() {
+        return transport_error(e);
+    }
+    if e.is_connect() {
+        ApiError::Timeout {
+            phase: "connecting",
+            secs: config.connect_timeout_secs,
+            setting: "connect_timeout_secs",
+        }
+    } else {
+        ApiError::Timeout {
+            phase: "waiting for the response",
+            secs: config.request_timeout_secs,
+            setting: "request_timeout_secs",
+        }
+    }
+}
+
+/// Build the authorization headers from the resolved token (`--token`, `HF_TOKEN`, keyring).
+/// A local server (Ollama, llama.cpp...) is called without one when none is set.
+fn auth_headers(config: &AppConfig) -> Result<HeaderMap, ApiError> {
+    match auth::resolve_token() {
+        Some((token, _)) => bearer_headers(&token),
+        None if is_local_url(&config.api_url) => {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            Ok(headers)
+        }
+        None => Err(ApiError::MissingToken),
+    }
+}
+
+/// Whether `url` points at this machine.
+fn is_local_url(url: &str) -> bool {
+    reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| matches!(host, "localhost" | "127.0.0.1" | "[::1]")))
+        .unwrap_or(false)
+}
+
+fn bearer_headers(token: &Secret) -> Result<HeaderMap, ApiError> {
+    let token = token.expose();
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        AUTHORIZATION,
+        HeaderValue::from_str(&format!("Bearer {token}")).map_err(|_| ApiError::InvalidToken)?,
+    );
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    Ok(headers)
+}
+
+/// Send one chat request and return the first choice's content.
+async fn send_chat(
+    client: &reqwest::Client,
+    config: &AppConfig,
+    headers: &HeaderMap,
+    body: &ChatRequest,
+) -> Result<Completion, ApiError> {
+    let mut choices = send_chat_choices(client, config, headers, body).await?;
+    Ok(choices.swap_remove(0))
+}
+
+/// Send one chat request and return every choice (never empty).
+async fn send_chat_choices(
+    client: &reqwest::Client,
+    config: &AppConfig,
+    headers: &HeaderMap,
+    body: &ChatRequest,
+) -> Result<Vec<Completion>, ApiError> {
+    let request = client.post(&config.api_url).headers(headers.clone()).json(body);
+    let resp = with_request_timeout(request, config)
+        .send()
+        .await
+        .map_err(|e| generation_error(e, config))?;
+
+    let status = resp.status();
+    let rate = record_rate(resp.headers(), config);
+    let text_body = resp.text().await.map_err(|e| generation_error(e, config))?;
+
+    if !status.is_success() {
+        return Err(ApiError::from_status(status.as_u16(), text_body).with_rate(rate.as_ref()));
+    }
+
+    let choices = parse_choices(&text_body)?;
+    Ok(choices.into_iter().map(|choice| Completion { rate: rate.clone(), ..choice }).collect())
+}
+
+/// Parse a success body into its choices. Chat completions are expected, but the
+/// inference `generated_text` shapes are accepted too, whichever endpoint sent them.
+fn parse_choices(text_body: &str) -> Result<Vec<Completion>, ApiError> {
+    // Some providers answer 200 with an error payload; surface its text
+    if let Some(message) = provider_error(text_body) {
+        return Err(ApiError::Provider {
+            message,
+            body_preview: body_preview(text_body),
+        });
+    }
+
+    let parsed: SuccessBody = serde_json::from_str(text_body).map_err(|_| {
+        ApiError::InvalidResponse(format!(
+            "Unrecognized response shape (neither chat completions nor generated_text): {}",
+            body_preview(text_body)
+        ))
+    })?;
+    let choices: Vec<Completion> = match parsed {
+        SuccessBody::Chat(chat) => chat
+            .choices
+            .into_iter()
+            .map(|choice| Completion {
+                content: choice.message.content,
+                finish_reason: choice.finish_reason,
+                rate: None,
+            })
+            .collect(),
+        SuccessBody::TextGenerationArray(outputs) => outputs.into_iter().map(Completion::from).collect(),
+        SuccessBody::TextGeneration(output) => vec![output.into()],
+    };
+
+    if choices.is_empty() {
+        return Err(ApiError::EmptyChoices {
+            body_preview: body_preview(text_body),
+        });
+    }
+    Ok(choices)
+}
+
+/// One way of turning a request into generated text.
+pub trait CodeGenerator {
+    async fn generate(
+        &self,
+        client: &reqwest::Client,
+        config: &AppConfig,
+        headers: &HeaderMap,
+        body: &ChatRequest,
+    ) -> Result<Completion, ApiError>;
+}
+
+/// OpenAI-compatible chat completions (the default).
+pub struct ChatBackend;
+
+impl CodeGenerator for ChatBackend {
+    async fn generate(
+        &self,
+        client: &reqwest::Client,
+        config: &AppConfig,
+        headers: &HeaderMap,
+        body: &ChatRequest,
+    ) -> Result<Completion, ApiError> {
+        send_chat(client, config, headers, body).await
+    }
+}
+
+/// HF Inference "text-generation" for completion-only models: the conversation is
+/// flattened into one prompt and posted to `api_url` (the model URL).
+pub struct TextGenerationBackend;
+
+impl TextGenerationBackend {
+    /// Flatten messages into a single prompt ending with an open assistant turn.
+    pub fn flatten(messages: &[Message]) -> String {
+        let mut prompt = String::new();
+        for message in messages {
+            let label = match message.role.as_str() {
+                "system" => "System",
+                "assistant" => "Assistant",
+                _ => "User",
+            };
+            prompt.push_str(&format!("### {label}:\n{}\n\n", message.content.trim_end()));
+        }
+        prompt.push_str("### Assistant:\n");
+        prompt
+    }
+}
+
+impl CodeGenerator for TextGenerationBackend {
+    async fn generate(
+        &self,
+        client: &reqwest::Client,
+        config: &AppConfig,
+        headers: &HeaderMap,
+        body: &ChatRequest,
+    ) -> Result<Completion, ApiError> {
+        let inputs = Self::flatten(&body.messages);
+        let request = TextGenerationRequest {
+            inputs: inputs.clone(),
+            parameters: TextGenerationParameters {
+                max_new_tokens: body.max_tokens,
+                temperature: body.temperature,
+                seed: body.seed,
+                return_full_text: false,
+            },
+        };
+
+        let request = client.post(&config.api_url).headers(headers.clone()).json(&request);
+        let resp = with_request_timeout(request, config)
+            .send()
+            .await
+            .map_err(|e| generation_error(e, config))?;
+
+        let status = resp.status();
+        let rate = record_rate(resp.headers(), config);
+        let text_body = resp.text().await.map_err(|e| generation_error(e, config))?;
+
+        if !status.is_success() {
+            return Err(ApiError::from_status(status.as_u16(), text_body).with_rate(rate.as_ref()));
+        }
+        let mut completion = parse_choices(&text_body)?.swap_remove(0);
+        completion.rate = rate;
+
+        // Some deployments ignore return_full_text and echo the prompt
+        if let Some(rest) = completion.content.strip_prefix(&inputs) {
+            completion.content = rest.to_string();
+        }
+        Ok(completion)
+    }
+}
+
+/// Send one request through the backend selected in the config.
+async fn send_request(
+    client: &reqwest::Client,
+    config: &AppConfig,
+    headers: &HeaderMap,
+    body: &ChatRequest,
+) -> Result<Completion, ApiError> {
+    match config.backend {
+        Backend::Chat => ChatBackend.generate(client, config, headers, body).await,
+        Backend::HfTextgen => TextGenerationBackend.generate(client, config, headers, body).await,
+    }
+}
+
+/// Extract the error text from a `{"error": ...}` or choice-less `{"message": ...}` body.
+fn provider_error(body: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(body).ok()?;
+    let obj = value.as_object()?;
+
+    let text_of = |v: &serde_json::Value| match v {
+        serde_json::Value::String(s) => Some(s.clone()),
+        serde_json::Value::Object(o) => o
+            .get("message")
+            .and_then(|m| m.as_str())
+            .map(str::to_string)
+            .or_else(|| Some(v.to_string())),
+        serde_json::Value::Null => None,
+        other => Some(other.to_string()),
+    };
+
+    if let Some(err) = obj.get("error") {
+        return text_of(err);
+    }
+    if !obj.contains_key("choices") {
+        return obj.get("message").and_then(text_of);
+    }
+    None
+}
+
+/// First 300 characters of a response body.
+fn body_preview(body: &str) -> String {
+    body[..find_char_boundary(body, 300)].to_string()
+}
+
+/// Assemble the exact message array sent to the API: system prompt first, then history.
+pub fn full_messages(history: &[Message], profile: Option<&str>, style: &PromptStyle) -> Vec<Message> {
+    let mut full_messages = vec![Message {
+        role: "system".to_string(),
+        content: build_system_prompt(profile, style, &user_text(history)),
+    }];
+    full_messages.extend_from_slice(history);
+    full_messages
+}
+
+/// Longest `Retry-After` honored between two attempts; a longer one is cut short.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Run `attempt` with exponential backoff on retryable errors, taking a token from the
+/// client-side rate limiter before every attempt. Returns the result, how many failed
+/// attempts preceded it and the time the limiter held them.
+async fn with_retries<T, F, Fut>(config: &AppConfig, mut attempt: F) -> Result<(T, u32, Duration)>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, ApiError>>,
+{
+    let mut last_err: Option<ApiError> = None;
+    let mut paced = Duration::ZERO;
+    for retry in 0..=config.max_retries {
+        if retry > 0 {
+            let base_delay = Duration::from_secs(1u64 << (retry - 1)); // 1s, 2s, 4s, ...
+            let jitter = Duration::from_millis(rand::random::<u64>() % 500);
+            // A 429 says how long to wait; retrying sooner would only get another one
+            let asked = match &last_err {
+                Some(ApiError::RateLimited { retry_after: Some(wait), .. }) => (*wait).min(MAX_RETRY_AFTER),
+                _ => Duration::ZERO,
+            };
+            tokio::time::sleep((base_delay + jitter).max(asked)).await;
+        }
+
+        paced += pace().await;
+        match attempt().await {
+            Ok(value) => return Ok((value, retry, paced)),
+            // Network error, rate-limited or server error → retry
+            Err(e) if e.is_retryable() => last_err = Some(e),
+            // Client errors (400, 401, 403, etc.) — fail fast
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Err(last_err
+        .map(anyhow::Error::from)
+        .unwrap_or_else(|| anyhow!("All retry attempts exhausted")))
+}
+
+/// Rough token count of a request: ~4 characters per token, plus a few per message for role markup.
+pub fn estimate_tokens(messages: &[Message]) -> usize {
+    messages
+        .iter()
+        .map(|m| m.content.chars().count().div_ceil(4) + 4)
+        .sum()
+}
+
+/// Generate code with conversation history for multi-turn refinement
+pub async fn generate_code_with_history(
+    messages: Vec<Message>,
+    config: &AppConfig,
+) -> Result<Generation> {
+    let headers = auth_headers(config)?;
+
+    let body = ChatRequest {
+        model: config.model.clone(),
+        messages: full_messages(&messages, config.profile.as_deref(), &config.style),
+        max_tokens: Some(config.max_tokens),
+        temperature: Some(config.temperature),
+        seed: config.seed,
+        n: None,
+    };
+    body.validate()?;
+
+    let client = &client(config)?;
+    let (completion, retries, paced) = with_retries(config, || send_request(client, config, &headers, &body)).await?;
+    Ok(Generation {
+        truncated: completion.truncated(),
+        content: completion.content,
+        rate: completion.rate,
+        retries,
+        paced,
+    })
+}
+
+/// Results of concurrent generations, by position in the request batch.
+pub struct Candidates {
+    pub succeeded: Vec<(usize, Generation)>,
+    pub failed: Vec<(usize, anyhow::Error)>,
+}
+
+impl Candidates {
+    pub fn get(&self, index: usize) -> Option<&Generation> {
+        self.succeeded.iter().find(|(i, _)| *i == index).map(|(_, g)| g)
+    }
+
+    /// `2 of 5 candidates failed (HuggingFace error 429: ...)`, when any did.
+    pub fn failure_note(&self) -> Option<String> {
+        let (_, first) = self.failed.first()?;
+        let total = self.succeeded.len() + self.failed.len();
+        Some(format!("{} of {} candidates failed ({:#})", self.failed.len(), total, first))
+    }
+}
+
+/// Send one generation per config at the same time. Each goes through the usual
+/// retries, so a candidate answered with 429 waits out its `Retry-After` and is retried
+/// on its own while the others complete; one that still fails doesn't sink the batch.
+pub async fn generate_candidates(messages: &[Message], configs: &[AppConfig]) -> Candidates {
+    let mut batch = tokio::task::JoinSet::new();
+    for (index, config) in configs.iter().cloned().enumerate() {
+        let messages = messages.to_vec();
+        batch.spawn(async move { (index, generate_code_with_history(messages, &config).await) });
+    }
+    let mut candidates = Candidates { succeeded: Vec::new(), failed: Vec::new() };
+    while let Some(joined) = batch.join_next().await {
+        match joined {
+            Ok((index, Ok(generation))) => candidates.succeeded.push((index, generation)),
+            Ok((index, Err(e))) => candidates.failed.push((index, e)),
+            // A panicked request task; its position is lost with it
+            Err(e) => candidates.failed.push((usize::MAX, anyhow!("Candidate request failed: {e}"))),
+        }
+    }
+    candidates.succeeded.sort_by_key(|(i, _)| *i);
+    candidates.failed.sort_by_key(|(i, _)| *i);
+    candidates
+}
+
+/// Ask for `n` alternative completions in a single request (chat backend only).
+/// Providers that ignore `n` return fewer choices.
+pub async fn generate_choices(
+    messages: Vec<Message>,
+    config: &AppConfig,
+    n: u32,
+) -> Result<Vec<String>> {
+    if config.backend != Backend::Chat {
+        return Err(anyhow!("Multiple choices need the chat backend"));
+    }
+    let headers = auth_headers(config)?;
+
+    let body = ChatRequest {
+        model: config.model.clone(),
+        messages: full_messages(&messages, config.profile.as_deref(), &config.style),
+        max_tokens: Some(config.max_tokens),
+        temperature: Some(config.temperature),
+        seed: config.seed,
+        n: Some(n),
+    };
+    body.validate()?;
+
+    let client = &client(config)?;
+    let (choices, _, _) = with_retries(config, || send_chat_choices(client, config, &headers, &body)).await?;
+    Ok(choices.into_iter().map(|c| c.content).collect())
+}
+
+/// System prompt for `/review`: critique only, never a rewrite.
+const REVIEW_PROMPT: &str = "You are a careful Python code reviewer. List potential bugs, edge cases not handled, \
+and risky operations (file deletion, network access, unbounded loops) in the code you are given. \
+Answer with a short numbered list, one finding per item, most serious first. \
+Do not rewrite the code. If you find nothing worth fixing, say so in one sentence.";
+
+/// Findings are short; keep the review cheap.
+const REVIEW_MAX_TOKENS: u32 = 600;
+
+/// Ask the model to critique `code`. Uses its own system prompt and is never part of
+/// the code-generation history.
+pub async fn review_code(code: &str, config: &AppConfig) -> Result<String> {
+    let headers = auth_headers(config)?;
+
+    let body = ChatRequest {
+        model: config.model.clone(),
+        messages: vec![
+            Message { role: "system".to_string(), content: REVIEW_PROMPT.to_string() },
+            Message { role: "user".to_string(), content: format!("```python\n{}\n```", code.trim_end()) },
+        ],
+        max_tokens: Some(REVIEW_MAX_TOKENS.min(config.max_tokens)),
+        temperature: Some(config.temperature),
+        seed: config.seed,
+        n: None,
+    };
+    body.validate()?;
+
+    let client = &client(config)?;
+    let (review, _, _) = with_retries(config, || send_request(client, config, &headers, &body)).await?;
+    Ok(review.content)
+}
+
+const SHORTEN_PROMPT: &str = "You rewrite Python programs to be as short and simple as possible. \
+Keep exactly the same behavior: same inputs, same output format, same command-line usage. \
+Use only the standard library, drop decorative output, banners, colors and optional features, \
+and keep the code readable. Output only the rewritten code in a single ```python block.";
+
+const ANNOTATE_PROMPT: &str = "You add PEP 484 type hints to Python programs. \
+Annotate every function and method signature, and variables whose type isn't obvious. \
+Do not change behavior, names, structure or output; only add annotations and the typing imports they need. \
+Output only the annotated code in a single ```python block.";
+
+/// Ask the model for a minimal, dependency-free rewrite of `code`. Uses its own system
+/// prompt and is never part of the code-generation history; the caller decides whether
+/// the result becomes the next version.
+pub async fn shorten_code(code: &str, config: &AppConfig) -> Result<String> {
+    transform_code(SHORTEN_PROMPT, code, config).await
+}
+
+/// Ask the model to add type hints to `code` without changing it otherwise (`/annotate`).
+/// Like `shorten_code`, outside the code-generation history.
+pub async fn annotate_code(code: &str, config: &AppConfig) -> Result<String> {
+    transform_code(ANNOTATE_PROMPT, code, config).await
+}
+
+const PATCH_PROMPT: &str = "You fix one function of a larger Python program. \
+You are given the program's outline and imports for context, the function, and the change to make. \
+Reply with the complete new version of that one function only: same name, a def at the start of the block, \
+no other functions, no class wrapper, no imports, no usage example. Keep the signature unless the change requires otherwise. \
+Output only the function in a single ```python block.";
+
+/// Ask for a new version of one function (`/patch`): `context` summarizes the rest of the
+/// file, so the reply stays small and the other definitions are left alone.
+pub async fn patch_definition(function: &str, context: &str, instruction: &str, config: &AppConfig) -> Result<String> {
+    let content = format!(
+        "Program context:\n{}\n\nFunction to change:\n```python\n{}\n```\n\nChange: {}",
+        context.trim_end(),
+        function.trim_end(),
+        instruction.trim()
+    );
+    one_off_request(PATCH_PROMPT, content, config).await
+}
+
+const DESCRIBE_PROMPT: &str = "Describe what the given Python script does in one short sentence \
+(at most 12 words, starting with a verb, e.g. \"Counts word frequencies in a text file\"). \
+Reply with the sentence only: no code, no quotes, no preamble.";
+
+/// A description needs a handful of tokens, so it stays cheap whatever `max_tokens` is.
+const DESCRIBE_MAX_TOKENS: u32 = 40;
+
+/// Ask for a one-line description of `code` (`describe`); returns the raw reply.
+pub async fn describe_code(code: &str, config: &AppConfig) -> Result<String> {
+    let config = AppConfig { max_tokens: DESCRIBE_MAX_TOKENS.min(config.max_tokens), ..config.clone() };
+    transform_code(DESCRIBE_PROMPT, code, &config).await
+}
+
+const INIT_PROMPT: &str = "You scaffold small Python projects. \
+Reply with every file of a minimal, working project, each in its own fenced block whose info string is the file's path \
+relative to the project root (```python PACKAGE/main.py, ```toml pyproject.toml). \
+Use a flat layout: a PACKAGE/ directory with __init__.py and main.py (runnable with `python -m PACKAGE.main`, \
+importing the package's other modules absolutely), plus a pyproject.toml with a [project] table naming its dependencies. \
+Standard library only unless the description needs more. No tests, README or explanations.";
+
+/// Ask for a minimal project named `name` (`/init`): the files of package `package`
+/// and a `pyproject.toml`, one fenced block each, named in the info string.
+pub async fn scaffold_project(name: &str, package: &str, description: &str, style: &PromptStyle, config: &AppConfig) -> Result<String> {
+    let mut system_prompt = INIT_PROMPT.replace("PACKAGE", package);
+    for directive in style.directives() {
+        system_prompt.push_str(&format!("\n- {directive}"));
+    }
+    let description = if description.trim().is_empty() { "a starter that prints a greeting" } else { description.trim() };
+    one_off_request(&system_prompt, format!("Project: {name} (package `{package}`)\nWhat it does: {description}"), config).await
+}
+
+/// One-off request rewriting `code` under `system_prompt`; returns the raw reply.
+async fn transform_code(system_prompt: &str, code: &str, config: &AppConfig) -> Result<String> {
+    one_off_request(system_prompt, format!("```python\n{}\n```", code.trim_end()), config).await
+}
+
+/// A request outside the code-generation history: `system_prompt`, then `content`.
+async fn one_off_request(system_prompt: &str, content: String, config: &AppConfig) -> Result<String> {
+    let headers = auth_headers(config)?;
+
+    let body = ChatRequest {
+        model: config.model.clone(),
+        messages: vec![
+            Message { role: "system".to_string(), content: system_prompt.to_string() },
+            Message { role: "user".to_string(), content },
+        ],
+        max_tokens: Some(config.max_tokens),
+        temperature: Some(config.temperature),
+        seed: config.seed,
+        n: None,
+    };
+    body.validate()?;
+
+    let client = &client(config)?;
+    let (reply, _, _) = with_retries(config, || send_request(client, config, &headers, &body)).await?;
+    Ok(reply.content)
+}
+
+/// Send a minimal request to check that the token and endpoint work.
+/// Returns the round-trip latency on success. Never retries.
+pub async fn ping(config: &AppConfig) -> Result<Duration, ApiError> {
+    let headers = auth_headers(config)?;
+    let body = ChatRequest {
+        model: config.model.clone(),
+        messages: vec![Message {
+            role: "user".to_string(),
+            content: "Say ok".to_string(),
+        }],
+        max_tokens: Some(5),
+        temperature: None,
+        seed: None,
+        n: None,
+    };
+
+    let start = Instant::now();
+    send_request(&client(config)?, config, &headers, &body).await?;
+    Ok(start.elapsed())
+}
+
+/// Hugging Face account lookup, used to validate a token.
+const WHOAMI_URL: &str = "https://huggingface.co/api/whoami-v2";
+
+#[derive(Deserialize)]
+struct WhoAmI {
+    name: String,
+}
+
+/// The account name a token belongs to; errors if the token is rejected.
+pub async fn whoami(config: &AppConfig, token: &Secret) -> Result<String, ApiError> {
+    whoami_at(&client(config)?, WHOAMI_URL, token).await
+}
+
+async fn whoami_at(client: &reqwest::Client, url: &str, token: &Secret) -> Result<String, ApiError> {
+    let resp = client
+        .get(url)
+        .headers(bearer_headers(token)?)
+        .timeout(Duration::from_secs(30))
+        .send()
+        .await
+        .map_err(transport_error)?;
+
+    let status = resp.status();
+    let text_body = resp
+        .text()
+        .await
+        .map_err(|e| ApiError::Network(format!("Failed to read whoami response: {}", e)))?;
+    if !status.is_success() {
+        return Err(ApiError::from_status(status.as_u16(), text_body));
+    }
+    let parsed: WhoAmI = serde_json::from_str(&text_body)
+        .map_err(|e| ApiError::InvalidResponse(format!("Failed to parse whoami: {}", e)))?;
+    Ok(parsed.name)
+}
+
+/// The OpenAI-compatible models listing next to a chat completions URL.
+pub fn models_url(api_url: &str) -> String {
+    let base = api_url.trim_end_matches('/');
+    let base = base.strip_suffix("/chat/completions").unwrap_or(base);
+    format!("{base}/models")
+}
+
+/// Heuristic for model ids that are tuned for code.
+pub fn is_code_model(id: &str) -> bool {
+    let id = id.to_lowercase();
+    ["code", "devstral", "deepseek", "qwen3-coder"]
+        .iter()
+        .any(|hint| id.contains(hint))
+}
+
+/// List the model ids the token can use, from the provider's `/models` endpoint.
+pub async fn list_models(config: &AppConfig) -> Result<Vec<String>, ApiError> {
+    let headers = auth_headers(config)?;
+    let resp = client(config)?
+        .get(models_url(&config.api_url))
+        .headers(headers)
+        .timeout(Duration::from_secs(30))
+        .send()
+        .await
+        .map_err(transport_error)?;
+
+    let status = resp.status();
+    let text_body = resp
+        .text()
+        .await
+        .map_err(|e| ApiError::Network(format!("Failed to read models response: {}", e)))?;
+    if !status.is_success() {
+        return Err(ApiError::from_status(status.as_u16(), text_body));
+    }
+
+    let parsed: ModelList = serde_json::from_str(&text_body)
+        .map_err(|e| ApiError::InvalidResponse(format!("Failed to parse model list: {}", e)))?;
+    let mut ids: Vec<String> = parsed.data.into_iter().map(|m| m.id).collect();
+    ids.sort();
+    ids.dedup();
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_creation() {
+        let msg = Message {
+            role: "user".to_string(),
+            content: "test content".to_string(),
+        };
+        assert_eq!(msg.role, "user");
+        assert_eq!(msg.content, "test content");
+    }
+
+    #[test]
+    fn test_message_clone() {
+        let msg = Message {
+            role: "assistant".to_string(),
+            content: "response".to_string(),
+        };
+        let cloned = msg.clone();
+        assert_eq!(msg.role, cloned.role);
+        assert_eq!(msg.content, cloned.content);
+    }
+
+    #[test]
+    fn test_chat_request_serialization() {
+        let request = ChatRequest {
+            model: "test-model".to_string(),
+            messages: vec![
+                Message {
+                    role: "system".to_string(),
+                    content: "You are helpful".to_string(),
+                },
+                Message {
+                    role: "user".to_string(),
+                    content: "Hello".to_string(),
+                },
+            ],
+            max_tokens: Some(100),
+            temperature: Some(0.5),
+            seed: None,
+            n: None,
+        };
+
+        let json = serde_json::to_string(&request);
+        assert!(json.is_ok());
+
+        let json_str = json.unwrap();
+        assert!(json_str.contains("test-model"));
+        assert!(json_str.contains("system"));
+        assert!(json_str.contains("user"));
+        assert!(json_str.contains("Hello"));
+    }
+
+    #[test]
+    fn test_chat_response_deserialization() {
+        let json = r#"{
+            "choices": [
+                {
+                    "message": {
+                        "role": "assistant",
+                        "content": "print('Hello, World!')"
+                    }
+                }
+            ]
+        }"#;
+
+        let response: Result<ChatResponse, _> = serde_json::from_str(json);
+        assert!(response.is_ok());
+
+        let response = response.unwrap();
+        assert_eq!(response.choices.len(), 1);
+        assert_eq!(response.choices[0].message.role, "assistant");
+        assert!(response.choices[0].message.content.contains("print"));
+    }
+
+    #[test]
+    fn test_message_vector_operations() {
+        let mut messages = vec![
+            Message {
+                role: "user".to_string(),
+                content: "First".to_string(),
+            },
+            Message {
+                role: "assistant".to_string(),
+                content: "Second".to_string(),
+            },
+        ];
+
+        assert_eq!(messages.len(), 2);
+
+        messages.push(Message {
+            role: "user".to_string(),
+            content: "Third".to_string(),
+        });
+
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages.last().unwrap().content, "Third");
+    }
+
+    #[test]
+    fn test_optional_parameters() {
+        let request = ChatRequest {
+            model: "test".to_string(),
+            messages: vec![],
+            max_tokens: None,
+            temperature: None,
+            seed: None,
+            n: None,
+        };
+
+        let json = serde_json::to_string(&request).unwrap();
+        // Optional fields should not appear in JSON when None
+        assert!(!json.contains("max_tokens"));
+        assert!(!json.contains("temperature"));
+        assert!(!json.contains("seed"));
+        assert!(!json.contains("\"n\""));
+    }
+
+    #[test]
+    fn test_parse_choices_returns_all_choices() {
+        let json = r#"{"choices": [
+            {"index": 0, "message": {"role": "assistant", "content": "print(1)"}},
+            {"index": 1, "message": {"role": "assistant", "content": "print(2)"}}
+        ]}"#;
+        let contents: Vec<String> = parse_choices(json).unwrap().into_iter().map(|c| c.content).collect();
+        assert_eq!(contents, vec!["print(1)", "print(2)"]);
+    }
+
+    #[test]
+    fn test_parse_choices_accepts_generated_text_shapes() {
+        let array = r#"[{"generated_text": "print('array')", "details": {"finish_reason": "length"}}]"#;
+        let choice = parse_choices(array).unwrap().swap_remove(0);
+        assert_eq!(choice.content, "print('array')");
+        assert!(choice.truncated());
+
+        let single = r#"{"generated_text": "print('single')"}"#;
+        assert_eq!(parse_choices(single).unwrap()[0].content, "print('single')");
+
+        let chat = r#"{"choices": [{"message": {"role": "assistant", "content": "print('chat')"}}]}"#;
+        assert_eq!(parse_choices(chat).unwrap()[0].content, "print('chat')");
+    }
+
+    #[test]
+    fn test_parse_choices_rejects_unknown_shape() {
+        let err = parse_choices(r#"{"output": "print(1)"}"#).unwrap_err();
+        assert!(matches!(err, ApiError::InvalidResponse(ref m) if m.contains("Unrecognized response shape")));
+        assert!(matches!(parse_choices("[]").unwrap_err(), ApiError::EmptyChoices { .. }));
+    }
+
+    #[test]
+    fn test_parse_choices_finish_reason_length() {
+        let json = r#"{"choices": [
+            {"index": 0, "message": {"role": "assistant", "content": "def f(:"}, "finish_reason": "length"}
+        ]}"#;
+        let choice = parse_choices(json).unwrap().swap_remove(0);
+        assert_eq!(choice.finish_reason.as_deref(), Some("length"));
+        assert!(choice.truncated());
+
+        let json = r#"{"choices": [{"message": {"role": "assistant", "content": "x"}, "finish_reason": "stop"}]}"#;
+        assert!(!parse_choices(json).unwrap()[0].truncated());
+        let json = r#"{"choices": [{"message": {"role": "assistant", "content": "x"}}]}"#;
+        assert_eq!(parse_choices(json).unwrap()[0].finish_reason, None);
+    }
+
+    #[test]
+    fn test_validate_request() {
+        let message = |role: &str, content: &str| Message { role: role.to_string(), content: content.to_string() };
+        let request = |messages: Vec<Message>| ChatRequest {
+            model: "m".to_string(),
+            messages,
+            max_tokens: None,
+            temperature: None,
+            seed: None,
+            n: None,
+        };
+        assert!(request(full_messages(&[message("user", "hi")], None, &PromptStyle::default())).validate().is_ok());
+
+        let cases = [
+            (vec![], "empty"),
+            (vec![message("user", "hi")], "not the system prompt"),
+            (full_messages(&[], None, &PromptStyle::default()), "no message after the system prompt"),
+            (full_messages(&[message("user", "hi"), message("assistant", "  \n")], None, &PromptStyle::default()), "message 3 (assistant) has empty content"),
+            (vec![message("system", ""), message("user", "hi")], "message 1 (system) has empty content"),
+        ];
+        for (messages, reason) in cases {
+            let err = request(messages).validate().unwrap_err();
+            assert!(matches!(err, ApiError::InvalidRequest(ref r) if r.contains(reason)), "{err}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_request_is_not_sent() {
+        std::env::set_var("HF_TOKEN", "test-token");
+        let mut server = mockito::Server::new_async().await;
+        let config = AppConfig { api_url: server.url(), max_retries: 2, ..AppConfig::default() };
+        let mock = server.mock("POST", "/").expect(0).create_async().await;
+
+        let err = generate_code_with_history(Vec::new(), &config).await.unwrap_err();
+        assert!(matches!(err.downcast_ref::<ApiError>(), Some(ApiError::InvalidRequest(_))), "{err}");
+        let empty = vec![Message { role: "user".to_string(), content: String::new() }];
+        let err = generate_code_with_history(empty, &config).await.unwrap_err();
+        assert!(err.to_string().contains("has empty content"), "{err}");
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_candidate_does_not_sink_the_others() {
+        std::env::set_var("HF_TOKEN", "test-token");
+        let mut server = mockito::Server::new_async().await;
+        let config = AppConfig { api_url: server.url(), max_retries: 1, ..AppConfig::default() };
+        let model = |name: &str| mockito::Matcher::PartialJsonString(format!(r#"{{"model": "{name}"}}"#));
+        server
+            .mock("POST", "/")
+            .match_body(model("busy"))
+            .with_status(429)
+            .with_header("retry-after", "3")
+            .with_body("slow down")
+            .expect(2)
+            .create_async()
+            .await;
+        server
+            .mock("POST", "/")
+            .match_body(model("ok"))
+            .with_status(200)
+            .with_body(r#"{"choices":[{"message":{"role":"assistant","content":"print(1)"}}]}"#)
+            .expect(2)
+            .create_async()
+            .await;
+
+        let configs: Vec<AppConfig> = ["ok", "busy", "ok"]
+            .iter()
+            .map(|name| AppConfig { model: name.to_string(), ..config.clone() })
+            .collect();
+        let messages = [Message { role: "user".to_string(), content: "hi".to_string() }];
+        let start = Instant::now();
+        let candidates = generate_candidates(&messages, &configs).await;
+        // The 429 was retried once, after the 3 seconds it asked for rather than the 1s backoff
+        assert!(start.elapsed() >= Duration::from_secs(3));
+        assert_eq!(candidates.succeeded.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(candidates.get(2).unwrap().content, "print(1)");
+        assert!(candidates.get(1).is_none());
+        assert_eq!(candidates.failed.len(), 1);
+        assert!(matches!(candidates.failed[0].1.downcast_ref::<ApiError>(), Some(ApiError::RateLimited { .. })));
+        let note = candidates.failure_note().unwrap();
+        assert!(note.starts_with("1 of 3 candidates failed (HuggingFace error 429: slow down"), "{note}");
+    }
+
+    #[test]
+    fn test_rate_limited_error_keeps_the_asked_wait() {
+        let rate = RateInfo { remaining: None, limit: None, reset_at: Some(Utc::now() + chrono::Duration::seconds(30)) };
+        let err = ApiError::from_status(429, String::new()).with_rate(Some(&rate));
+        let ApiError::RateLimited { retry_after: Some(wait), .. } = err else { panic!("{err:?}") };
+        assert!(wait > Duration::from_secs(28) && wait <= Duration::from_secs(30), "{wait:?}");
+        // A reset already past is no wait at all; other errors are unchanged
+        let past = RateInfo { reset_at: Some(Utc::now() - chrono::Duration::seconds(5)), ..rate };
+        let err = ApiError::from_status(429, String::new()).with_rate(Some(&past));
+        assert!(matches!(err, ApiError::RateLimited { retry_after: Some(Duration::ZERO), .. }));
+        assert!(matches!(ApiError::from_status(500, String::new()).with_rate(Some(&past)), ApiError::Http { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_generate_choices_sends_n() {
+        std::env::set_var("HF_TOKEN", "test-token");
+        let mut server = mockito::Server::new_async().await;
+        let config = AppConfig {
+            api_url: server.url(),
+            ..AppConfig::default()
+        };
+        server
+            .mock("POST", "/")
+            .match_body(mockito::Matcher::PartialJsonString(r#"{"n": 2}"#.to_string()))
+            .with_status(200)
+            .with_body(r#"{"choices":[{"message":{"role":"assistant","content":"a"}},{"message":{"role":"assistant","content":"b"}}]}"#)
+            .create_async()
+            .await;
+        let messages = vec![Message { role: "user".to_string(), content: "hi".to_string() }];
+        assert_eq!(generate_choices(messages, &config, 2).await.unwrap(), vec!["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn test_review_code_uses_reviewer_prompt() {
+        std::env::set_var("HF_TOKEN", "test-token");
+        let mut server = mockito::Server::new_async().await;
+        let config = AppConfig {
+            api_url: server.url(),
+            ..AppConfig::default()
+        };
+        server
+            .mock("POST", "/")
+            .match_body(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::Regex("Do not rewrite the code".to_string()),
+                mockito::Matcher::PartialJsonString(format!(r#"{{"max_tokens": {}}}"#, REVIEW_MAX_TOKENS)),
+            ]))
+            .with_status(200)
+            .with_body(r#"{"choices":[{"message":{"role":"assistant","content":"1. No input validation"}}]}"#)
+            .create_async()
+            .await;
+        assert_eq!(review_code("x = int(input())", &config).await.unwrap(), "1. No input validation");
+    }
+
+    #[tokio::test]
+    async fn test_shorten_code_uses_shorten_prompt() {
+        std::env::set_var("HF_TOKEN", "test-token");
+        let mut server = mockito::Server::new_async().await;
+        let config = AppConfig {
+            api_url: server.url(),
+            ..AppConfig::default()
+        };
+        server
+            .mock("POST", "/")
+            .match_body(mockito::Matcher::Regex("Keep exactly the same behavior".to_string()))
+            .with_status(200)
+            .with_body(r#"{"choices":[{"message":{"role":"assistant","content":"print(1)"}}]}"#)
+            .create_async()
+            .await;
+        assert_eq!(shorten_code("print(1)  # long", &config).await.unwrap(), "print(1)");
+    }
+
+    #[tokio::test]
+    async fn test_annotate_code_uses_annotate_prompt() {
+        std::env::set_var("HF_TOKEN", "test-token");
+        let mut server = mockito::Server::new_async().await;
+        let config = AppConfig {
+            api_url: server.url(),
+            ..AppConfig::default()
+        };
+        server
+            .mock("POST", "/")
+            .match_body(mockito::Matcher::Regex("PEP 484 type hints".to_string()))
+            .with_status(200)
+            .with_body(r#"{"choices":[{"message":{"role":"assistant","content":"def f(x: int) -> int: ..."}}]}"#)
+            .create_async()
+            .await;
+        assert_eq!(annotate_code("def f(x): ...", &config).await.unwrap(), "def f(x: int) -> int: ...");
+    }
+
+    #[tokio::test]
+    async fn test_patch_definition_sends_only_the_function() {
+        std::env::set_var("HF_TOKEN", "test-token");
+        let mut server = mockito::Server::new_async().await;
+        let config = AppConfig { api_url: server.url(), ..AppConfig::default() };
+        server
+            .mock("POST", "/")
+            .match_body(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::Regex("You fix one function".to_string()),
+                mockito::Matcher::Regex("Change: use rect collisions".to_string()),
+                mockito::Matcher::Regex("class Game \\(line 3\\)".to_string()),
+            ]))
+            .with_status(200)
+            .with_body(r#"{"choices":[{"message":{"role":"assistant","content":"def collide(self): ..."}}]}"#)
+            .create_async()
+            .await;
+        let reply = patch_definition("def collide(self):\n    return False", "class Game (line 3)", "use rect collisions", &config).await;
+        assert_eq!(reply.unwrap(), "def collide(self): ...");
+    }
+
+    #[tokio::test]
+    async fn test_describe_code_is_a_small_request() {
+        std::env::set_var("HF_TOKEN", "test-token");
+        let mut server = mockito::Server::new_async().await;
+        let config = AppConfig { api_url: server.url(), ..AppConfig::default() };
+        server
+            .mock("POST", "/")
+            .match_body(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::PartialJsonString(format!(r#"{{"max_tokens": {}}}"#, DESCRIBE_MAX_TOKENS)),
+                mockito::Matcher::Regex("in one short sentence".to_string()),
+                mockito::Matcher::Regex("print\\(sum\\(range\\(10\\)\\)\\)".to_string()),
+            ]))
+            .with_status(200)
+            .with_body(r#"{"choices":[{"message":{"role":"assistant","content":"Prints the sum of 0 to 9"}}]}"#)
+            .create_async()
+            .await;
+        let reply = describe_code("print(sum(range(10)))", &config).await;
+        assert_eq!(reply.unwrap(), "Prints the sum of 0 to 9");
+    }
+
+    #[tokio::test]
+    async fn test_scaffold_project_names_the_package() {
+        std::env::set_var("HF_TOKEN", "test-token");
+        let mut server = mockito::Server::new_async().await;
+        let config = AppConfig { api_url: server.url(), ..AppConfig::default() };
+        server
+            .mock("POST", "/")
+            .match_body(mockito::Matcher::AllOf(vec![
+                mockito::Matcher::Regex("python -m my_tool.main".to_string()),
+                mockito::Matcher::Regex("Project: my-tool \\(package `my_tool`\\)".to_string()),
+                mockito::Matcher::Regex("What it does: a greeting starter".to_string()),
+            ]))
+            .with_status(200)
+            .with_body(r#"{"choices":[{"message":{"role":"assistant","content":"files"}}]}"#)
+            .create_async()
+            .await;
+        let reply = scaffold_project("my-tool", "my_tool", "a greeting starter", &PromptStyle::default(), &config).await;
+        assert_eq!(reply.unwrap(), "files");
+    }
+
+    #[test]
+    fn test_full_messages_prepends_system_prompt() {
+        let history = vec![Message {
+            role: "user".to_string(),
+            content: "hi".to_string(),
+        }];
+        let full = full_messages(&history, None, &PromptStyle::default());
+        assert_eq!(full.len(), 2);
+        assert_eq!(full[0].role, "system");
+        assert_eq!(full[0].content, CORE_PROMPT);
+        assert_eq!(full[1].content, "hi");
+    }
+
+    #[test]
+    fn test_system_prompt_not_empty() {
+        assert!(!CORE_PROMPT.is_empty());
+        assert!(CORE_PROMPT.contains("Python"));
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(&[]), 0);
+        let messages = vec![
+            Message { role: "system".to_string(), content: "x".repeat(400) },
+            Message { role: "user".to_string(), content: "hello".to_string() },
+        ];
+        assert_eq!(estimate_tokens(&messages), 104 + 6);
+    }
+
+    #[test]
+    fn test_prompt_addenda_follow_prompt() {
+        assert!(prompt_addenda(None, "print hello world").is_empty());
+        assert!(prompt_addenda(None, "sum the numbers in data.csv").is_empty());
+        assert_eq!(prompt_addenda(None, "Make a Pygame snake"), vec!["bug-prevention", "game"]);
+        assert_eq!(prompt_addenda(None, "a tkinter calculator"), vec!["bug-prevention", "game"]);
+        assert_eq!(prompt_addenda(None, "build some games for kids"), vec!["bug-prevention", "game"]);
+        assert_eq!(prompt_addenda(None, "a Bank class with deposits"), vec!["bug-prevention"]);
+        // Substrings don't count: "program" is not "pong"/"game"
+        assert!(prompt_addenda(None, "a program that classifies text").is_empty());
+    }
+
+    #[test]
+    fn test_prompt_addenda_profiles() {
+        assert_eq!(prompt_addenda(Some("full"), "print hello"), vec!["bug-prevention", "game"]);
+        assert!(prompt_addenda(Some("minimal"), "a pygame game").is_empty());
+        // Unknown profiles fall back to prompt detection
+        assert_eq!(prompt_addenda(Some("other"), "pong"), vec!["bug-prevention", "game"]);
+    }
+
+    #[test]
+    fn test_build_system_prompt() {
+        let plain = PromptStyle::default();
+        assert_eq!(build_system_prompt(None, &plain, "print hello"), CORE_PROMPT);
+        let game = build_system_prompt(None, &plain, "flappy bird");
+        assert!(game.starts_with(CORE_PROMPT));
+        assert!(game.contains(BUG_PREVENTION_ADDENDUM));
+        assert!(game.contains(GAME_ADDENDUM));
+    }
+
+    #[test]
+    fn test_style_directives_extend_the_prompt() {
+        let mut style = PromptStyle::default();
+        style.apply("hints on").unwrap();
+        style.apply("pyversion 3.8").unwrap();
+        let prompt = build_system_prompt(None, &style, "flappy bird");
+        // Appended after the addenda, the core prompt untouched
+        assert!(prompt.starts_with(CORE_PROMPT));
+        assert!(prompt.ends_with(
+            "STYLE:\n- Annotate every function and method signature with type hints (PEP 484).\n\
+             - Target Python 3.8: use no syntax or standard-library feature introduced after it."
+        ));
+        assert!(prompt.find(GAME_ADDENDUM) < prompt.find("STYLE:"));
+        let history = [Message { role: "user".to_string(), content: "print hello".to_string() }];
+        assert_eq!(system_prompt_variant(&history, None, &style), "core+style(hints,py3.8)");
+
+        style.apply("hints off").unwrap();
+        style.apply("docstrings on").unwrap();
+        assert_eq!(style.label(), "docstrings,py3.8");
+        style.apply("pyversion off").unwrap();
+        assert_eq!(style.label(), "docstrings");
+        style.apply("result on").unwrap();
+        assert_eq!(style.label(), "docstrings,result");
+        assert!(style.directives()[1].contains("json.dumps(result)"));
+        style.apply("reset").unwrap();
+        assert!(style.is_empty());
+        assert_eq!(build_system_prompt(None, &style, "print hello"), CORE_PROMPT);
+
+        for bad in ["", "hints", "hints maybe", "pyversion 2.7", "pyversion x", "colors on"] {
+            assert!(style.apply(bad).is_err(), "{bad}");
+        }
+        assert!(style.is_empty());
+    }
+
+    #[test]
+    fn test_refinement_keeps_addenda() {
+        let history = vec![
+            Message { role: "user".to_string(), content: "a pong game".to_string() },
+            Message { role: "assistant".to_string(), content: "import pygame".to_string() },
+            Message { role: "user".to_string(), content: "make it faster".to_string() },
+        ];
+        assert_eq!(system_prompt_variant(&history, None, &PromptStyle::default()), "core+bug-prevention+game");
+        assert_eq!(system_prompt_variant(&history[2..], None, &PromptStyle::default()), "core");
+    }
+
+    #[test]
+    fn test_api_error_from_status() {
+        assert!(matches!(ApiError::from_status(401, String::new()), ApiError::Unauthorized { .. }));
+        assert!(matches!(ApiError::from_status(403, String::new()), ApiError::Unauthorized { .. }));
+        assert!(matches!(ApiError::from_status(429, String::new()), ApiError::RateLimited { .. }));
+        assert!(matches!(ApiError::from_status(500, String::new()), ApiError::Http { status: 500, .. }));
+    }
+
+    #[test]
+    fn test_provider_error_probe() {
+        assert_eq!(provider_error(r#"{"error": "Model too busy"}"#).as_deref(), Some("Model too busy"));
+        assert_eq!(
+            provider_error(r#"{"error": {"message": "overloaded", "type": "server"}}"#).as_deref(),
+            Some("overloaded")
+        );
+        assert_eq!(provider_error(r#"{"message": "Service unavailable"}"#).as_deref(), Some("Service unavailable"));
+        assert_eq!(provider_error(r#"{"choices": [], "message": "ignored"}"#), None);
+        assert_eq!(provider_error(r#"{"choices": [{"message": {"role": "assistant", "content": "x"}}]}"#), None);
+        assert_eq!(provider_error("not json"), None);
+    }
+
+    #[test]
+    fn test_body_preview_is_bounded() {
+        assert_eq!(body_preview(&"é".repeat(400)).len(), 300);
+        assert_eq!(body_preview("short"), "short");
+    }
+
+    #[tokio::test]
+    async fn test_soft_failures_are_typed() {
+        std::env::set_var("HF_TOKEN", "test-token");
+        let mut server = mockito::Server::new_async().await;
+        let config = AppConfig {
+            api_url: server.url(),
+            ..AppConfig::default()
+        };
+        let headers = auth_headers(&config).unwrap();
+        let client = reqwest::Client::new();
+        let body = ChatRequest {
+            model: "m".to_string(),
+            messages: vec![],
+            max_tokens: None,
+            temperature: None,
+            seed: None,
+            n: None,
+        };
+
+        let busy = server
+            .mock("POST", "/")
+            .with_status(200)
+            .with_body(r#"{"error": "Model too busy"}"#)
+            .create_async()
+            .await;
+        let err = send_chat(&client, &config, &headers, &body).await.unwrap_err();
+        assert!(matches!(&err, ApiError::Provider { message, .. } if message == "Model too busy"));
+        assert!(err.is_soft_failure());
+        assert!(!err.is_retryable());
+        busy.remove_async().await;
+
+        server
+            .mock("POST", "/")
+            .with_status(200)
+            .with_body(r#"{"choices": []}"#)
+            .create_async()
+            .await;
+        let err = send_chat(&client, &config, &headers, &body).await.unwrap_err();
+        assert!(matches!(err, ApiError::EmptyChoices { .. }));
+        assert!(err.is_soft_failure());
+        assert!(err.is_retryable());
+        assert_eq!(err.body_preview(), Some(r#"{"choices": []}"#));
+    }
+
+    #[test]
+    fn test_api_error_is_retryable() {
+        assert!(ApiError::Network("reset".to_string()).is_retryable());
+        assert!(ApiError::from_status(429, String::new()).is_retryable());
+        assert!(ApiError::from_status(503, String::new()).is_retryable());
+        assert!(!ApiError::from_status(400, String::new()).is_retryable());
+        assert!(!ApiError::from_status(401, String::new()).is_retryable());
+        assert!(!ApiError::MissingToken.is_retryable());
+    }
+
+    #[test]
+    fn test_models_url() {
+        assert_eq!(
+            models_url("https://router.huggingface.co/v1/chat/completions"),
+            "https://router.huggingface.co/v1/models"
+        );
+        assert_eq!(models_url("http://localhost:1234/"), "http://localhost:1234/models");
+    }
+
+    #[test]
+    fn test_is_code_model() {
+        assert!(is_code_model("Qwen/Qwen2.5-Coder-32B-Instruct"));
+        assert!(is_code_model("bigcode/starcoder2-15b"));
+        assert!(!is_code_model("meta-llama/Llama-3.1-8B-Instruct"));
+    }
+
+    #[tokio::test]
+    async fn test_list_models() {
+        std::env::set_var("HF_TOKEN", "test-token");
+        let mut server = mockito::Server::new_async().await;
+        let config = AppConfig {
+            api_url: format!("{}/v1/chat/completions", server.url()),
+            ..AppConfig::default()
+        };
+
+        let listing = server
+            .mock("GET", "/v1/models")
+            .with_status(200)
+            .with_body(r#"{"object":"list","data":[{"id":"b/coder"},{"id":"a/chat"}]}"#)
+            .create_async()
+            .await;
+        assert_eq!(list_models(&config).await.unwrap(), vec!["a/chat", "b/coder"]);
+        listing.remove_async().await;
+
+        server.mock("GET", "/v1/models").with_status(404).create_async().await;
+        let err = list_models(&config).await.unwrap_err();
+        assert!(matches!(err, ApiError::Http { status: 404, .. }));
+    }
+
+    #[test]
+    fn test_text_generation_flatten() {
+        let messages = vec![
+            Message { role: "system".to_string(), content: "Be terse.".to_string() },
+            Message { role: "user".to_string(), content: "add two numbers".to_string() },
+            Message { role: "assistant".to_string(), content: "print(1 + 2)\n".to_string() },
+            Message { role: "user".to_string(), content: "use input()".to_string() },
+        ];
+        assert_eq!(
+            TextGenerationBackend::flatten(&messages),
+            "### System:\nBe terse.\n\n### User:\nadd two numbers\n\n\
+             ### Assistant:\nprint(1 + 2)\n\n### User:\nuse input()\n\n### Assistant:\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_text_generation_backend() {
+        std::env::set_var("HF_TOKEN", "test-token");
+        let mut server = mockito::Server::new_async().await;
+        let config = AppConfig {
+            api_url: server.url(),
+            backend: Backend::HfTextgen,
+            ..AppConfig::default()
+        };
+        let messages = vec![Message { role: "user".to_string(), content: "hi".to_string() }];
+        let prompt = TextGenerationBackend::flatten(&messages);
+
+        let echoed = server
+            .mock("POST", "/")
+            .match_body(mockito::Matcher::PartialJsonString(
+                r#"{"parameters": {"max_new_tokens": 16284, "return_full_text": false}}"#.to_string(),
+            ))
+            .with_status(200)
+            .with_body(serde_json::json!([{ "generated_text": format!("{prompt}print('hi')") }]).to_string())
+            .create_async()
+            .await;
+        let body = ChatRequest {
+            model: config.model.clone(),
+            messages,
+            max_tokens: Some(config.max_tokens),
+            temperature: None,
+            seed: None,
+            n: None,
+        };
+        let headers = auth_headers(&config).unwrap();
+        let text = send_request(&reqwest::Client::new(), &config, &headers, &body).await.unwrap();
+        assert_eq!(text.content, "print('hi')");
+        echoed.remove_async().await;
+
+        server.mock("POST", "/").with_status(200).with_body("[]").create_async().await;
+        let err = send_request(&reqwest::Client::new(), &config, &headers, &body).await.unwrap_err();
+        assert!(matches!(err, ApiError::EmptyChoices { .. }));
+    }
+
+    #[test]
+    fn test_build_client_rejects_bad_ca_cert() {
+        let config = AppConfig {
+            ca_cert: Some("definitely/not/a/cert.pem".to_string()),
+            ..AppConfig::default()
+        };
+        let err = build_client(&config).unwrap_err();
+        assert!(matches!(&err, ApiError::ProxyOrTls(msg) if msg.contains("ca_cert")));
+        assert!(!err.is_retryable());
+
+        let config = AppConfig {
+            proxy: Some("http://proxy.internal:3128".to_string()),
+            danger_accept_invalid_certs: true,
+            ..AppConfig::default()
+        };
+        assert!(build_client(&config).is_ok());
+    }
+
+    #[test]
+    fn test_client_is_rebuilt_when_its_settings_change() {
+        assert!(client(&AppConfig::default()).is_ok());
+        let bad = AppConfig { ca_cert: Some("definitely/not/a/cert.pem".to_string()), ..AppConfig::default() };
+        assert!(matches!(client(&bad), Err(ApiError::ProxyOrTls(_))));
+        let slow = AppConfig { connect_timeout_secs: 1, ..AppConfig::default() };
+        assert_ne!(ClientKey::of(&slow), ClientKey::of(&AppConfig::default()));
+    }
+
+    #[tokio::test]
+    async fn test_whoami() {
+        let mut server = mockito::Server::new_async().await;
+        let url = format!("{}/api/whoami-v2", server.url());
+        let client = reqwest::Client::new();
+        let ok = server
+            .mock("GET", "/api/whoami-v2")
+            .match_header("authorization", "Bearer good")
+            .with_status(200)
+            .with_body(r#"{"type":"user","name":"alice"}"#)
+            .create_async()
+            .await;
+        assert_eq!(whoami_at(&client, &url, &Secret::new("good")).await.unwrap(), "alice");
+        ok.remove_async().await;
+
+        server.mock("GET", "/api/whoami-v2").with_status(401).create_async().await;
+        let err = whoami_at(&client, &url, &Secret::new("bad")).await.unwrap_err();
+        assert!(matches!(err, ApiError::Unauthorized { status: 401, .. }));
+    }
+
+    #[test]
+    fn test_timeout_error_names_setting() {
+        let err = ApiError::Timeout {
+            phase: "waiting for the response",
+            secs: 300,
+            setting: "request_timeout_secs",
+        };
+        let text = err.to_string();
+        assert!(text.contains("300s") && text.contains("request_timeout_secs") && text.contains("--timeout"));
+        assert!(err.is_retryable());
+    }
+
+    #[test]
+    fn test_is_local_url() {
+        assert!(is_local_url("http://localhost:11434/v1/chat/completions"));
+        assert!(is_local_url("http://127.0.0.1:8080/v1/chat/completions"));
+        assert!(is_local_url("http://[::1]:11434/v1"));
+        assert!(!is_local_url("https://router.huggingface.co/v1/chat/completions"));
+        assert!(!is_local_url("not a url"));
+    }
+
+    #[tokio::test]
+    async fn test_unauthorized_is_explained_and_not_retried() {
+        std::env::set_var("HF_TOKEN", "test-token");
+        let mut server = mockito::Server::new_async().await;
+        let config = AppConfig { api_url: server.url(), ..AppConfig::default() };
+        let mock = server
+            .mock("POST", "/")
+            .with_status(401)
+            .with_body(r#"{"error":"Invalid credentials in Authorization header"}"#)
+            .expect(1)
+            .create_async()
+            .await;
+        let history = vec![Message { role: "user".to_string(), content: "hi".to_string() }];
+        let err = generate_code_with_history(history, &config).await.unwrap_err();
+        mock.assert_async().await;
+        let text = err.to_string();
+        assert!(text.starts_with("Hugging Face rejected the token (401)"), "{text}");
+        assert!(text.contains("Invalid credentials") && text.contains("HF_TOKEN"), "{text}");
+
+        let forbidden = ApiError::from_status(403, String::new()).to_string();
+        assert!(forbidden.starts_with("Hugging Face refused access (403): the token lacks permission"), "{forbidden}");
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.insert(*name, HeaderValue::from_static(value));
+        }
+        map
+    }
+
+    #[test]
+    fn test_rate_info_from_captured_header_sets() {
+        let now = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let in_secs = |secs: i64| Some(now + chrono::Duration::seconds(secs));
+
+        // OpenAI-compatible providers: per-request and per-token quotas, Go durations
+        let rate = RateInfo::from_headers(&headers(&[
+            ("x-ratelimit-limit-requests", "500"),
+            ("x-ratelimit-remaining-requests", "42"),
+            ("x-ratelimit-reset-requests", "12m0s"),
+            ("x-ratelimit-remaining-tokens", "9000"),
+            ("x-ratelimit-reset-tokens", "6ms"),
+        ]), now).unwrap();
+        assert_eq!(rate, RateInfo { remaining: Some(42), limit: Some(500), reset_at: in_secs(720) });
+        assert_eq!(rate.summary(now), "42/500 requests remaining, resets in 12m");
+
+        // Plain x-ratelimit-* with a Unix reset time
+        let rate = RateInfo::from_headers(&headers(&[
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset", "1700000090"),
+        ]), now).unwrap();
+        assert_eq!(rate.reset_at, in_secs(90));
+        assert_eq!(rate.exhausted_for(now), Some(Duration::from_secs(90)));
+        assert_eq!(rate.summary(now), "0 requests remaining, resets in 2m");
+
+        // IETF draft: separate fields with a policy, or one combined header
+        let rate = RateInfo::from_headers(&headers(&[
+            ("ratelimit-limit", "100, 100;w=3600"),
+            ("ratelimit-remaining", "7"),
+            ("ratelimit-reset", "30"),
+            ("ratelimit-policy", "100;w=3600"),
+        ]), now).unwrap();
+        assert_eq!(rate, RateInfo { remaining: Some(7), limit: Some(100), reset_at: in_secs(30) });
+        let rate = RateInfo::from_headers(&headers(&[("ratelimit", "limit=100, remaining=0, reset=3900")]), now).unwrap();
+        assert_eq!(rate.summary(now), "0/100 requests remaining, resets in 1h05m");
+
+        // A 429 with only Retry-After, as an HTTP date
+        let rate = RateInfo::from_headers(&headers(&[("retry-after", "Tue, 14 Nov 2023 22:13:21 GMT")]), now).unwrap();
+        assert_eq!(rate.reset_at, in_secs(1));
+        assert_eq!(rate.exhausted_for(now), None);
+
+        assert_eq!(RateInfo::from_headers(&headers(&[("content-type", "application/json"), ("x-ratelimit-reset", "soon")]), now), None);
+    }
+
+    #[tokio::test]
+    async fn test_generation_carries_rate_info() {
+        std::env::set_var("HF_TOKEN", "test-token");
+        let mut server = mockito::Server::new_async().await;
+        let config = AppConfig { api_url: server.url(), max_retries: 0, ..AppConfig::default() };
+        server
+            .mock("POST", "/")
+            .with_status(200)
+            .with_header("x-ratelimit-remaining", "41")
+            .with_header("x-ratelimit-reset", "600")
+            .with_body(r#"{"choices":[{"message":{"role":"assistant","content":"print(1)"}}]}"#)
+            .create_async()
+            .await;
+        let history = vec![Message { role: "user".to_string(), content: "hi".to_string() }];
+        let generation = generate_code_with_history(history, &config).await.unwrap();
+        let rate = generation.rate.unwrap();
+        assert_eq!(rate.remaining, Some(41));
+        assert!(rate.summary(Utc::now()).starts_with("41 requests remaining, resets in 10m"), "{}", rate.summary(Utc::now()));
+        assert_eq!(last_rate(&config).and_then(|rate| rate.remaining), Some(41));
+        // Another model on the same endpoint has its own quota
+        assert_eq!(last_rate(&AppConfig { model: "other/model".to_string(), ..config }), None);
+    }
+
+    #[test]
+    fn test_user_agent_override() {
+        assert_eq!(user_agent_from(None), format!("python-maker-bot/{}", env!("CARGO_PKG_VERSION")));
+        assert_eq!(user_agent_from(Some("  ".to_string())), user_agent_from(None));
+        assert_eq!(user_agent_from(Some("acme-bot/2".to_string())), "acme-bot/2");
+    }
+
+    #[test]
+    fn test_token_bucket_paces_after_the_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(30, start);
+        // The burst goes straight out
+        for _ in 0..5 {
+            assert_eq!(bucket.acquire(start), Duration::ZERO);
+        }
+        // Then one request every 2s (30 per minute), queued requests each a slot later
+        assert_eq!(bucket.acquire(start), Duration::from_secs(2));
+        assert_eq!(bucket.acquire(start), Duration::from_secs(4));
+        // Waiting out the queue leaves no token in hand
+        assert_eq!(bucket.acquire(start + Duration::from_secs(4)), Duration::from_secs(2));
+        // A long pause refills up to the burst, no more
+        let later = start + Duration::from_secs(600);
+        for _ in 0..5 {
+            assert_eq!(bucket.acquire(later), Duration::ZERO);
+        }
+        assert!(bucket.acquire(later) > Duration::ZERO);
+
+        // Tiny limits still let one request through
+        let mut slow = TokenBucket::new(1, start);
+        assert_eq!(slow.acquire(start), Duration::ZERO);
+        assert_eq!(slow.acquire(start), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_rpm_from_env() {
+        assert_eq!(rpm_from(None), Some(DEFAULT_RPM));
+        assert_eq!(rpm_from(Some("12".to_string())), Some(12));
+        assert_eq!(rpm_from(Some("0".to_string())), None);
+        assert_eq!(rpm_from(Some("fast".to_string())), Some(DEFAULT_RPM));
+    }
+
+    #[tokio::test]
+    async fn test_requests_carry_user_agent() {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/")
+            .match_header("user-agent", user_agent().as_str())
+            .with_status(200)
+            .create_async()
+            .await;
+        let client = build_client(&AppConfig::default()).unwrap();
+        let response = client.get(server.url()).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_ping_success_and_unauthorized() {
+        std::env::set_var("HF_TOKEN", "test-token");
+        let mut server = mockito::Server::new_async().await;
+        let config = AppConfig {
+            api_url: server.url(),
+            ..AppConfig::default()
+        };
+
+        let ok = server
+            .mock("POST", "/")
+            .with_status(200)
+            .with_body(r#"{"choices":[{"message":{"role":"assistant","content":"ok"}}]}"#)
+            .create_async()
+            .await;
+        assert!(ping(&config).await.is_ok());
+        ok.remove_async().await;
+
+        server
+            .mock("POST", "/")
+            .with_status(401)
+            .with_body("Invalid credentials")
+            .create_async()
+            .await;
+        let err = ping(&config).await.unwrap_err();
+        assert!(matches!(err, ApiError::Unauthorized { status: 401, .. }));
     }
 }
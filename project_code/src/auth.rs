@@ -0,0 +1,253 @@
+use crate::api;
+use crate::config::AppConfig;
+use crate::theme::Themed;
+use colored::*;
+use std::fmt;
+use std::sync::OnceLock;
+
+/// Keyring service and account the token is stored under.
+#[cfg(feature = "keyring")]
+const KEYRING_SERVICE: &str = "python-maker-bot";
+#[cfg(feature = "keyring")]
+const KEYRING_USER: &str = "hf-token";
+
+/// A token that never shows up in `Debug` output or logs.
+#[derive(Clone, Default, PartialEq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret(***)")
+    }
+}
+
+/// Where the token in use came from, in resolution order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenSource {
+    Flag,
+    Env,
+    Keyring,
+}
+
+impl fmt::Display for TokenSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenSource::Flag => write!(f, "--token flag"),
+            TokenSource::Env => write!(f, "HF_TOKEN environment variable (or .env)"),
+            TokenSource::Keyring => write!(f, "OS keyring"),
+        }
+    }
+}
+
+/// `auth` subcommands.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuthCommand {
+    Login,
+    Logout,
+    Status,
+}
+
+static CLI_TOKEN: OnceLock<Secret> = OnceLock::new();
+
+/// Remember the `--token` value for the rest of the process.
+pub fn set_cli_token(token: Secret) {
+    let _ = CLI_TOKEN.set(token);
+}
+
+/// Resolve the token: `--token` flag, then `HF_TOKEN`, then the OS keyring.
+pub fn resolve_token() -> Option<(Secret, TokenSource)> {
+    pick_token(
+        CLI_TOKEN.get().cloned(),
+        std::env::var("HF_TOKEN").ok(),
+        keyring_token,
+    )
+}
+
+/// Resolution order, with the keyring only consulted when needed.
+fn pick_token(
+    flag: Option<Secret>,
+    env: Option<String>,
+    keyring: impl FnOnce() -> Option<String>,
+) -> Option<(Secret, TokenSource)> {
+    let non_empty = |s: &String| !s.trim().is_empty();
+    if let Some(token) = flag.filter(|t| non_empty(&t.0)) {
+        return Some((token, TokenSource::Flag));
+    }
+    if let Some(token) = env.filter(non_empty) {
+        return Some((Secret(token), TokenSource::Env));
+    }
+    keyring()
+        .filter(non_empty)
+        .map(|token| (Secret(token), TokenSource::Keyring))
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_entry() -> keyring::Result<keyring::Entry> {
+    keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER)
+}
+
+/// Store `token` in the OS keyring, replacing any previous one.
+#[cfg(feature = "keyring")]
+pub fn store_token(token: &Secret) -> keyring::Result<()> {
+    keyring_entry().and_then(|entry| entry.set_password(token.expose()))
+}
+
+#[cfg(feature = "keyring")]
+fn keyring_token() -> Option<String> {
+    keyring_entry().and_then(|entry| entry.get_password()).ok()
+}
+
+#[cfg(not(feature = "keyring"))]
+fn keyring_token() -> Option<String> {
+    None
+}
+
+/// Run an `auth` subcommand. Returns true on success.
+pub async fn run(command: AuthCommand, config: &AppConfig) -> bool {
+    match command {
+        AuthCommand::Login => login(config).await,
+        AuthCommand::Logout => logout(),
+        AuthCommand::Status => status(config).await,
+    }
+}
+
+#[cfg(feature = "keyring")]
+async fn login(config: &AppConfig) -> bool {
+    let token = match rpassword::prompt_password("Hugging Face token (input hidden): ") {
+        Ok(t) => Secret(t.trim().to_string()),
+        Err(e) => {
+            println!("{} {}", "✗ Could not read the token:".error_color(), e);
+            return false;
+        }
+    };
+    if token.0.is_empty() {
+        println!("{}", "✗ No token entered.".error_color());
+        return false;
+    }
+
+    let account = match api::whoami(config, &token).await {
+        Ok(name) => name,
+        Err(e) => {
+            println!("{} {}", "✗ Token rejected:".error_color(), e);
+            return false;
+        }
+    };
+    match store_token(&token) {
+        Ok(()) => {
+            println!("{} {}", "✓ Token stored in the OS keyring for".success_color(), account.emphasis_color());
+            true
+        }
+        Err(e) => {
+            println!("{} {}", "✗ Could not store the token:".error_color(), e);
+            false
+        }
+    }
+}
+
+#[cfg(feature = "keyring")]
+fn logout() -> bool {
+    match keyring_entry().and_then(|entry| entry.delete_credential()) {
+        Ok(()) => {
+            println!("{}", "✓ Token removed from the OS keyring.".success_color());
+            true
+        }
+        Err(keyring::Error::NoEntry) => {
+            println!("{}", "No token stored in the OS keyring.".warning_color());
+            true
+        }
+        Err(e) => {
+            println!("{} {}", "✗ Could not remove the token:".error_color(), e);
+            false
+        }
+    }
+}
+
+#[cfg(not(feature = "keyring"))]
+async fn login(_config: &AppConfig) -> bool {
+    keyring_unsupported()
+}
+
+#[cfg(not(feature = "keyring"))]
+fn logout() -> bool {
+    keyring_unsupported()
+}
+
+#[cfg(not(feature = "keyring"))]
+fn keyring_unsupported() -> bool {
+    println!("{}", "✗ This build has no keyring support; rebuild with `--features keyring`.".error_color());
+    false
+}
+
+async fn status(config: &AppConfig) -> bool {
+    let Some((token, source)) = resolve_token() else {
+        println!("{}", "✗ No token found.".error_color());
+        println!("  {}", missing_token_hint().dimmed());
+        return false;
+    };
+    println!("{} {}", "Token source:".heading_color(), source);
+    match api::whoami(config, &token).await {
+        Ok(name) => {
+            println!("{} {}", "Account:".heading_color(), name.emphasis_color());
+            true
+        }
+        Err(e) => {
+            println!("{} {}", "✗ Token check failed:".error_color(), e);
+            false
+        }
+    }
+}
+
+/// Where to put a token, depending on what this build supports.
+pub fn missing_token_hint() -> &'static str {
+    if cfg!(feature = "keyring") {
+        "Run `auth login`, set HF_TOKEN (environment or .env), or pass --token."
+    } else {
+        "Set HF_TOKEN (environment or .env) or pass --token."
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_debug_is_redacted() {
+        let secret = Secret::new("hf_abcdef");
+        assert_eq!(format!("{:?}", secret), "Secret(***)");
+        assert_eq!(secret.expose(), "hf_abcdef");
+    }
+
+    #[test]
+    fn test_pick_token_order() {
+        let flag = Some(Secret::new("from-flag"));
+        let env = Some("from-env".to_string());
+        let keyring = || Some("from-keyring".to_string());
+
+        let (token, source) = pick_token(flag, env.clone(), keyring).unwrap();
+        assert_eq!((token.expose(), source), ("from-flag", TokenSource::Flag));
+
+        let (token, source) = pick_token(None, env, keyring).unwrap();
+        assert_eq!((token.expose(), source), ("from-env", TokenSource::Env));
+
+        let (token, source) = pick_token(None, Some("  ".to_string()), keyring).unwrap();
+        assert_eq!((token.expose(), source), ("from-keyring", TokenSource::Keyring));
+
+        assert!(pick_token(None, None, || None).is_none());
+    }
+
+    #[test]
+    fn test_keyring_not_consulted_when_env_set() {
+        let result = pick_token(None, Some("env".to_string()), || panic!("keyring read"));
+        assert_eq!(result.unwrap().1, TokenSource::Env);
+    }
+}
@@ -0,0 +1,149 @@
+use crate::api::Message;
+use crate::export::SessionTurn;
+use crate::provenance::Provenance;
+
+/// Name of the branch every session starts on.
+pub const MAIN_BRANCH: &str = "main";
+
+/// A conversation line kept aside while another one is active (`/branch`).
+#[derive(Debug, Clone)]
+pub struct Branch {
+    pub name: String,
+    pub history: Vec<Message>,
+    pub last_code: String,
+    pub last_provenance: Option<Provenance>,
+    /// The code versions of the line (`/versions`, `/deps --diff`, the exports).
+    pub turns: Vec<SessionTurn>,
+}
+
+impl Branch {
+    /// A new line named `name` that restarts after exchange `n` of this one: its first
+    /// `n` exchanges and turns, and the code they end on.
+    pub fn fork(&self, name: String, n: usize) -> Branch {
+        let history = first_exchanges(&self.history, n);
+        Branch {
+            name,
+            last_code: last_code(&history),
+            history,
+            last_provenance: None,
+            turns: self.turns.iter().take(n).cloned().collect(),
+        }
+    }
+
+    /// One-line summary for `/branch list`: exchange count and latest request.
+    pub fn summary(&self) -> String {
+        format!("{} exchange(s), last: {}", exchange_count(&self.history), preview(&self.history))
+    }
+}
+
+/// Exchanges in `history`: each starts with a user message.
+pub fn exchange_count(history: &[Message]) -> usize {
+    history.iter().filter(|m| m.role == "user").count()
+}
+
+/// The first `n` exchanges of `history` (everything before the (n+1)th user message).
+pub fn first_exchanges(history: &[Message], n: usize) -> Vec<Message> {
+    let end = history
+        .iter()
+        .enumerate()
+        .filter(|(_, m)| m.role == "user")
+        .nth(n)
+        .map_or(history.len(), |(i, _)| i);
+    history[..end].to_vec()
+}
+
+/// The code a history ends on: its last assistant turn.
+pub fn last_code(history: &[Message]) -> String {
+    history
+        .iter()
+        .rev()
+        .find(|m| m.role == "assistant")
+        .map(|m| m.content.clone())
+        .unwrap_or_default()
+}
+
+/// The latest user request, on one line and cut to 60 characters.
+pub fn preview(history: &[Message]) -> String {
+    let Some(request) = history.iter().rev().find(|m| m.role == "user") else {
+        return "(empty)".to_string();
+    };
+    let line = request.content.split_whitespace().collect::<Vec<_>>().join(" ");
+    match line.char_indices().nth(60) {
+        Some((cut, _)) => format!("{}…", &line[..cut]),
+        None => line,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history() -> Vec<Message> {
+        let turn = |role: &str, content: &str| Message { role: role.to_string(), content: content.to_string() };
+        vec![
+            turn("user", "write a todo app"),
+            turn("assistant", "v1"),
+            turn("user", "add due dates"),
+            turn("assistant", "v2"),
+            turn("user", "use sqlite"),
+            turn("assistant", "v3"),
+        ]
+    }
+
+    #[test]
+    fn test_first_exchanges() {
+        let history = history();
+        assert_eq!(exchange_count(&history), 3);
+        let two = first_exchanges(&history, 2);
+        assert_eq!(two.len(), 4);
+        assert_eq!(last_code(&two), "v2");
+        assert_eq!(first_exchanges(&history, 5).len(), 6);
+        assert!(first_exchanges(&history, 0).is_empty());
+    }
+
+    #[test]
+    fn test_versions_follow_the_branch() {
+        let versions = |b: &Branch| b.turns.iter().map(|t| t.code.clone()).collect::<Vec<_>>();
+        let turns = ["v1", "v2", "v3"]
+            .iter()
+            .map(|code| {
+                let mut turn = SessionTurn::new("request");
+                turn.set_code(code);
+                turn
+            })
+            .collect();
+        let mut active = Branch {
+            name: MAIN_BRANCH.to_string(),
+            history: history(),
+            last_code: "v3".to_string(),
+            last_provenance: None,
+            turns,
+        };
+
+        // Fork after exchange 1, then refine on the new branch
+        let fork = active.fork("branch-2".to_string(), 1);
+        let main = std::mem::replace(&mut active, fork);
+        assert_eq!((active.last_code.as_str(), versions(&active)), ("v1", vec!["v1".to_string()]));
+        active.history.push(Message { role: "user".to_string(), content: "use requests".to_string() });
+        active.history.push(Message { role: "assistant".to_string(), content: "import requests".to_string() });
+        let mut refined = SessionTurn::new("use requests");
+        refined.set_code("import requests");
+        active.turns.push(refined);
+        assert_eq!(versions(&active), ["v1", "import requests"]);
+        assert_eq!(versions(&main), ["v1", "v2", "v3"]);
+
+        // Switching back brings the main line's versions back; the fork keeps its own
+        let fork = std::mem::replace(&mut active, main);
+        assert_eq!(versions(&active), ["v1", "v2", "v3"]);
+        assert_eq!(versions(&fork), ["v1", "import requests"]);
+        assert_eq!(fork.turns[1].deps, ["requests"]);
+    }
+
+    #[test]
+    fn test_preview() {
+        assert_eq!(preview(&history()), "use sqlite");
+        assert_eq!(preview(&[]), "(empty)");
+        let long = vec![Message { role: "user".to_string(), content: "é".repeat(70) }];
+        assert_eq!(preview(&long).chars().count(), 61);
+    }
+}
@@ -0,0 +1,235 @@
+use crate::auth::{AuthCommand, Secret};
+use crate::recipe::RecipeRun;
+use crate::run::RunCommand;
+use anyhow::{anyhow, Result};
+
+/// Command-line options, parsed from `std::env::args()`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CliArgs {
+    /// Disable session logging entirely (`--no-log`).
+    pub no_log: bool,
+    /// Ping the API and exit instead of starting the REPL (`--check`).
+    pub check: bool,
+    /// Hugging Face token, taking precedence over HF_TOKEN and the keyring (`--token <value>`).
+    pub token: Option<Secret>,
+    /// `auth login|logout|status` subcommand.
+    pub auth: Option<AuthCommand>,
+    /// Overrides `request_timeout_secs` (`--timeout <secs>`).
+    pub timeout_secs: Option<u64>,
+    /// Plain output without ANSI colors (`--no-color`, or `NO_COLOR` in the environment).
+    pub no_color: bool,
+    /// `run <script>` subcommand; its own flags follow the script path.
+    pub run: Option<RunCommand>,
+    /// `recipe run <file> [--keep-going] [--yes]` subcommand.
+    pub recipe: Option<RecipeRun>,
+    /// Don't run the setup wizard on first launch (`--no-wizard`).
+    pub no_wizard: bool,
+    /// Run generated code even when it contains a `blocked_patterns` construct (`--allow-dangerous`).
+    pub allow_dangerous: bool,
+}
+
+impl CliArgs {
+    /// Parse arguments (without the program name).
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Self> {
+        let mut cli = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--no-log" => cli.no_log = true,
+                "--check" => cli.check = true,
+                "--no-color" => cli.no_color = true,
+                "--no-wizard" => cli.no_wizard = true,
+                "--allow-dangerous" => cli.allow_dangerous = true,
+                "--token" => {
+                    let token = args.next().ok_or_else(|| anyhow!("--token needs a value"))?;
+                    cli.token = Some(Secret::new(token));
+                }
+                "--timeout" => {
+                    let value = args.next().ok_or_else(|| anyhow!("--timeout needs a number of seconds"))?;
+                    let secs = value
+                        .parse()
+                        .map_err(|_| anyhow!("--timeout expects seconds, got {value}"))?;
+                    cli.timeout_secs = Some(secs);
+                }
+                "auth" => {
+                    cli.auth = Some(match args.next().as_deref() {
+                        Some("login") => AuthCommand::Login,
+                        Some("logout") => AuthCommand::Logout,
+                        Some("status") => AuthCommand::Status,
+                        _ => return Err(anyhow!("Usage: auth login|logout|status")),
+                    });
+                }
+                "run" => cli.run = Some(parse_run(&mut args)?),
+                "recipe" => cli.recipe = Some(parse_recipe(&mut args)?),
+                other => return Err(anyhow!("Unknown argument: {other}")),
+            }
+        }
+        Ok(cli)
+    }
+
+    /// Turn colors on or off for the whole process. Call before printing anything.
+    pub fn apply_color_choice(&self) {
+        use std::io::IsTerminal;
+        let colorize = should_colorize(
+            self.no_color,
+            std::env::var("NO_COLOR").ok().as_deref(),
+            std::env::var("CLICOLOR_FORCE").ok().as_deref(),
+            std::io::stdout().is_terminal(),
+        );
+        colored::control::set_override(colorize);
+    }
+}
+
+/// `run <script> [--quiet|-q] [--json] [--timeout <secs>]`; takes every remaining argument.
+fn parse_run(args: &mut impl Iterator<Item = String>) -> Result<RunCommand> {
+    const USAGE: &str = "Usage: run <script.py> [--quiet] [--json] [--timeout <secs>]";
+    let script = args.next().filter(|a| !a.starts_with('-')).ok_or_else(|| anyhow!(USAGE))?;
+    let mut run = RunCommand { script: script.into(), ..RunCommand::default() };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--quiet" | "-q" => run.quiet = true,
+            "--json" => run.json = true,
+            "--timeout" => {
+                let value = args.next().ok_or_else(|| anyhow!("--timeout needs a number of seconds"))?;
+                let secs = value
+                    .parse()
+                    .map_err(|_| anyhow!("--timeout expects seconds, got {value}"))?;
+                run.timeout_secs = Some(secs);
+            }
+            other => return Err(anyhow!("Unknown run argument: {other}\n{USAGE}")),
+        }
+    }
+    Ok(run)
+}
+
+/// `recipe run <file> [--keep-going] [--yes]`; takes every remaining argument.
+fn parse_recipe(args: &mut impl Iterator<Item = String>) -> Result<RecipeRun> {
+    const USAGE: &str = "Usage: recipe run <file.yaml> [--keep-going] [--yes]";
+    if args.next().as_deref() != Some("run") {
+        return Err(anyhow!(USAGE));
+    }
+    let path = args.next().filter(|a| !a.starts_with('-')).ok_or_else(|| anyhow!(USAGE))?;
+    let mut run = RecipeRun { path: path.into(), ..RecipeRun::default() };
+    for arg in args {
+        match arg.as_str() {
+            "--keep-going" => run.keep_going = true,
+            "--yes" | "-y" => run.yes = true,
+            other => return Err(anyhow!("Unknown recipe argument: {other}\n{USAGE}")),
+        }
+    }
+    Ok(run)
+}
+
+/// The single "should colorize" decision: off with `--no-color` or a non-empty `NO_COLOR`
+/// (https://no-color.org), and off when stdout is piped unless `CLICOLOR_FORCE` is set.
+pub fn should_colorize(no_color_flag: bool, no_color_env: Option<&str>, force_env: Option<&str>, is_terminal: bool) -> bool {
+    if no_color_flag || no_color_env.is_some_and(|v| !v.is_empty()) {
+        return false;
+    }
+    is_terminal || force_env.is_some_and(|v| !v.is_empty() && v != "0")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_no_args() {
+        let cli = CliArgs::parse(args(&[])).unwrap();
+        assert_eq!(cli, CliArgs::default());
+    }
+
+    #[test]
+    fn test_parse_no_log() {
+        let cli = CliArgs::parse(args(&["--no-log"])).unwrap();
+        assert!(cli.no_log);
+    }
+
+    #[test]
+    fn test_parse_check() {
+        let cli = CliArgs::parse(args(&["--check", "--no-log"])).unwrap();
+        assert!(cli.check);
+        assert!(cli.no_log);
+    }
+
+    #[test]
+    fn test_parse_token_and_auth() {
+        let cli = CliArgs::parse(args(&["--token", "hf_x", "auth", "status"])).unwrap();
+        assert_eq!(cli.token.unwrap().expose(), "hf_x");
+        assert_eq!(cli.auth, Some(AuthCommand::Status));
+        assert!(CliArgs::parse(args(&["--token"])).is_err());
+        assert!(CliArgs::parse(args(&["auth", "whoami"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_no_color() {
+        assert!(CliArgs::parse(args(&["--no-color"])).unwrap().no_color);
+    }
+
+    #[test]
+    fn test_parse_no_wizard() {
+        assert!(CliArgs::parse(args(&["--no-wizard"])).unwrap().no_wizard);
+        assert!(!CliArgs::parse(args(&[])).unwrap().no_wizard);
+    }
+
+    #[test]
+    fn test_parse_allow_dangerous() {
+        assert!(CliArgs::parse(args(&["--allow-dangerous"])).unwrap().allow_dangerous);
+        assert!(!CliArgs::parse(args(&["--no-log"])).unwrap().allow_dangerous);
+    }
+
+    #[test]
+    fn test_should_colorize() {
+        assert!(should_colorize(false, None, None, true));
+        assert!(!should_colorize(true, None, None, true));
+        assert!(!should_colorize(false, Some("1"), None, true));
+        // An empty NO_COLOR doesn't count, per the convention
+        assert!(should_colorize(false, Some(""), None, true));
+        assert!(!should_colorize(false, None, None, false));
+        assert!(should_colorize(false, None, Some("1"), false));
+        assert!(!should_colorize(false, Some("1"), Some("1"), true));
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        let cli = CliArgs::parse(args(&["--timeout", "600"])).unwrap();
+        assert_eq!(cli.timeout_secs, Some(600));
+        assert!(CliArgs::parse(args(&["--timeout", "soon"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_run() {
+        let cli = CliArgs::parse(args(&["--no-color", "run", "report.py", "-q", "--timeout", "5"])).unwrap();
+        assert!(cli.no_color);
+        assert_eq!(cli.timeout_secs, None, "--timeout after run is the script limit");
+        let run = cli.run.unwrap();
+        assert_eq!(run.script, std::path::PathBuf::from("report.py"));
+        assert!(run.quiet && !run.json);
+        assert_eq!(run.timeout_secs, Some(5));
+        assert!(CliArgs::parse(args(&["run"])).is_err());
+        assert!(CliArgs::parse(args(&["run", "--json"])).is_err());
+        assert!(CliArgs::parse(args(&["run", "a.py", "--check"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_recipe() {
+        let cli = CliArgs::parse(args(&["recipe", "run", "session.yaml", "--keep-going", "--yes"])).unwrap();
+        let run = cli.recipe.unwrap();
+        assert_eq!(run.path, std::path::PathBuf::from("session.yaml"));
+        assert!(run.keep_going);
+        assert!(run.yes);
+        let plain = CliArgs::parse(args(&["recipe", "run", "s.yaml"])).unwrap().recipe.unwrap();
+        assert!(!plain.keep_going && !plain.yes);
+        assert!(CliArgs::parse(args(&["recipe", "export", "s.yaml"])).is_err());
+        assert!(CliArgs::parse(args(&["recipe", "run"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_unknown_flag() {
+        assert!(CliArgs::parse(args(&["--bogus"])).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+/// Description of one REPL slash command, used for parsing and for `/help`.
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub usage: &'static str,
+    pub help: &'static str,
+    /// `help` in French, for `/lang fr`.
+    pub help_fr: &'static str,
+}
+
+/// The central command registry. Every slash command the REPL understands is listed here.
+pub const COMMANDS: &[CommandSpec] = &[
+    CommandSpec { name: "/quit", aliases: &["/exit"], usage: "/quit, /exit", help: "Exit the program", help_fr: "Quitter le programme" },
+    CommandSpec { name: "/help", aliases: &[], usage: "/help", help: "Show this help", help_fr: "Afficher cette aide" },
+    CommandSpec { name: "/lang", aliases: &[], usage: "/lang en|fr", help: "Switch the interface between English and French", help_fr: "Passer l'interface en anglais ou en français" },
+    CommandSpec { name: "/view", aliases: &[], usage: "/view [a-b|name]", help: "Page through the current code, or show a line range or one class/function", help_fr: "Parcourir le code actuel page par page, ou afficher des lignes ou une classe/fonction" },
+    CommandSpec { name: "/theme", aliases: &[], usage: "/theme dark|light|mono", help: "Switch the color scheme (light for light terminals, mono for no colors)", help_fr: "Changer les couleurs (light pour les terminaux clairs, mono pour aucune couleur)" },
+    CommandSpec { name: "/clear", aliases: &[], usage: "/clear", help: "Clear conversation history", help_fr: "Effacer l'historique de conversation" },
+    CommandSpec { name: "/refine", aliases: &[], usage: "/refine [text]", help: "Refine the last generated code (asks for text if omitted)", help_fr: "Améliorer le dernier code généré (demande le texte s'il est omis)" },
+    CommandSpec { name: "/edit", aliases: &[], usage: "/edit [prompt]", help: "Review a prompt before it is sent: send it, edit (re-enter) it or cancel", help_fr: "Relire une requête avant son envoi : l'envoyer, la modifier (ressaisir) ou l'annuler" },
+    CommandSpec { name: "/continue", aliases: &[], usage: "/continue", help: "Ask the model to finish code that was cut off by the token limit", help_fr: "Demander au modèle de terminer un code coupé par la limite de jetons" },
+    CommandSpec { name: "/review", aliases: &["/why"], usage: "/review, /why", help: "Have the model list bugs, unhandled edge cases and risky operations in the last code", help_fr: "Demander au modèle les bugs, cas limites non gérés et opérations risquées du dernier code" },
+    CommandSpec { name: "/shorten", aliases: &[], usage: "/shorten", help: "Ask for a minimal, standard-library-only rewrite of the last code, show the diff and keep it or not", help_fr: "Demander une réécriture minimale, sans dépendances externes, du dernier code, afficher le diff et la garder ou non" },
+    CommandSpec { name: "/init", aliases: &[], usage: "/init <name> [description]", help: "Ask for a minimal project (package, __init__.py, main module, pyproject.toml), write it under <name>/ and offer to run it", help_fr: "Demander un projet minimal (paquet, __init__.py, module principal, pyproject.toml), l'écrire dans <name>/ et proposer de le lancer" },
+    CommandSpec { name: "/patch", aliases: &[], usage: "/patch <function|Class.method> <instruction>", help: "Regenerate a single function of the last code (only it and the file's outline are sent), show the diff and splice it back in", help_fr: "Régénérer une seule fonction du dernier code (seuls elle et le plan du fichier sont envoyés), afficher le diff et la réinsérer" },
+    CommandSpec { name: "/py", aliases: &[], usage: "/py <code>", help: "Evaluate a Python expression or statements (separated by ;) without writing a script", help_fr: "Évaluer une expression ou des instructions Python (séparées par ;) sans écrire de script" },
+    CommandSpec { name: "/py!", aliases: &[], usage: "/py! <code>", help: "Like /py, but show the full traceback on errors", help_fr: "Comme /py, mais avec la trace complète en cas d'erreur" },
+    CommandSpec { name: "/annotate", aliases: &[], usage: "/annotate", help: "Have the model add type hints to the last code (checked with mypy when installed)", help_fr: "Faire ajouter des annotations de type au dernier code (vérifiées avec mypy s'il est installé)" },
+    CommandSpec { name: "/choices", aliases: &[], usage: "/choices [n]", help: "Ask for n (2-5, default 3) alternatives to the last code in one request and pick one", help_fr: "Demander n variantes (2-5, 3 par défaut) du dernier code en une requête et en choisir une" },
+    CommandSpec { name: "/vs", aliases: &[], usage: "/vs <modelA> <modelB> [prompt]", help: "Ask two models at once (the prompt, or the request behind the current code), show both side by side with a diff and adopt one", help_fr: "Interroger deux modèles en même temps (le prompt, ou la requête du code actuel), les afficher côte à côte avec un diff et en adopter un" },
+    CommandSpec { name: "/recover", aliases: &[], usage: "/recover", help: "Reload the last generated code from the scratch file (e.g. after a crash)", help_fr: "Recharger le dernier code généré depuis le fichier de secours (ex. après un plantage)" },
+    CommandSpec { name: "/deps", aliases: &[], usage: "/deps [--fix|--pin|--diff]", help: "Show the imports of the last code without running it: pip package, stdlib/installed/missing, and what the install step will do (--fix drops unused imports via pyflakes, --diff compares with the previous version)", help_fr: "Afficher les imports du dernier code sans l'exécuter : paquet pip, stdlib/installé/manquant, et ce que fera l'étape d'installation (--fix retire les imports inutilisés via pyflakes, --diff compare avec la version précédente)" },
+    CommandSpec { name: "/versions", aliases: &[], usage: "/versions", help: "List this session's code versions with their dependencies", help_fr: "Lister les versions du code de la session avec leurs dépendances" },
+    CommandSpec { name: "/spec", aliases: &[], usage: "/spec [add <in> => <out> | run | clear]", help: "Input/expected-output examples sent with every request and checked after generation (\\n for newlines)", help_fr: "Exemples entrée/sortie attendue envoyés avec chaque requête et vérifiés après génération (\\n pour les retours à la ligne)" },
+    CommandSpec { name: "/golden", aliases: &[], usage: "/golden <file> | off", help: "Compare the stdout of the next run with a golden file (PASS, or FAIL with a diff; offers to create a missing one)", help_fr: "Comparer la sortie standard de la prochaine exécution à un fichier de référence (PASS, ou FAIL avec un diff ; propose de créer un fichier absent)" },
+    CommandSpec { name: "/branch", aliases: &[], usage: "/branch <n> [name] | list | switch <name>", help: "Fork the conversation after exchange n into a new branch (the current one is kept), list branches or switch between them", help_fr: "Repartir de l'échange n dans une nouvelle branche (l'actuelle est conservée), lister les branches ou passer de l'une à l'autre" },
+    CommandSpec { name: "/save", aliases: &[], usage: "/save <file>", help: "Save last code to a file", help_fr: "Enregistrer le dernier code dans un fichier" },
+    CommandSpec { name: "/history", aliases: &[], usage: "/history", help: "Show conversation history", help_fr: "Afficher l'historique de conversation" },
+    CommandSpec { name: "/style", aliases: &[], usage: "/style [hints on|off | docstrings on|off | pyversion <3.x>|off | reset]", help: "Add short style directives to the system prompt for this session (no argument shows them)", help_fr: "Ajouter de courtes consignes de style au prompt système pour la session (sans argument : les afficher)" },
+    CommandSpec { name: "/stats", aliases: &[], usage: "/stats [export <file.json|file.csv>]", help: "Show session statistics, or export them with a per-model breakdown", help_fr: "Afficher les statistiques de session, ou les exporter avec le détail par modèle" },
+    CommandSpec { name: "/list", aliases: &[], usage: "/list", help: "List all generated scripts", help_fr: "Lister les scripts générés" },
+    CommandSpec { name: "/replay", aliases: &[], usage: "/replay [<session> <turn>]", help: "Run again the code a turn of a logged session ended with (no arguments lists the sessions)", help_fr: "Réexécuter le code final d'un tour d'une session journalisée (sans argument : liste des sessions)" },
+    CommandSpec { name: "/run", aliases: &[], usage: "/run <file>", help: "Execute a previously generated script", help_fr: "Exécuter un script généré précédemment" },
+    CommandSpec { name: "/cd", aliases: &[], usage: "/cd [path]", help: "Show or set the directory scripts run in for this session", help_fr: "Afficher ou changer le dossier d'exécution des scripts pour cette session" },
+    CommandSpec { name: "/context", aliases: &[], usage: "/context <path>", help: "Add a reference file to every request (/context clear to drop)", help_fr: "Joindre un fichier de référence à chaque requête (/context clear pour retirer)" },
+    CommandSpec { name: "/clean", aliases: &[], usage: "/clean --logs | --env <task>", help: "Apply the log retention policy now (and drop empty date directories), or delete a task environment", help_fr: "Appliquer maintenant la politique de rétention des logs (et supprimer les dossiers de date vides), ou supprimer l'environnement d'une tâche" },
+    CommandSpec { name: "/export-md", aliases: &[], usage: "/export-md <file.md>", help: "Write the session as a Markdown report: each prompt, its code and its output", help_fr: "Écrire la session en rapport Markdown : chaque requête, son code et sa sortie" },
+    CommandSpec { name: "/recipe", aliases: &[], usage: "/recipe export|run <file.yaml>", help: "Save this session's prompts and settings as a recipe, or replay one, confirming each step (--yes runs them all, --keep-going past failures)", help_fr: "Enregistrer les requêtes et réglages de la session comme recette, ou en rejouer une en confirmant chaque étape (--yes les lance toutes, --keep-going après un échec)" },
+    CommandSpec { name: "/env", aliases: &[], usage: "/env [info|use <task>|off|rebuild]", help: "Show, switch or rebuild the per-task Python environment scripts run in", help_fr: "Afficher, changer ou reconstruire l'environnement Python de la tâche" },
+    CommandSpec { name: "/cleanpyc", aliases: &[], usage: "/cleanpyc", help: "Remove the __pycache__ directories under the generated directory", help_fr: "Supprimer les dossiers __pycache__ du dossier des scripts générés" },
+    CommandSpec { name: "/provenance", aliases: &[], usage: "/provenance", help: "Show how the current code was generated", help_fr: "Montrer comment le code actuel a été généré" },
+    CommandSpec { name: "/models", aliases: &[], usage: "/models [all]", help: "List code models available to your token (all: every model)", help_fr: "Lister les modèles de code accessibles avec votre jeton (all : tous les modèles)" },
+    CommandSpec { name: "/setup", aliases: &[], usage: "/setup", help: "Run the setup wizard again (backend, token, model, directories)", help_fr: "Relancer l'assistant de configuration (backend, token, modèle, dossiers)" },
+    CommandSpec { name: "/model", aliases: &[], usage: "/model [name]", help: "Show or switch the model for this session", help_fr: "Afficher ou changer le modèle pour cette session" },
+    CommandSpec { name: "/count", aliases: &[], usage: "/count", help: "Estimate the tokens the next request will send", help_fr: "Estimer les jetons envoyés par la prochaine requête" },
+    CommandSpec { name: "/status", aliases: &[], usage: "/status", help: "Show the active model, profile, sandbox, paths and token budget", help_fr: "Afficher modèle, profil, sandbox, chemins et budget de jetons" },
+    CommandSpec { name: "/plainprompt", aliases: &[], usage: "/plainprompt", help: "Toggle the session indicators in the prompt", help_fr: "Afficher ou masquer les indicateurs de session dans l'invite" },
+    CommandSpec { name: "/ping", aliases: &[], usage: "/ping", help: "Check that the API token and endpoint work", help_fr: "Vérifier que le jeton et l'endpoint de l'API fonctionnent" },
+];
+
+/// A slash command split into its canonical name and argument text.
+#[derive(Debug, PartialEq)]
+pub struct ParsedCommand {
+    pub name: String,
+    /// Trimmed arguments, with one layer of matching surrounding quotes removed.
+    pub args: String,
+    /// False when the name isn't in the registry.
+    pub known: bool,
+}
+
+/// Parse a REPL line. Returns `None` for ordinary prompts (anything not starting with `/`).
+pub fn parse(line: &str) -> Option<ParsedCommand> {
+    let line = line.trim();
+    if !line.starts_with('/') {
+        return None;
+    }
+
+    let (name, rest) = match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], &line[i..]),
+        None => (line, ""),
+    };
+
+    let spec = COMMANDS
+        .iter()
+        .find(|c| c.name == name || c.aliases.contains(&name));
+
+    Some(ParsedCommand {
+        name: spec.map_or(name, |c| c.name).to_string(),
+        args: unquote(rest.trim()).to_string(),
+        known: spec.is_some(),
+    })
+}
+
+/// Strip one pair of matching `"` or `'` around `s`.
+fn unquote(s: &str) -> &str {
+    for quote in ['"', '\''] {
+        if s.len() >= 2 && s.starts_with(quote) && s.ends_with(quote) {
+            return &s[1..s.len() - 1];
+        }
+    }
+    s
+}
+
+/// Answer to the `/edit` review of a pending prompt.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReviewChoice {
+    Send,
+    Edit,
+    Cancel,
+}
+
+/// Enter (or y/s) sends, e edits, c (or n/q) cancels; anything else asks again.
+pub fn parse_review_choice(answer: &str) -> Option<ReviewChoice> {
+    match answer.trim().to_lowercase().as_str() {
+        "" | "y" | "yes" | "s" | "send" | "o" | "oui" => Some(ReviewChoice::Send),
+        "e" | "edit" => Some(ReviewChoice::Edit),
+        "c" | "cancel" | "n" | "no" | "q" => Some(ReviewChoice::Cancel),
+        _ => None,
+    }
+}
+
+/// Use the inline argument when present, otherwise ask interactively.
+/// Returns `None` when both are empty.
+pub fn inline_or_ask(args: &str, ask: impl FnOnce() -> String) -> Option<String> {
+    let text = if args.trim().is_empty() {
+        ask()
+    } else {
+        args.to_string()
+    };
+    let text = text.trim();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_review_choice() {
+        assert_eq!(parse_review_choice(""), Some(ReviewChoice::Send));
+        assert_eq!(parse_review_choice(" E "), Some(ReviewChoice::Edit));
+        assert_eq!(parse_review_choice("c"), Some(ReviewChoice::Cancel));
+        assert_eq!(parse_review_choice("maybe"), None);
+    }
+
+    #[test]
+    fn test_parse_plain_prompt() {
+        assert!(parse("write a snake game").is_none());
+    }
+
+    #[test]
+    fn test_parse_command_without_args() {
+        let cmd = parse("  /stats  ").unwrap();
+        assert_eq!(cmd.name, "/stats");
+        assert_eq!(cmd.args, "");
+        assert!(cmd.known);
+    }
+
+    #[test]
+    fn test_parse_alias() {
+        assert_eq!(parse("/exit").unwrap().name, "/quit");
+    }
+
+    #[test]
+    fn test_parse_inline_args() {
+        let cmd = parse("/refine   add a high-score table saved to scores.json  ").unwrap();
+        assert_eq!(cmd.name, "/refine");
+        assert_eq!(cmd.args, "add a high-score table saved to scores.json");
+    }
+
+    #[test]
+    fn test_parse_quoted_args() {
+        assert_eq!(parse("/refine \"use 'single' quotes\"").unwrap().args, "use 'single' quotes");
+        assert_eq!(parse("/save 'my script.py'").unwrap().args, "my script.py");
+        assert_eq!(parse("/refine \"unbalanced").unwrap().args, "\"unbalanced");
+    }
+
+    #[test]
+    fn test_parse_unknown_command() {
+        let cmd = parse("/frobnicate now").unwrap();
+        assert!(!cmd.known);
+        assert_eq!(cmd.name, "/frobnicate");
+    }
+
+    #[test]
+    fn test_inline_or_ask_one_line() {
+        let text = inline_or_ask("add colors", || panic!("should not ask"));
+        assert_eq!(text.as_deref(), Some("add colors"));
+    }
+
+    #[test]
+    fn test_inline_or_ask_interactive() {
+        assert_eq!(inline_or_ask("", || "  add sound  ".to_string()).as_deref(), Some("add sound"));
+        assert_eq!(inline_or_ask("", String::new), None);
+    }
+
+    #[test]
+    fn test_registry_help_is_translated() {
+        for c in COMMANDS {
+            assert!(!c.help.is_empty() && !c.help_fr.is_empty(), "{} is missing help text", c.name);
+        }
+    }
+
+    #[test]
+    fn test_registry_names_are_unique() {
+        let mut names: Vec<&str> = COMMANDS
+            .iter()
+            .flat_map(|c| std::iter::once(c.name).chain(c.aliases.iter().copied()))
+            .collect();
+        let total = names.len();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), total);
+    }
+}
@@ -1,6 +1,24 @@
 use serde::Deserialize;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use crate::api::PromptStyle;
+use crate::hooks::Hook;
+use crate::requirements::normalize_package;
+
+/// How a refinement request is worded when `refine_template` is unset or invalid.
+pub const DEFAULT_REFINE_TEMPLATE: &str = "Please refine the previous code: {}";
+
+/// Which API shape requests are sent in.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+pub enum Backend {
+    /// OpenAI-compatible chat completions at `api_url`.
+    #[default]
+    #[serde(rename = "chat")]
+    Chat,
+    /// HF Inference text-generation; `api_url` is the model URL.
+    #[serde(rename = "hf-textgen")]
+    HfTextgen,
+}
 
 /// Application configuration, loaded from `.pymakebot.toml`.
 #[derive(Debug, Clone, Deserialize)]
@@ -16,6 +34,95 @@ pub struct AppConfig {
     pub max_retries: u32,
     pub log_dir: String,
     pub generated_dir: String,
+    /// strftime template of the subdirectory of `generated_dir` each script is written to;
+    /// empty keeps every script directly in `generated_dir`.
+    pub generated_layout: String,
+    /// Sampling seed forwarded to the API when set, for reproducible generations.
+    pub seed: Option<u64>,
+    /// Past this many bytes, a `/context` file is cut down to the blocks relevant to each
+    /// request (see `utils::relevant_context`).
+    pub max_context_bytes: usize,
+    /// Automatically ask the model to fix freshly generated code that fails `py_compile`.
+    pub auto_repair: bool,
+    /// Directory scripts run in; unset means the generated directory (use "." for the launch directory).
+    pub work_dir: Option<String>,
+    /// Wrap executed scripts in firejail/bwrap (no network, read-only filesystem) when installed.
+    pub sandbox: bool,
+    /// With `sandbox`, refuse to run scripts when no sandbox tool is available instead of warning.
+    pub sandbox_required: bool,
+    /// System prompt profile: "full" always sends the game and bug-prevention rules,
+    /// "minimal" never does; unset picks them from the prompt.
+    pub profile: Option<String>,
+    /// `/style` directives added to the system prompt; session-only, not read from the file.
+    #[serde(skip)]
+    pub style: PromptStyle,
+    /// Request format: "chat" (default) or "hf-textgen" for completion-only models.
+    pub backend: Backend,
+    /// Show the one-line status (model, profile, sandbox...) above the prompt when it changes.
+    pub status_line: bool,
+    /// Explicit proxy for all requests; unset uses HTTPS_PROXY/HTTP_PROXY/NO_PROXY.
+    pub proxy: Option<String>,
+    /// Extra CA certificate (PEM file) to trust, e.g. a corporate TLS-inspecting proxy's.
+    pub ca_cert: Option<String>,
+    /// Last resort: skip TLS certificate verification entirely.
+    pub danger_accept_invalid_certs: bool,
+    /// Model context window in tokens; /count warns as the next request approaches it.
+    pub context_limit_tokens: usize,
+    /// Give up connecting to the API after this many seconds.
+    pub connect_timeout_secs: u64,
+    /// Cap on a whole generation request, including the model's thinking time (0 = none).
+    pub request_timeout_secs: u64,
+    /// Copy of the latest generated code, overwritten after every generation;
+    /// unset means `<generated_dir>/.last.py`. `/recover` loads it back.
+    pub scratch_file: Option<String>,
+    /// Log retention, applied at startup and by `/clean --logs` (0 disables a limit).
+    pub log_max_age_days: u64,
+    pub log_max_total_mb: u64,
+    pub log_max_files: usize,
+    /// Remove `__pycache__` directories under the generated directory after each captured run.
+    pub clean_pycache: bool,
+    /// Print script output line by line while it runs instead of all at once at the end.
+    pub stream_output: bool,
+    /// Keep only the latest frame of progress bars (tqdm-style `\r` redraws, runs of
+    /// `n/total` lines) in captured output; streamed runs draw them in place.
+    pub collapse_progress: bool,
+    /// Ask for the final result as JSON between `===PMB_RESULT===` lines and parse it
+    /// from the output (also `/style result on|off`).
+    pub capture_result: bool,
+    /// Trial runs (rehearsing risky code in a copy of the run directory) are skipped when
+    /// the run directory holds more than this many MB.
+    pub trial_run_max_mb: u64,
+    /// After each generation, ask the model (one small request) for a one-line description
+    /// of the script, shown in its header and `/list`. Off, the prompt's start is used.
+    pub describe: bool,
+    /// After a successful run that used third-party packages, pin their installed
+    /// versions in `<script>.requirements.txt`.
+    pub pin_requirements: bool,
+    /// Generated code longer than this is summarized (outline, imports, first lines)
+    /// instead of printed in full; `/view` shows the rest. 0 always prints everything.
+    pub display_max_lines: usize,
+    /// Installs of fewer than this many packages, all in `trusted_packages`, proceed
+    /// without the confirmation prompt (0 always asks, unless `auto_install_deps`).
+    pub trusted_install_threshold: usize,
+    /// Well-known pip packages that may be installed without asking (see above).
+    pub trusted_packages: Vec<String>,
+    /// Task environment (`<generated_dir>/envs/<name>/`) to start in; unset uses the
+    /// system interpreter. Switch with `/env use <name>`.
+    pub task_env: Option<String>,
+    /// Python placed before the generated code in every script written (logging setup,
+    /// environment tweaks...). Not shown with the code.
+    pub code_prologue: Option<String>,
+    /// Python placed after the generated code, e.g. an `if __name__ == "__main__":` guard.
+    pub code_epilogue: Option<String>,
+    /// Wording of refinement requests, with `{}` where the instruction goes (exactly once),
+    /// e.g. "Améliore le code précédent : {}". `BOT_REFINE_TEMPLATE` takes precedence.
+    pub refine_template: Option<String>,
+    /// Constructs that keep generated code from running unless `--allow-dangerous` is
+    /// given: "os.system", "subprocess-shell", "eval", "exec", "__import__". Empty disables the block.
+    pub blocked_patterns: Vec<String>,
+    /// Commands every generated script is piped through after extraction, in order
+    /// (`[[post_generation_hooks]]` tables; see `hooks.rs`).
+    pub post_generation_hooks: Vec<Hook>,
 }
 
 impl Default for AppConfig {
@@ -31,6 +138,48 @@ impl Default for AppConfig {
             max_retries: 3,
             log_dir: "logs".to_string(),
             generated_dir: "generated".to_string(),
+            generated_layout: "%Y/%m/%d/".to_string(),
+            seed: None,
+            max_context_bytes: 20_000,
+            auto_repair: true,
+            work_dir: None,
+            sandbox: false,
+            sandbox_required: false,
+            profile: None,
+            style: PromptStyle::default(),
+            backend: Backend::Chat,
+            status_line: true,
+            proxy: None,
+            ca_cert: None,
+            danger_accept_invalid_certs: false,
+            context_limit_tokens: 32_768,
+            connect_timeout_secs: 10,
+            request_timeout_secs: 300,
+            scratch_file: None,
+            log_max_age_days: 30,
+            log_max_total_mb: 50,
+            log_max_files: 200,
+            clean_pycache: true,
+            stream_output: false,
+            collapse_progress: true,
+            capture_result: false,
+            trial_run_max_mb: 50,
+            describe: true,
+            pin_requirements: true,
+            display_max_lines: 200,
+            trusted_install_threshold: 3,
+            trusted_packages: [
+                "requests", "numpy", "pandas", "matplotlib", "pillow", "beautifulsoup4",
+                "pyyaml", "python-dateutil", "tqdm", "rich",
+            ]
+            .map(String::from)
+            .to_vec(),
+            task_env: None,
+            code_prologue: None,
+            code_epilogue: None,
+            refine_template: None,
+            blocked_patterns: ["os.system", "subprocess-shell", "eval", "exec", "__import__"].map(String::from).to_vec(),
+            post_generation_hooks: Vec::new(),
         }
     }
 }
@@ -52,6 +201,49 @@ impl AppConfig {
         Self::default()
     }
 
+    /// The refinement wording: `BOT_REFINE_TEMPLATE`, else `refine_template`, else the
+    /// default. `Err` explains why the chosen one is unusable; callers then use the default.
+    pub fn refine_template(&self) -> Result<String, String> {
+        refine_template_from(std::env::var("BOT_REFINE_TEMPLATE").ok(), self.refine_template.as_deref())
+    }
+
+    /// Where the latest generated code is kept for `/recover`.
+    pub fn scratch_path(&self) -> PathBuf {
+        match &self.scratch_file {
+            Some(path) => PathBuf::from(path),
+            None => Path::new(&self.generated_dir).join(".last.py"),
+        }
+    }
+
+    /// Whether installing these pip packages can skip the confirmation prompt.
+    pub fn install_is_trusted(&self, packages: &[&str]) -> bool {
+        let trusted = |p: &&str| {
+            let p = normalize_package(p);
+            self.trusted_packages.iter().any(|t| normalize_package(t) == p)
+        };
+        !packages.is_empty() && packages.len() < self.trusted_install_threshold && packages.iter().all(trusted)
+    }
+
+    /// The install confirmation policy in one line, for `/stats`.
+    pub fn install_policy(&self) -> String {
+        if self.auto_install_deps {
+            "never ask (auto_install_deps)".to_string()
+        } else if self.trusted_install_threshold == 0 || self.trusted_packages.is_empty() {
+            "always ask".to_string()
+        } else {
+            format!(
+                "ask unless fewer than {} package(s), all trusted: {}",
+                self.trusted_install_threshold,
+                self.trusted_packages.join(", ")
+            )
+        }
+    }
+
+    /// The configuration file `load` reads, if any exists.
+    pub fn config_file() -> Option<PathBuf> {
+        Self::config_paths().into_iter().find(|path| path.is_file())
+    }
+
     fn config_paths() -> Vec<PathBuf> {
         let mut paths = vec![PathBuf::from("pymakebot.toml")];
         if let Some(home) = dirs::home_dir() {
@@ -61,9 +253,30 @@ impl AppConfig {
     }
 }
 
+fn refine_template_from(env: Option<String>, configured: Option<&str>) -> Result<String, String> {
+    let (template, source) = match env.filter(|t| !t.trim().is_empty()) {
+        Some(template) => (template, "BOT_REFINE_TEMPLATE"),
+        None => match configured {
+            Some(template) => (template.to_string(), "refine_template"),
+            None => return Ok(DEFAULT_REFINE_TEMPLATE.to_string()),
+        },
+    };
+    match template.matches("{}").count() {
+        1 => Ok(template),
+        0 => Err(format!("{source} has no {{}} placeholder for the instruction")),
+        n => Err(format!("{source} has {n} {{}} placeholders, expected exactly one")),
+    }
+}
+
+/// A refinement request: `template` with its `{}` replaced by `instruction`.
+pub fn refine_request(template: &str, instruction: &str) -> String {
+    template.replacen("{}", instruction, 1)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hooks::OnFailure;
 
     #[test]
     fn test_default_config() {
@@ -77,6 +290,88 @@ mod tests {
         assert_eq!(cfg.max_retries, 3);
         assert_eq!(cfg.log_dir, "logs");
         assert_eq!(cfg.generated_dir, "generated");
+        assert_eq!(cfg.generated_layout, "%Y/%m/%d/");
+        assert_eq!(cfg.seed, None);
+        assert_eq!(cfg.max_context_bytes, 20_000);
+        assert!(cfg.auto_repair);
+        assert_eq!(cfg.work_dir, None);
+        assert!(!cfg.sandbox);
+        assert!(!cfg.sandbox_required);
+        assert_eq!(cfg.profile, None);
+        assert!(cfg.style.is_empty());
+        assert_eq!(cfg.backend, Backend::Chat);
+        assert!(cfg.status_line);
+        assert_eq!(cfg.proxy, None);
+        assert_eq!(cfg.ca_cert, None);
+        assert!(!cfg.danger_accept_invalid_certs);
+        assert_eq!(cfg.context_limit_tokens, 32_768);
+        assert_eq!(cfg.connect_timeout_secs, 10);
+        assert_eq!(cfg.request_timeout_secs, 300);
+        assert!(cfg.scratch_file.is_none());
+        assert_eq!(cfg.log_max_age_days, 30);
+        assert_eq!(cfg.log_max_total_mb, 50);
+        assert_eq!(cfg.log_max_files, 200);
+        assert!(cfg.clean_pycache);
+        assert!(!cfg.stream_output);
+        assert!(cfg.collapse_progress);
+        assert!(!cfg.capture_result);
+        assert_eq!(cfg.trial_run_max_mb, 50);
+        assert!(cfg.describe);
+        assert!(cfg.pin_requirements);
+        assert_eq!(cfg.display_max_lines, 200);
+        assert_eq!(cfg.trusted_install_threshold, 3);
+        assert!(cfg.trusted_packages.iter().any(|p| p == "requests"));
+        assert_eq!(cfg.task_env, None);
+        assert_eq!(cfg.code_prologue, None);
+        assert_eq!(cfg.code_epilogue, None);
+        assert_eq!(cfg.refine_template, None);
+        assert_eq!(cfg.blocked_patterns, vec!["os.system", "subprocess-shell", "eval", "exec", "__import__"]);
+        assert!(cfg.post_generation_hooks.is_empty());
+    }
+
+    #[test]
+    fn test_install_is_trusted() {
+        let cfg = AppConfig::default();
+        assert!(cfg.install_is_trusted(&["requests"]));
+        assert!(cfg.install_is_trusted(&["Pillow", "PyYAML"]));
+        // Unknown package, or too many packages
+        assert!(!cfg.install_is_trusted(&["requests", "leftpad"]));
+        assert!(!cfg.install_is_trusted(&["requests", "numpy", "pandas"]));
+        assert!(!cfg.install_is_trusted(&[]));
+        let strict = AppConfig { trusted_install_threshold: 0, ..AppConfig::default() };
+        assert!(!strict.install_is_trusted(&["requests"]));
+        assert_eq!(strict.install_policy(), "always ask");
+
+        let cfg: AppConfig = toml::from_str("trusted_install_threshold = 5\ntrusted_packages = [\"leftpad\"]").unwrap();
+        assert!(cfg.install_is_trusted(&["leftpad"]));
+        assert!(!cfg.install_is_trusted(&["requests"]));
+    }
+
+    #[test]
+    fn test_refine_template() {
+        assert_eq!(refine_template_from(None, None).unwrap(), DEFAULT_REFINE_TEMPLATE);
+        let french = "Améliore le code précédent : {}";
+        assert_eq!(refine_template_from(None, Some(french)).unwrap(), french);
+        // The environment wins over the file, unless it is blank
+        assert_eq!(refine_template_from(Some("Refine: {}".to_string()), Some(french)).unwrap(), "Refine: {}");
+        assert_eq!(refine_template_from(Some(" ".to_string()), Some(french)).unwrap(), french);
+
+        let err = refine_template_from(None, Some("Refine it")).unwrap_err();
+        assert_eq!(err, "refine_template has no {} placeholder for the instruction");
+        let err = refine_template_from(Some("{} and {}".to_string()), None).unwrap_err();
+        assert_eq!(err, "BOT_REFINE_TEMPLATE has 2 {} placeholders, expected exactly one");
+
+        assert_eq!(refine_request(french, "ajoute un --top"), "Améliore le code précédent : ajoute un --top");
+        // Braces in the instruction are left alone
+        assert_eq!(refine_request("Refine: {}", "use {} in format"), "Refine: use {} in format");
+    }
+
+    #[test]
+    fn test_scratch_path() {
+        let cfg = AppConfig::default();
+        assert_eq!(cfg.scratch_path(), Path::new("generated").join(".last.py"));
+        let cfg = AppConfig { scratch_file: Some("/tmp/pmb.py".to_string()), ..AppConfig::default() };
+        assert_eq!(cfg.scratch_path(), PathBuf::from("/tmp/pmb.py"));
     }
 
     #[test]
@@ -106,6 +401,17 @@ mod tests {
             max_retries = 5
             log_dir = "my_logs"
             generated_dir = "my_scripts"
+            seed = 1234
+            sandbox = true
+            backend = "hf-textgen"
+
+            [[post_generation_hooks]]
+            command = "black -q -"
+
+            [[post_generation_hooks]]
+            command = "./policy-scan.sh"
+            timeout_secs = 30
+            on_failure = "reject"
         "#;
         let cfg: AppConfig = toml::from_str(toml_str).unwrap();
         assert_eq!(cfg.model, "test-model");
@@ -118,6 +424,14 @@ mod tests {
         assert_eq!(cfg.max_retries, 5);
         assert_eq!(cfg.log_dir, "my_logs");
         assert_eq!(cfg.generated_dir, "my_scripts");
+        assert_eq!(cfg.seed, Some(1234));
+        assert!(cfg.sandbox);
+        assert!(!cfg.sandbox_required);
+        assert_eq!(cfg.backend, Backend::HfTextgen);
+        assert_eq!(cfg.post_generation_hooks, vec![
+            Hook { command: "black -q -".to_string(), timeout_secs: 10, on_failure: OnFailure::Warn },
+            Hook { command: "./policy-scan.sh".to_string(), timeout_secs: 30, on_failure: OnFailure::Reject },
+        ]);
     }
 
     #[test]
@@ -0,0 +1,142 @@
+//! Triage of failed runs: what kind of failure the error output shows, and which command
+//! is most likely to help.
+
+use regex::Regex;
+use std::sync::LazyLock;
+
+static MISSING_MODULE_RE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"No module named ['"]?([A-Za-z_][\w.]*)"#).unwrap());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FailureKind {
+    MissingModule,
+    Syntax,
+    NameOrAttribute,
+    NoDisplay,
+    Permission,
+    Timeout,
+    Other,
+}
+
+/// Markers of each kind, most specific first. The last error line of the traceback is
+/// matched before the whole output, so the exception that ended the run wins.
+const MARKERS: &[(&str, FailureKind)] = &[
+    ("ModuleNotFoundError", FailureKind::MissingModule),
+    ("No module named", FailureKind::MissingModule),
+    ("SyntaxError", FailureKind::Syntax),
+    ("IndentationError", FailureKind::Syntax),
+    ("TabError", FailureKind::Syntax),
+    ("NameError", FailureKind::NameOrAttribute),
+    ("UnboundLocalError", FailureKind::NameOrAttribute),
+    ("AttributeError", FailureKind::NameOrAttribute),
+    ("No available video device", FailureKind::NoDisplay),
+    ("no display name and no $DISPLAY", FailureKind::NoDisplay),
+    ("couldn't connect to display", FailureKind::NoDisplay),
+    ("cannot open display", FailureKind::NoDisplay),
+    ("PermissionError", FailureKind::Permission),
+    ("Permission denied", FailureKind::Permission),
+];
+
+impl FailureKind {
+    pub fn label(self) -> &'static str {
+        match self {
+            FailureKind::MissingModule => "missing module",
+            FailureKind::Syntax => "syntax error",
+            FailureKind::NameOrAttribute => "name/attribute error",
+            FailureKind::NoDisplay => "no display",
+            FailureKind::Permission => "permission denied",
+            FailureKind::Timeout => "timeout",
+            FailureKind::Other => "other",
+        }
+    }
+
+    /// One-line advice for this failure; `stderr` fills in details such as the module name.
+    pub fn suggestion(self, stderr: &str) -> Option<String> {
+        Some(match self {
+            FailureKind::MissingModule => match missing_module(stderr) {
+                Some(module) => format!(
+                    "`{module}` is not installed: install it with `pip install {}` (or set auto_install_deps = true), then /run the script again",
+                    crate::utils::pip_name(module.split('.').next().unwrap_or(&module))
+                ),
+                None => "A module is missing: install it with pip (or set auto_install_deps = true), then /run the script again".to_string(),
+            },
+            FailureKind::Syntax => {
+                "The code doesn't parse, often a reply cut off by the token limit: /continue to finish it, or /refine fix the syntax error".to_string()
+            }
+            FailureKind::NameOrAttribute => {
+                "The code uses something that doesn't exist: /refine with the traceback above (say which name or attribute is wrong)".to_string()
+            }
+            FailureKind::NoDisplay => {
+                "No display for the GUI: run where a desktop session is available, or install Xvfb to check it headless".to_string()
+            }
+            FailureKind::Permission => {
+                "Access was denied: review the paths the script writes to, or /cd to a directory you can write in".to_string()
+            }
+            FailureKind::Timeout => {
+                "The script ran out of time: raise execution_timeout_secs, or /refine to remove an infinite loop or wait".to_string()
+            }
+            FailureKind::Other => return None,
+        })
+    }
+}
+
+/// The module named by a `ModuleNotFoundError`.
+fn missing_module(stderr: &str) -> Option<String> {
+    MISSING_MODULE_RE.captures(stderr).map(|c| c[1].to_string())
+}
+
+/// Classify a failed run from its error output and exit code (`None` when it was killed).
+pub fn classify_failure(stderr: &str, exit_code: Option<i32>) -> FailureKind {
+    if exit_code.is_none() && stderr.contains("timed out after") {
+        return FailureKind::Timeout;
+    }
+    let last_line = stderr.lines().rev().find(|l| !l.trim().is_empty()).unwrap_or_default();
+    [last_line, stderr]
+        .iter()
+        .find_map(|text| MARKERS.iter().find(|(marker, _)| text.contains(marker)).map(|&(_, kind)| kind))
+        .unwrap_or(FailureKind::Other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_failure() {
+        let cases: &[(&str, Option<i32>, FailureKind)] = &[
+            (
+                "Traceback (most recent call last):\n  File \"s.py\", line 1, in <module>\n    import requests\nModuleNotFoundError: No module named 'requests'",
+                Some(1),
+                FailureKind::MissingModule,
+            ),
+            ("  File \"s.py\", line 3\n    def f(\n         ^\nSyntaxError: '(' was never closed", Some(1), FailureKind::Syntax),
+            ("IndentationError: expected an indented block after 'if' statement on line 2", Some(1), FailureKind::Syntax),
+            ("Traceback ...\nNameError: name 'pd' is not defined", Some(1), FailureKind::NameOrAttribute),
+            ("AttributeError: 'list' object has no attribute 'push'", Some(1), FailureKind::NameOrAttribute),
+            ("pygame 2.5.2\nTraceback ...\npygame.error: No available video device", Some(1), FailureKind::NoDisplay),
+            ("_tkinter.TclError: no display name and no $DISPLAY environment variable", Some(1), FailureKind::NoDisplay),
+            ("PermissionError: [Errno 13] Permission denied: '/etc/out.txt'", Some(1), FailureKind::Permission),
+            ("Process timed out after 30 seconds. You can increase this with execution_timeout_secs", None, FailureKind::Timeout),
+            // The exception that ended the run wins over one raised earlier...
+            ("NameError: name 'x' is not defined\n\nDuring handling of the above exception, another exception occurred:\nPermissionError: [Errno 13]", Some(1), FailureKind::Permission),
+            // ...and when it says nothing useful, the rest of the output is searched
+            ("ModuleNotFoundError: No module named 'ujson'\n\nDuring handling of the above exception, another exception occurred:\nValueError: bad input", Some(1), FailureKind::MissingModule),
+            ("ValueError: invalid literal for int() with base 10: 'x'", Some(1), FailureKind::Other),
+            ("", Some(2), FailureKind::Other),
+            ("", None, FailureKind::Other),
+        ];
+        for (stderr, exit_code, expected) in cases {
+            assert_eq!(classify_failure(stderr, *exit_code), *expected, "{stderr}");
+        }
+    }
+
+    #[test]
+    fn test_suggestions() {
+        let stderr = "ModuleNotFoundError: No module named 'yaml'";
+        let hint = FailureKind::MissingModule.suggestion(stderr).unwrap();
+        assert!(hint.contains("pip install PyYAML"), "{hint}");
+        assert!(FailureKind::Syntax.suggestion("").unwrap().contains("/continue"));
+        assert!(FailureKind::Timeout.suggestion("").unwrap().contains("execution_timeout_secs"));
+        assert_eq!(FailureKind::Other.suggestion("boom"), None);
+    }
+}
@@ -0,0 +1,242 @@
+//! Per-task Python environments: `<generated_dir>/envs/<task>/` holds a venv, a manifest
+//! of the packages installed into it and the requirements it can be rebuilt from.
+
+use crate::requirements::normalize_package;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// What has been installed into a task environment.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EnvManifest {
+    pub python_version: String,
+    /// Pip names, normalized and sorted.
+    pub packages: Vec<String>,
+}
+
+/// The environment of one task.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskEnv {
+    pub name: String,
+    pub dir: PathBuf,
+}
+
+/// Task names become directory names: letters, digits, `-` and `_` only.
+pub fn validate_task_name(name: &str) -> Result<&str> {
+    let name = name.trim();
+    if name.is_empty() {
+        anyhow::bail!("Task name is empty");
+    }
+    if let Some(bad) = name.chars().find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))) {
+        anyhow::bail!("Task name has an unsupported character {bad:?} (use letters, digits, '-' and '_')");
+    }
+    Ok(name)
+}
+
+/// Tasks that have an environment under `generated_dir`, sorted.
+pub fn list_tasks(generated_dir: &Path) -> Vec<String> {
+    let mut tasks: Vec<String> = fs::read_dir(generated_dir.join("envs"))
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .collect();
+    tasks.sort();
+    tasks
+}
+
+impl TaskEnv {
+    pub fn new(generated_dir: &Path, name: &str) -> Result<Self> {
+        let name = validate_task_name(name)?;
+        Ok(Self { name: name.to_string(), dir: generated_dir.join("envs").join(name) })
+    }
+
+    pub fn venv_dir(&self) -> PathBuf {
+        self.dir.join("venv")
+    }
+
+    /// The venv's interpreter.
+    pub fn python(&self) -> PathBuf {
+        if cfg!(windows) {
+            self.venv_dir().join("Scripts").join("python.exe")
+        } else {
+            self.venv_dir().join("bin").join("python")
+        }
+    }
+
+    pub fn manifest_path(&self) -> PathBuf {
+        self.dir.join("manifest.json")
+    }
+
+    /// Requirements `/env rebuild` installs from: the latest pins of the task's scripts,
+    /// or the manifest's package names when nothing was pinned.
+    pub fn requirements_path(&self) -> PathBuf {
+        self.dir.join("requirements.txt")
+    }
+
+    pub fn exists(&self) -> bool {
+        self.python().exists()
+    }
+
+    /// Create the venv if it doesn't exist yet; returns whether it was created.
+    pub fn ensure(&self) -> Result<bool> {
+        if self.exists() {
+            return Ok(false);
+        }
+        fs::create_dir_all(&self.dir).with_context(|| format!("Could not create {}", self.dir.display()))?;
+        let mut last_err = String::from("Could not run python/python3");
+        for cmd in ["python3", "python"] {
+            match Command::new(cmd).arg("-m").arg("venv").arg(self.venv_dir()).stdout(Stdio::null()).output() {
+                Ok(out) if out.status.success() => {
+                    let manifest = EnvManifest { python_version: self.python_version(), packages: Vec::new() };
+                    self.save_manifest(&manifest)?;
+                    return Ok(true);
+                }
+                Ok(out) => last_err = String::from_utf8_lossy(&out.stderr).trim().to_string(),
+                Err(e) => last_err = format!("Failed to run {cmd}: {e}"),
+            }
+        }
+        anyhow::bail!("Could not create the venv for task '{}': {last_err}", self.name)
+    }
+
+    /// Python version of the venv's interpreter, or "unknown".
+    pub fn python_version(&self) -> String {
+        Command::new(self.python())
+            .args(["-c", "import platform; print(platform.python_version())"])
+            .output()
+            .ok()
+            .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+
+    /// The manifest; a missing one is empty.
+    pub fn manifest(&self) -> Result<EnvManifest> {
+        let path = self.manifest_path();
+        if !path.exists() {
+            return Ok(EnvManifest::default());
+        }
+        let contents = fs::read_to_string(&path).with_context(|| format!("Could not read {}", path.display()))?;
+        serde_json::from_str(&contents).with_context(|| format!("Could not parse {}", path.display()))
+    }
+
+    fn save_manifest(&self, manifest: &EnvManifest) -> Result<()> {
+        let path = self.manifest_path();
+        fs::write(&path, serde_json::to_string_pretty(manifest)?)
+            .with_context(|| format!("Could not write {}", path.display()))
+    }
+
+    /// Add freshly installed pip packages to the manifest.
+    pub fn record_installed(&self, packages: &[String]) -> Result<()> {
+        let mut manifest = self.manifest()?;
+        for package in packages {
+            let package = normalize_package(package);
+            if !manifest.packages.contains(&package) {
+                manifest.packages.push(package);
+            }
+        }
+        manifest.packages.sort();
+        self.save_manifest(&manifest)
+    }
+
+    /// Make `pinned` (a `<script>.requirements.txt`) the task's requirements.
+    pub fn adopt_requirements(&self, pinned: &Path) -> Result<()> {
+        fs::copy(pinned, self.requirements_path())
+            .with_context(|| format!("Could not copy {} into the task environment", pinned.display()))?;
+        Ok(())
+    }
+
+    /// Delete the venv and create it again from the task's requirements.
+    pub fn rebuild(&self) -> Result<EnvManifest> {
+        let previous = self.manifest()?;
+        let requirements = self.requirements_path();
+        let venv = self.venv_dir();
+        if venv.exists() {
+            fs::remove_dir_all(&venv).with_context(|| format!("Could not remove {}", venv.display()))?;
+        }
+        self.ensure()?;
+
+        let mut install = Command::new(self.python());
+        install.args(["-m", "pip", "install", "--quiet"]);
+        if requirements.exists() {
+            install.arg("-r").arg(&requirements);
+        } else if !previous.packages.is_empty() {
+            install.args(&previous.packages);
+        } else {
+            return self.manifest();
+        }
+        let out = install.output().context("Failed to run pip in the task environment")?;
+        if !out.status.success() {
+            anyhow::bail!("pip install failed: {}", String::from_utf8_lossy(&out.stderr).trim());
+        }
+        let packages = if requirements.exists() {
+            fs::read_to_string(&requirements)?.lines().filter_map(requirement_name).collect()
+        } else {
+            previous.packages
+        };
+        self.record_installed(&packages)?;
+        self.manifest()
+    }
+
+    /// Delete the whole task directory.
+    pub fn remove(&self) -> Result<()> {
+        fs::remove_dir_all(&self.dir).with_context(|| format!("Could not remove {}", self.dir.display()))
+    }
+}
+
+/// The package name of a requirements line (`name==1.0`, `name>=2`...), skipping comments.
+fn requirement_name(line: &str) -> Option<String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with('-') {
+        return None;
+    }
+    let end = line.find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))).unwrap_or(line.len());
+    Some(normalize_package(&line[..end])).filter(|n| !n.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_task_name() {
+        assert_eq!(validate_task_name(" scraper_2 ").unwrap(), "scraper_2");
+        assert!(validate_task_name("").is_err());
+        assert!(validate_task_name("../x").is_err());
+        assert!(validate_task_name("a b").is_err());
+    }
+
+    #[test]
+    fn test_layout() {
+        let env = TaskEnv::new(Path::new("generated"), "game").unwrap();
+        assert_eq!(env.dir, Path::new("generated").join("envs").join("game"));
+        assert!(env.python().starts_with(env.dir.join("venv")));
+        assert_eq!(env.requirements_path(), env.dir.join("requirements.txt"));
+    }
+
+    #[test]
+    fn test_manifest_records_packages_once() {
+        let dir = PathBuf::from("test_env_manifest_dir");
+        let env = TaskEnv::new(&dir, "t").unwrap();
+        fs::create_dir_all(&env.dir).unwrap();
+        assert_eq!(env.manifest().unwrap(), EnvManifest::default());
+        env.record_installed(&["requests".to_string(), "Pillow".to_string()]).unwrap();
+        env.record_installed(&["requests".to_string()]).unwrap();
+        assert_eq!(env.manifest().unwrap().packages, vec!["pillow", "requests"]);
+        assert_eq!(list_tasks(&dir), vec!["t"]);
+        env.remove().unwrap();
+        assert!(list_tasks(&dir).is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_requirement_name() {
+        assert_eq!(requirement_name("requests==2.32.3").as_deref(), Some("requests"));
+        assert_eq!(requirement_name("typing_extensions>=4").as_deref(), Some("typing-extensions"));
+        assert_eq!(requirement_name("# Python 3.12"), None);
+        assert_eq!(requirement_name("-r base.txt"), None);
+    }
+}
@@ -0,0 +1,276 @@
+//! The session's turns (prompt, code version, dependencies, output) and their export as a
+//! shareable Markdown report (`/export-md`).
+
+use crate::python_exec::CodeExecutionResult;
+use crate::recipe::RecipeStep;
+use crate::utils::{extract_imports, is_stdlib, pip_name};
+
+/// Output of one run of a turn's code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TurnOutput {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+}
+
+impl From<&CodeExecutionResult> for TurnOutput {
+    fn from(result: &CodeExecutionResult) -> Self {
+        Self {
+            stdout: result.stdout.clone(),
+            stderr: result.stderr.clone(),
+            exit_code: result.exit_code,
+            timed_out: result.timed_out,
+        }
+    }
+}
+
+/// One generation request of the session: the prompt, the code it produced (one code
+/// version) with its dependencies and, when it was run, the latest run's output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionTurn {
+    pub prompt: String,
+    pub code: String,
+    /// Pip packages the code imports, sorted.
+    pub deps: Vec<String>,
+    pub output: Option<TurnOutput>,
+}
+
+/// Third-party packages `code` imports, as sorted pip names.
+pub fn dependency_set(code: &str) -> Vec<String> {
+    let mut deps: Vec<String> = extract_imports(code)
+        .iter()
+        .filter(|module| !is_stdlib(module))
+        .map(|module| pip_name(module).to_string())
+        .collect();
+    deps.sort();
+    deps.dedup();
+    deps
+}
+
+/// How the dependencies changed from one code version to the next.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DepsDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl DepsDiff {
+    pub fn between(previous: &[String], current: &[String]) -> Self {
+        Self {
+            added: current.iter().filter(|d| !previous.contains(d)).cloned().collect(),
+            removed: previous.iter().filter(|d| !current.contains(d)).cloned().collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+
+    /// `+ pandas, – csvkit`, or `unchanged`.
+    pub fn summary(&self) -> String {
+        if self.is_empty() {
+            return "unchanged".to_string();
+        }
+        let added = self.added.iter().map(|d| format!("+ {d}"));
+        let removed = self.removed.iter().map(|d| format!("– {d}"));
+        added.chain(removed).collect::<Vec<_>>().join(", ")
+    }
+}
+
+/// The dependency change each turn made compared with the previous code version; `None`
+/// for turns without code and for the first version.
+pub fn deps_changes(turns: &[SessionTurn]) -> Vec<Option<DepsDiff>> {
+    let mut previous: Option<&[String]> = None;
+    turns
+        .iter()
+        .map(|turn| {
+            if turn.code.trim().is_empty() {
+                return None;
+            }
+            let change = previous.map(|p| DepsDiff::between(p, &turn.deps));
+            previous = Some(&turn.deps);
+            change
+        })
+        .collect()
+}
+
+/// What the latest code version changed in the dependencies, if there was an earlier one.
+pub fn last_deps_change(turns: &[SessionTurn]) -> Option<DepsDiff> {
+    deps_changes(turns).pop().flatten()
+}
+
+impl SessionTurn {
+    pub fn new(prompt: &str) -> Self {
+        Self { prompt: prompt.to_string(), code: String::new(), deps: Vec::new(), output: None }
+    }
+
+    /// Record a new version of this turn's code.
+    pub fn set_code(&mut self, code: &str) {
+        self.code = code.to_string();
+        self.deps = dependency_set(code);
+    }
+
+    pub fn recipe_step(&self) -> RecipeStep {
+        RecipeStep { prompt: self.prompt.clone(), exit_code: self.output.as_ref().and_then(|o| o.exit_code) }
+    }
+}
+
+/// A fenced block that `text` cannot close early: the fence is longer than any run of
+/// backticks inside it.
+fn fenced(lang: &str, text: &str) -> String {
+    let longest = text
+        .split(|c| c != '`')
+        .map(str::len)
+        .max()
+        .unwrap_or(0);
+    let fence = "`".repeat(longest.max(2) + 1);
+    format!("{fence}{lang}\n{}\n{fence}\n", text.trim_end())
+}
+
+/// The heading text for a prompt: its first line, cut to 80 characters.
+pub fn heading(prompt: &str) -> String {
+    let first = prompt.lines().find(|l| !l.trim().is_empty()).unwrap_or_default().trim();
+    match first.char_indices().nth(80) {
+        Some((cut, _)) => format!("{}…", &first[..cut]),
+        None => first.to_string(),
+    }
+}
+
+/// The Markdown report: a heading per prompt, the code, then what running it printed.
+pub fn render_markdown(turns: &[SessionTurn], model: &str, exported_at: &str) -> String {
+    let mut out = format!("# Python Maker Bot session\n\n_Exported {exported_at} · model `{model}`_\n");
+    for (i, turn) in turns.iter().enumerate() {
+        out.push_str(&format!("\n## {}. {}\n\n", i + 1, heading(&turn.prompt)));
+        if turn.prompt.trim() != heading(&turn.prompt) {
+            for line in turn.prompt.trim().lines() {
+                out.push_str(&format!("> {line}\n"));
+            }
+            out.push('\n');
+        }
+        if turn.code.trim().is_empty() {
+            out.push_str("_No code was generated._\n");
+            continue;
+        }
+        out.push_str(&fenced("python", &turn.code));
+        let Some(output) = &turn.output else {
+            out.push_str("\n_Not run._\n");
+            continue;
+        };
+        let status = match (output.timed_out, output.exit_code) {
+            (true, _) => "timed out".to_string(),
+            (false, Some(code)) => format!("exit code {code}"),
+            (false, None) => "no exit code".to_string(),
+        };
+        out.push_str(&format!("\n**Output** ({status}):\n\n"));
+        if output.stdout.trim().is_empty() && output.stderr.trim().is_empty() {
+            out.push_str("_No output._\n");
+        }
+        if !output.stdout.trim().is_empty() {
+            out.push_str(&fenced("text", &output.stdout));
+        }
+        if !output.stderr.trim().is_empty() {
+            out.push_str("\nstderr:\n\n");
+            out.push_str(&fenced("text", &output.stderr));
+        }
+    }
+    out.push_str(&dependency_table(turns));
+    out
+}
+
+/// The "Dependency evolution" section: each code version's packages and what changed.
+fn dependency_table(turns: &[SessionTurn]) -> String {
+    let rows: Vec<String> = turns
+        .iter()
+        .zip(deps_changes(turns))
+        .enumerate()
+        .filter(|(_, (turn, _))| !turn.code.trim().is_empty())
+        .map(|(i, (turn, change))| {
+            let deps = if turn.deps.is_empty() { "_standard library only_".to_string() } else { turn.deps.join(", ") };
+            let change = change.map_or("—".to_string(), |c| c.summary());
+            format!("| {} | {} | {} |\n", i + 1, deps, change)
+        })
+        .collect();
+    if rows.is_empty() {
+        return String::new();
+    }
+    format!("\n## Dependency evolution\n\n| Request | Dependencies | Change |\n|---|---|---|\n{}", rows.concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn turn(prompt: &str, code: &str, output: Option<(&str, &str, i32)>) -> SessionTurn {
+        SessionTurn {
+            prompt: prompt.to_string(),
+            code: code.to_string(),
+            deps: dependency_set(code),
+            output: output.map(|(stdout, stderr, code)| TurnOutput {
+                stdout: stdout.to_string(),
+                stderr: stderr.to_string(),
+                exit_code: Some(code),
+                timed_out: false,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_render_markdown() {
+        let turns = vec![
+            turn("print a greeting", "print('hi')", Some(("hi\n", "", 0))),
+            turn("Please refine the previous code: fail loudly\nwith a traceback", "raise SystemExit(2)", Some(("", "boom\n", 2))),
+            turn("explain", "", None),
+        ];
+        let md = render_markdown(&turns, "some/model", "2024-01-01 10:00");
+        assert!(md.starts_with("# Python Maker Bot session\n\n_Exported 2024-01-01 10:00 · model `some/model`_\n"));
+        assert!(md.contains("\n## 1. print a greeting\n\n```python\nprint('hi')\n```\n\n**Output** (exit code 0):\n\n```text\nhi\n```\n"));
+        assert!(md.contains("## 2. Please refine the previous code: fail loudly\n\n> Please refine the previous code: fail loudly\n> with a traceback\n"));
+        assert!(md.contains("**Output** (exit code 2):\n\n\nstderr:\n\n```text\nboom\n```\n"));
+        assert!(md.contains("## 3. explain\n\n_No code was generated._\n"));
+    }
+
+    #[test]
+    fn test_backticks_in_output_cannot_close_the_block() {
+        let turns = vec![turn("md", "print('```')", Some(("```\nstill output\n", "", 0)))];
+        let md = render_markdown(&turns, "m", "now");
+        assert!(md.contains("````python\nprint('```')\n````\n"), "{md}");
+        assert!(md.contains("````text\n```\nstill output\n````\n"), "{md}");
+    }
+
+    #[test]
+    fn test_dependency_set_and_diff() {
+        assert_eq!(dependency_set("import os\nimport pandas as pd\nfrom PIL import Image\nimport pandas"), vec!["Pillow", "pandas"]);
+        let diff = DepsDiff::between(&["requests".to_string()], &["pandas".to_string(), "requests".to_string()]);
+        assert_eq!(diff.summary(), "+ pandas");
+        let diff = DepsDiff::between(&["numpy".to_string(), "requests".to_string()], &["pandas".to_string()]);
+        assert_eq!(diff.summary(), "+ pandas, – numpy, – requests");
+        assert_eq!(DepsDiff::default().summary(), "unchanged");
+    }
+
+    #[test]
+    fn test_deps_changes_skip_turns_without_code() {
+        let turns = vec![
+            turn("csv", "import csv", None),
+            turn("explain", "", None),
+            turn("faster", "import pandas", None),
+            turn("same", "import pandas as pd", None),
+        ];
+        let changes = deps_changes(&turns);
+        assert_eq!(changes[0], None);
+        assert_eq!(changes[1], None);
+        assert_eq!(changes[2].as_ref().unwrap().added, vec!["pandas"]);
+        assert!(changes[3].as_ref().unwrap().is_empty());
+
+        let md = render_markdown(&turns, "m", "now");
+        assert!(md.contains("## Dependency evolution\n\n| Request | Dependencies | Change |\n|---|---|---|\n\
+                             | 1 | _standard library only_ | — |\n| 3 | pandas | + pandas |\n| 4 | pandas | unchanged |\n"), "{md}");
+    }
+
+    #[test]
+    fn test_recipe_step() {
+        let step = turn("p", "x", Some(("", "", 3))).recipe_step();
+        assert_eq!(step, RecipeStep { prompt: "p".to_string(), exit_code: Some(3) });
+        assert_eq!(turn("p", "", None).recipe_step().exit_code, None);
+    }
+}
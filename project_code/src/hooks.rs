@@ -0,0 +1,212 @@
+//! Post-generation hooks: user commands (formatters, policy scanners, custom linters) that
+//! every generated script goes through, in order, after extraction and before display.
+//! A hook reads the code on stdin and prints the code to keep on stdout.
+
+use crate::python_exec::{in_own_group, kill_group};
+use serde::Deserialize;
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use wait_timeout::ChildExt;
+
+/// One `[[post_generation_hooks]]` entry of the config.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Hook {
+    /// Shell command (`sh -c`, `cmd /C` on Windows).
+    pub command: String,
+    pub timeout_secs: u64,
+    pub on_failure: OnFailure,
+}
+
+impl Default for Hook {
+    fn default() -> Self {
+        Self { command: String::new(), timeout_secs: 10, on_failure: OnFailure::Warn }
+    }
+}
+
+/// What a failing hook (non-zero exit, timeout, no output) does to the generation.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+pub enum OnFailure {
+    /// Keep the code from before the hook and go on with the next one.
+    #[default]
+    #[serde(rename = "warn")]
+    Warn,
+    /// Drop the generation; later hooks don't run.
+    #[serde(rename = "reject")]
+    Reject,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HookStatus {
+    Unchanged,
+    Modified,
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct HookReport {
+    pub command: String,
+    pub status: HookStatus,
+    pub elapsed: Duration,
+}
+
+/// The code after every hook ran, what each one did, and the command that rejected the
+/// generation, if one did.
+#[derive(Debug, Clone)]
+pub struct HookRun {
+    pub code: String,
+    pub reports: Vec<HookReport>,
+    pub rejected_by: Option<String>,
+}
+
+/// Pass `code` through `hooks` in order.
+pub fn run_hooks(code: &str, hooks: &[Hook]) -> HookRun {
+    let mut run = HookRun { code: code.to_string(), reports: Vec::new(), rejected_by: None };
+    for hook in hooks.iter().filter(|h| !h.command.trim().is_empty()) {
+        let start = Instant::now();
+        let status = match run_one(hook, &run.code) {
+            Ok(output) if output.trim_end() == run.code.trim_end() => HookStatus::Unchanged,
+            Ok(output) => {
+                run.code = output;
+                HookStatus::Modified
+            }
+            Err(reason) => HookStatus::Failed(reason),
+        };
+        let failed = matches!(status, HookStatus::Failed(_));
+        run.reports.push(HookReport { command: hook.command.clone(), status, elapsed: start.elapsed() });
+        if failed && hook.on_failure == OnFailure::Reject {
+            run.rejected_by = Some(hook.command.clone());
+            break;
+        }
+    }
+    run
+}
+
+fn shell(command: &str) -> Command {
+    if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.args(["/C", command]);
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", command]);
+        cmd
+    }
+}
+
+/// Run one hook on `code` and return what it printed, or why it failed.
+fn run_one(hook: &Hook, code: &str) -> Result<String, String> {
+    let mut command = shell(&hook.command);
+    // Its own group, so a timeout also stops whatever the hook started
+    in_own_group(&mut command).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut child = command.spawn().map_err(|e| format!("could not start: {e}"))?;
+
+    let stdin = child.stdin.take();
+    let input = code.to_string();
+    // A hook that exits without reading its input closes the pipe; that is not an error
+    let writer = std::thread::spawn(move || {
+        if let Some(mut stdin) = stdin {
+            let _ = stdin.write_all(input.as_bytes());
+        }
+    });
+    let mut stdout = child.stdout.take();
+    let mut stderr = child.stderr.take();
+    let out = std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(s) = stdout.as_mut() {
+            let _ = s.read_to_end(&mut buf);
+        }
+        buf
+    });
+    let err = std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(s) = stderr.as_mut() {
+            let _ = s.read_to_end(&mut buf);
+        }
+        buf
+    });
+
+    let status = child.wait_timeout(Duration::from_secs(hook.timeout_secs.max(1))).map_err(|e| e.to_string())?;
+    if status.is_none() {
+        kill_group(&mut child);
+    }
+    let _ = writer.join();
+    let stdout = String::from_utf8_lossy(&out.join().unwrap_or_default()).into_owned();
+    let stderr = String::from_utf8_lossy(&err.join().unwrap_or_default()).into_owned();
+
+    let Some(status) = status else {
+        return Err(format!("timed out after {}s", hook.timeout_secs.max(1)));
+    };
+    if !status.success() {
+        let detail = stderr.lines().find(|l| !l.trim().is_empty()).map(|l| format!(": {}", l.trim())).unwrap_or_default();
+        return Err(match status.code() {
+            Some(code) => format!("exit code {code}{detail}"),
+            None => format!("killed by a signal{detail}"),
+        });
+    }
+    if stdout.trim().is_empty() {
+        return Err("printed no code".to_string());
+    }
+    Ok(stdout)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    fn script(dir: &Path, name: &str, body: &str) -> String {
+        let path = dir.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        std::path::absolute(&path).unwrap().display().to_string()
+    }
+
+    fn hook(command: &str, on_failure: OnFailure) -> Hook {
+        Hook { command: command.to_string(), timeout_secs: 5, on_failure }
+    }
+
+    const CODE: &str = "# compute a total\nx = 1  # inline\nprint(x)\n";
+
+    #[test]
+    fn test_hooks_run_in_order_and_failures_follow_the_policy() {
+        let dir = Path::new("test_hooks_dir");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let upper = script(dir, "upper_comments.sh", "sed -e '/^[[:space:]]*#/y/abcdefghijklmnopqrstuvwxyz/ABCDEFGHIJKLMNOPQRSTUVWXYZ/'");
+        let fail = script(dir, "fail.sh", "cat > /dev/null\necho 'policy: os.system is forbidden' >&2\nexit 3");
+        let mark = script(dir, "mark.sh", "cat\necho '# checked'");
+
+        // Warn: the failing hook is skipped and the next one still runs
+        let run = run_hooks(CODE, &[hook(&upper, OnFailure::Warn), hook(&fail, OnFailure::Warn), hook(&mark, OnFailure::Warn), hook("cat", OnFailure::Warn)]);
+        assert_eq!(run.code, "# COMPUTE A TOTAL\nx = 1  # inline\nprint(x)\n# checked\n");
+        assert_eq!(run.reports[0].status, HookStatus::Modified);
+        assert_eq!(run.reports[1].status, HookStatus::Failed("exit code 3: policy: os.system is forbidden".to_string()));
+        assert_eq!(run.reports[2].status, HookStatus::Modified);
+        assert_eq!(run.reports[3].status, HookStatus::Unchanged);
+        assert_eq!(run.rejected_by, None);
+
+        // Reject: the generation is dropped and later hooks don't run
+        let run = run_hooks(CODE, &[hook(&upper, OnFailure::Warn), hook(&fail, OnFailure::Reject), hook(&mark, OnFailure::Warn)]);
+        assert_eq!(run.rejected_by, Some(fail.clone()));
+        assert_eq!(run.reports.len(), 2);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_hook_timeout_and_empty_output_fail() {
+        let slow = Hook { command: "sleep 30".to_string(), timeout_secs: 1, on_failure: OnFailure::Warn };
+        let start = Instant::now();
+        let run = run_hooks(CODE, &[slow, hook("cat > /dev/null", OnFailure::Warn), hook("", OnFailure::Reject)]);
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(run.reports[0].status, HookStatus::Failed("timed out after 1s".to_string()));
+        assert_eq!(run.reports[1].status, HookStatus::Failed("printed no code".to_string()));
+        // Blank commands are skipped
+        assert_eq!(run.reports.len(), 2);
+        assert_eq!(run.code, CODE);
+    }
+}
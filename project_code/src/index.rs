@@ -0,0 +1,380 @@
+use crate::provenance::Provenance;
+use crate::utils::prompt_summary;
+use anyhow::{Context, Result};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// One entry of `<generated_dir>/index.json`, describing how a script was produced.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct IndexEntry {
+    /// Script path relative to the generated directory, e.g. `2024/05/02/script_….py`.
+    pub script: String,
+    pub prompt: String,
+    pub provenance: Provenance,
+}
+
+impl IndexEntry {
+    /// What `/list` shows: the model's description, else the start of the prompt.
+    pub fn description(&self) -> String {
+        self.provenance.description.clone().unwrap_or_else(|| prompt_summary(&self.prompt))
+    }
+}
+
+pub fn index_path(dir: &Path) -> PathBuf {
+    dir.join("index.json")
+}
+
+/// Load all entries; a missing index is an empty one.
+pub fn load_index(dir: &Path) -> Result<Vec<IndexEntry>> {
+    let path = index_path(dir);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let contents = fs::read_to_string(&path)
+        .with_context(|| format!("Could not read index {}", path.display()))?;
+    serde_json::from_str(&contents)
+        .with_context(|| format!("Could not parse index {}", path.display()))
+}
+
+/// Insert `entry`, replacing any previous entry for the same script. The read-modify-write
+/// happens under the directory lock, and the new index replaces the old one in a single
+/// rename, so concurrent instances neither lose entries nor read a half-written file.
+pub fn upsert_entry(dir: &Path, entry: IndexEntry) -> Result<()> {
+    let _lock = DirLock::acquire(dir)?;
+    let mut entries = load_index(dir)?;
+    match entries.iter_mut().find(|e| e.script == entry.script) {
+        Some(existing) => *existing = entry,
+        None => entries.push(entry),
+    }
+    let path = index_path(dir);
+    let json = serde_json::to_string_pretty(&entries)?;
+    let tmp = dir.join(format!(".index.json.{}.tmp", std::process::id()));
+    fs::write(&tmp, json).with_context(|| format!("Could not write index {}", tmp.display()))?;
+    fs::rename(&tmp, &path).with_context(|| format!("Could not replace index {}", path.display()))
+}
+
+/// Lock file of a generated directory, shared by every instance using it.
+const LOCK_FILE: &str = ".index.lock";
+/// How long to wait for another instance before giving up.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Advisory lock on a generated directory: an OS lock (`flock`/`LockFileEx`) on the
+/// `.index.lock` file, held around index updates and while a new script name is picked.
+/// The file itself stays; the system releases the lock when the handle is closed or its
+/// owner exits, so a crashed instance never leaves the directory locked.
+pub struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Wait for the lock of `dir`, giving up after `LOCK_TIMEOUT`.
+    pub fn acquire(dir: &Path) -> Result<Self> {
+        let path = dir.join(LOCK_FILE);
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Could not open lock {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => return Ok(Self { _file: file }),
+            Err(TryLockError::WouldBlock) => {}
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Could not lock {}", path.display()))
+            }
+        }
+        // Another instance holds it (briefly): wait without stalling the other tasks
+        // of this runtime's worker
+        let deadline = Instant::now() + LOCK_TIMEOUT;
+        blocking(|| loop {
+            match file.try_lock() {
+                Ok(()) => return Ok(()),
+                Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(5))
+                }
+                Err(TryLockError::WouldBlock) => anyhow::bail!(
+                    "{} is locked by another instance; try again once it has finished",
+                    dir.display()
+                ),
+                Err(TryLockError::Error(e)) => {
+                    return Err(e).with_context(|| format!("Could not lock {}", path.display()))
+                }
+            }
+        })?;
+        Ok(Self { _file: file })
+    }
+}
+
+/// Run a blocking wait, handing the worker's other tasks to another thread first when
+/// called from the multi-threaded tokio runtime.
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    use tokio::runtime::{Handle, RuntimeFlavor};
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => tokio::task::block_in_place(f),
+        _ => f(),
+    }
+}
+
+/// Create a new file in `dir` (the generated directory `base` or one of its layout
+/// directories) named from `stem` and `extension`, adding `_2`, `_3`... when the name is
+/// taken, and return its path. The name is picked and the (empty) file created under
+/// the lock of `base`, so two instances never get the same one.
+pub fn create_unique_file(base: &Path, dir: &Path, stem: &str, extension: &str) -> Result<PathBuf> {
+    let _lock = DirLock::acquire(base)?;
+    for n in 1.. {
+        let name = if n == 1 { format!("{stem}.{extension}") } else { format!("{stem}_{n}.{extension}") };
+        let path = dir.join(name);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => return Ok(path),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e).with_context(|| format!("Could not create {}", path.display())),
+        }
+    }
+    unreachable!("ran out of file names")
+}
+
+/// The subdirectory of the generated directory a script written at `now` goes to, from a
+/// strftime `layout` such as `%Y/%m/%d`. An empty layout keeps every script at the top.
+pub fn layout_dir(layout: &str, now: DateTime<Local>) -> Result<PathBuf> {
+    if StrftimeItems::new(layout).any(|item| item == Item::Error) {
+        anyhow::bail!("Invalid generated_layout {layout:?}: unknown % specifier");
+    }
+    let dir = PathBuf::from(now.format(layout).to_string());
+    if !dir.components().all(|c| matches!(c, Component::Normal(_))) {
+        anyhow::bail!("Invalid generated_layout {layout:?}: it must stay inside the generated directory");
+    }
+    Ok(dir)
+}
+
+/// Whether a directory under the generated directory holds scripts: task environments,
+/// caches and dot directories do not.
+fn holds_scripts(name: &str) -> bool {
+    !name.starts_with('.') && name != "__pycache__" && name != "envs"
+}
+
+/// Every generated script under `dir`, whatever the layout, as sorted paths relative to it.
+/// Dotfiles such as the `.last.py` scratch copy are skipped.
+pub fn list_scripts(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut scripts = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let entries = fs::read_dir(&current).with_context(|| format!("Could not read {}", current.display()))?;
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(t) if t.is_dir() && holds_scripts(&name) => pending.push(path),
+                Ok(t) if t.is_file() && !name.starts_with('.') && path.extension().is_some_and(|ext| ext == "py") => {
+                    scripts.push(path.strip_prefix(dir).unwrap_or(&path).to_path_buf());
+                }
+                _ => {}
+            }
+        }
+    }
+    scripts.sort();
+    Ok(scripts)
+}
+
+/// Find a script given as a path (absolute, relative to the launch or generated
+/// directory) or by file name alone, looked up in the index then across the layout.
+pub fn resolve_script(dir: &Path, name: &str) -> Option<PathBuf> {
+    let given = Path::new(name);
+    if given.is_file() {
+        return Some(given.to_path_buf());
+    }
+    if dir.join(given).is_file() {
+        return Some(dir.join(given));
+    }
+    let file_name = given.file_name()?;
+    let by_name = |script: &Path| script.file_name() == Some(file_name);
+    let indexed = load_index(dir)
+        .unwrap_or_default()
+        .into_iter()
+        .rev()
+        .map(|entry| PathBuf::from(entry.script))
+        .find(|script| by_name(script) && dir.join(script).is_file());
+    indexed
+        .or_else(|| list_scripts(dir).ok()?.into_iter().rev().find(|script| by_name(script)))
+        .map(|script| dir.join(script))
+}
+
+/// One pattern per directory level of `layout`, matching the names it produces:
+/// `%Y/%m/%d` gives three levels of digits.
+fn layout_levels(layout: &str) -> Vec<Regex> {
+    layout
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut pattern = String::from("^");
+            for item in StrftimeItems::new(part) {
+                match item {
+                    Item::Literal(text) | Item::Space(text) => pattern.push_str(&regex::escape(text)),
+                    Item::OwnedLiteral(text) | Item::OwnedSpace(text) => pattern.push_str(&regex::escape(&text)),
+                    Item::Numeric(..) => pattern.push_str("[-+ 0-9]+"),
+                    _ => pattern.push_str(".+"),
+                }
+            }
+            pattern.push('$');
+            Regex::new(&pattern).expect("escaped layout pattern")
+        })
+        .collect()
+}
+
+/// Remove the empty layout directories under `dir` (date directories left behind once
+/// their scripts are deleted), deepest first. Only directories whose names `layout` could
+/// have produced, at its depth, are touched; `dir` itself is kept. Returns how many were
+/// removed.
+pub fn prune_empty_dirs(dir: &Path, layout: &str) -> Result<usize> {
+    fn prune(dir: &Path, levels: &[Regex]) -> Result<(usize, bool)> {
+        let mut removed = 0;
+        let mut empty = true;
+        for entry in fs::read_dir(dir).with_context(|| format!("Could not read {}", dir.display()))?.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+            let layout_dir = levels.split_first().filter(|(level, _)| is_dir && holds_scripts(&name) && level.is_match(&name));
+            let Some((_, deeper)) = layout_dir else {
+                empty = false;
+                continue;
+            };
+            let (below, child_empty) = prune(&entry.path(), deeper)?;
+            removed += below;
+            if child_empty && fs::remove_dir(entry.path()).is_ok() {
+                removed += 1;
+            } else {
+                empty = false;
+            }
+        }
+        Ok((removed, empty))
+    }
+    Ok(prune(dir, &layout_levels(layout))?.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use chrono::TimeZone;
+
+    fn entry(script: &str, prompt: &str) -> IndexEntry {
+        IndexEntry {
+            script: script.to_string(),
+            prompt: prompt.to_string(),
+            provenance: Provenance::new(&AppConfig::default(), &[]),
+        }
+    }
+
+    #[test]
+    fn test_entry_description_falls_back_to_the_prompt() {
+        let mut e = entry("a.py", "write a   script that reads data.csv\nand plots the monthly totals per region");
+        assert_eq!(e.description(), "write a script that reads data.csv and plots the monthly tot…");
+        e.provenance.description = Some("Plots monthly totals per region".to_string());
+        assert_eq!(e.description(), "Plots monthly totals per region");
+    }
+
+    #[test]
+    fn test_load_missing_index() {
+        let entries = load_index(Path::new("test_index_missing_dir")).unwrap();
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn test_upsert_appends_and_replaces() {
+        let dir = Path::new("test_index_upsert_dir");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+
+        upsert_entry(dir, entry("a.py", "first")).unwrap();
+        upsert_entry(dir, entry("b.py", "second")).unwrap();
+        upsert_entry(dir, entry("a.py", "first, refined")).unwrap();
+
+        let entries = load_index(dir).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].prompt, "first, refined");
+        assert_eq!(entries[1].script, "b.py");
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_lock_is_exclusive_and_released_on_drop() {
+        let dir = Path::new("test_index_lock_dir");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+
+        let lock = DirLock::acquire(dir).unwrap();
+        // A second handle (as another instance would open) cannot take it
+        let other = File::options().write(true).open(dir.join(LOCK_FILE)).unwrap();
+        assert!(matches!(other.try_lock(), Err(TryLockError::WouldBlock)));
+        drop(lock);
+        other.try_lock().unwrap();
+        drop(other);
+
+        // A leftover lock file from an exited instance is not held by anyone
+        let start = Instant::now();
+        drop(DirLock::acquire(dir).unwrap());
+        assert!(start.elapsed() < LOCK_TIMEOUT);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_create_unique_file() {
+        let dir = Path::new("test_index_unique_dir");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let day = dir.join("2024/05/02");
+        fs::create_dir_all(&day).unwrap();
+        assert_eq!(create_unique_file(dir, &day, "script_x", "py").unwrap(), day.join("script_x.py"));
+        assert_eq!(create_unique_file(dir, &day, "script_x", "py").unwrap(), day.join("script_x_2.py"));
+        assert_eq!(create_unique_file(dir, dir, "script_x", "py").unwrap(), dir.join("script_x.py"));
+        assert_eq!(create_unique_file(dir, &day, "script_x", "py").unwrap(), day.join("script_x_3.py"));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_layout_dir() {
+        let now = Local.with_ymd_and_hms(2024, 5, 2, 14, 30, 0).unwrap();
+        assert_eq!(layout_dir("%Y/%m/%d/", now).unwrap(), Path::new("2024/05/02"));
+        assert_eq!(layout_dir("%Y-%m", now).unwrap(), Path::new("2024-05"));
+        assert_eq!(layout_dir("", now).unwrap(), Path::new(""));
+        assert!(layout_dir("%Q", now).is_err());
+        assert!(layout_dir("/tmp/%Y", now).is_err());
+        assert!(layout_dir("../%Y", now).is_err());
+    }
+
+    #[test]
+    fn test_list_resolve_and_prune_across_layout() {
+        let dir = Path::new("test_index_layout_dir");
+        let _ = fs::remove_dir_all(dir);
+        for sub in ["2024/05/02", "2024/06/01", "2023/01/01", "envs/game/lib", "__pycache__", "assets", "2024/05/02/cache"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        for file in ["script_a.py", "2024/05/02/script_b.py", "2024/06/01/script_c.py", ".last.py", "envs/game/lib/site.py", "__pycache__/x.py"] {
+            fs::write(dir.join(file), "print(1)").unwrap();
+        }
+
+        let scripts = list_scripts(dir).unwrap();
+        assert_eq!(scripts, vec![Path::new("2024/05/02/script_b.py"), Path::new("2024/06/01/script_c.py"), Path::new("script_a.py")]);
+
+        upsert_entry(dir, entry("2024/06/01/script_c.py", "c")).unwrap();
+        assert_eq!(resolve_script(dir, "script_c.py"), Some(dir.join("2024/06/01/script_c.py")));
+        assert_eq!(resolve_script(dir, "script_b.py"), Some(dir.join("2024/05/02/script_b.py")));
+        assert_eq!(resolve_script(dir, "2024/05/02/script_b.py"), Some(dir.join("2024/05/02/script_b.py")));
+        assert_eq!(resolve_script(dir, "nope.py"), None);
+
+        // Nothing is pruned without a layout, and only its directories with one
+        assert_eq!(prune_empty_dirs(dir, "").unwrap(), 0);
+        assert_eq!(prune_empty_dirs(dir, "%Y/%m/%d/").unwrap(), 3);
+        assert!(!dir.join("2023").exists());
+        assert!(dir.join("2024/05/02").exists() && dir.join("envs/game/lib").exists());
+        assert!(dir.join("assets").exists() && dir.join("2024/05/02/cache").exists());
+
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,102 @@
+//! The main REPL prompt: line editing and persistent history on a terminal (rustyline),
+//! plain `read_line` when stdin is piped.
+
+use rustyline::config::Config;
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::Editor;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+
+/// Entries kept in the history file.
+const MAX_HISTORY: usize = 1000;
+
+/// Where prompt history persists between sessions.
+pub fn history_path() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(".python_maker_bot_history"))
+}
+
+pub enum PromptReader {
+    /// Arrow-key recall and editing; history saved after every line.
+    Editor { editor: Box<Editor<(), DefaultHistory>>, history: Option<PathBuf> },
+    /// Non-interactive stdin.
+    Plain,
+}
+
+impl PromptReader {
+    pub fn new() -> Self {
+        if !io::stdin().is_terminal() {
+            return PromptReader::Plain;
+        }
+        let config = Config::builder()
+            .max_history_size(MAX_HISTORY)
+            .and_then(|b| b.history_ignore_dups(true))
+            .map(|b| b.history_ignore_space(true).auto_add_history(false).build());
+        let Ok(Ok(mut editor)) = config.map(Editor::with_config) else {
+            return PromptReader::Plain;
+        };
+        let history = history_path();
+        if let Some(path) = &history {
+            // A missing file just means a first session
+            let _ = editor.load_history(path);
+        }
+        PromptReader::Editor { editor: Box::new(editor), history }
+    }
+
+    /// Read one line without its newline; `None` at end of input (Ctrl+D, closed pipe).
+    /// Ctrl+C clears the line and returns an empty one.
+    pub fn read(&mut self, prompt: &str) -> Option<String> {
+        match self {
+            PromptReader::Plain => {
+                print!("{prompt}");
+                let _ = io::stdout().flush();
+                read_plain(&mut io::stdin().lock())
+            }
+            PromptReader::Editor { editor, history } => match editor.readline(prompt) {
+                Ok(line) => {
+                    let line = line.trim().to_string();
+                    if !line.is_empty() {
+                        let _ = editor.add_history_entry(line.as_str());
+                        if let Some(path) = history {
+                            let _ = editor.save_history(path);
+                        }
+                    }
+                    Some(line)
+                }
+                Err(ReadlineError::Interrupted) => Some(String::new()),
+                Err(_) => None,
+            },
+        }
+    }
+}
+
+/// One trimmed line from `reader`, `None` at end of input.
+fn read_plain(reader: &mut impl BufRead) -> Option<String> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim().to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_plain() {
+        let mut input = io::Cursor::new("/help\n  make a game  \r\n\nlast");
+        assert_eq!(read_plain(&mut input).as_deref(), Some("/help"));
+        assert_eq!(read_plain(&mut input).as_deref(), Some("make a game"));
+        assert_eq!(read_plain(&mut input).as_deref(), Some(""));
+        assert_eq!(read_plain(&mut input).as_deref(), Some("last"));
+        assert_eq!(read_plain(&mut input), None);
+    }
+
+    #[test]
+    fn test_history_path() {
+        if let Some(path) = history_path() {
+            assert!(path.ends_with(".python_maker_bot_history"));
+        }
+    }
+}
@@ -1,19 +1,48 @@
 use std::io::{self, Write};
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use crate::api::{self, Message};
-use crate::config::AppConfig;
-use crate::python_exec::{CodeExecutor, ExecutionMode};
-use crate::utils::{extract_python_code, find_char_boundary};
-use crate::logger::{Logger, SessionMetrics};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use crate::api::{self, ApiError, Message};
+use crate::cli::CliArgs;
+use crate::commands::{self, ReviewChoice, COMMANDS};
+use crate::config::{refine_request, AppConfig, Backend, DEFAULT_REFINE_TEMPLATE};
+use crate::diagnostics;
+use crate::env::{self, TaskEnv};
+use crate::spec::{self, SpecCase};
+use crate::branch::{self, Branch};
+use crate::python_exec::{self, CodeExecutionResult, DepRow, DepStatus, CodeExecutor, ExecutionMode, OutputEncoding, RunOptions, Sandbox, SandboxStatus, SandboxTool};
+use crate::utils::{
+    clean_description, code_outline, compile_error_summary, def_signature, definition_range,
+    detect_language, existing_write_targets, extract_code, extract_continuation, extract_definition,
+    extract_files, extract_imports, extract_python_code, find_char_boundary, highlight_python_line,
+    is_refusal, is_stdlib, join_continuation, line_diff, looks_like_python, min_python_version,
+    numbered_items, package_name, parse_prompt_overrides, pip_name, project_main_module,
+    prompt_summary, qualified_definition_range, qualified_names, read_context_file,
+    relevant_context, remove_unused_imports, sanitize_filename, sanitize_untrusted, side_by_side,
+    splice_definition, strip_language_tag, traceback_script_lines, write_file, write_project,
+    CodeFile, CodeLanguage, DiffLine, FsError, PromptOverrides,
+};
+use crate::input::PromptReader;
+use crate::logger::{self, Logger, RetentionPolicy, SessionMetrics};
+use crate::messages::{self, tr, Lang, Msg};
+use crate::provenance::{self, render_script, Provenance};
+use crate::setup;
+use crate::hooks::{self, HookStatus};
+use crate::index;
+use crate::export::{self, DepsDiff, SessionTurn, TurnOutput};
+use crate::recipe::{self, Recipe, RecipeRun, RecipeStep};
+use crate::theme::{self, Theme, Themed};
 use colored::*;
 
 // Fonction publique utilisable depuis main.rs affichant un bandeau de bienvenue
 pub fn print_banner() {
-    println!("{}", "====================================".bright_cyan());
-    println!("{}", "      PYTHON MAKER BOT v0.2.1       ".bright_cyan().bold());
-    println!("{}", "====================================".bright_cyan());
-    println!("{}", " AI-Powered Python Code Generator".bright_white());
-    println!("{}\n", " Type /help for commands or /quit to exit".dimmed());
+    let theme = theme::current();
+    println!("{}", theme::paint("====================================", theme.banner));
+    println!("{}", theme::paint("      PYTHON MAKER BOT v0.2.1       ", theme.banner).bold());
+    println!("{}", theme::paint("====================================", theme.banner));
+    println!("{}", theme::paint(tr(Msg::BannerTagline), theme.tagline));
+    println!("{}\n", tr(Msg::BannerHint).dimmed());
 }
 
 // Fonction utilitaire pour poser des question à l'utilisateur et récupérer la réponse
@@ -29,99 +58,2103 @@ pub fn ask_user(question: &str) -> String {
 // Fonction utilitaire qui pose une une question oui/non en utilisant ask_user
 // Elle renvoi un booléen
 pub fn confirm(question: &str) -> bool {
-    let ans = ask_user(&format!("{question} (o/n) : "));
-    ans.to_lowercase().starts_with('o')
+    let ans = ask_user(&format!("{question} {}", tr(Msg::YesNo)));
+    messages::is_yes(&ans)
 }
 
 // Fonction d'affichage pour le code python généré
 pub fn display_code(code: &str) {
-    println!("\n{}", "━━━━━━━━━━━ Generated Code ━━━━━━━━━━━".bright_green().bold());
+    let theme = theme::current();
+    println!("\n{}", theme::paint("━━━━━━━━━━━ Generated Code ━━━━━━━━━━━", theme.code_frame).bold());
     // Simple syntax highlighting for Python
     for line in code.lines() {
-        let trimmed = line.trim_start();
-        if trimmed.starts_with('#') {
-            println!("{}", line.bright_black());
-        } else if trimmed.starts_with("def ") || trimmed.starts_with("class ") {
-            println!("{}", line.bright_yellow());
-        } else if trimmed.starts_with("import ") || trimmed.starts_with("from ") {
-            println!("{}", line.bright_magenta());
+        println!("{}", highlight_python_line(line));
+    }
+    println!("{}\n", theme::paint("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━", theme.code_frame));
+}
+
+/// Lines of code shown at the top of a summarized listing.
+const SUMMARY_HEAD_LINES: usize = 30;
+/// Lines per page when `/view` pages through the whole listing.
+const VIEW_PAGE_LINES: usize = 60;
+
+/// Show freshly generated code: in full, or summarized when it is longer than
+/// `display_max_lines` so the terminal scrollback isn't flooded.
+fn show_code(code: &str, config: &AppConfig) {
+    let total = code.lines().count();
+    if config.display_max_lines == 0 || total <= config.display_max_lines {
+        display_code(code);
+        print_boilerplate_note(config);
+        return;
+    }
+    let theme = theme::current();
+    println!("\n{}", theme::paint(&format!("━━━━━━━━━━━ Generated Code ({total} lines) ━━━━━━━━━━━"), theme.code_frame).bold());
+    let outline = code_outline(code);
+    if !outline.is_empty() {
+        println!("{}", "Outline:".bold());
+        for item in &outline {
+            println!("  {}{} {} {}", " ".repeat(item.indent), item.kind.dimmed(), item.name, format!("(line {})", item.line).dimmed());
+        }
+    }
+    let imports = extract_imports(code);
+    if !imports.is_empty() {
+        println!("{} {}", "Imports:".bold(), imports.join(", "));
+    }
+    println!("{}", format!("First {SUMMARY_HEAD_LINES} lines:").bold());
+    for line in code.lines().take(SUMMARY_HEAD_LINES) {
+        println!("{}", highlight_python_line(line));
+    }
+    println!("{}", format!("… {} more line(s)", total - SUMMARY_HEAD_LINES.min(total)).dimmed());
+    let example = outline.iter().find(|i| i.indent == 0).map_or("main", |i| i.name.as_str());
+    println!(
+        "{}",
+        format!("Use /view for everything, /view 120-180 for a range, /view {example} for one definition.").dimmed()
+    );
+    println!("{}\n", theme::paint("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━", theme.code_frame));
+    print_boilerplate_note(config);
+}
+
+/// The configured prologue/epilogue are written around the code but not displayed with it.
+fn print_boilerplate_note(config: &AppConfig) {
+    let lines = |text: &Option<String>| text.as_deref().map_or(0, |t| t.trim_end().lines().count());
+    let (prologue, epilogue) = (lines(&config.code_prologue), lines(&config.code_epilogue));
+    if prologue + epilogue > 0 {
+        println!(
+            "{}",
+            format!("(+ {prologue}-line prologue and {epilogue}-line epilogue from the config, added when the script is written)").dimmed()
+        );
+    }
+}
+
+/// After a failed run, tell where the script's traceback lines fall in the generated code:
+/// the provenance header and the prologue shift them.
+fn print_traceback_mapping(result: &CodeExecutionResult, script_path: &Path, code: &str) {
+    let (Some(name), Ok(script)) = (script_path.file_name().map(|n| n.to_string_lossy()), fs::read_to_string(script_path)) else {
+        return;
+    };
+    let Some(offset) = provenance::code_line_offset(&script, code) else { return };
+    let code_lines = code.lines().count();
+    let mapped: Vec<String> = traceback_script_lines(&result.stderr, &name)
+        .into_iter()
+        .map(|line| match line.checked_sub(offset) {
+            Some(n) if (1..=code_lines).contains(&n) => format!("{line} → code line {n}"),
+            Some(n) if n > code_lines => format!("{line} → epilogue"),
+            _ if line > 0 && line <= offset => format!("{line} → header/prologue"),
+            _ => format!("{line} → ?"),
+        })
+        .collect();
+    if offset > 0 && !mapped.is_empty() {
+        println!("{}", format!("Traceback lines in the script vs the generated code: {}", mapped.join(", ")).dimmed());
+    }
+}
+
+/// The hard block on `blocked_patterns`: explain why `code` won't run and return true.
+/// With `--allow-dangerous` the findings are only shown as a warning.
+fn blocked_by_patterns(executor: &CodeExecutor, code: &str, config: &AppConfig, allow_dangerous: bool, logger: &Logger) -> bool {
+    let findings = executor.scan_dangerous(code, &config.blocked_patterns);
+    if findings.is_empty() {
+        return false;
+    }
+    let title = if allow_dangerous { "⚠ Dangerous constructs (allowed by --allow-dangerous):".warning_color() } else { "⛔ Execution blocked:".error_color() };
+    println!("\n{} {}", title.bold(), "the code contains constructs that can run arbitrary commands or code".dimmed());
+    for f in &findings {
+        println!("  line {:<4} {} — {}", f.line, f.snippet.highlight_color(), f.reason());
+    }
+    let names: Vec<&str> = findings.iter().map(|f| f.name).collect();
+    let _ = logger.log(&format!("DANGEROUS: {} ({})", names.join(", "), if allow_dangerous { "allowed" } else { "blocked" }));
+    if !allow_dangerous {
+        println!(
+            "{}",
+            "Ask the model for a version without them, restart with --allow-dangerous to run it anyway, \
+             or remove the pattern from blocked_patterns in pymakebot.toml."
+                .dimmed()
+        );
+    }
+    !allow_dangerous
+}
+
+/// The gate every path that runs generated code goes through: the `blocked_patterns`
+/// block, then for a script run from the run directory the overwrite and Python version
+/// warnings and the trial-run offer, then `question` (`None` when the user already asked
+/// for this run). False when the code must not run.
+pub(crate) fn pre_run_gate(
+    executor: &CodeExecutor,
+    code: &str,
+    script_path: Option<&Path>,
+    config: &AppConfig,
+    allow_dangerous: bool,
+    logger: &Logger,
+    question: Option<&str>,
+) -> bool {
+    if blocked_by_patterns(executor, code, config, allow_dangerous, logger) {
+        return false;
+    }
+    if let Some(script_path) = script_path {
+        println!("{} {}", "Run directory:".dimmed(), executor.work_dir().display().to_string().dimmed());
+        for path in existing_write_targets(code, executor.work_dir()) {
+            println!("{} {}", "⚠ will overwrite".error_color().bold(), path.display());
+        }
+        warn_python_version(executor, code, script_path, logger);
+        offer_trial_run(executor, script_path, code, config, logger);
+    }
+    question.is_none_or(confirm)
+}
+
+/// For code the danger scanner flagged (and `--allow-dangerous` let through) or that
+/// overwrites files: offer to rehearse it in a sandboxed copy of the run directory and show what it
+/// did to the files there. The real run is still asked for afterwards.
+fn offer_trial_run(executor: &CodeExecutor, script_path: &Path, code: &str, config: &AppConfig, logger: &Logger) {
+    let flagged = !executor.scan_dangerous(code, &config.blocked_patterns).is_empty();
+    if !flagged && existing_write_targets(code, executor.work_dir()).is_empty() {
+        return;
+    }
+    if SandboxTool::detect().is_none() {
+        println!("{}", "Install firejail or bwrap to rehearse it in a sandboxed copy of the run directory first.".dimmed());
+        return;
+    }
+    if !confirm("Trial run first in a throwaway copy of the run directory?") {
+        return;
+    }
+    println!("{}", "⏳ Rehearsing in a copy of the run directory...".dimmed());
+    let trial = match executor.trial_run(script_path, config.execution_timeout_secs, config.trial_run_max_mb * 1024 * 1024) {
+        Ok(trial) => trial,
+        Err(e) => {
+            println!("{} {:#}", "⚠ Trial run skipped:".warning_color(), e);
+            return;
+        }
+    };
+    let status = match (trial.result.exit_code, trial.result.signal) {
+        _ if trial.result.timed_out => "timed out".error_color(),
+        (Some(0), _) => "exited with 0".success_color(),
+        (Some(code), _) => format!("exited with {code}").error_color(),
+        (None, Some(signal)) => format!("killed by {}", python_exec::signal_name(signal)).error_color(),
+        (None, None) => "was stopped".error_color(),
+    };
+    println!("\n{} {}", "🧪 Trial run".heading_color().bold(), status);
+    if trial.changes.is_empty() {
+        println!("  {}", "no files created, modified or deleted".dimmed());
+    }
+    for line in trial.changes.lines() {
+        let colored = match line.chars().next() {
+            Some('+') => line.success_color(),
+            Some('~') => line.warning_color(),
+            _ => line.error_color(),
+        };
+        println!("  {colored}");
+    }
+    if !trial.result.is_success() {
+        if let Some(last) = trial.result.stderr.lines().rev().find(|l| !l.trim().is_empty()) {
+            println!("  {}", last.dimmed());
+        }
+    }
+    let _ = logger.log(&format!("TRIAL RUN: exit {:?}, {}", trial.result.exit_code, trial.changes.lines().join(", ")));
+}
+
+/// `/py` output is cut after this many characters.
+const QUICK_EVAL_MAX_CHARS: usize = 4000;
+
+/// `text` cut to about `max` bytes (on a char boundary), with a note of how much was dropped.
+fn truncate_output(text: &str, max: usize) -> String {
+    if text.len() <= max {
+        return text.to_string();
+    }
+    let end = find_char_boundary(text, max);
+    format!("{}\n… ({} more characters)", &text[..end], text[end..].chars().count())
+}
+
+/// Print lines `start..=end` (1-based) of `code` with line numbers.
+fn print_numbered(code: &str, start: usize, end: usize) {
+    let width = end.to_string().len();
+    for (i, line) in code.lines().enumerate().skip(start - 1).take(end + 1 - start) {
+        println!("{} {}", format!("{:>width$} │", i + 1).dimmed(), highlight_python_line(line));
+    }
+}
+
+/// `/view`: the whole listing a page at a time, `/view a-b` for a line range,
+/// `/view name` for one class or function.
+fn view_code(code: &str, args: &str) {
+    let total = code.lines().count();
+    if args.is_empty() {
+        let mut start = 1;
+        while start <= total {
+            let end = (start + VIEW_PAGE_LINES - 1).min(total);
+            print_numbered(code, start, end);
+            if end == total {
+                break;
+            }
+            let answer = ask_user(&format!("{}", format!("-- {end}/{total}, Enter for more, q to stop -- ").dimmed()));
+            if answer.eq_ignore_ascii_case("q") {
+                break;
+            }
+            start = end + 1;
+        }
+        return;
+    }
+    if let Some((a, b)) = args.split_once('-') {
+        match (a.trim().parse::<usize>(), b.trim().parse::<usize>()) {
+            (Ok(a), Ok(b)) if a >= 1 && a <= b && a <= total => print_numbered(code, a, b.min(total)),
+            _ => println!("{}", format!("Invalid range '{args}' (the code has {total} lines).").warning_color()),
+        }
+        return;
+    }
+    match definition_range(code, args) {
+        Some((start, end)) => print_numbered(code, start, end),
+        None => println!("{}", format!("No class or function named '{args}'.").warning_color()),
+    }
+}
+
+/// Unchanged lines shown around each change in `print_diff`.
+const DIFF_CONTEXT_LINES: usize = 2;
+
+/// Print a line diff from `old` to `new`, collapsing long unchanged stretches.
+fn print_diff(old: &str, new: &str) {
+    let diff = line_diff(old, new);
+    let near_change = |i: usize| {
+        let lo = i.saturating_sub(DIFF_CONTEXT_LINES);
+        let hi = (i + DIFF_CONTEXT_LINES + 1).min(diff.len());
+        diff[lo..hi].iter().any(|l| !matches!(l, DiffLine::Same(_)))
+    };
+    let mut skipped = 0;
+    for (i, line) in diff.iter().enumerate() {
+        match line {
+            DiffLine::Same(text) if near_change(i) => {
+                if skipped > 0 {
+                    println!("{}", format!("  … {} unchanged line(s)", skipped).dimmed());
+                    skipped = 0;
+                }
+                println!("  {}", text.dimmed());
+            }
+            DiffLine::Same(_) => skipped += 1,
+            DiffLine::Removed(text) => println!("{}", format!("- {}", text).error_color()),
+            DiffLine::Added(text) => println!("{}", format!("+ {}", text).success_color()),
+        }
+    }
+    if skipped > 0 {
+        println!("{}", format!("  … {} unchanged line(s)", skipped).dimmed());
+    }
+}
+
+/// Imports of `code` that are not in the standard library.
+fn third_party_imports(code: &str) -> usize {
+    extract_imports(code).iter().filter(|m| !is_stdlib(m)).count()
+}
+
+/// Print every command from the registry.
+fn print_help() {
+    println!("\n{}", tr(Msg::HelpTitle).heading_color().bold());
+    let width = COMMANDS.iter().map(|c| c.usage.len()).max().unwrap_or(0);
+    for c in COMMANDS {
+        let help = match messages::lang() {
+            Lang::En => c.help,
+            Lang::Fr => c.help_fr,
+        };
+        println!("  {} - {}", format!("{:<width$}", c.usage).success_color(), help);
+    }
+    println!();
+}
+
+/// Ping the configured endpoint and report the outcome. Returns true on success.
+pub async fn check_api(config: &AppConfig) -> bool {
+    println!("{} {} @ {}", "Pinging".heading_color(), config.model.emphasis_color(), config.api_url.dimmed());
+    match api::ping(config).await {
+        Ok(latency) => {
+            println!("{} ({} ms)", "✓ API reachable".success_color(), latency.as_millis());
+            true
+        }
+        Err(e) => {
+            println!("{} {}", "✗ Ping failed:".error_color(), e);
+            let hint = match e {
+                ApiError::MissingToken | ApiError::InvalidToken => crate::auth::missing_token_hint(),
+                ApiError::Unauthorized { .. } => "Check that HF_TOKEN is valid and can access this model.",
+                ApiError::RateLimited { .. } => "You are being rate-limited; wait a moment and retry.",
+                ApiError::Network(_) => "Check your network connection and api_url.",
+                ApiError::Timeout { .. } => "The model is slow to answer; raise the timeout named above.",
+                ApiError::ProxyOrTls(_) => "Configure proxy/ca_cert in pymakebot.toml (see the error above).",
+                ApiError::Http { .. } | ApiError::InvalidResponse(_) => "Check api_url and model in pymakebot.toml.",
+                ApiError::InvalidRequest(_) => "The request was malformed and not sent; this is a bug, please report it.",
+                ApiError::Provider { .. } | ApiError::EmptyChoices { .. } => "The model is reachable but busy; try again shortly.",
+            };
+            println!("  {}", hint.dimmed());
+            false
+        }
+    }
+}
+
+/// Call the API, recording latency and retries in `metrics` and logging the response.
+async fn timed_generation(
+    history: &[Message],
+    config: &AppConfig,
+    metrics: &mut SessionMetrics,
+    logger: &Logger,
+) -> anyhow::Result<String> {
+    let prompt = history.last().map(|m| m.content.as_str()).unwrap_or_default();
+    let variant = api::system_prompt_variant(history, config.profile.as_deref(), &config.style);
+    let _ = logger.log(&format!("SYSTEM PROMPT: {}", variant));
+    // The last response said the quota is used up: this request would only get a 429
+    if let Some(rate) = api::last_rate(config).filter(|rate| rate.exhausted_for(chrono::Utc::now()).is_some()) {
+        anyhow::bail!("No requests left in the rate-limit quota ({}); not sent", rate.summary(chrono::Utc::now()));
+    }
+    let start = Instant::now();
+    let generation = api::generate_code_with_history(history.to_vec(), config).await?;
+    let latency = start.elapsed().saturating_sub(generation.paced);
+    if generation.paced >= Duration::from_secs(1) {
+        let _ = logger.log(&format!("PACED: held {:.1}s by the client-side rate limit", generation.paced.as_secs_f64()));
+        println!("{}", format!("(held {:.0}s to stay under the BOT_RPM request pace)", generation.paced.as_secs_f64()).dimmed());
+    }
+
+    metrics.record_api_latency(latency, prompt);
+    record_model_call(metrics, config, latency, &api::full_messages(history, config.profile.as_deref(), &config.style), &generation.content);
+    metrics.api_retries += generation.retries as usize;
+    let _ = logger.log_api_response(&generation.content, latency);
+    if generation.truncated {
+        let _ = logger.log(&format!("TRUNCATED: hit max_tokens ({})", config.max_tokens));
+        println!("{}", "⚠ output was truncated (hit token limit); consider raising max_tokens, or /continue to finish it".warning_color());
+    }
+    if let Some(rate) = &generation.rate {
+        let summary = rate.summary(chrono::Utc::now());
+        if !summary.is_empty() {
+            println!("{}", format!("({summary})").dimmed());
+        }
+    }
+    Ok(generation.content)
+}
+
+/// `/choices` for backends without `n`: one concurrent request per choice, with
+/// successive seeds when a seed is set so they differ. Failed candidates are reported
+/// and the others returned; an error only when none came back.
+async fn candidate_choices(messages: &[Message], config: &AppConfig, n: u32, logger: &Logger) -> anyhow::Result<Vec<String>> {
+    let configs: Vec<AppConfig> = (0..n as u64)
+        .map(|i| AppConfig { seed: config.seed.map(|seed| seed.wrapping_add(i)), ..config.clone() })
+        .collect();
+    let mut candidates = api::generate_candidates(messages, &configs).await;
+    if let Some(note) = candidates.failure_note() {
+        let _ = logger.log_error(&note);
+        println!("{}", format!("⚠ {note}").warning_color());
+    }
+    if candidates.succeeded.is_empty() {
+        return Err(candidates.failed.swap_remove(0).1);
+    }
+    Ok(candidates.succeeded.into_iter().map(|(_, generation)| generation.content).collect())
+}
+
+/// How scripts' output is handled, from the config.
+fn run_options(config: &AppConfig) -> RunOptions {
+    RunOptions { collapse_progress: config.collapse_progress, capture_result: config.capture_result }
+}
+
+/// One-line description of `code` for its header, the index and `/list`: asked to the
+/// model once per distinct code (`cache` is keyed by its hash) unless `describe = false`;
+/// the start of `prompt` when disabled or when the request fails.
+async fn describe_script(
+    code: &str,
+    prompt: &str,
+    config: &AppConfig,
+    cache: &mut HashMap<String, String>,
+    metrics: &mut SessionMetrics,
+    logger: &Logger,
+) -> String {
+    if !config.describe {
+        return prompt_summary(prompt);
+    }
+    let hash = provenance::code_sha256(code);
+    if let Some(description) = cache.get(&hash) {
+        metrics.cache_hits += 1;
+        return description.clone();
+    }
+    let start = Instant::now();
+    match api::describe_code(code, config).await {
+        Ok(reply) => {
+            metrics.record_api_latency(start.elapsed(), "describe");
+            record_model_call(metrics, config, start.elapsed(), &code_message(code), &reply);
+            match clean_description(&reply) {
+                Some(description) => {
+                    cache.insert(hash, description.clone());
+                    description
+                }
+                None => prompt_summary(prompt),
+            }
+        }
+        Err(e) => {
+            let _ = logger.log_error(&format!("Description request failed: {}", e));
+            prompt_summary(prompt)
+        }
+    }
+}
+
+/// What `/patch` tells the model about the rest of the file: its imports and outline.
+fn patch_context(code: &str) -> String {
+    let mut context = String::new();
+    let imports = extract_imports(code);
+    if !imports.is_empty() {
+        context.push_str(&format!("Imports: {}\n", imports.join(", ")));
+    }
+    context.push_str("Outline:\n");
+    for item in code_outline(code) {
+        context.push_str(&format!("{}{} {} (line {})\n", " ".repeat(item.indent), item.kind, item.name, item.line));
+    }
+    context
+}
+
+/// Count an answered request in the per-model breakdown, with estimated token counts.
+fn record_model_call(metrics: &mut SessionMetrics, config: &AppConfig, latency: Duration, sent: &[Message], reply: &str) {
+    let reply = Message { role: "assistant".to_string(), content: reply.to_string() };
+    metrics.record_model_call(&config.model, latency, api::estimate_tokens(sent), api::estimate_tokens(&[reply]));
+}
+
+/// The single user message of a one-off request about `code` (`/review`, `/shorten`, `/annotate`).
+fn code_message(code: &str) -> [Message; 1] {
+    [Message { role: "user".to_string(), content: code.to_string() }]
+}
+
+/// For multi-file replies, `py_compile` each `.py` file and list PASS/FAIL per file before
+/// anything runs, so a broken module is not hidden behind a main file that compiles.
+fn report_file_compile(executor: &CodeExecutor, files: &[CodeFile], logger: &Logger) {
+    let results = match executor.compile_files(files) {
+        Ok(results) => results,
+        Err(e) => {
+            let _ = logger.log_error(&format!("Per-file compile check failed: {:#}", e));
+            return;
+        }
+    };
+    if results.is_empty() {
+        return;
+    }
+    println!("\n{}", format!("🧪 Compiling the {} files separately:", results.len()).heading_color());
+    let width = results.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    for (name, result) in &results {
+        match result {
+            Ok(()) => println!("  {} {}", "PASS".success_color().bold(), name),
+            Err(e) => println!("  {} {:<width$}  {}", "FAIL".error_color().bold(), name, compile_error_summary(e).dimmed()),
+        }
+    }
+    let failed: Vec<&str> = results.iter().filter(|(_, r)| r.is_err()).map(|(name, _)| name.as_str()).collect();
+    let _ = logger.log(&format!("FILE COMPILE: {} of {} failed {:?}", failed.len(), results.len(), failed));
+    if let Some(first) = failed.first() {
+        println!("{}", format!("Fix it with e.g. /refine fix the syntax error in {first}").dimmed());
+    }
+}
+
+/// Pick how to run `code`. GUI code on a headless Linux box goes under a
+/// virtual display when Xvfb is installed, otherwise it runs interactively as before.
+fn choose_execution_mode(executor: &CodeExecutor, code: &str, stream: bool) -> ExecutionMode {
+    if !executor.needs_interactive_mode(code) {
+        return if stream { ExecutionMode::Streamed } else { ExecutionMode::Captured };
+    }
+    if python_exec::is_headless() {
+        if !python_exec::has_virtual_display() {
+            println!("{}", "⚠️  No display found and Xvfb is not installed; GUI windows will fail to open.".warning_color());
+        } else if confirm(tr(Msg::ConfirmXvfb)) {
+            println!("{}", format!(
+                "🖥️  Running headless for {}s under Xvfb...",
+                python_exec::HEADLESS_GUI_TIMEOUT_SECS
+            ).highlight_color().bold());
+            return ExecutionMode::HeadlessGui;
+        }
+    }
+    println!("{}", "🎮 Interactive mode detected (pygame/input/GUI)".highlight_color().bold());
+    println!("{}", "   Running with inherited stdio for user interaction...".dimmed());
+    ExecutionMode::Interactive
+}
+
+fn sandbox_description(status: SandboxStatus) -> ColoredString {
+    match status {
+        SandboxStatus::Disabled => "off".dimmed(),
+        SandboxStatus::Active(tool) => format!("active ({}, network disabled)", tool.binary()).success_color(),
+        SandboxStatus::Unavailable { required: true } => "required but unavailable (scripts refused)".error_color(),
+        SandboxStatus::Unavailable { required: false } => "unavailable (running unsandboxed)".warning_color(),
+    }
+}
+
+/// After a failed run, classify its error output, count it and suggest the command most
+/// likely to help, under the Execution Result block.
+fn triage_failure(result: &CodeExecutionResult, mode: ExecutionMode, metrics: &mut SessionMetrics, logger: &Logger) {
+    let output = failure_output(result, mode);
+    let kind = diagnostics::classify_failure(output, result.exit_code);
+    *metrics.failures_by_kind.entry(kind).or_default() += 1;
+    let _ = logger.log(&format!("FAILURE KIND: {}", kind.label()));
+    if let Some(hint) = kind.suggestion(output) {
+        println!("💡 {}", theme::paint(&hint, theme::current().warning));
+    }
+}
+
+/// Before a run, warn when the code needs a newer Python than the interpreter it will run
+/// with, and name an installed interpreter that can run it.
+fn warn_python_version(executor: &CodeExecutor, code: &str, script_path: &Path, logger: &Logger) {
+    let Some(required) = min_python_version(code) else { return };
+    let Some(current) = executor.python_version() else { return };
+    if current >= required.version {
+        return;
+    }
+    let (major, minor) = required.version;
+    let warning = format!(
+        "⚠️ This code needs Python {major}.{minor}+ ({}, line {}); the interpreter is {}.{}",
+        required.feature, required.line, current.0, current.1
+    );
+    println!("{}", theme::paint(&warning, theme::current().warning));
+    let _ = logger.log(&format!("PYTHON VERSION: needs {major}.{minor} ({}), have {}.{}", required.feature, current.0, current.1));
+    match python_exec::find_python_at_least(required.version) {
+        Some((python, _)) => println!("   {} is installed: run it with `{} {}`", python.display(), python.display(), script_path.display()),
+        None => println!("   {}", format!("Install Python {major}.{minor} or later, or /refine to avoid {}", required.feature).dimmed()),
+    }
+}
+
+/// `/stats export <file.json|file.csv>`: write the session metrics, in the format the
+/// extension names.
+fn export_stats(metrics: &SessionMetrics, target: &str, logger: &Logger) {
+    if target.is_empty() {
+        println!("{}", "Usage: /stats export <file.json|file.csv>".warning_color());
+        return;
+    }
+    let path = Path::new(target);
+    let export = metrics.export();
+    let contents = match path.extension().and_then(|e| e.to_str()).map(str::to_ascii_lowercase).as_deref() {
+        Some("json") => match export.to_json() {
+            Ok(json) => json + "\n",
+            Err(e) => {
+                println!("{} {}", "✗ Cannot serialize the stats:".error_color(), e);
+                return;
+            }
+        },
+        Some("csv") => export.to_csv(),
+        _ => {
+            println!("{}", "Export to a .json or .csv file.".warning_color());
+            return;
+        }
+    };
+    match write_file(path, contents) {
+        Ok(()) => {
+            let _ = logger.log(&format!("STATS EXPORT: {}", path.display()));
+            println!("{} {} ({} models)", "✓ Stats exported to:".success_color(), path.display(), export.models.len());
+        }
+        Err(e) => println!("{} {}", "✗ Failed to export:".error_color(), e),
+    }
+}
+
+/// A headless GUI run counts as a success if it was still alive when stopped.
+fn execution_succeeded(result: &CodeExecutionResult, mode: ExecutionMode) -> bool {
+    result.is_success() || (mode == ExecutionMode::HeadlessGui && result.timed_out)
+}
+
+/// What to send the model when a run fails: interactive runs have one merged terminal
+/// stream (empty when it ran without a pty and nothing was captured), captured runs
+/// have stderr.
+fn failure_output(result: &CodeExecutionResult, mode: ExecutionMode) -> &str {
+    match mode {
+        ExecutionMode::Interactive => &result.stdout,
+        _ => &result.stderr,
+    }
+}
+
+fn print_execution_result(result: &CodeExecutionResult, mode: ExecutionMode, show_path: bool) {
+    let theme = theme::current();
+    println!("\n{}", theme::paint("━━━━━━━━━━━ Execution Result ━━━━━━━━━━━", theme.result_frame).bold());
+    if show_path {
+        println!("{} {}", "Script saved at:".dimmed(), result.script_path.display());
+    }
+    if mode == ExecutionMode::HeadlessGui {
+        if execution_succeeded(result, mode) {
+            println!("{}", theme::paint("✓ Got past initialization under the virtual display (nothing was visible)", theme.success));
+        } else {
+            println!("{}", theme::paint("✗ Crashed under the virtual display", theme.error));
+        }
+    }
+    if let Some(signal) = result.signal {
+        println!("{}", theme::paint(&format!("✗ Killed by {} (signal {signal})", python_exec::signal_name(signal)), theme.error));
+    }
+    if result.reaped_descendants {
+        println!("{}", theme::paint("⚠️  The script had started other processes; they were stopped with it", theme.warning));
+    }
+    let binary = matches!(result.output_encoding, OutputEncoding::Binary { .. });
+    if mode == ExecutionMode::Streamed {
+        // Already printed line by line
+        println!(
+            "{}",
+            format!("(captured {} stdout / {} stderr lines)", result.stdout.lines().count(), result.stderr.lines().count()).dimmed()
+        );
+        let limits = result.stream_limits;
+        if limits.coalesced_lines > 0 {
+            println!("{}", format!("({} line(s) came too fast to print live; they are in the capture)", limits.coalesced_lines).dimmed());
+        }
+        if limits.collapsed_frames > 0 {
+            println!("{}", format!("({} progress frame(s) drawn in place; the capture keeps the latest)", limits.collapsed_frames).dimmed());
+        }
+        if limits.truncated {
+            println!("{}", theme::paint(&format!("⚠️  Capture truncated after {} KB per stream", python_exec::MAX_CAPTURE_BYTES / 1024), theme.warning));
+        }
+        if result.timed_out {
+            println!("{}", theme::paint(result.stderr.lines().last().unwrap_or_default(), theme.error));
+        }
+    } else if mode == ExecutionMode::Interactive {
+        // Already shown live; the capture is only summarized here
+        if !result.stdout.is_empty() {
+            println!(
+                "{}",
+                format!("(output captured: {} lines, saved to {})", result.stdout.lines().count(), result.script_path.with_extension("out").display()).dimmed()
+            );
+        }
+    } else if !result.stdout.is_empty() {
+        println!("\n{}:", theme::paint("STDOUT", theme.success).bold());
+        if binary && result.stdout.starts_with("[binary") {
+            println!("{}", theme::paint(&result.stdout, theme.warning));
+        } else {
+            println!("{}", result.stdout);
+        }
+    }
+    if !result.stderr.is_empty() && mode != ExecutionMode::Streamed {
+        println!("\n{}:", theme::paint("STDERR", theme.error).bold());
+        if binary && result.stderr.starts_with("[binary") {
+            println!("{}", theme::paint(&result.stderr, theme.warning));
+        } else {
+            println!("{}", result.stderr);
+        }
+    }
+    if result.output_encoding == OutputEncoding::Lossy {
+        println!("{}", "(output was not valid UTF-8; undecodable bytes are shown as �)".dimmed());
+    }
+    if let Some(value) = &result.result {
+        println!("\n{}:", theme::paint("RESULT", theme.success).bold());
+        println!("{}", serde_json::to_string_pretty(value).unwrap_or_else(|_| value.to_string()));
+    } else if let Some(error) = &result.result_error {
+        println!("{}", theme::paint(&format!("⚠️  No result: {error}"), theme.warning));
+    }
+    println!("{}", theme::paint("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━", theme.result_frame));
+}
+
+/// Let the user choose which detected dependencies to install, one by one.
+/// Packages declined here are remembered in `declined` and not offered again this session.
+/// Returns the import names of the modules to install.
+fn pick_dependencies(
+    executor: &CodeExecutor,
+    deps: &[String],
+    config: &AppConfig,
+    declined: &mut HashSet<String>,
+) -> Vec<String> {
+    let mut modules: Vec<&String> = Vec::new();
+    for dep in deps {
+        if !modules.contains(&dep) {
+            modules.push(dep);
+        }
+    }
+    let skipped: Vec<&str> = modules.iter().filter(|m| declined.contains(m.as_str())).map(|m| m.as_str()).collect();
+    if !skipped.is_empty() {
+        println!("{} {}", "Skipping dependencies declined earlier:".dimmed(), skipped.join(", ").dimmed());
+    }
+    modules.retain(|m| !declined.contains(m.as_str()));
+
+    // (import name, pip name, installed, selected)
+    let mut rows: Vec<(&str, &str, bool, bool)> = modules
+        .iter()
+        .map(|m| {
+            let installed = executor.is_installed(m);
+            (m.as_str(), pip_name(m), installed, !installed)
+        })
+        .collect();
+    if rows.iter().all(|row| row.2) {
+        return Vec::new();
+    }
+
+    let to_install: Vec<&str> = rows.iter().filter(|row| row.3).map(|row| row.1).collect();
+    if !config.auto_install_deps && config.install_is_trusted(&to_install) {
+        println!("{} {}", "Installing trusted dependencies:".dimmed(), to_install.join(", ").dimmed());
+        return rows.iter().filter(|row| row.3).map(|row| row.0.to_string()).collect();
+    }
+
+    println!("\n{}", "⚠️  Detected non-standard dependencies:".warning_color());
+    if !config.auto_install_deps {
+        loop {
+            for (i, (module, pip, installed, selected)) in rows.iter().enumerate() {
+                let mark = if *selected { "[x]".success_color() } else { "[ ]".dimmed() };
+                let pip = if pip == module { String::new() } else { format!(" → {pip}") };
+                let status = if *installed { " (installed)".dimmed() } else { "".normal() };
+                println!("  {} {}. {}{}{}", mark, i + 1, module.highlight_color(), pip, status);
+            }
+            let answer = ask_user(tr(Msg::AskDependencies));
+            let answer = answer.trim();
+            if answer.is_empty() {
+                break;
+            }
+            if answer.eq_ignore_ascii_case("n") {
+                rows.iter_mut().for_each(|row| row.3 = false);
+                break;
+            }
+            for token in answer.split(|c: char| c == ',' || c.is_whitespace()).filter(|t| !t.is_empty()) {
+                match token.parse::<usize>() {
+                    Ok(n) if (1..=rows.len()).contains(&n) => rows[n - 1].3 = !rows[n - 1].3,
+                    _ => println!("{} {}", "Ignoring invalid choice:".warning_color(), token),
+                }
+            }
+        }
+    }
+
+    let mut selected = Vec::new();
+    for (module, _, installed, chosen) in rows {
+        if chosen {
+            selected.push(module.to_string());
+        } else if !installed {
+            declined.insert(module.to_string());
+        }
+    }
+    selected
+}
+
+/// Overwrite the scratch copy of the latest code so a crash never loses it.
+fn save_scratch(config: &AppConfig, code: &str, logger: &Logger) {
+    let path = config.scratch_path();
+    let result = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&path, code));
+    if let Err(e) = result {
+        let _ = logger.log_error(&format!("Scratch save to {} failed: {}", path.display(), e));
+    }
+}
+
+/// Apply the log retention policy, sparing this session's log. Returns a one-line summary
+/// when something was removed.
+fn clean_logs(config: &AppConfig, logger: &Logger) -> anyhow::Result<Option<String>> {
+    let removed = logger::apply_retention(
+        Path::new(&config.log_dir),
+        &RetentionPolicy::from_config(config),
+        logger.current_file(),
+    )?;
+    if removed.is_empty() {
+        return Ok(None);
+    }
+    let bytes: u64 = removed.iter().map(|(_, size)| size).sum();
+    for (path, _) in &removed {
+        let _ = logger.log(&format!("LOG RETENTION: removed {}", path.display()));
+    }
+    Ok(Some(format!("{} old log file(s), {:.1} MB", removed.len(), bytes as f64 / (1024.0 * 1024.0))))
+}
+
+/// `__pycache__` left under the generated directory by a captured run, removed when
+/// `clean_pycache` is on. Interactive runs may still be going, so they are left alone.
+fn clean_after_run(executor: &CodeExecutor, config: &AppConfig, mode: ExecutionMode, logger: &Logger) {
+    if !config.clean_pycache || !matches!(mode, ExecutionMode::Captured | ExecutionMode::Streamed) {
+        return;
+    }
+    match executor.clean_pycache() {
+        Ok(0) => {}
+        Ok(removed) => {
+            let _ = logger.log(&format!("PYCACHE: removed {} director{}", removed, if removed == 1 { "y" } else { "ies" }));
+        }
+        Err(e) => {
+            let _ = logger.log_error(&format!("__pycache__ cleanup failed: {:#}", e));
+        }
+    }
+}
+
+/// After a successful captured run, pin the third-party packages the script used.
+fn pin_after_run(executor: &CodeExecutor, config: &AppConfig, mode: ExecutionMode, script_path: &Path, logger: &Logger) {
+    if !config.pin_requirements || !matches!(mode, ExecutionMode::Captured | ExecutionMode::Streamed) {
+        return;
+    }
+    match executor.pin_requirements(script_path) {
+        Ok(Some((path, count))) => {
+            let _ = logger.log(&format!("PINNED: {} package(s) in {}", count, path.display()));
+            println!("{}", format!("📌 Pinned {} package(s) in {}", count, path.display()).dimmed());
+        }
+        Ok(None) => {}
+        Err(e) => {
+            let _ = logger.log_error(&format!("Requirements pinning failed: {:#}", e));
+        }
+    }
+}
+
+/// Create (if needed) and switch to the environment of `task`.
+fn use_task_env(executor: &mut CodeExecutor, config: &AppConfig, task: &str) {
+    let env = match TaskEnv::new(Path::new(&config.generated_dir), task) {
+        Ok(env) => env,
+        Err(e) => {
+            println!("{} {:#}", "✗".error_color(), e);
+            return;
+        }
+    };
+    if !env.exists() {
+        println!("{}", format!("⏳ Creating the environment for task '{}'...", env.name).dimmed());
+    }
+    match env.ensure() {
+        Ok(_) => {
+            println!("{} {} ({})", "✓ Using task environment".success_color(), env.name.emphasis_color(), env.dir.display());
+            executor.set_env(Some(env));
+        }
+        Err(e) => println!("{} {:#}", "✗".error_color(), e),
+    }
+}
+
+/// `/env info`: where the active environment lives and what is installed in it.
+fn print_env_info(executor: &CodeExecutor, config: &AppConfig) {
+    let Some(env) = executor.env() else {
+        println!("{}", "No task environment: scripts use the system interpreter.".dimmed());
+        let tasks = env::list_tasks(Path::new(&config.generated_dir));
+        if !tasks.is_empty() {
+            println!("{} {}", "Task environments:".bold(), tasks.join(", "));
+        }
+        return;
+    };
+    println!("\n{} {}", "Task:".heading_color().bold(), env.name);
+    println!("  {:<14} {}", "Path:", env.dir.display());
+    println!("  {:<14} {}", "Python:", env.python_version());
+    match env.manifest() {
+        Ok(manifest) if manifest.packages.is_empty() => println!("  {:<14} {}", "Packages:", "(none)".dimmed()),
+        Ok(manifest) => println!("  {:<14} {}", "Packages:", manifest.packages.join(", ")),
+        Err(e) => println!("  {:<14} {:#}", "Packages:", e),
+    }
+    let requirements = env.requirements_path();
+    if requirements.exists() {
+        println!("  {:<14} {}", "Requirements:", requirements.display());
+    }
+}
+
+/// Print one row per imported module with its pip package and status (stdlib, installed,
+/// missing), then what the install step before the next run would do.
+fn print_dependency_table(executor: &CodeExecutor, code: &str, config: &AppConfig, declined: &HashSet<String>) {
+    let rows = executor.dependency_report(code);
+    if rows.is_empty() {
+        println!("{}", "The code has no imports.".dimmed());
+        return;
+    }
+    let width = rows.iter().map(|r| r.module.len()).max().unwrap_or(0).max("Module".len());
+    let pip_width = rows.iter().filter_map(|r| r.pip.as_ref()).map(|p| p.len()).max().unwrap_or(0).max("pip package".len());
+
+    println!("\n  {:<width$}  {:<pip_width$}  {}", "Module".bold(), "pip package".bold(), "Status".bold());
+    for row in &rows {
+        let status = match row.status {
+            DepStatus::Stdlib => row.status.label().dimmed(),
+            DepStatus::Installed => row.status.label().success_color(),
+            DepStatus::Missing => row.status.label().error_color(),
+            DepStatus::Unknown => row.status.label().warning_color(),
+        };
+        println!("  {:<width$}  {:<pip_width$}  {}", row.module.emphasis_color(), row.pip.as_deref().unwrap_or("-"), status);
+    }
+    if rows.iter().any(|r| r.status == DepStatus::Unknown) {
+        println!("{}", "  (no Python interpreter answered the import probe)".dimmed());
+    }
+
+    // What the install step before the next run would do with the missing ones
+    let missing: Vec<&DepRow> = rows.iter().filter(|r| r.status == DepStatus::Missing).collect();
+    let (skipped, offered): (Vec<&DepRow>, Vec<&DepRow>) = missing.iter().partition(|r| declined.contains(&r.module));
+    let packages: Vec<&str> = offered.iter().filter_map(|r| r.pip.as_deref()).collect();
+    if packages.is_empty() {
+        println!("\n{}", "Install step: nothing to install.".dimmed());
+    } else if config.auto_install_deps {
+        println!("\n{} {}", "Install step: will install automatically".dimmed(), packages.join(", "));
+    } else if config.install_is_trusted(&packages) {
+        println!("\n{} {}", "Install step: will install without asking (trusted):".dimmed(), packages.join(", "));
+    } else {
+        println!("\n{} {}", "Install step: will offer".dimmed(), packages.join(", "));
+    }
+    if !skipped.is_empty() {
+        let names: Vec<&str> = skipped.iter().map(|r| r.module.as_str()).collect();
+        println!("{} {}", "Skipped, declined earlier:".dimmed(), names.join(", "));
+    }
+    println!();
+}
+
+/// Run the script once per `/spec` case and report pass/fail. Returns the number passed.
+fn run_specs(executor: &CodeExecutor, script_path: &Path, cases: &[SpecCase], timeout_secs: u64) -> usize {
+    println!("\n{}", "🧪 Checking specs".heading_color().bold());
+    let mut passed = 0;
+    for (i, case) in cases.iter().enumerate() {
+        match executor.execute_with_input(script_path, &case.input, timeout_secs) {
+            Ok(result) if result.is_success() && spec::outputs_match(&result.stdout, &case.expected) => {
+                passed += 1;
+                println!("  {} case {}", "✓".success_color(), i + 1);
+            }
+            Ok(result) => {
+                println!("  {} case {}", "✗".error_color(), i + 1);
+                println!("    {} {:?}", "expected:".dimmed(), case.expected);
+                println!("    {} {:?}", "got:     ".dimmed(), result.stdout.trim_end());
+                if !result.is_success() && !result.stderr.is_empty() {
+                    println!("    {} {}", "stderr:".dimmed(), result.stderr.trim_end().lines().last().unwrap_or_default());
+                }
+            }
+            Err(e) => println!("  {} case {}: {}", "✗".error_color(), i + 1, e),
+        }
+    }
+    let summary = format!("{}/{} specs passed", passed, cases.len());
+    if passed == cases.len() {
+        println!("{}\n", summary.success_color());
+    } else {
+        println!("{}\n", summary.warning_color());
+    }
+    passed
+}
+
+/// `/edit`: show the pending prompt until it is sent as is, rewritten, or cancelled (`None`).
+fn review_prompt(args: &str) -> Option<String> {
+    let mut text = commands::inline_or_ask(args, || ask_user(tr(Msg::AskPrompt)))?;
+    loop {
+        println!("\n{}", tr(Msg::PromptToSend).heading_color().bold());
+        println!("{}", text.emphasis_color());
+        match commands::parse_review_choice(&ask_user(tr(Msg::AskSendEditCancel))) {
+            Some(ReviewChoice::Send) => return Some(text),
+            Some(ReviewChoice::Cancel) => return None,
+            Some(ReviewChoice::Edit) => {
+                // An empty answer keeps the current text
+                if let Some(new) = commands::inline_or_ask("", || ask_user(tr(Msg::AskPrompt))) {
+                    text = new;
+                }
+            }
+            None => println!("{}", tr(Msg::SendEditCancelHint).warning_color()),
+        }
+    }
+}
+
+/// Compare a run's stdout with the golden file set by `/golden`, which is used up by this
+/// run. A missing golden file can be created from the output of a successful run.
+fn check_golden(golden: &mut Option<PathBuf>, result: &CodeExecutionResult, success: bool, logger: &Logger) {
+    let Some(path) = golden.take() else {
+        return;
+    };
+    let actual = spec::normalize_output(&result.stdout);
+    match fs::read_to_string(&path) {
+        Ok(expected) => {
+            let expected = spec::normalize_output(&expected);
+            if expected == actual {
+                println!("{} {}", "✓ Golden PASS:".success_color().bold(), path.display());
+                let _ = logger.log(&format!("GOLDEN PASS: {}", path.display()));
+            } else {
+                println!("{} {} {}", "✗ Golden FAIL:".error_color().bold(), path.display(), "(- golden, + this run)".dimmed());
+                print_diff(&expected, &actual);
+                let _ = logger.log(&format!("GOLDEN FAIL: {}", path.display()));
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            if !success {
+                println!("{} {}", "⚠️  No golden file yet, and this run failed; it is kept for the next run:".warning_color(), path.display());
+                *golden = Some(path);
+            } else if confirm(&format!("No golden file {}. Create it from this output?", path.display())) {
+                match write_file(&path, format!("{actual}\n")) {
+                    Ok(()) => {
+                        println!("{} {}", "✓ Golden file created:".success_color(), path.display());
+                        let _ = logger.log(&format!("GOLDEN CREATED: {}", path.display()));
+                    }
+                    Err(e) => println!("{} {}", "✗".error_color(), e),
+                }
+            }
+        }
+        Err(e) => println!("{} {} ({})", "✗ Could not read the golden file:".error_color(), path.display(), e),
+    }
+}
+
+/// Save code the model wrote in another language under a file name with its extension.
+fn save_foreign_code(code: &str, language: CodeLanguage) {
+    let default = format!("generated_code.{}", language.extension());
+    let filename = ask_user(&format!("File name (Enter for {default}): "));
+    let filename = match filename.trim() {
+        "" => default,
+        name if !name.contains('.') => format!("{name}.{}", language.extension()),
+        name => name.to_string(),
+    };
+    let filename = match sanitize_filename(&filename) {
+        Ok(name) => name,
+        Err(e) => {
+            println!("{} {}", "✗".error_color(), e);
+            return;
+        }
+    };
+    match write_file(Path::new(&filename), format!("{}\n", strip_language_tag(code).trim_end())) {
+        Ok(()) => println!("{} {}", "✓ Code saved to:".success_color(), filename.emphasis_color()),
+        Err(e) => println!("{} {}", "✗ Failed to save:".error_color(), e),
+    }
+}
+
+/// A copy of `config` with the one-shot prompt flags applied.
+fn apply_overrides(config: &AppConfig, overrides: &PromptOverrides) -> AppConfig {
+    let mut config = config.clone();
+    if let Some(t) = overrides.temperature {
+        config.temperature = t;
+    }
+    if let Some(model) = &overrides.model {
+        config.model = model.clone();
+    }
+    if let Some(n) = overrides.max_tokens {
+        config.max_tokens = n;
+    }
+    if overrides.seed.is_some() {
+        config.seed = overrides.seed;
+    }
+    config
+}
+
+fn override_summary(overrides: &PromptOverrides) -> String {
+    let mut parts = Vec::new();
+    if let Some(t) = overrides.temperature {
+        parts.push(format!("temperature {}", t));
+    }
+    if let Some(model) = &overrides.model {
+        parts.push(format!("model {}", model));
+    }
+    if let Some(n) = overrides.max_tokens {
+        parts.push(format!("max_tokens {}", n));
+    }
+    if let Some(seed) = overrides.seed {
+        parts.push(format!("seed {}", seed));
+    }
+    parts.join(", ")
+}
+
+/// Install the selected modules' packages and report what failed.
+fn install_dependencies(executor: &CodeExecutor, modules: &[String]) {
+    println!("Installing dependencies: {}", modules.iter().map(|m| pip_name(m)).collect::<Vec<_>>().join(", "));
+    let report = executor.install_packages(modules);
+    if !report.already_satisfied.is_empty() {
+        println!("{} {}", "✓ Already satisfied:".success_color(), report.already_satisfied.join(", ").dimmed());
+    }
+    if report.installed.is_empty() && report.failed.is_empty() {
+        return;
+    }
+    match report.into_result() {
+        Ok(report) if report.failed.is_empty() => {
+            println!("{} {}", "✓ Dependencies installed successfully:".success_color(), report.installed.join(", "));
+        }
+        Ok(report) => {
+            println!("{} {}", "✓ Installed:".success_color(), report.installed.join(", "));
+            for (package, error) in &report.failed {
+                let last_line = error.lines().last().unwrap_or_default();
+                println!("{} {} {}", "✗ Could not install".error_color(), package.highlight_color(), last_line.dimmed());
+            }
+            println!("{}", "Proceeding anyway...".dimmed());
+        }
+        Err(e) => {
+            println!("{} {}", "⚠️  Failed to install dependencies:".warning_color(), e);
+            println!("{}", "Proceeding anyway...".dimmed());
+        }
+    }
+}
+
+/// Compact session indicators before the prompt, e.g. `[5msg|code|ctx1] > `.
+fn repl_prompt(history_len: usize, has_code: bool, context_files: usize) -> String {
+    let mut parts = Vec::new();
+    if history_len > 0 {
+        parts.push(format!("{history_len}msg"));
+    }
+    if has_code {
+        parts.push("code".to_string());
+    }
+    if context_files > 0 {
+        parts.push(format!("ctx{context_files}"));
+    }
+    if parts.is_empty() {
+        "> ".to_string()
+    } else {
+        format!("{} > ", format!("[{}]", parts.join("|")).dimmed())
+    }
+}
+
+/// One-line summary of the settings that change behavior, e.g.
+/// `[Qwen2.5-Coder-32B-Instruct | profile: auto | sandbox: off | auto-repair: on]`.
+fn status_line(config: &AppConfig, sandbox: SandboxStatus) -> String {
+    let model = config.model.rsplit('/').next().unwrap_or(&config.model);
+    let on_off = |b: bool| if b { "on" } else { "off" };
+    let sandbox = match sandbox {
+        SandboxStatus::Active(tool) => tool.binary(),
+        SandboxStatus::Unavailable { .. } => "unavailable",
+        SandboxStatus::Disabled => "off",
+    };
+    let mut parts = vec![
+        model.to_string(),
+        format!("profile: {}", config.profile.as_deref().unwrap_or("auto")),
+        format!("sandbox: {sandbox}"),
+        format!("auto-repair: {}", on_off(config.auto_repair)),
+    ];
+    if !config.style.is_empty() {
+        parts.push(format!("style: {}", config.style.label()));
+    }
+    if config.backend != Backend::Chat {
+        parts.push("backend: hf-textgen".to_string());
+    }
+    format!("[{}]", parts.join(" | "))
+}
+
+/// Create the executor for `generated_dir`. When a regular file is in the way, offer
+/// `<generated_dir>_scripts` for this session instead (updating `config`).
+fn open_generated_dir(config: &mut AppConfig) -> Option<CodeExecutor> {
+    let error = match CodeExecutor::new(&config.generated_dir) {
+        Ok(executor) => return Some(executor),
+        Err(e) => e,
+    };
+    println!("{} {:#}", "✗".error_color(), error);
+    let Some(FsError::FileInTheWay { path, .. }) = error.downcast_ref::<FsError>() else {
+        return None;
+    };
+    let alternative = format!("{}_scripts", config.generated_dir.trim_end_matches(['/', '\\']));
+    if !confirm(&format!("Use {alternative} for generated scripts instead (set generated_dir to keep it)?")) {
+        println!("{}", format!("Move {} out of the way, then start again.", path.display()).dimmed());
+        return None;
+    }
+    match CodeExecutor::new(&alternative) {
+        Ok(executor) => {
+            config.generated_dir = alternative;
+            Some(executor)
+        }
+        Err(e) => {
+            println!("{} {:#}", "✗".error_color(), e);
+            None
+        }
+    }
+}
+
+/// Apply the configured layout, run options, run directory, sandbox and task environment
+/// to a fresh executor: the REPL and `recipe run` execute code the same way.
+pub(crate) fn configure_executor(executor: CodeExecutor, config: &AppConfig) -> CodeExecutor {
+    let mut executor = executor
+        .with_layout(&config.generated_layout)
+        .with_run_options(run_options(config));
+    if let Some(dir) = &config.work_dir {
+        executor = executor.with_work_dir(dir);
+    }
+    if config.sandbox {
+        let sandbox = if config.sandbox_required { Sandbox::Required } else { Sandbox::Preferred };
+        executor = executor.with_sandbox(sandbox);
+        match executor.sandbox_status() {
+            SandboxStatus::Unavailable { required: true } => println!("{}",
+                "⚠️  Sandboxing is required but neither firejail nor bwrap is installed; scripts will not run.".warning_color()),
+            SandboxStatus::Unavailable { required: false } => println!("{}",
+                "⚠️  Neither firejail nor bwrap is installed; scripts will run unsandboxed.".warning_color()),
+            _ => {}
+        }
+    }
+    if let Some(task) = &config.task_env {
+        use_task_env(&mut executor, config, task);
+    }
+    executor
+}
+
+/// The session logger, or a disabled one with `--no-log` or when `log_dir` is unusable.
+pub(crate) fn open_logger(config: &AppConfig, cli: &CliArgs) -> Logger {
+    if cli.no_log {
+        return Logger::disabled();
+    }
+    match Logger::new(&config.log_dir) {
+        Ok(logger) => logger,
+        Err(e) => {
+            println!("{} {:#}", "⚠️  Logging disabled:".warning_color(), e);
+            Logger::disabled()
+        }
+    }
+}
+
+/// Index a written script and note it in the session log, where `/replay` finds it.
+fn record_script(executor: &CodeExecutor, logger: &Logger, path: &Path, provenance: &Provenance, prompt: &str) {
+    let _ = logger.log_script(path);
+    if let Err(e) = executor.record_script(path, provenance, prompt) {
+        let _ = logger.log_error(&format!("Index update failed: {}", e));
+    }
+}
+
+/// The active conversation line, which accepting new code updates.
+struct ActiveLine<'a> {
+    history: &'a mut Vec<Message>,
+    last_code: &'a mut String,
+    last_provenance: &'a mut Option<Provenance>,
+    turns: &'a mut Vec<SessionTurn>,
+}
+
+/// New code the user kept from `/annotate`, `/shorten` or `/patch`.
+struct Refinement<'a> {
+    /// What the change asked for, in the refinement wording.
+    request: String,
+    code: &'a str,
+    /// How the script is noted in the session log and the index (`patch main`).
+    label: &'a str,
+    /// Reference context of the request, part of the provenance hash.
+    context: &'a [Message],
+}
+
+/// Record `refinement` as a refinement turn so later requests build on it: it joins the
+/// history and the versions, gets a description, and is saved and written as a script.
+async fn accept_refinement(
+    line: ActiveLine<'_>,
+    refinement: Refinement<'_>,
+    config: &AppConfig,
+    executor: &CodeExecutor,
+    logger: &Logger,
+    metrics: &mut SessionMetrics,
+    descriptions: &mut HashMap<String, String>,
+) {
+    let Refinement { request, code, label, context } = refinement;
+    line.history.push(Message { role: "user".to_string(), content: request.clone() });
+    let mut provenance = Provenance::new(config, &api::full_messages(&with_context(context, line.history), config.profile.as_deref(), &config.style));
+    provenance.description = Some(describe_script(code, &request, config, descriptions, metrics, logger).await);
+    let mut turn = SessionTurn::new(&request);
+    turn.set_code(code);
+    line.turns.push(turn);
+    let _ = logger.log_turn(line.turns.len(), &request);
+    if let Some(change) = export::last_deps_change(line.turns).filter(|c| !c.is_empty()) {
+        println!("{} {}", "📦 Dependencies changed:".warning_color(), change.summary());
+        let _ = logger.log(&format!("DEPENDENCIES: {}", change.summary()));
+    }
+
+    line.history.push(Message { role: "assistant".to_string(), content: code.to_string() });
+    trim_history(line.history, config.max_history_messages);
+    *line.last_code = code.to_string();
+    save_scratch(config, line.last_code, logger);
+    *line.last_provenance = Some(provenance.clone());
+    match executor.write_script(&render_script(code, Some(&provenance), config)) {
+        Ok(path) => {
+            record_script(executor, logger, &path, &provenance, label);
+            println!("{} {}", "✓ Saved as".success_color(), path.display());
+        }
+        Err(e) => println!("{} {}", "✗ Failed to write script:".error_color(), e),
+    }
+}
+
+/// Pass freshly extracted code through the `post_generation_hooks`, logging each result
+/// and showing failures. `None` when a `reject` hook failed and the code must be dropped.
+pub(crate) fn apply_hooks(code: &str, config: &AppConfig, logger: &Logger) -> Option<String> {
+    let run = hooks::run_hooks(code, &config.post_generation_hooks);
+    for report in &run.reports {
+        let outcome = match &report.status {
+            HookStatus::Unchanged => "unchanged".to_string(),
+            HookStatus::Modified => "modified the code".to_string(),
+            HookStatus::Failed(reason) => format!("failed ({reason})"),
+        };
+        let _ = logger.log(&format!("HOOK `{}`: {} [{} ms]", report.command, outcome, report.elapsed.as_millis()));
+        match &report.status {
+            HookStatus::Failed(_) if run.rejected_by.as_ref() == Some(&report.command) => {}
+            HookStatus::Failed(_) => println!("{}", format!("⚠️  Hook `{}` {outcome}; keeping the code from before it.", report.command).warning_color()),
+            HookStatus::Modified => println!("{}", format!("🪝 Hook `{}` modified the code.", report.command).dimmed()),
+            HookStatus::Unchanged => {}
+        }
+    }
+    if let Some(command) = &run.rejected_by {
+        let reason = run.reports.last().map(|r| match &r.status {
+            HookStatus::Failed(reason) => reason.clone(),
+            _ => String::new(),
+        }).unwrap_or_default();
+        println!("{}", format!("✗ Generation rejected by hook `{command}`: {reason}").warning_color());
+        return None;
+    }
+    Some(run.code)
+}
+
+/// Verbose `/status`: everything in the status line plus paths and budget usage.
+fn print_status(
+    config: &AppConfig,
+    executor: &CodeExecutor,
+    history: &[Message],
+    reference_context: &[Message],
+) {
+    let prompt_tokens = api::estimate_tokens(&api::full_messages(
+        &with_context(reference_context, history),
+        config.profile.as_deref(),
+        &config.style,
+    ));
+    let generated_dir = std::path::absolute(executor.base_dir()).unwrap_or_else(|_| executor.base_dir().to_path_buf());
+
+    println!("\n{}", "━━━━━━━━━━━ Status ━━━━━━━━━━━".heading_color().bold());
+    println!("  {:<18} {}", "Model:", config.model);
+    println!("  {:<18} {}", "Endpoint:", config.api_url);
+    println!("  {:<18} {}", "Profile:", config.profile.as_deref().unwrap_or("auto"));
+    if !config.style.is_empty() {
+        println!("  {:<18} {}", "Style:", config.style.label());
+    }
+    println!("  {:<18} {}", "System prompt:", api::system_prompt_variant(history, config.profile.as_deref(), &config.style));
+    println!("  {:<18} {}", "Sandbox:", sandbox_description(executor.sandbox_status()));
+    println!("  {:<18} {}", "Auto-repair:", if config.auto_repair { "on" } else { "off" });
+    println!("  {:<18} {}", "Auto-install deps:", if config.auto_install_deps { "on" } else { "off" });
+    println!("  {:<18} {}/{}", "History messages:", history.len(), config.max_history_messages);
+    println!("  {:<18} {}", "Context files:", reference_context.len());
+    println!("  {:<18} ~{} prompt tokens (est.) + {} max reply tokens", "Token budget:", prompt_tokens, config.max_tokens);
+    println!("  {:<18} {}", "Generated dir:", generated_dir.display());
+    println!("  {:<18} {}", "Run directory:", executor.work_dir().display());
+    println!("  {:<18} {}", "Task env:", executor.env().map_or("(system interpreter)".to_string(), |e| e.name.clone()));
+    println!("{}", "━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━".heading_color());
+}
+
+/// Lines of each alternative shown by `/choices`.
+const CHOICE_PREVIEW_LINES: usize = 15;
+
+/// Automatic repair rounds after a freshly generated script fails `py_compile`.
+const MAX_REPAIR_ATTEMPTS: u32 = 2;
+
+/// Program output embedded in a follow-up request is cut to this many bytes.
+const MAX_UNTRUSTED_OUTPUT: usize = 4000;
+
+/// Follow-up asking the model to fix code that doesn't compile.
+fn repair_prompt(syntax_err: &str) -> String {
+    format!(
+        "Your previous answer does not compile:\n{}\n\
+         Return ONLY the corrected, complete Python program inside a single ```python fenced block. \
+         No explanations outside the code.",
+        sanitize_untrusted(syntax_err, MAX_UNTRUSTED_OUTPUT)
+    )
+}
+
+/// Messages actually sent for a request: `/context` references first, then the conversation.
+fn with_context(context: &[Message], history: &[Message]) -> Vec<Message> {
+    context.iter().chain(history).cloned().collect()
+}
+
+/// `/context` files are read up to this size; past `max_context_bytes` only the blocks
+/// relevant to each request are sent.
+const MAX_CONTEXT_FILE_BYTES: usize = 2_000_000;
+
+/// A file added with `/context`, kept whole so each request can pick what it needs.
+struct ContextFile {
+    path: String,
+    contents: String,
+}
+
+/// The `/context` files as messages for a request about `prompt`: large files are cut
+/// down to their blocks relevant to it (see `relevant_context`).
+fn context_messages(files: &[ContextFile], prompt: &str, max_bytes: usize) -> Vec<Message> {
+    files
+        .iter()
+        .map(|file| {
+            let (contents, trimmed) = relevant_context(&file.contents, prompt, max_bytes);
+            let note = if trimmed { ", only the parts relevant to this request" } else { "" };
+            Message {
+                role: "user".to_string(),
+                content: format!("Here is existing code for reference ({}{}):\n{}", file.path, note, contents),
+            }
+        })
+        .collect()
+}
+
+/// The `/context` files as messages for the latest request of `history`.
+fn reference_context(files: &[ContextFile], history: &[Message], max_bytes: usize) -> Vec<Message> {
+    let last_prompt = history.iter().rev().find(|m| m.role == "user").map_or("", |m| m.content.as_str());
+    context_messages(files, last_prompt, max_bytes)
+}
+
+/// What goes ahead of the conversation in a request: the reference files, then the
+/// spec examples. Built only when a request is about to be sent or counted.
+fn request_context(files: &[ContextFile], history: &[Message], specs: &[SpecCase], max_bytes: usize) -> Vec<Message> {
+    reference_context(files, history, max_bytes).into_iter().chain(spec::spec_message(specs)).collect()
+}
+
+/// Trim conversation history to at most `max` messages, dropping the oldest
+/// user/assistant pairs first.
+fn trim_history(history: &mut Vec<Message>, max: usize) {
+    while history.len() > max {
+        // Remove in pairs (user + assistant) from the front
+        if history.len() >= 2 {
+            history.drain(..2);
         } else {
-            println!("{}", line);
+            history.remove(0);
+        }
+    }
+}
+
+// Boucle interactive : affiche le bandeau de lancement
+pub async fn start_repl(config: &AppConfig, cli: &CliArgs) {
+    print_banner();
+
+    let mut config = config.clone();
+    let Some(executor) = open_generated_dir(&mut config) else {
+        return;
+    };
+    let config = &config;
+    let mut executor = configure_executor(executor, config);
+    let logger = open_logger(config, cli);
+    let _ = logger.log(&format!(
+        "TIMEOUTS: connect={}s request={}s",
+        config.connect_timeout_secs, config.request_timeout_secs
+    ));
+    match clean_logs(config, &logger) {
+        Ok(Some(summary)) => println!("{} {}", "🧹 Removed".dimmed(), summary.dimmed()),
+        Ok(None) => {}
+        Err(e) => println!("{} {:#}", "⚠️  Log cleanup failed:".warning_color(), e),
+    }
+    let mut metrics = SessionMetrics::new();
+    // Script descriptions by code hash, so code that comes back unchanged isn't described twice
+    let mut descriptions: HashMap<String, String> = HashMap::new();
+    // Session copy so /model can switch models without touching the file config
+    let mut session_config = config.clone();
+    session_config.style.json_result = config.capture_result;
+    let refine_template = config.refine_template().unwrap_or_else(|e| {
+        println!("{} {}", "⚠️  Using the default refinement wording:".warning_color(), e);
+        DEFAULT_REFINE_TEMPLATE.to_string()
+    });
+
+    // Conversation history for multi-turn refinement
+    let mut conversation_history: Vec<Message> = Vec::new();
+    let mut last_generated_code = String::new();
+    let mut last_provenance: Option<Provenance> = None;
+    // Reference files from /context, kept apart so /context clear leaves the conversation intact
+    let mut context_files: Vec<ContextFile> = Vec::new();
+    let mut plain_prompt = false;
+    // Last status line shown; it is printed again only when something changed
+    let mut shown_status: Option<String> = None;
+    // Dependencies the user chose not to install; not offered again this session
+    let mut declined_deps: HashSet<String> = HashSet::new();
+    // `/spec` examples, kept across refinements
+    let mut specs: Vec<SpecCase> = Vec::new();
+    // `/golden`: the file the stdout of the next run is compared with
+    let mut golden: Option<PathBuf> = None;
+    // `/branch`: the active branch's name and the branches set aside
+    let mut current_branch = branch::MAIN_BRANCH.to_string();
+    let mut other_branches: Vec<Branch> = Vec::new();
+    // Generation requests so far, with their code and latest run, for `/export-md`,
+    // `/recipe export`, `/versions` and `/deps --diff`
+    let mut turns: Vec<SessionTurn> = Vec::new();
+    let mut prompt_reader = PromptReader::new();
+
+    loop {
+        let config = &session_config;
+        // A pending /golden comparison needs the stdout exactly as the script printed it
+        let collapse = config.collapse_progress && golden.is_none();
+        if executor.run_options().collapse_progress != collapse {
+            let options = RunOptions { collapse_progress: collapse, ..executor.run_options() };
+            executor = executor.with_run_options(options);
+        }
+        if let Some(failure) = logger.take_failure() {
+            println!("{} {}", "⚠️  Session logging is failing:".warning_color(), failure);
+        }
+
+        if config.status_line {
+            let line = status_line(config, executor.sandbox_status());
+            if shown_status.as_ref() != Some(&line) {
+                println!("{}", line.dimmed());
+                shown_status = Some(line);
+            }
+        }
+
+        let prompt_text = if plain_prompt {
+            "> ".to_string()
+        } else {
+            repl_prompt(conversation_history.len(), !last_generated_code.is_empty(), context_files.len())
+        };
+        // End of input (Ctrl+D, or the end of piped commands) quits like /quit
+        let Some(prompt) = prompt_reader.read(&prompt_text) else {
+            println!("{}", tr(Msg::Goodbye));
+            break;
+        };
+        let command = commands::parse(&prompt);
+        let (cmd, args) = match &command {
+            Some(c) => (c.name.as_str(), c.args.as_str()),
+            None => ("", ""),
+        };
+
+        if command.as_ref().is_some_and(|c| !c.known) {
+            println!("{} {} {}", "Unknown command".warning_color(), cmd.emphasis_color(), "(type /help for the list)".dimmed());
+            continue;
+        }
+
+        if cmd == "/quit" {
+            println!("{}", tr(Msg::Goodbye));
+            break;
+        }
+
+        if cmd == "/help" {
+            print_help();
+            continue;
+        }
+
+        if cmd == "/lang" {
+            match Lang::parse(args) {
+                Some(lang) => {
+                    messages::set_lang(lang);
+                    println!("{}", tr(Msg::LangSwitched).success_color());
+                }
+                None => println!("{} {}", tr(Msg::LangUsage).warning_color(), format!("({})", messages::lang().code()).dimmed()),
+            }
+            continue;
+        }
+
+        if cmd == "/view" {
+            if last_generated_code.is_empty() {
+                println!("{}", "No code to view. Generate some code first!".warning_color());
+            } else {
+                view_code(&last_generated_code, args);
+            }
+            continue;
+        }
+
+        if cmd == "/theme" {
+            let names: Vec<&str> = theme::THEMES.iter().map(|t| t.name).collect();
+            match Theme::by_name(args) {
+                Some(selected) => {
+                    theme::set_theme(selected);
+                    println!("{}", theme::paint(&format!("✓ Theme set to {}.", selected.name), selected.success));
+                }
+                None => println!(
+                    "{} {}",
+                    format!("Usage: /theme {}", names.join("|")).warning_color(),
+                    format!("({})", theme::current().name).dimmed()
+                ),
+            }
+            continue;
+        }
+
+        if cmd == "/count" {
+            // Same assembly as the next request: system prompt, /context files, then history
+            let reference_context = reference_context(&context_files, &conversation_history, config.max_context_bytes);
+            let context: Vec<Message> = reference_context.iter().cloned().chain(spec::spec_message(&specs)).collect();
+            let next_request = api::full_messages(&with_context(&context, &conversation_history), config.profile.as_deref(), &config.style);
+            let tokens = api::estimate_tokens(&next_request);
+            let system = api::estimate_tokens(&next_request[..1]);
+            let context = api::estimate_tokens(&reference_context);
+            println!("\n{} ~{} tokens (estimate, ~4 chars/token)", "Next request:".heading_color().bold(), tokens);
+            println!("  {:<16} ~{}", "System prompt:", system);
+            println!("  {:<16} ~{}", "Context files:", context);
+            println!("  {:<16} ~{} ({} messages)", "History:", tokens - system - context, conversation_history.len());
+            println!("  {:<16} {}", "Reply budget:", config.max_tokens);
+            let limit = config.context_limit_tokens;
+            if tokens + config.max_tokens as usize > limit {
+                println!("{}", format!(
+                    "⚠️  Prompt + reply budget exceeds the {limit}-token context limit; /clear or lower max_tokens."
+                ).warning_color());
+            } else if tokens * 10 >= limit * 8 {
+                println!("{}", format!("⚠️  Prompt is over 80% of the {limit}-token context limit.").warning_color());
+            }
+            println!();
+            continue;
+        }
+
+        if cmd == "/status" {
+            print_status(config, &executor, &conversation_history, &reference_context(&context_files, &conversation_history, config.max_context_bytes));
+            continue;
+        }
+
+        if cmd == "/plainprompt" {
+            plain_prompt = !plain_prompt;
+            let state = if plain_prompt { "plain `> `" } else { "with session indicators" };
+            println!("{} {}", "✓ Prompt is now".success_color(), state);
+            continue;
+        }
+
+        if cmd == "/stats" {
+            let (sub, target) = args.split_once(char::is_whitespace).unwrap_or((args, ""));
+            if sub == "export" {
+                export_stats(&metrics, target.trim(), &logger);
+                continue;
+            }
+            metrics.display();
+            println!("{} {}", "Sandbox:".bold(), sandbox_description(executor.sandbox_status()));
+            println!("{} {}", "Install policy:".bold(), config.install_policy());
+            if let Some(rate) = api::last_rate(config) {
+                let summary = rate.summary(chrono::Utc::now());
+                println!("{} {}", "Rate limit:".bold(), if summary.is_empty() { "reset time passed".to_string() } else { summary });
+            }
+            continue;
+        }
+
+        if cmd == "/models" {
+            match api::list_models(config).await {
+                Ok(ids) => {
+                    let show_all = args == "all";
+                    let shown: Vec<&String> = ids.iter().filter(|id| show_all || api::is_code_model(id)).collect();
+                    println!("\n{}", format!("Available models ({} of {}):", shown.len(), ids.len()).heading_color().bold());
+                    for id in shown {
+                        let marker = if *id == config.model { " (current)".success_color().to_string() } else { String::new() };
+                        println!("  {}{}", id.emphasis_color(), marker);
+                    }
+                    if !show_all {
+                        println!("{}", "Showing likely code models; /models all lists everything.".dimmed());
+                    }
+                }
+                Err(e) => {
+                    println!("{} {}", "⚠️  Could not list models:".warning_color(), e);
+                    println!("\n{}", "Some known code models:".heading_color().bold());
+                    for id in api::CURATED_CODE_MODELS {
+                        println!("  {}", id.emphasis_color());
+                    }
+                }
+            }
+            println!("{}", "Switch with /model <name>.".dimmed());
+            continue;
+        }
+
+        if cmd == "/setup" {
+            match setup::run(config).await {
+                Ok(configured) => {
+                    if configured.generated_dir != config.generated_dir || configured.log_dir != config.log_dir {
+                        println!("{}", "New directories are used from the next launch.".dimmed());
+                    }
+                    session_config = AppConfig {
+                        generated_dir: config.generated_dir.clone(),
+                        log_dir: config.log_dir.clone(),
+                        ..configured
+                    };
+                }
+                Err(e) => println!("{} {:#}", "✗ Setup failed:".error_color(), e),
+            }
+            continue;
+        }
+
+        if cmd == "/model" {
+            if args.is_empty() {
+                println!("{} {}", "Current model:".heading_color(), config.model.emphasis_color());
+            } else {
+                session_config.model = args.to_string();
+                println!("{} {}", "✓ Switched model to".success_color(), args.emphasis_color());
+            }
+            continue;
+        }
+
+        if cmd == "/style" {
+            if !args.is_empty() {
+                if let Err(e) = session_config.style.apply(args) {
+                    println!("{}", e.to_string().warning_color());
+                    continue;
+                }
+                let _ = logger.log(&format!("STYLE: {}", session_config.style.label()));
+                // The executor parses the markers exactly when the prompt asks for them
+                let mut options = run_options(&session_config);
+                options.capture_result = session_config.style.json_result;
+                executor = executor.with_run_options(options);
+            }
+            let directives = session_config.style.directives();
+            if directives.is_empty() {
+                println!("{}", "No style directives. Try /style hints on or /style pyversion 3.8.".dimmed());
+            } else {
+                println!("{}", "Style directives added to the system prompt:".heading_color());
+                for directive in directives {
+                    println!("  - {directive}");
+                }
+            }
+            continue;
+        }
+
+        if cmd == "/context" {
+            let arg = args;
+            if arg.is_empty() {
+                if context_files.is_empty() {
+                    println!("{}", "No reference context loaded. Use /context <path>.".warning_color());
+                } else {
+                    println!("\n{}", "Reference context:".heading_color().bold());
+                    for file in &context_files {
+                        let trimmed = if file.contents.len() > config.max_context_bytes { ", trimmed to the relevant blocks" } else { "" };
+                        println!("  - {} ({} bytes{})", file.path.dimmed(), file.contents.len(), trimmed);
+                    }
+                    println!();
+                }
+            } else if arg == "clear" {
+                context_files.clear();
+                println!("{}", "✓ Reference context cleared.".success_color());
+            } else {
+                match read_context_file(Path::new(arg), MAX_CONTEXT_FILE_BYTES) {
+                    Ok((contents, truncated)) => {
+                        if truncated {
+                            println!("{}", format!("⚠️  File truncated to {MAX_CONTEXT_FILE_BYTES} bytes").warning_color());
+                        }
+                        if contents.len() > config.max_context_bytes {
+                            println!("{}", format!(
+                                "⚠️  Larger than max_context_bytes ({}): each request gets only the blocks relevant to it.",
+                                config.max_context_bytes
+                            ).warning_color());
+                        }
+                        context_files.push(ContextFile { path: arg.to_string(), contents });
+                        println!("{} {}", "✓ Added reference context:".success_color(), arg.emphasis_color());
+                    }
+                    Err(e) => println!("{} {:#}", "✗ Failed to load context:".error_color(), e),
+                }
+            }
+            continue;
         }
-    }
-    println!("{}\n", "━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━".bright_green());
-}
 
-/// Trim conversation history to at most `max` messages, dropping the oldest
-/// user/assistant pairs first.
-fn trim_history(history: &mut Vec<Message>, max: usize) {
-    while history.len() > max {
-        // Remove in pairs (user + assistant) from the front
-        if history.len() >= 2 {
-            history.drain(..2);
-        } else {
-            history.remove(0);
+        if cmd == "/provenance" {
+            match &last_provenance {
+                Some(p) => println!("\n{}\n", p.header().emphasis_color()),
+                None => println!("{}", "No generated code yet.".warning_color()),
+            }
+            continue;
         }
-    }
-}
 
-// Boucle interactive : affiche le bandeau de lancement
-pub async fn start_repl(config: &AppConfig) {
-    print_banner();
+        if cmd == "/choices" {
+            let n = match args {
+                "" => 3,
+                _ => match args.parse::<u32>() {
+                    Ok(n) if (2..=5).contains(&n) => n,
+                    _ => {
+                        println!("{}", "Usage: /choices [2-5]".warning_color());
+                        continue;
+                    }
+                },
+            };
+            if last_generated_code.is_empty() || conversation_history.last().is_none_or(|m| m.role != "assistant") {
+                println!("{}", "No code to compare. Generate some code first!".warning_color());
+                continue;
+            }
 
-    let executor = CodeExecutor::new(&config.generated_dir).expect("Impossible de créer le dossier");
-    let logger = Logger::new(&config.log_dir).expect("Failed to create logger");
-    let mut metrics = SessionMetrics::new();
+            // Re-ask the request that produced the current code
+            let request_history = &conversation_history[..conversation_history.len() - 1];
+            let request_prompt = request_history.last().map(|m| m.content.clone()).unwrap_or_default();
+            let context = request_context(&context_files, request_history, &specs, config.max_context_bytes);
+            let messages = with_context(&context, request_history);
+            metrics.total_requests += 1;
+            let _ = logger.log_api_request(&format!("Choices x{}: {}", n, request_prompt));
+            println!("{}", format!("⏳ Requesting {} alternatives...", n).dimmed());
 
-    // Conversation history for multi-turn refinement
-    let mut conversation_history: Vec<Message> = Vec::new();
-    let mut last_generated_code = String::new();
+            let start = Instant::now();
+            let choices = if config.backend == Backend::Chat {
+                api::generate_choices(messages.clone(), config, n).await
+            } else {
+                candidate_choices(&messages, config, n, &logger).await
+            };
+            match choices {
+                Ok(choices) => {
+                    metrics.record_api_latency(start.elapsed(), &request_prompt);
+                    record_model_call(&mut metrics, config, start.elapsed(), &api::full_messages(&messages, config.profile.as_deref(), &config.style), &choices.concat());
+                    let codes: Vec<String> = choices.iter().map(|c| extract_python_code(c)).collect();
+                    if codes.len() < n as usize {
+                        println!("{}", format!("The provider returned {} of {} choices.", codes.len(), n).dimmed());
+                    }
+                    for (i, code) in codes.iter().enumerate() {
+                        let lines = code.lines().count();
+                        println!("\n{}", format!("── Choice {} ({} lines) ──", i + 1, lines).heading_color().bold());
+                        for line in code.lines().take(CHOICE_PREVIEW_LINES) {
+                            println!("{}", line);
+                        }
+                        if lines > CHOICE_PREVIEW_LINES {
+                            println!("{}", "…".dimmed());
+                        }
+                    }
 
-    loop {
-        let prompt = ask_user("> ");
+                    let answer = ask_user(&format!("\n[1-{}] {} ", codes.len(), tr(Msg::AskKeepChoice)));
+                    let Some(code) = answer.trim().parse::<usize>().ok()
+                        .filter(|i| (1..=codes.len()).contains(i))
+                        .map(|i| codes[i - 1].clone())
+                    else {
+                        println!("{}", "Keeping the current code.".dimmed());
+                        continue;
+                    };
+                    let Some(code) = apply_hooks(&code, config, &logger) else {
+                        continue;
+                    };
 
-        if prompt == "/quit" || prompt == "/exit" {
-            println!("Goodbye!");
-            break;
+                    let provenance = Provenance::new(config, &api::full_messages(&messages, config.profile.as_deref(), &config.style));
+                    if let Some(last) = conversation_history.last_mut() {
+                        last.content = code.clone();
+                    }
+                    last_generated_code = code.clone();
+                    save_scratch(config, &last_generated_code, &logger);
+                    last_provenance = Some(provenance.clone());
+                    show_code(&code, config);
+                    match executor.write_script(&render_script(&code, Some(&provenance), config)) {
+                        Ok(path) => {
+                            record_script(&executor, &logger, &path, &provenance, &request_prompt);
+                            println!("{} {}", "✓ Saved as".success_color(), path.display());
+                        }
+                        Err(e) => println!("{} {}", "✗ Failed to write script:".error_color(), e),
+                    }
+                }
+                Err(e) => {
+                    metrics.api_errors += 1;
+                    let _ = logger.log_error(&format!("API error: {}", e));
+                    println!("{} {}", "✗ API error:".error_color(), e);
+                }
+            }
+            continue;
         }
 
-        if prompt == "/help" {
-            println!("\n{}", "Available Commands:".bright_cyan().bold());
-            println!("  {}  - Exit the program", "/quit, /exit".green());
-            println!("  {}         - Show this help", "/help".green());
-            println!("  {}        - Clear conversation history", "/clear".green());
-            println!("  {}       - Refine the last generated code", "/refine".green());
-            println!("  {} <file> - Save last code to a file", "/save".green());
-            println!("  {}      - Show conversation history", "/history".green());
-            println!("  {}        - Show session statistics", "/stats".green());
-            println!("  {}         - List all generated scripts", "/list".green());
-            println!("  {} <file>  - Execute a previously generated script", "/run".green());
-            println!();
+        if cmd == "/vs" {
+            let mut words = args.split_whitespace();
+            let (Some(model_a), Some(model_b)) = (words.next(), words.next()) else {
+                println!("{}", "Usage: /vs <modelA> <modelB> [prompt]".warning_color());
+                continue;
+            };
+            // The rest of the line, its own spacing kept, is the prompt
+            let new_prompt = args
+                .trim_start()
+                .strip_prefix(model_a)
+                .map(str::trim_start)
+                .and_then(|rest| rest.strip_prefix(model_b))
+                .map(str::trim)
+                .filter(|p| !p.is_empty());
+
+            // A new prompt is asked on top of the history; without one, the request that
+            // produced the current code is asked again
+            let mut request_history = conversation_history.clone();
+            match new_prompt {
+                Some(text) => request_history.push(Message {
+                    role: "user".to_string(),
+                    content: text.to_string(),
+                }),
+                None if conversation_history.last().is_some_and(|m| m.role == "assistant") => {
+                    request_history.pop();
+                }
+                None => {
+                    println!("{}", "Nothing to compare yet: add a prompt, e.g. /vs <modelA> <modelB> write a CSV deduplicator".warning_color());
+                    continue;
+                }
+            }
+            let request_prompt = request_history.last().map(|m| m.content.clone()).unwrap_or_default();
+            let context = request_context(&context_files, &request_history, &specs, config.max_context_bytes);
+            let messages = with_context(&context, &request_history);
+            let configs = [
+                AppConfig { model: model_a.to_string(), ..config.clone() },
+                AppConfig { model: model_b.to_string(), ..config.clone() },
+            ];
+            metrics.total_requests += 2;
+            let _ = logger.log_api_request(&format!("Compare {} vs {}: {}", model_a, model_b, request_prompt));
+            println!("{}", format!("⏳ Asking {} and {}...", model_a, model_b).dimmed());
+
+            let start = Instant::now();
+            let candidates = api::generate_candidates(&messages, &configs).await;
+            metrics.record_api_latency(start.elapsed(), &request_prompt);
+
+            // A failed model is reported and the other one is still shown
+            for (index, e) in &candidates.failed {
+                let model = configs.get(*index).map_or("a model", |cfg| cfg.model.as_str());
+                metrics.api_errors += 1;
+                let _ = logger.log_error(&format!("API error from {}: {:#}", model, e));
+                println!("{} {}: {:#}", "✗ API error from".error_color(), model, e);
+            }
+            let mut codes: Vec<Option<String>> = Vec::new();
+            for (index, cfg) in configs.iter().enumerate() {
+                let Some(generation) = candidates.get(index) else {
+                    codes.push(None);
+                    continue;
+                };
+                metrics.api_retries += generation.retries as usize;
+                record_model_call(&mut metrics, cfg, start.elapsed(), &api::full_messages(&messages, cfg.profile.as_deref(), &cfg.style), &generation.content);
+                let _ = logger.log_api_response(&format!("[{}]\n{}", cfg.model, generation.content), start.elapsed());
+                codes.push(Some(extract_python_code(&generation.content)));
+            }
+
+            match (&codes[0], &codes[1]) {
+                (Some(a), Some(b)) => {
+                    let width = std::env::var("COLUMNS").ok().and_then(|c| c.parse::<usize>().ok()).unwrap_or(120);
+                    let column = (width.saturating_sub(3) / 2).max(20);
+                    println!("\n{}", side_by_side(&format!("A: {}", model_a), &format!("B: {}", model_b), column).join("\n").heading_color().bold());
+                    for row in side_by_side(a, b, column) {
+                        println!("{}", row);
+                    }
+                    println!("\n{}", "Diff (A → B)".heading_color().bold());
+                    print_diff(a, b);
+                    println!(
+                        "{}",
+                        format!("A: {} lines, {} third-party imports · B: {} lines, {} third-party imports",
+                            a.lines().count(), third_party_imports(a), b.lines().count(), third_party_imports(b)).dimmed()
+                    );
+                }
+                (Some(code), None) | (None, Some(code)) => show_code(code, config),
+                (None, None) => continue,
+            }
+
+            let answer = ask_user("Adopt which version? [a/b, Enter keeps the current code] ");
+            let picked = match answer.trim().to_lowercase().as_str() {
+                "a" => 0,
+                "b" => 1,
+                _ => {
+                    println!("{}", "Keeping the current code.".dimmed());
+                    continue;
+                }
+            };
+            let Some(code) = codes[picked].clone() else {
+                println!("{}", "That model returned nothing; keeping the current code.".warning_color());
+                continue;
+            };
+            let Some(code) = apply_hooks(&code, config, &logger) else {
+                continue;
+            };
+
+            let provenance = Provenance::new(&configs[picked], &api::full_messages(&messages, configs[picked].profile.as_deref(), &configs[picked].style));
+            if new_prompt.is_some() {
+                conversation_history = request_history;
+                conversation_history.push(Message {
+                    role: "assistant".to_string(),
+                    content: code.clone(),
+                });
+                trim_history(&mut conversation_history, config.max_history_messages);
+            } else if let Some(last) = conversation_history.last_mut() {
+                last.content = code.clone();
+            }
+            last_generated_code = code.clone();
+            save_scratch(config, &last_generated_code, &logger);
+            last_provenance = Some(provenance.clone());
+            match executor.write_script(&render_script(&code, Some(&provenance), config)) {
+                Ok(path) => {
+                    record_script(&executor, &logger, &path, &provenance, &request_prompt);
+                    println!("{} {} ({})", "✓ Saved as".success_color(), path.display(), configs[picked].model);
+                }
+                Err(e) => println!("{} {}", "✗ Failed to write script:".error_color(), e),
+            }
             continue;
         }
 
-        if prompt == "/stats" {
-            metrics.display();
+        if cmd == "/ping" {
+            check_api(config).await;
+            continue;
+        }
+
+        if cmd == "/export-md" {
+            if args.is_empty() {
+                println!("{}", "Usage: /export-md <file.md>".warning_color());
+                continue;
+            }
+            if turns.is_empty() {
+                println!("{}", "No requests to export yet.".warning_color());
+                continue;
+            }
+            let file = if args.contains('.') { args.to_string() } else { format!("{args}.md") };
+            let file = match sanitize_filename(&file) {
+                Ok(file) => file,
+                Err(e) => {
+                    println!("{} {}", "✗".error_color(), e);
+                    continue;
+                }
+            };
+            let exported_at = chrono::Local::now().format("%Y-%m-%d %H:%M").to_string();
+            let markdown = export::render_markdown(&turns, &config.model, &exported_at);
+            let path = Path::new(&file);
+            match fs::write(path, markdown) {
+                Ok(()) => {
+                    let shown = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+                    println!("{} {} ({} request(s))", "✓ Report written to".success_color(), shown.display(), turns.len());
+                }
+                Err(e) => println!("{} Could not write {}: {}", "✗".error_color(), path.display(), e),
+            }
+            continue;
+        }
+
+        if cmd == "/recipe" {
+            let (sub, rest) = args.split_once(' ').unwrap_or((args, ""));
+            let mut words = rest.split_whitespace();
+            match (sub, words.next()) {
+                ("export", Some(file)) => {
+                    if turns.is_empty() {
+                        println!("{}", "No requests to export yet.".warning_color());
+                        continue;
+                    }
+                    let file = if file.contains('.') { file.to_string() } else { format!("{file}.yaml") };
+                    let file = match sanitize_filename(&file) {
+                        Ok(file) => file,
+                        Err(e) => {
+                            println!("{} {}", "✗".error_color(), e);
+                            continue;
+                        }
+                    };
+                    let steps: Vec<RecipeStep> = turns.iter().map(SessionTurn::recipe_step).collect();
+                    match Recipe::from_session(config, &steps).save(Path::new(&file)) {
+                        Ok(()) => println!("{} {} ({} step(s))", "✓ Recipe written to".success_color(), file, steps.len()),
+                        Err(e) => println!("{} {:#}", "✗".error_color(), e),
+                    }
+                }
+                ("run", Some(file)) => {
+                    let flags: Vec<&str> = words.collect();
+                    let run = RecipeRun {
+                        path: PathBuf::from(file),
+                        keep_going: flags.contains(&"--keep-going"),
+                        yes: flags.contains(&"--yes"),
+                    };
+                    let loaded = match Recipe::load(&run.path) {
+                        Ok(loaded) => loaded,
+                        Err(e) => {
+                            println!("{} {:#}", "✗".error_color(), e);
+                            continue;
+                        }
+                    };
+                    match recipe::replay(&loaded, config, &executor, &logger, &run, cli.allow_dangerous).await {
+                        Ok(outcomes) => println!("\n{}", recipe::report(&outcomes, loaded.steps.len())),
+                        Err(e) => println!("{} {:#}", "✗ Replay failed:".error_color(), e),
+                    }
+                }
+                _ => println!("{}", "Usage: /recipe export <file.yaml> | /recipe run <file.yaml> [--keep-going] [--yes]".warning_color()),
+            }
+            continue;
+        }
+
+        if cmd == "/branch" {
+            let mut parts = args.split_whitespace();
+            match parts.next() {
+                None | Some("list") => {
+                    println!("\n{}", "Branches:".heading_color().bold());
+                    println!(
+                        "  {} {} — {} exchange(s), last: {}",
+                        "*".success_color(),
+                        current_branch.success_color().bold(),
+                        branch::exchange_count(&conversation_history),
+                        branch::preview(&conversation_history)
+                    );
+                    for b in &other_branches {
+                        println!("    {} — {}", b.name, b.summary());
+                    }
+                    println!();
+                }
+                Some("switch") => {
+                    let Some(name) = parts.next() else {
+                        println!("{}", "Usage: /branch switch <name>".warning_color());
+                        continue;
+                    };
+                    if name == current_branch {
+                        println!("{}", format!("Already on {}.", name).dimmed());
+                        continue;
+                    }
+                    let Some(index) = other_branches.iter().position(|b| b.name == name) else {
+                        println!("{} {}", "✗ No branch named".error_color(), name);
+                        continue;
+                    };
+                    let target = other_branches.remove(index);
+                    other_branches.push(Branch {
+                        name: std::mem::replace(&mut current_branch, target.name),
+                        history: std::mem::replace(&mut conversation_history, target.history),
+                        last_code: std::mem::replace(&mut last_generated_code, target.last_code),
+                        last_provenance: std::mem::replace(&mut last_provenance, target.last_provenance),
+                        turns: std::mem::replace(&mut turns, target.turns),
+                    });
+                    save_scratch(config, &last_generated_code, &logger);
+                    let _ = logger.log(&format!("BRANCH: switched to {}", current_branch));
+                    println!("{} {} ({})", "✓ Switched to".success_color(), current_branch, branch::preview(&conversation_history).dimmed());
+                }
+                Some(n) => {
+                    let exchanges = branch::exchange_count(&conversation_history);
+                    let Some(n) = n.parse::<usize>().ok().filter(|n| (1..=exchanges).contains(n)) else {
+                        println!("{}", format!("Usage: /branch <1-{}> [name] | list | switch <name>", exchanges.max(1)).warning_color());
+                        continue;
+                    };
+                    let name = parts.next().map(str::to_string).unwrap_or_else(|| {
+                        (2..).map(|i| format!("branch-{i}")).find(|candidate| {
+                            *candidate != current_branch && other_branches.iter().all(|b| b.name != *candidate)
+                        }).unwrap_or_default()
+                    });
+                    if name == current_branch || other_branches.iter().any(|b| b.name == name) {
+                        println!("{} {}", "✗ A branch with that name already exists:".error_color(), name);
+                        continue;
+                    }
+                    // The current line is kept as it is; the new branch restarts after exchange n
+                    let kept = Branch {
+                        name: std::mem::take(&mut current_branch),
+                        history: std::mem::take(&mut conversation_history),
+                        last_code: std::mem::take(&mut last_generated_code),
+                        last_provenance: last_provenance.take(),
+                        turns: std::mem::take(&mut turns),
+                    };
+                    Branch {
+                        name: current_branch,
+                        history: conversation_history,
+                        last_code: last_generated_code,
+                        last_provenance,
+                        turns,
+                    } = kept.fork(name, n);
+                    other_branches.push(kept);
+                    save_scratch(config, &last_generated_code, &logger);
+                    let _ = logger.log(&format!("BRANCH: created {} from exchange {}", current_branch, n));
+                    println!(
+                        "{} {} {}",
+                        "✓ Created and switched to".success_color(),
+                        current_branch.bold(),
+                        format!("(history up to exchange {}: {})", n, branch::preview(&conversation_history)).dimmed()
+                    );
+                }
+            }
             continue;
         }
 
-        if prompt == "/clear" {
+        if cmd == "/clear" {
             conversation_history.clear();
             last_generated_code.clear();
-            println!("{}", "✓ Conversation history cleared.".green());
+            last_provenance = None;
+            println!("{}", "✓ Conversation history cleared.".success_color());
             continue;
         }
 
-        if prompt == "/history" {
+        if cmd == "/history" {
             if conversation_history.is_empty() {
-                println!("{}", "No conversation history yet.".yellow());
+                println!("{}", "No conversation history yet.".warning_color());
             } else {
-                println!("\n{}", "Conversation History:".bright_cyan().bold());
+                println!("\n{}", "Conversation History:".heading_color().bold());
                 for (i, msg) in conversation_history.iter().enumerate() {
                     let role_color = if msg.role == "user" {
-                        msg.role.bright_blue()
+                        msg.role.heading_color()
                     } else {
-                        msg.role.bright_green()
+                        msg.role.success_color()
                     };
                     println!("\n{}. [{}]", i + 1, role_color);
                     let preview = if msg.content.len() > 100 {
@@ -137,104 +2170,450 @@ pub async fn start_repl(config: &AppConfig) {
             continue;
         }
 
-        if prompt.starts_with("/save") {
+        if cmd == "/save" {
             if last_generated_code.is_empty() {
-                println!("{}", "No code to save. Generate some code first!".yellow());
+                println!("{}", "No code to save. Generate some code first!".warning_color());
                 continue;
             }
 
-            let parts: Vec<&str> = prompt.split_whitespace().collect();
-            let filename = if parts.len() > 1 {
-                parts[1].to_string()
+            let filename = if !args.is_empty() {
+                args.to_string()
             } else {
-                ask_user("Enter filename (e.g., script.py): ")
+                ask_user(tr(Msg::AskFilename))
             };
 
             if filename.is_empty() {
-                println!("{}", "Save cancelled.".yellow());
+                println!("{}", "Save cancelled.".warning_color());
+                continue;
+            }
+            let filename = match sanitize_filename(&filename) {
+                Ok(name) => name,
+                Err(e) => {
+                    println!("{} {}", "✗".error_color(), e);
+                    continue;
+                }
+            };
+
+            match write_file(Path::new(&filename), render_script(&last_generated_code, last_provenance.as_ref(), config)) {
+                Ok(_) => println!("{} {}", "✓ Code saved to:".success_color(), filename.emphasis_color()),
+                Err(e) => println!("{} {}", "✗ Failed to save file:".error_color(), e),
+            }
+            continue;
+        }
+
+        if cmd == "/versions" {
+            let changes = export::deps_changes(&turns);
+            let versions: Vec<_> = turns.iter().zip(changes).filter(|(turn, _)| !turn.code.trim().is_empty()).collect();
+            if versions.is_empty() {
+                println!("{}", "No code generated yet this session.".warning_color());
+                continue;
+            }
+            println!("\n{}", "Code versions:".heading_color().bold());
+            for (i, (turn, change)) in versions.iter().enumerate() {
+                let deps = if turn.deps.is_empty() { "stdlib only".to_string() } else { turn.deps.join(", ") };
+                let change = change.as_ref().filter(|c| !c.is_empty()).map(|c| format!(" ({})", c.summary())).unwrap_or_default();
+                println!("  {}. {} {}", i + 1, export::heading(&turn.prompt), format!("— {} line(s), deps: {deps}{change}", turn.code.lines().count()).dimmed());
+            }
+            println!();
+            continue;
+        }
+
+        if cmd == "/deps" {
+            if last_generated_code.is_empty() {
+                println!("{}", "No code to analyze. Generate some code first!".warning_color());
+                continue;
+            }
+            match args {
+                "" => print_dependency_table(&executor, &last_generated_code, config, &declined_deps),
+                "--fix" => {
+                    let Some(unused) = executor.unused_imports(&last_generated_code) else {
+                        println!("{}", "pyflakes is not installed (pip install pyflakes).".warning_color());
+                        continue;
+                    };
+                    if unused.is_empty() {
+                        println!("{}", "No unused imports.".success_color());
+                        continue;
+                    }
+                    println!("\n{}", "Unused imports:".heading_color().bold());
+                    for (line, name) in &unused {
+                        println!("  line {:<4} {}", line, name.highlight_color());
+                    }
+                    if !confirm("Remove them?") {
+                        continue;
+                    }
+                    let code = remove_unused_imports(&last_generated_code, &unused);
+                    if let Some(last) = conversation_history.last_mut().filter(|m| m.role == "assistant" && m.content == last_generated_code) {
+                        last.content = code.clone();
+                    }
+                    last_generated_code = code;
+                    save_scratch(config, &last_generated_code, &logger);
+                    show_code(&last_generated_code, config);
+                    println!("{}", "Use /save to write the cleaned code to a file.".dimmed());
+                }
+                "--diff" => {
+                    let versions: Vec<&SessionTurn> = turns.iter().filter(|t| !t.code.trim().is_empty()).collect();
+                    let [.., previous, current] = versions.as_slice() else {
+                        println!("{}", "Only one code version so far; nothing to compare.".warning_color());
+                        continue;
+                    };
+                    let diff = DepsDiff::between(&previous.deps, &current.deps);
+                    let list = |deps: &[String]| if deps.is_empty() { "none".dimmed().to_string() } else { deps.join(", ") };
+                    let unchanged: Vec<String> = current.deps.iter().filter(|d| previous.deps.contains(d)).cloned().collect();
+                    println!("\n{}", format!("Dependencies from version {} to {}:", versions.len() - 1, versions.len()).heading_color().bold());
+                    println!("  {} {}", format!("{:<10}", "Added:").success_color(), list(&diff.added));
+                    println!("  {} {}", format!("{:<10}", "Removed:").error_color(), list(&diff.removed));
+                    println!("  {:<10} {}\n", "Unchanged:", list(&unchanged));
+                }
+                "--pin" => {
+                    let Some(script) = executor.last_script() else {
+                        println!("{}", "No script written yet this session; run the code first.".warning_color());
+                        continue;
+                    };
+                    match executor.pin_requirements(&script) {
+                        Ok(Some((path, count))) => println!("{} {} package(s) in {}", "📌 Pinned".success_color(), count, path.display()),
+                        Ok(None) => println!("{}", "The code only uses the standard library; nothing to pin.".dimmed()),
+                        Err(e) => println!("{} {:#}", "✗ Pinning failed:".error_color(), e),
+                    }
+                }
+                _ => println!("{}", "Usage: /deps [--fix|--pin|--diff]".warning_color()),
+            }
+            continue;
+        }
+
+        if cmd == "/spec" {
+            let (action, rest) = args.split_once(' ').unwrap_or((args, ""));
+            match action {
+                "" => {
+                    if specs.is_empty() {
+                        println!("{}", "No specs. Add one with /spec add <input> => <expected>".dimmed());
+                    }
+                    for (i, case) in specs.iter().enumerate() {
+                        println!("  {}. {:?} => {:?}", i + 1, case.input, case.expected);
+                    }
+                }
+                "add" => {
+                    let case = if rest.trim().is_empty() {
+                        let input = ask_user("stdin (\\n for newlines): ");
+                        let expected = ask_user("expected stdout (\\n for newlines): ");
+                        SpecCase::parse(&format!("{} => {}", input, expected))
+                    } else {
+                        SpecCase::parse(rest)
+                    };
+                    match case {
+                        Some(case) => {
+                            specs.push(case);
+                            println!("{} {}", "✓ Spec added; total:".success_color(), specs.len());
+                        }
+                        None => println!("{}", "Usage: /spec add <input> => <expected>".warning_color()),
+                    }
+                }
+                "clear" => {
+                    specs.clear();
+                    println!("{}", "Specs cleared.".success_color());
+                }
+                "run" => {
+                    if specs.is_empty() || last_generated_code.is_empty() {
+                        println!("{}", "Need both specs and generated code.".warning_color());
+                        continue;
+                    }
+                    match executor.write_script(&render_script(&last_generated_code, last_provenance.as_ref(), config)) {
+                        Ok(path) => {
+                            if pre_run_gate(&executor, &last_generated_code, Some(&path), config, cli.allow_dangerous, &logger, None) {
+                                run_specs(&executor, &path, &specs, config.execution_timeout_secs);
+                            }
+                        }
+                        Err(e) => println!("{} {}", "✗ Failed to write script:".error_color(), e),
+                    }
+                }
+                _ => println!("{}", "Usage: /spec [add <input> => <expected> | run | clear]".warning_color()),
+            }
+            continue;
+        }
+
+        if cmd == "/golden" {
+            match args {
+                "" => match &golden {
+                    Some(path) => println!("{} {}", "The next run is compared with:".heading_color(), path.display()),
+                    None => println!("{}", "No golden file set. Usage: /golden <file> | off".dimmed()),
+                },
+                "off" => {
+                    golden = None;
+                    println!("{}", "Golden check cancelled.".success_color());
+                }
+                file => {
+                    let path = PathBuf::from(file);
+                    let note = if path.is_file() { "compared with" } else { "saved to (it does not exist yet)" };
+                    println!("{} {} {}", "The stdout of the next run will be".heading_color(), note.heading_color(), path.display());
+                    golden = Some(path);
+                }
+            }
+            continue;
+        }
+
+        if cmd == "/cd" {
+            if args.is_empty() {
+                println!("{} {}", "Run directory:".heading_color(), executor.work_dir().display());
+                continue;
+            }
+            let dir = Path::new(args);
+            if !dir.is_dir() {
+                println!("{} {}", "✗ Not a directory:".error_color(), args);
+                continue;
+            }
+            let dir = std::path::absolute(dir).unwrap_or_else(|_| dir.to_path_buf());
+            executor = executor.with_work_dir(&dir);
+            let _ = logger.log(&format!("RUN DIRECTORY: {}", dir.display()));
+            println!("{} {}", "✓ Scripts will run in".success_color(), dir.display());
+            if executor.sandbox_status() != SandboxStatus::Disabled {
+                println!("{}", "  It is the only directory sandboxed scripts can write to.".dimmed());
+            }
+            continue;
+        }
+
+        if cmd == "/clean" {
+            if let Some(task) = args.strip_prefix("--env") {
+                let task = task.trim();
+                let env = match TaskEnv::new(Path::new(&config.generated_dir), task) {
+                    Ok(env) if env.dir.exists() => env,
+                    Ok(_) => {
+                        println!("{}", format!("No environment for task '{task}'.").warning_color());
+                        continue;
+                    }
+                    Err(e) => {
+                        println!("{} {:#}", "✗".error_color(), e);
+                        continue;
+                    }
+                };
+                if !confirm(&format!("Delete the environment of task '{}' ({})?", env.name, env.dir.display())) {
+                    continue;
+                }
+                if executor.env().is_some_and(|active| active.name == env.name) {
+                    executor.set_env(None);
+                    println!("{}", "Switched back to the system interpreter.".dimmed());
+                }
+                match env.remove() {
+                    Ok(()) => println!("{} {}", "✓ Removed".success_color(), env.dir.display()),
+                    Err(e) => println!("{} {:#}", "✗".error_color(), e),
+                }
+                continue;
+            }
+            if args != "--logs" {
+                println!("{}", "Usage: /clean --logs | /clean --env <task>".warning_color());
                 continue;
             }
+            match clean_logs(config, &logger) {
+                Ok(Some(summary)) => println!("{} {}", "✓ Removed".success_color(), summary),
+                Ok(None) => println!("{}", "Nothing to remove; the logs are within the retention limits.".dimmed()),
+                Err(e) => println!("{} {:#}", "✗ Log cleanup failed:".error_color(), e),
+            }
+            match index::prune_empty_dirs(executor.base_dir(), &config.generated_layout) {
+                Ok(0) => {}
+                Ok(removed) => println!("{} {} empty director{} under {}", "✓ Removed".success_color(), removed, if removed == 1 { "y" } else { "ies" }, executor.base_dir().display()),
+                Err(e) => println!("{} {:#}", "✗ Cleanup failed:".error_color(), e),
+            }
+            continue;
+        }
+
+        if cmd == "/env" {
+            let (sub, rest) = args.split_once(' ').unwrap_or((args, ""));
+            match sub {
+                "" | "info" => print_env_info(&executor, config),
+                "use" if !rest.trim().is_empty() => use_task_env(&mut executor, config, rest.trim()),
+                "off" => {
+                    executor.set_env(None);
+                    println!("{}", "✓ Scripts use the system interpreter again.".success_color());
+                }
+                "rebuild" => match executor.env() {
+                    Some(env) => {
+                        println!("{}", format!("⏳ Rebuilding the environment of task '{}'...", env.name).dimmed());
+                        match env.rebuild() {
+                            Ok(manifest) => println!("{} {} package(s)", "✓ Rebuilt with".success_color(), manifest.packages.len()),
+                            Err(e) => println!("{} {:#}", "✗ Rebuild failed:".error_color(), e),
+                        }
+                    }
+                    None => println!("{}", "No task environment is active; use /env use <task> first.".warning_color()),
+                },
+                _ => println!("{}", "Usage: /env [info] | /env use <task> | /env off | /env rebuild".warning_color()),
+            }
+            continue;
+        }
 
-            match fs::write(&filename, &last_generated_code) {
-                Ok(_) => println!("{} {}", "✓ Code saved to:".green(), filename.bright_white()),
-                Err(e) => println!("{} {}", "✗ Failed to save file:".red(), e),
+        if cmd == "/cleanpyc" {
+            match executor.clean_pycache() {
+                Ok(0) => println!("{}", "No __pycache__ directories to remove.".dimmed()),
+                Ok(removed) => println!("{} {} __pycache__ director{} under {}", "✓ Removed".success_color(), removed, if removed == 1 { "y" } else { "ies" }, executor.base_dir().display()),
+                Err(e) => println!("{} {:#}", "✗ Cleanup failed:".error_color(), e),
             }
             continue;
         }
 
-        if prompt == "/list" {
-            match fs::read_dir(&config.generated_dir) {
-                Ok(entries) => {
-                    let mut scripts: Vec<_> = entries
-                        .filter_map(|e| e.ok())
-                        .filter(|e| e.path().extension().is_some_and(|ext| ext == "py"))
-                        .collect();
+        if cmd == "/recover" {
+            let path = config.scratch_path();
+            match fs::read_to_string(&path) {
+                Ok(code) if !code.trim().is_empty() => {
+                    last_generated_code = code.clone();
+                    last_provenance = None;
+                    // Make it the model's last turn so /refine works on it
+                    conversation_history.push(Message {
+                        role: "assistant".to_string(),
+                        content: code.clone(),
+                    });
+                    trim_history(&mut conversation_history, config.max_history_messages);
+                    show_code(&code, config);
+                    println!("{} {}", "✓ Recovered from".success_color(), path.display());
+                }
+                Ok(_) => println!("{} {}", "Scratch file is empty:".warning_color(), path.display()),
+                Err(e) => println!("{} {} ({})", "✗ Nothing to recover from".error_color(), path.display(), e),
+            }
+            continue;
+        }
 
+        if cmd == "/list" {
+            match index::list_scripts(executor.base_dir()) {
+                Ok(scripts) => {
                     if scripts.is_empty() {
-                        println!("{}", "No generated scripts found.".yellow());
+                        println!("{}", "No generated scripts found.".warning_color());
+                    } else {
+                        println!("\n{}", "Generated Scripts:".heading_color().bold());
+                        let entries = index::load_index(executor.base_dir()).unwrap_or_default();
+                        for (i, script) in scripts.iter().enumerate() {
+                            let name = script.to_string_lossy();
+                            match entries.iter().find(|e| e.script == name) {
+                                Some(entry) => println!("  {}. {}  {}", i + 1, name.emphasis_color(), entry.description().dimmed()),
+                                None => println!("  {}. {}", i + 1, name.emphasis_color()),
+                            }
+                        }
+                        println!();
+                    }
+                }
+                Err(e) => println!("{} {}", "✗ Failed to list scripts:".error_color(), e),
+            }
+            continue;
+        }
+
+        if cmd == "/replay" {
+            let log_dir = Path::new(&config.log_dir);
+            let mut parts = args.split_whitespace();
+            let (Some(session), Some(index)) = (parts.next(), parts.next()) else {
+                let sessions = logger::list_sessions(log_dir);
+                if sessions.is_empty() {
+                    println!("{}", format!("No session logs in {}.", log_dir.display()).warning_color());
+                } else {
+                    println!("\n{}", "Recent sessions:".heading_color().bold());
+                    for path in sessions.iter().take(10) {
+                        let turns = fs::read_to_string(path).map(|log| logger::session_turn_scripts(&log).len()).unwrap_or(0);
+                        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+                        println!("  {} {}", name.emphasis_color(), format!("({turns} turn(s))").dimmed());
+                    }
+                    println!();
+                }
+                println!("{}", "Usage: /replay <session> <turn>".warning_color());
+                continue;
+            };
+            let Some(path) = logger::find_session(log_dir, session) else {
+                println!("{} {}", "✗ No session log named".error_color(), session);
+                continue;
+            };
+            let turns = match fs::read_to_string(&path) {
+                Ok(log) => logger::session_turn_scripts(&log),
+                Err(e) => {
+                    println!("{} {}: {}", "✗ Could not read".error_color(), path.display(), e);
+                    continue;
+                }
+            };
+            if turns.is_empty() {
+                println!("{}", format!("{session} recorded no turns (sessions logged before /replay existed don't store their scripts).").warning_color());
+                continue;
+            }
+            let turn = match index.parse::<usize>() {
+                Ok(n) if (1..=turns.len()).contains(&n) => n,
+                _ => {
+                    println!("{}", format!("{session} has {} turn(s); pick one from 1 to {}.", turns.len(), turns.len()).warning_color());
+                    continue;
+                }
+            };
+            let Some(script_path) = &turns[turn - 1] else {
+                println!("{}", format!("Turn {turn} of {session} produced no code.").warning_color());
+                continue;
+            };
+            let code = match fs::read_to_string(script_path) {
+                Ok(code) => code,
+                Err(e) => {
+                    println!("{} {} ({})", "✗ The script of that turn is gone:".error_color(), script_path.display(), e);
+                    continue;
+                }
+            };
+            if !pre_run_gate(&executor, &code, Some(script_path), config, cli.allow_dangerous, &logger, None) {
+                continue;
+            }
+            println!("\n{}", format!("Replaying turn {turn} of {session}: {}", script_path.display()).heading_color());
+            let _ = logger.log(&format!("REPLAY: {} turn {} ({})", path.display(), turn, script_path.display()));
+            let mode = choose_execution_mode(&executor, &code, config.stream_output);
+            match executor.execute_script(script_path, mode, config.execution_timeout_secs) {
+                Ok(result) => {
+                    clean_after_run(&executor, config, mode, &logger);
+                    let success = execution_succeeded(&result, mode);
+                    if success {
+                        metrics.successful_executions += 1;
                     } else {
-                        scripts.sort_by_key(|e| e.file_name());
-                        println!("\n{}", "Generated Scripts:".bright_cyan().bold());
-                        for (i, entry) in scripts.iter().enumerate() {
-                            println!("  {}. {}", i + 1, entry.file_name().to_string_lossy().bright_white());
-                        }
-                        println!();
+                        metrics.failed_executions += 1;
                     }
+                    let _ = logger.log_execution(success, &result.stdout);
+                    print_execution_result(&result, mode, false);
+                    if !success {
+                        triage_failure(&result, mode, &mut metrics, &logger);
+                    }
+                    check_golden(&mut golden, &result, success, &logger);
+                }
+                Err(e) => {
+                    metrics.failed_executions += 1;
+                    let _ = logger.log_error(&format!("Execution error: {}", e));
+                    println!("{} {}", "✗ Execution error:".error_color(), e);
                 }
-                Err(e) => println!("{} {}", "✗ Failed to list scripts:".red(), e),
             }
             continue;
         }
 
-        if prompt.starts_with("/run") {
-            let parts: Vec<&str> = prompt.split_whitespace().collect();
-            let filename = if parts.len() > 1 {
-                parts[1].to_string()
+        if cmd == "/run" {
+            let filename = if !args.is_empty() {
+                args.to_string()
             } else {
-                ask_user("Enter script filename (e.g., script_20251209_152023.py): ")
+                ask_user(tr(Msg::AskScriptFilename))
             };
 
             if filename.is_empty() {
-                println!("{}", "Run cancelled.".yellow());
+                println!("{}", "Run cancelled.".warning_color());
                 continue;
             }
 
-            let script_path = if filename.starts_with(&format!("{}/", config.generated_dir)) {
-                filename
-            } else {
-                format!("{}/{}", config.generated_dir, filename)
+            let Some(script_path) = index::resolve_script(executor.base_dir(), &filename) else {
+                println!("{} {}", "✗ No such script:".error_color(), filename);
+                continue;
             };
-
             match fs::read_to_string(&script_path) {
                 Ok(code) => {
-                    println!("\n{}", format!("Running: {}", script_path).bright_cyan());
+                    if !pre_run_gate(&executor, &code, Some(&script_path), config, cli.allow_dangerous, &logger, None) {
+                        continue;
+                    }
+                    println!("\n{}", format!("Running: {}", script_path.display()).heading_color());
 
                     // Check for dependencies
                     let deps = executor.detect_dependencies(&code);
-                    if !deps.is_empty() {
-                        println!("\n{} {}",
-                            "⚠️  Detected non-standard dependencies:".yellow(),
-                            deps.join(", ").bright_yellow());
-                        if config.auto_install_deps || confirm("Install these dependencies?") {
-                            if let Err(e) = executor.install_packages(&deps) {
-                                println!("{} {}", "⚠️  Failed to install dependencies:".yellow(), e);
-                                println!("{}", "Proceeding anyway...".dimmed());
-                            }
-                        }
+                    let selected = pick_dependencies(&executor, &deps, config, &mut declined_deps);
+                    if !selected.is_empty() {
+                        install_dependencies(&executor, &selected);
                     }
 
                     // Detect if interactive mode is needed
-                    let mode = if executor.needs_interactive_mode(&code) {
-                        println!("{}", "🎮 Interactive mode detected (pygame/input/GUI)".bright_magenta().bold());
-                        println!("{}", "   Running with inherited stdio for user interaction...".dimmed());
-                        ExecutionMode::Interactive
-                    } else {
-                        ExecutionMode::Captured
-                    };
+                    let mode = choose_execution_mode(&executor, &code, config.stream_output);
 
                     match executor.run_existing_script(&script_path, mode, config.execution_timeout_secs) {
                         Ok(result) => {
-                            let success = result.is_success();
+                            clean_after_run(&executor, config, mode, &logger);
+                            let success = execution_succeeded(&result, mode);
+                            if success {
+                                pin_after_run(&executor, config, mode, &script_path, &logger);
+                            }
                             if success {
                                 metrics.successful_executions += 1;
                             } else {
@@ -243,70 +2622,746 @@ pub async fn start_repl(config: &AppConfig) {
 
                             let _ = logger.log_execution(success, &result.stdout);
 
-                            println!("\n{}", "━━━━━━━━━━━ Execution Result ━━━━━━━━━━━".bright_blue().bold());
-                            if !result.stdout.is_empty() {
-                                println!("\n{}:", "STDOUT".green().bold());
-                                println!("{}", result.stdout);
-                            }
-                            if !result.stderr.is_empty() {
-                                println!("\n{}:", "STDERR".red().bold());
-                                println!("{}", result.stderr);
+                            print_execution_result(&result, mode, false);
+                            if !success {
+                                triage_failure(&result, mode, &mut metrics, &logger);
                             }
-                            println!("{}", "━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━".bright_blue());
+                            check_golden(&mut golden, &result, success, &logger);
                         }
                         Err(e) => {
                             metrics.failed_executions += 1;
                             let _ = logger.log_error(&format!("Execution error: {}", e));
-                            println!("{} {}", "✗ Execution error:".red(), e);
+                            println!("{} {}", "✗ Execution error:".error_color(), e);
+                        }
+                    }
+                }
+                Err(e) => println!("{} {}", "✗ Failed to read script:".error_color(), e),
+            }
+            continue;
+        }
+
+        if cmd == "/continue" {
+            if last_generated_code.is_empty() {
+                println!("{}", "No code to continue. Generate some code first!".warning_color());
+                continue;
+            }
+            // Relevant to the request being continued, not to the continuation prompt
+            let context = request_context(&context_files, &conversation_history, &specs, config.max_context_bytes);
+            // The partial code must be the model's last turn (it already is after a generation)
+            let pushed_partial = conversation_history.last().is_none_or(|m| m.role != "assistant" || m.content != last_generated_code);
+            if pushed_partial {
+                conversation_history.push(Message {
+                    role: "assistant".to_string(),
+                    content: last_generated_code.clone(),
+                });
+            }
+            conversation_history.push(Message {
+                role: "user".to_string(),
+                content: "Continue the code from where you left off. Output only the remaining Python, \
+                          without repeating what you already wrote."
+                    .to_string(),
+            });
+            let _ = logger.log_api_request("Continue truncated code");
+            metrics.total_requests += 1;
+
+            let result = timed_generation(&with_context(&context, &conversation_history), config, &mut metrics, &logger).await;
+            // Only the merged code stays in history, as a single assistant turn
+            conversation_history.pop();
+            if pushed_partial {
+                conversation_history.pop();
+            }
+            match result {
+                Ok(raw_response) => {
+                    let code = join_continuation(&last_generated_code, &extract_continuation(&raw_response));
+                    let Some(code) = apply_hooks(&code, config, &logger) else {
+                        continue;
+                    };
+                    let provenance = Provenance::new(config, &api::full_messages(&with_context(&context, &conversation_history), config.profile.as_deref(), &config.style));
+                    match conversation_history.last_mut() {
+                        Some(last) if last.role == "assistant" => last.content = code.clone(),
+                        _ => conversation_history.push(Message {
+                            role: "assistant".to_string(),
+                            content: code.clone(),
+                        }),
+                    }
+                    trim_history(&mut conversation_history, config.max_history_messages);
+                    last_generated_code = code.clone();
+                    save_scratch(config, &last_generated_code, &logger);
+                    last_provenance = Some(provenance.clone());
+                    show_code(&code, config);
+                    match executor.write_script(&render_script(&code, Some(&provenance), config)) {
+                        Ok(path) => {
+                            record_script(&executor, &logger, &path, &provenance, "continue");
+                            println!("{} {}", "✓ Saved as".success_color(), path.display());
                         }
+                        Err(e) => println!("{} {}", "✗ Failed to write script:".error_color(), e),
+                    }
+                }
+                Err(e) => {
+                    metrics.api_errors += 1;
+                    let _ = logger.log_error(&format!("API error: {}", e));
+                    println!("{} {}", "✗ API error:".error_color(), e);
+                }
+            }
+            continue;
+        }
+
+        if cmd == "/py" || cmd == "/py!" {
+            // The raw text: argument unquoting would break `/py 'a' + 'b'`
+            let code = prompt.trim().strip_prefix(cmd).unwrap_or(args).trim();
+            if code.is_empty() {
+                println!("{}", "Usage: /py <expression or statements>".warning_color());
+                continue;
+            }
+            metrics.quick_evals += 1;
+            match executor.quick_eval(code, config.execution_timeout_secs) {
+                Ok(result) if result.timed_out => {
+                    println!("{}", format!("⏱ Timed out after {}s", config.execution_timeout_secs).error_color());
+                }
+                Ok(result) => {
+                    if !result.stdout.is_empty() {
+                        println!("{}", truncate_output(result.stdout.trim_end(), QUICK_EVAL_MAX_CHARS));
+                    }
+                    if !result.is_success() {
+                        let stderr = result.stderr.trim_end();
+                        let shown = if cmd == "/py!" { stderr } else { stderr.lines().last().unwrap_or_default() };
+                        println!("{}", truncate_output(shown, QUICK_EVAL_MAX_CHARS).error_color());
+                    } else if !result.stderr.is_empty() {
+                        println!("{}", truncate_output(result.stderr.trim_end(), QUICK_EVAL_MAX_CHARS).warning_color());
+                    }
+                }
+                Err(e) => println!("{} {:#}", "✗".error_color(), e),
+            }
+            continue;
+        }
+
+        if cmd == "/annotate" {
+            if last_generated_code.is_empty() {
+                println!("{}", "No code to annotate. Generate some code first!".warning_color());
+                continue;
+            }
+            metrics.total_requests += 1;
+            let _ = logger.log_api_request("Add type hints to the current code");
+            println!("{}", "⏳ Asking for type hints...".dimmed());
+
+            let start = Instant::now();
+            let response = match api::annotate_code(&last_generated_code, config).await {
+                Ok(response) => response,
+                Err(e) => {
+                    metrics.api_errors += 1;
+                    let _ = logger.log_error(&format!("API error: {}", e));
+                    println!("{} {}", "✗ API error:".error_color(), e);
+                    continue;
+                }
+            };
+            metrics.record_api_latency(start.elapsed(), "annotate");
+            record_model_call(&mut metrics, config, start.elapsed(), &code_message(&last_generated_code), &response);
+            let code = extract_python_code(&response);
+            if code.trim().is_empty() || code.trim() == last_generated_code.trim() {
+                println!("{}", "The model returned no annotated version; keeping the current code.".warning_color());
+                continue;
+            }
+            let Some(code) = apply_hooks(&code, config, &logger) else {
+                continue;
+            };
+
+            println!("\n{}", "🏷️  Type hints added".heading_color().bold());
+            print_diff(&last_generated_code, &code);
+            let context = request_context(&context_files, &conversation_history, &specs, config.max_context_bytes);
+            accept_refinement(
+                ActiveLine { history: &mut conversation_history, last_code: &mut last_generated_code, last_provenance: &mut last_provenance, turns: &mut turns },
+                Refinement {
+                    request: refine_request(&refine_template, "add PEP 484 type hints without changing its behavior."),
+                    code: &code,
+                    label: "annotate",
+                    context: &context,
+                },
+                config, &executor, &logger, &mut metrics, &mut descriptions,
+            )
+            .await;
+
+            match executor.type_check(&code) {
+                Some(errors) if errors.is_empty() => println!("{}", "✓ mypy: no type errors.".success_color()),
+                Some(errors) => {
+                    println!("{}", format!("⚠️  mypy found {} type error(s):", errors.len()).warning_color());
+                    for (line, message) in &errors {
+                        println!("  {} {}", format!("line {line}:").dimmed(), message);
+                    }
+                }
+                None => println!("{}", "mypy is not installed (pip install mypy); the hints were not checked.".dimmed()),
+            }
+            continue;
+        }
+
+        if cmd == "/shorten" {
+            if last_generated_code.is_empty() {
+                println!("{}", "No code to shorten. Generate some code first!".warning_color());
+                continue;
+            }
+            metrics.total_requests += 1;
+            let _ = logger.log_api_request("Shorten the current code");
+            println!("{}", "⏳ Asking for a minimal version...".dimmed());
+
+            let start = Instant::now();
+            let response = match api::shorten_code(&last_generated_code, config).await {
+                Ok(response) => response,
+                Err(e) => {
+                    metrics.api_errors += 1;
+                    let _ = logger.log_error(&format!("API error: {}", e));
+                    println!("{} {}", "✗ API error:".error_color(), e);
+                    continue;
+                }
+            };
+            metrics.record_api_latency(start.elapsed(), "shorten");
+            record_model_call(&mut metrics, config, start.elapsed(), &code_message(&last_generated_code), &response);
+            let code = extract_python_code(&response);
+            if code.trim().is_empty() || code.trim() == last_generated_code.trim() {
+                println!("{}", "The model returned no shorter version; keeping the current code.".warning_color());
+                continue;
+            }
+            let Some(code) = apply_hooks(&code, config, &logger) else {
+                continue;
+            };
+
+            println!("\n{}", "✂️  Minimal version".heading_color().bold());
+            print_diff(&last_generated_code, &code);
+            let (old_lines, new_lines) = (last_generated_code.lines().count(), code.lines().count());
+            let (old_deps, new_deps) = (third_party_imports(&last_generated_code), third_party_imports(&code));
+            println!(
+                "{}",
+                format!(
+                    "lines: {} → {} ({:+}), third-party imports: {} → {} ({:+})",
+                    old_lines, new_lines, new_lines as i64 - old_lines as i64,
+                    old_deps, new_deps, new_deps as i64 - old_deps as i64
+                )
+                .dimmed()
+            );
+            if !confirm("Keep the shortened version?") {
+                println!("{}", "Keeping the current code.".dimmed());
+                continue;
+            }
+
+            let context = request_context(&context_files, &conversation_history, &specs, config.max_context_bytes);
+            accept_refinement(
+                ActiveLine { history: &mut conversation_history, last_code: &mut last_generated_code, last_provenance: &mut last_provenance, turns: &mut turns },
+                Refinement {
+                    request: refine_request(
+                        &refine_template,
+                        "rewrite it as a minimal version using only the standard library, keeping the same behavior.",
+                    ),
+                    code: &code,
+                    label: "shorten",
+                    context: &context,
+                },
+                config, &executor, &logger, &mut metrics, &mut descriptions,
+            )
+            .await;
+            continue;
+        }
+
+        if cmd == "/init" {
+            let (name, description) = args.split_once(char::is_whitespace).map_or((args, ""), |(n, d)| (n, d.trim()));
+            if name.is_empty() {
+                println!("{}", "Usage: /init <name> [description]".warning_color());
+                continue;
+            }
+            let Some(package) = package_name(name).filter(|_| sanitize_filename(name).is_ok()) else {
+                println!("{}", format!("'{name}' can't name a Python package: start with a letter, then letters, digits, '-' or '_'.").warning_color());
+                continue;
+            };
+            let project_dir = PathBuf::from(name);
+            if project_dir.exists() {
+                println!("{}", format!("{} already exists; /init never overwrites it. Pick another name.", project_dir.display()).warning_color());
+                continue;
+            }
+            metrics.total_requests += 1;
+            let _ = logger.log_api_request(&format!("Scaffold project {name}: {description}"));
+            println!("{}", format!("⏳ Asking for a {name} project...").dimmed());
+
+            let start = Instant::now();
+            let response = match api::scaffold_project(name, &package, description, &config.style, config).await {
+                Ok(response) => response,
+                Err(e) => {
+                    metrics.api_errors += 1;
+                    let _ = logger.log_error(&format!("API error: {}", e));
+                    println!("{} {}", "✗ API error:".error_color(), e);
+                    continue;
+                }
+            };
+            metrics.record_api_latency(start.elapsed(), "init");
+            record_model_call(&mut metrics, config, start.elapsed(), &code_message(description), &response);
+            let mut files = extract_files(&response);
+            if files.is_empty() {
+                println!("{}", "The reply isn't a multi-file project; nothing was written.".warning_color());
+                continue;
+            }
+            let init = format!("{package}/__init__.py");
+            if !files.iter().any(|f| f.name == init) {
+                files.push(CodeFile { name: init, code: String::new() });
+            }
+            if !files.iter().any(|f| f.name == "pyproject.toml") {
+                println!("{}", "⚠ The reply has no pyproject.toml.".warning_color());
+            }
+            let mut rejected = false;
+            for file in files.iter_mut().filter(|f| f.name.ends_with(".py") && !f.code.trim().is_empty()) {
+                match apply_hooks(&file.code, config, &logger) {
+                    Some(code) => file.code = code,
+                    None => {
+                        rejected = true;
+                        break;
+                    }
+                }
+            }
+            if rejected {
+                println!("{}", "Nothing was written.".dimmed());
+                continue;
+            }
+            let modules: Vec<CodeFile> = files.iter().filter(|f| f.name.ends_with(".py")).cloned().collect();
+            report_file_compile(&executor, &modules, &logger);
+
+            let written = match write_project(&project_dir, &files) {
+                Ok(written) => written,
+                Err(e) => {
+                    println!("{} {:#}", "✗".error_color(), e);
+                    continue;
+                }
+            };
+            let _ = logger.log(&format!("INIT: {} ({} files)", project_dir.display(), written.len()));
+            println!("{} {}", "✓ Project created:".success_color(), project_dir.display());
+            for path in &written {
+                println!("  {}", path.display());
+            }
+
+            let Some(module) = project_main_module(&files, &package) else {
+                println!("{}", format!("No {package}/main.py to run.").dimmed());
+                continue;
+            };
+            let code = modules.iter().map(|f| f.code.as_str()).collect::<Vec<_>>().join("\n\n");
+            let question = format!("Run python -m {module} now?");
+            if !pre_run_gate(&executor, &code, None, config, cli.allow_dangerous, &logger, Some(&question)) {
+                continue;
+            }
+            match executor.run_module(&project_dir, &module, config.execution_timeout_secs) {
+                Ok(result) => {
+                    let success = execution_succeeded(&result, ExecutionMode::Captured);
+                    if success {
+                        metrics.successful_executions += 1;
+                    } else {
+                        metrics.failed_executions += 1;
                     }
+                    let _ = logger.log_execution(success, &result.stdout);
+                    print_execution_result(&result, ExecutionMode::Captured, false);
+                }
+                Err(e) => {
+                    metrics.failed_executions += 1;
+                    let _ = logger.log_error(&format!("Execution error: {}", e));
+                    println!("{} {}", "✗ Execution error:".error_color(), e);
+                }
+            }
+            continue;
+        }
+
+        if cmd == "/patch" {
+            let (name, instruction) = args.split_once(char::is_whitespace).map_or((args, ""), |(n, i)| (n, i.trim()));
+            if name.is_empty() || instruction.is_empty() {
+                println!("{}", "Usage: /patch <function|Class.method> <instruction>".warning_color());
+                continue;
+            }
+            if last_generated_code.is_empty() {
+                println!("{}", "No code to patch. Generate some code first!".warning_color());
+                continue;
+            }
+            let Some(range) = qualified_definition_range(&last_generated_code, name) else {
+                println!("{}", format!("No function named '{name}' in the current code.").warning_color());
+                let names = qualified_names(&last_generated_code);
+                if !names.is_empty() {
+                    println!("{}", format!("Definitions: {}", names.join(", ")).dimmed());
+                }
+                continue;
+            };
+            let function = last_generated_code.lines().skip(range.0 - 1).take(range.1 - range.0 + 1).collect::<Vec<_>>().join("\n");
+            let Some(old_signature) = def_signature(&function) else {
+                println!("{}", format!("'{name}' is a class: /patch one of its methods (e.g. {name}.<method>) or /refine the whole file.").warning_color());
+                continue;
+            };
+            metrics.total_requests += 1;
+            let _ = logger.log_api_request(&format!("Patch {name} (lines {}-{}): {instruction}", range.0, range.1));
+            println!("{}", format!("⏳ Asking for a new version of {name}...").dimmed());
+
+            let start = Instant::now();
+            let outline = patch_context(&last_generated_code);
+            let response = match api::patch_definition(&function, &outline, instruction, config).await {
+                Ok(response) => response,
+                Err(e) => {
+                    metrics.api_errors += 1;
+                    let _ = logger.log_error(&format!("API error: {}", e));
+                    println!("{} {}", "✗ API error:".error_color(), e);
+                    continue;
+                }
+            };
+            metrics.record_api_latency(start.elapsed(), "patch");
+            record_model_call(&mut metrics, config, start.elapsed(), &code_message(&function), &response);
+            let Some(replacement) = extract_definition(&extract_python_code(&response), name) else {
+                let leaf = name.rsplit('.').next().unwrap_or(name);
+                println!("{}", format!("The reply has no function named {leaf}; keeping the current code.").warning_color());
+                continue;
+            };
+            let code = splice_definition(&last_generated_code, range, &replacement);
+            if code == last_generated_code {
+                println!("{}", "The model returned the function unchanged; keeping the current code.".warning_color());
+                continue;
+            }
+            let Some(code) = apply_hooks(&code, config, &logger) else {
+                continue;
+            };
+
+            println!("\n{}", format!("🩹 {name} (lines {}-{})", range.0, range.1).heading_color().bold());
+            print_diff(&last_generated_code, &code);
+            match def_signature(&replacement) {
+                Some(new_signature) if new_signature != old_signature => println!(
+                    "{} {} → {}\n  {}",
+                    "⚠ The signature changed:".warning_color(),
+                    old_signature,
+                    new_signature,
+                    "check the places that call it".dimmed()
+                ),
+                _ => {}
+            }
+            match executor.compile_files(&[CodeFile { name: "patched.py".to_string(), code: code.clone() }]) {
+                Ok(results) => match results.first() {
+                    Some((_, Err(e))) => println!("{} {}", "✗ The patched code doesn't compile:".error_color(), compile_error_summary(e)),
+                    _ => println!("{}", "✓ The patched code compiles".success_color()),
+                },
+                Err(e) => {
+                    let _ = logger.log_error(&format!("Syntax check of the patch failed: {:#}", e));
                 }
-                Err(e) => println!("{} {}", "✗ Failed to read script:".red(), e),
             }
+            if !confirm("Keep the patched version?") {
+                println!("{}", "Keeping the current code.".dimmed());
+                continue;
+            }
+
+            let context = request_context(&context_files, &conversation_history, &specs, config.max_context_bytes);
+            accept_refinement(
+                ActiveLine { history: &mut conversation_history, last_code: &mut last_generated_code, last_provenance: &mut last_provenance, turns: &mut turns },
+                Refinement {
+                    request: refine_request(&refine_template, &format!("in {name}, {instruction}")),
+                    code: &code,
+                    label: &format!("patch {name}"),
+                    context: &context,
+                },
+                config, &executor, &logger, &mut metrics, &mut descriptions,
+            )
+            .await;
             continue;
         }
 
-        if prompt == "/refine" {
+        // `@key=value` flags on a regular prompt, for this request only
+        let mut overrides = PromptOverrides::default();
+
+        if cmd == "/review" {
             if last_generated_code.is_empty() {
-                println!("{}", "No code to refine. Generate some code first!".yellow());
+                println!("{}", "No code to review. Generate some code first!".warning_color());
+                continue;
+            }
+            metrics.total_requests += 1;
+            let _ = logger.log_api_request("Review of the current code");
+            println!("{}", "⏳ Reviewing the current code...".dimmed());
+
+            let start = Instant::now();
+            let review = match api::review_code(&last_generated_code, config).await {
+                Ok(review) => review,
+                Err(e) => {
+                    metrics.api_errors += 1;
+                    let _ = logger.log_error(&format!("API error: {}", e));
+                    println!("{} {}", "✗ API error:".error_color(), e);
+                    continue;
+                }
+            };
+            metrics.record_api_latency(start.elapsed(), "review");
+            record_model_call(&mut metrics, config, start.elapsed(), &code_message(&last_generated_code), &review);
+            // Kept out of the conversation history: the review is prose, not a code turn
+            let _ = logger.log(&format!("REVIEW:\n{}", review));
+
+            let findings = numbered_items(&review);
+            println!("\n{}", "🔍 Review".heading_color().bold());
+            if findings.is_empty() {
+                println!("{}\n", review.trim());
+                continue;
+            }
+            for (i, finding) in findings.iter().enumerate() {
+                println!("  {}. {}", (i + 1).to_string().highlight_color(), finding);
+            }
+
+            let answer = ask_user(&format!("\n{}", tr(Msg::AskFixFindings)));
+            let answer = answer.trim();
+            let selected: Vec<usize> = if answer.eq_ignore_ascii_case("a") {
+                (1..=findings.len()).collect()
+            } else {
+                answer
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter_map(|t| t.parse::<usize>().ok())
+                    .filter(|n| (1..=findings.len()).contains(n))
+                    .collect()
+            };
+            if selected.is_empty() {
                 continue;
             }
-            print!("{}", "What would you like to change or add? ".cyan());
-            io::stdout().flush().unwrap();
-            let mut refinement = String::new();
-            io::stdin().read_line(&mut refinement).unwrap();
-            let refinement = refinement.trim();
 
-            if refinement.is_empty() {
+            let chosen: Vec<String> = selected.iter().map(|n| format!("- {}", findings[n - 1])).collect();
+            conversation_history.push(Message {
+                role: "user".to_string(),
+                content: refine_request(&refine_template, &format!("fix these review findings:\n{}", chosen.join("\n"))),
+            });
+            println!("{}", format!("Refining with finding(s) {}", selected.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(", ")).dimmed());
+        } else if cmd == "/refine" {
+            if last_generated_code.is_empty() {
+                println!("{}", "No code to refine. Generate some code first!".warning_color());
                 continue;
             }
+            let inline = !args.is_empty();
+            let Some(refinement) = commands::inline_or_ask(args, || {
+                ask_user(&tr(Msg::AskRefinement).heading_color().to_string())
+            }) else {
+                continue;
+            };
 
             // Add refinement request to history
             conversation_history.push(Message {
                 role: "user".to_string(),
-                content: format!("Please refine the previous code: {}", refinement),
+                content: refine_request(&refine_template, &refinement),
             });
+
+            // Preview what will be sent before spending an API call; a one-line
+            // `/refine <text>` was typed deliberately and goes straight out
+            if !inline {
+                println!("\n{}", "Refinement to send:".heading_color().bold());
+                println!("{}", conversation_history.last().unwrap().content.emphasis_color());
+                println!(
+                    "{}",
+                    format!(
+                        "(current code: {} lines, {} chars)",
+                        last_generated_code.lines().count(),
+                        last_generated_code.len()
+                    )
+                    .dimmed()
+                );
+                if !confirm(tr(Msg::ConfirmSendRefinement)) {
+                    conversation_history.pop();
+                    println!("{}", "Refinement cancelled.".warning_color());
+                    continue;
+                }
+            }
         } else {
-            // Regular prompt - add to history
+            // `/edit` reviews the prompt first; then it goes out like a regular one
+            let prompt = if cmd == "/edit" {
+                let Some(reviewed) = review_prompt(args) else {
+                    println!("{}", tr(Msg::PromptCancelled).warning_color());
+                    continue;
+                };
+                reviewed
+            } else {
+                prompt
+            };
+            // Regular prompt - flags are stripped so only the clean text joins the history
+            let (parsed, clean_prompt) = parse_prompt_overrides(&prompt);
+            for warning in &parsed.warnings {
+                println!("{} {}", "⚠️ ".warning_color(), warning.warning_color());
+            }
+            if clean_prompt.is_empty() {
+                continue;
+            }
+            overrides = parsed;
             conversation_history.push(Message {
                 role: "user".to_string(),
-                content: prompt.clone(),
+                content: clean_prompt,
             });
         }
 
+        let request_config;
+        let config = if overrides.is_empty() {
+            config
+        } else {
+            request_config = apply_overrides(config, &overrides);
+            let _ = logger.log(&format!(
+                "OVERRIDES: model={} temperature={} max_tokens={} seed={:?}",
+                request_config.model, request_config.temperature, request_config.max_tokens, request_config.seed
+            ));
+            println!("{}", format!("(this request: {})", override_summary(&overrides)).dimmed());
+            &request_config
+        };
+
+        // Large /context files are cut down to what this request is about
+        let context = request_context(&context_files, &conversation_history, &specs, config.max_context_bytes);
+
         // Log the request
         let _ = logger.log_api_request(&conversation_history.last().unwrap().content);
+        turns.push(SessionTurn::new(&conversation_history.last().unwrap().content));
+        let _ = logger.log_turn(turns.len(), &conversation_history.last().unwrap().content);
         metrics.total_requests += 1;
 
-        // Call Hugging Face with conversation history
-        match api::generate_code_with_history(conversation_history.clone(), config).await {
+        // Call Hugging Face with conversation history, offering a retry on soft failures
+        // (the user message is already in history, so nothing needs retyping)
+        let generation = loop {
+            let result = timed_generation(&with_context(&context, &conversation_history), config, &mut metrics, &logger).await;
+            if let Err(e) = &result {
+                if let Some(api_err) = e.downcast_ref::<ApiError>().filter(|e| e.is_soft_failure()) {
+                    let _ = logger.log_error(&format!("Soft API failure: {}", api_err));
+                    if let Some(preview) = api_err.body_preview() {
+                        let _ = logger.log(&format!("DEBUG response body: {}", preview));
+                    }
+                    println!("{} {}", "⚠️ ".warning_color(), api_err.to_string().warning_color());
+                    if confirm(tr(Msg::ConfirmRetry)) {
+                        metrics.api_errors += 1;
+                        metrics.total_requests += 1;
+                        continue;
+                    }
+                }
+            }
+            break result;
+        };
+
+        // A refusal of an ordinary task is usually the long system prompt misfiring:
+        // offer one retry with the core rules only
+        let generation = match generation {
+            Ok(raw_response) if is_refusal(&raw_response) => {
+                metrics.refusals += 1;
+                let _ = logger.log(&format!("REFUSAL (attempt 1): {}", raw_response.trim()));
+                println!("{} {}", "⚠️  The model refused:".warning_color(), raw_response.trim().dimmed());
+                if confirm("Retry once with a simplified system prompt?") {
+                    let retry_config = AppConfig {
+                        profile: Some("minimal".to_string()),
+                        ..config.clone()
+                    };
+                    let mut messages = with_context(&context, &conversation_history);
+                    if let Some(last) = messages.last_mut() {
+                        last.content.push_str("\n\n(This is an ordinary programming task; please answer with the Python code.)");
+                    }
+                    metrics.total_requests += 1;
+                    let _ = logger.log_api_request("Refusal retry with the minimal system prompt");
+                    let retry = timed_generation(&messages, &retry_config, &mut metrics, &logger).await;
+                    match &retry {
+                        Ok(text) if is_refusal(text) => {
+                            let _ = logger.log(&format!("REFUSAL (attempt 2): {}", text.trim()));
+                        }
+                        Ok(_) => metrics.refusal_recoveries += 1,
+                        Err(_) => {}
+                    }
+                    retry
+                } else {
+                    Ok(raw_response)
+                }
+            }
+            other => other,
+        };
+
+        match generation {
             Ok(raw_response) => {
-                // Log the response
-                let _ = logger.log_api_response(&raw_response);
+                // Fingerprint exactly what was sent, before the reply joins the history
+                let mut provenance = Provenance::new(config, &api::full_messages(&with_context(&context, &conversation_history), config.profile.as_deref(), &config.style));
+                let request_prompt = conversation_history.last().unwrap().content.clone();
+                let mut raw_response = raw_response;
 
                 // Extract clean Python code from the response
-                let code = extract_python_code(&raw_response);
+                let extracted = extract_code(&raw_response);
+                let _ = logger.log(&format!(
+                    "EXTRACTION: {}{}",
+                    extracted.strategy.as_str(),
+                    if extracted.html_unescaped { " (HTML entities unescaped)" } else { "" }
+                ));
+                let mut code = extracted.code;
+
+                // A clarifying question or plain prose: show it, keep it in history, don't offer to run it
+                if !looks_like_python(&code) {
+                    println!("\n{}", "💬 The model replied without code:".heading_color().bold());
+                    println!("{}\n", raw_response.trim());
+                    conversation_history.push(Message {
+                        role: "assistant".to_string(),
+                        content: raw_response.trim().to_string(),
+                    });
+                    trim_history(&mut conversation_history, config.max_history_messages);
+                    continue;
+                }
+
+                // Code in another language: never checked, installed for or run with Python
+                let language = detect_language(&code);
+                if language != CodeLanguage::Python {
+                    println!(
+                        "\n{} {}",
+                        format!("⚠️  This looks like {} code, not Python.", language.name()).warning_color().bold(),
+                        "It won't be checked or run.".dimmed()
+                    );
+                    show_code(&code, config);
+                    let _ = logger.log(&format!("LANGUAGE: reply looks like {}", language.name()));
+                    conversation_history.push(Message {
+                        role: "assistant".to_string(),
+                        content: code.clone(),
+                    });
+                    let answer = ask_user(&format!(
+                        "[s] save it as .{} / [p] ask for it in Python / Enter to drop it: ",
+                        language.extension()
+                    ));
+                    match answer.trim().to_lowercase().as_str() {
+                        "s" => {
+                            save_foreign_code(&code, language);
+                            trim_history(&mut conversation_history, config.max_history_messages);
+                            continue;
+                        }
+                        "p" => {
+                            conversation_history.push(Message {
+                                role: "user".to_string(),
+                                content: format!(
+                                    "That is {} code. This tool only runs Python: write the same program in Python.",
+                                    language.name()
+                                ),
+                            });
+                            metrics.total_requests += 1;
+                            let _ = logger.log_api_request(&format!("Redo in Python (was {})", language.name()));
+                            provenance = Provenance::new(config, &api::full_messages(&with_context(&context, &conversation_history), config.profile.as_deref(), &config.style));
+                            match timed_generation(&with_context(&context, &conversation_history), config, &mut metrics, &logger).await {
+                                Ok(response) => {
+                                    code = extract_code(&response).code;
+                                    raw_response = response;
+                                }
+                                Err(e) => {
+                                    metrics.api_errors += 1;
+                                    let _ = logger.log_error(&format!("API error: {}", e));
+                                    println!("{} {}", "✗ API error:".error_color(), e);
+                                    conversation_history.pop();
+                                    continue;
+                                }
+                            }
+                            if !looks_like_python(&code) || detect_language(&code) != CodeLanguage::Python {
+                                println!("{}", "✗ The model still didn't answer with Python code.".error_color());
+                                println!("{}\n", raw_response.trim());
+                                conversation_history.pop();
+                                continue;
+                            }
+                        }
+                        _ => {
+                            // Drop the reply and the request behind it
+                            conversation_history.pop();
+                            conversation_history.pop();
+                            continue;
+                        }
+                    }
+                }
+                let Some(code) = apply_hooks(&code, config, &logger) else {
+                    // Nothing to keep: the request leaves the history, the turns and the
+                    // session log's turn list as if it had never been made
+                    conversation_history.pop();
+                    turns.pop();
+                    let _ = logger.log_turn_dropped(turns.len() + 1);
+                    continue;
+                };
+
+                provenance.description = Some(describe_script(&code, &request_prompt, config, &mut descriptions, &mut metrics, &logger).await);
+                last_provenance = Some(provenance.clone());
                 last_generated_code = code.clone();
+                if let Some(turn) = turns.last_mut() {
+                    turn.set_code(&code);
+                }
+                save_scratch(config, &last_generated_code, &logger);
 
                 // Add assistant response to history
                 conversation_history.push(Message {
@@ -317,27 +3372,93 @@ pub async fn start_repl(config: &AppConfig) {
                 // Trim history to configured limit
                 trim_history(&mut conversation_history, config.max_history_messages);
 
-                display_code(&code);
+                show_code(&code, config);
+                if let Some(change) = export::last_deps_change(&turns).filter(|c| !c.is_empty()) {
+                    println!("{} {}", "📦 Dependencies changed:".warning_color(), change.summary());
+                    let _ = logger.log(&format!("DEPENDENCIES: {}", change.summary()));
+                }
+                let files = extract_files(&raw_response);
+                if !files.is_empty() {
+                    report_file_compile(&executor, &files, &logger);
+                }
 
                 // Write the script first, then syntax-check before executing
-                let script_path = match executor.write_script(&code) {
+                let script_path = match executor.write_script(&render_script(&code, Some(&provenance), config)) {
                     Ok(p) => p,
                     Err(e) => {
-                        println!("{} {}", "✗ Failed to write script:".red(), e);
+                        println!("{} {}", "✗ Failed to write script:".error_color(), e);
                         continue;
                     }
                 };
+                record_script(&executor, &logger, &script_path, &provenance, &request_prompt);
+
+                // Syntax check, with automatic repair rounds for extraction/format failures
+                let mut syntax_result = executor.syntax_check(&script_path);
+                if config.auto_repair && syntax_result.is_err() {
+                    let mut attempt = 0;
+                    while let Err(syntax_err) = &syntax_result {
+                        if attempt == MAX_REPAIR_ATTEMPTS {
+                            break;
+                        }
+                        attempt += 1;
+                        println!(
+                            "{} {}",
+                            format!("🔧 Repair attempt {}/{}:", attempt, MAX_REPAIR_ATTEMPTS).warning_color(),
+                            "code doesn't compile, asking the model for a corrected version".dimmed()
+                        );
+                        conversation_history.push(Message {
+                            role: "user".to_string(),
+                            content: repair_prompt(syntax_err),
+                        });
+                        metrics.total_requests += 1;
+                        metrics.repair_attempts += 1;
+                        let _ = logger.log_api_request(&format!("Repair attempt {}: {}", attempt, syntax_err));
 
-                // Syntax check
-                if let Err(syntax_err) = executor.syntax_check(&script_path) {
-                    println!("\n{} {}", "✗ Syntax error detected:".red().bold(), syntax_err);
-                    if confirm("Auto-refine to fix this error?") {
+                        match timed_generation(&with_context(&context, &conversation_history), config, &mut metrics, &logger).await {
+                            Ok(raw_response) => {
+                                let provenance = Provenance::new(config, &api::full_messages(&with_context(&context, &conversation_history), config.profile.as_deref(), &config.style));
+                                last_provenance = Some(provenance.clone());
+                                last_generated_code = extract_python_code(&raw_response);
+                                save_scratch(config, &last_generated_code, &logger);
+                                conversation_history.push(Message {
+                                    role: "assistant".to_string(),
+                                    content: last_generated_code.clone(),
+                                });
+                                trim_history(&mut conversation_history, config.max_history_messages);
+
+                                if let Err(e) = executor.rewrite_script(&script_path, &render_script(&last_generated_code, Some(&provenance), config)) {
+                                    println!("{} {}", "✗ Failed to write repaired script:".error_color(), e);
+                                    break;
+                                }
+                                record_script(&executor, &logger, &script_path, &provenance, &request_prompt);
+                                syntax_result = executor.syntax_check(&script_path);
+                            }
+                            Err(e) => {
+                                metrics.api_errors += 1;
+                                let _ = logger.log_error(&format!("API error during repair: {}", e));
+                                println!("{} {}", "✗ API error during repair:".error_color(), e);
+                                conversation_history.pop();
+                                break;
+                            }
+                        }
+                    }
+
+                    if syntax_result.is_ok() {
+                        metrics.successful_repairs += 1;
+                        println!("{}", "✓ Repaired code compiles.".success_color());
+                        show_code(&last_generated_code, config);
+                    }
+                }
+
+                if let Err(syntax_err) = syntax_result {
+                    println!("\n{} {}", "✗ Syntax error detected:".error_color().bold(), syntax_err);
+                    if confirm(tr(Msg::ConfirmAutoRefineSyntax)) {
                         // Add syntax error to conversation history for auto-refine
                         conversation_history.push(Message {
                             role: "user".to_string(),
                             content: format!(
                                 "The code has a syntax error. Please fix it:\n{}",
-                                syntax_err
+                                sanitize_untrusted(&syntax_err, MAX_UNTRUSTED_OUTPUT)
                             ),
                         });
                         // Skip execution, let the loop iterate to call the API again
@@ -345,11 +3466,13 @@ pub async fn start_repl(config: &AppConfig) {
                         metrics.total_requests += 1;
                         let _ = logger.log_api_request(&format!("Auto-refine syntax: {}", syntax_err));
 
-                        match api::generate_code_with_history(conversation_history.clone(), config).await {
+                        match timed_generation(&with_context(&context, &conversation_history), config, &mut metrics, &logger).await {
                             Ok(raw_response) => {
-                                let _ = logger.log_api_response(&raw_response);
+                                let provenance = Provenance::new(config, &api::full_messages(&with_context(&context, &conversation_history), config.profile.as_deref(), &config.style));
+                                last_provenance = Some(provenance.clone());
                                 let fixed_code = extract_python_code(&raw_response);
                                 last_generated_code = fixed_code.clone();
+                                save_scratch(config, &last_generated_code, &logger);
 
                                 conversation_history.push(Message {
                                     role: "assistant".to_string(),
@@ -357,24 +3480,25 @@ pub async fn start_repl(config: &AppConfig) {
                                 });
                                 trim_history(&mut conversation_history, config.max_history_messages);
 
-                                display_code(&fixed_code);
+                                show_code(&fixed_code, config);
 
                                 // Overwrite the script with the fixed code
-                                if let Err(e) = fs::write(&script_path, &fixed_code) {
-                                    println!("{} {}", "✗ Failed to write fixed script:".red(), e);
+                                if let Err(e) = executor.rewrite_script(&script_path, &render_script(&fixed_code, Some(&provenance), config)) {
+                                    println!("{} {}", "✗ Failed to write fixed script:".error_color(), e);
                                     continue;
                                 }
+                                record_script(&executor, &logger, &script_path, &provenance, &request_prompt);
 
                                 // Re-check syntax
                                 if let Err(err2) = executor.syntax_check(&script_path) {
-                                    println!("{} {}", "✗ Still has syntax errors:".red(), err2);
+                                    println!("{} {}", "✗ Still has syntax errors:".error_color(), err2);
                                     continue;
                                 }
                             }
                             Err(e) => {
                                 metrics.api_errors += 1;
                                 let _ = logger.log_error(&format!("API error during auto-refine: {}", e));
-                                println!("{} {}", "✗ API error during auto-refine:".red(), e);
+                                println!("{} {}", "✗ API error during auto-refine:".error_color(), e);
                                 conversation_history.pop();
                                 continue;
                             }
@@ -384,33 +3508,35 @@ pub async fn start_repl(config: &AppConfig) {
                     }
                 }
 
-                if confirm("Execute this script?") {
+                let question = Some(tr(Msg::ConfirmExecute));
+                if pre_run_gate(&executor, &last_generated_code, Some(&script_path), config, cli.allow_dangerous, &logger, question) {
+                    // The specs run the script too, so only once it is allowed to run
+                    if !specs.is_empty() {
+                        run_specs(&executor, &script_path, &specs, config.execution_timeout_secs);
+                    }
+
                     // Check for dependencies
                     let deps = executor.detect_dependencies(&last_generated_code);
-                    if !deps.is_empty() {
-                        println!("\n{} {}",
-                            "⚠️  Detected non-standard dependencies:".yellow(),
-                            deps.join(", ").bright_yellow());
-                        if config.auto_install_deps || confirm("Install these dependencies?") {
-                            if let Err(e) = executor.install_packages(&deps) {
-                                println!("{} {}", "⚠️  Failed to install dependencies:".yellow(), e);
-                                println!("{}", "Proceeding anyway...".dimmed());
-                            }
-                        }
+                    let selected = pick_dependencies(&executor, &deps, config, &mut declined_deps);
+                    if !selected.is_empty() {
+                        install_dependencies(&executor, &selected);
                     }
 
                     // Detect if interactive mode is needed
-                    let mode = if executor.needs_interactive_mode(&last_generated_code) {
-                        println!("{}", "🎮 Interactive mode detected (pygame/input/GUI)".bright_magenta().bold());
-                        println!("{}", "   Running with inherited stdio for user interaction...".dimmed());
-                        ExecutionMode::Interactive
-                    } else {
-                        ExecutionMode::Captured
-                    };
+                    let mode = choose_execution_mode(&executor, &last_generated_code, config.stream_output);
 
                     match executor.execute_script(&script_path, mode, config.execution_timeout_secs) {
                         Ok(result) => {
-                            let success = result.is_success();
+                            clean_after_run(&executor, config, mode, &logger);
+                            let success = execution_succeeded(&result, mode);
+                            if success {
+                                pin_after_run(&executor, config, mode, &script_path, &logger);
+                            }
+                            if let Some(turn) = turns.last_mut() {
+                                // The code that actually ran (it may have been fixed or edited since generation)
+                                turn.set_code(&last_generated_code);
+                                turn.output = Some(TurnOutput::from(&result));
+                            }
                             if success {
                                 metrics.successful_executions += 1;
                             } else {
@@ -419,37 +3545,35 @@ pub async fn start_repl(config: &AppConfig) {
 
                             let _ = logger.log_execution(success, &result.stdout);
 
-                            println!("\n{}", "━━━━━━━━━━━ Execution Result ━━━━━━━━━━━".bright_blue().bold());
-                            println!("{} {:?}", "Script saved at:".dimmed(), result.script_path);
-                            if !result.stdout.is_empty() {
-                                println!("\n{}:", "STDOUT".green().bold());
-                                println!("{}", result.stdout);
-                            }
-                            if !result.stderr.is_empty() {
-                                println!("\n{}:", "STDERR".red().bold());
-                                println!("{}", result.stderr);
+                            print_execution_result(&result, mode, true);
+                            if !success {
+                                triage_failure(&result, mode, &mut metrics, &logger);
+                                print_traceback_mapping(&result, &script_path, &last_generated_code);
                             }
-                            println!("{}", "━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━".bright_blue());
+                            check_golden(&mut golden, &result, success, &logger);
 
                             // Offer auto-refine on runtime errors
-                            if !success && !result.stderr.is_empty()
-                                && confirm("Auto-refine to fix this runtime error?")
+                            let error_output = failure_output(&result, mode);
+                            if !success && !error_output.is_empty()
+                                && confirm(tr(Msg::ConfirmAutoRefineRuntime))
                             {
                                 conversation_history.push(Message {
                                     role: "user".to_string(),
                                     content: format!(
                                         "The code crashed with this runtime error. Please fix it:\n{}",
-                                        result.stderr
+                                        sanitize_untrusted(error_output, MAX_UNTRUSTED_OUTPUT)
                                     ),
                                 });
                                 metrics.total_requests += 1;
-                                let _ = logger.log_api_request(&format!("Auto-refine runtime: {}", result.stderr));
+                                let _ = logger.log_api_request(&format!("Auto-refine runtime: {}", error_output));
 
-                                match api::generate_code_with_history(conversation_history.clone(), config).await {
+                                match timed_generation(&with_context(&context, &conversation_history), config, &mut metrics, &logger).await {
                                     Ok(raw_response) => {
-                                        let _ = logger.log_api_response(&raw_response);
+                                        let provenance = Provenance::new(config, &api::full_messages(&with_context(&context, &conversation_history), config.profile.as_deref(), &config.style));
+                                        last_provenance = Some(provenance.clone());
                                         let fixed_code = extract_python_code(&raw_response);
                                         last_generated_code = fixed_code.clone();
+                                        save_scratch(config, &last_generated_code, &logger);
 
                                         conversation_history.push(Message {
                                             role: "assistant".to_string(),
@@ -457,17 +3581,33 @@ pub async fn start_repl(config: &AppConfig) {
                                         });
                                         trim_history(&mut conversation_history, config.max_history_messages);
 
-                                        display_code(&fixed_code);
+                                        show_code(&fixed_code, config);
 
                                         // Overwrite the script with the fixed code
-                                        if let Err(e) = fs::write(&script_path, &fixed_code) {
-                                            println!("{} {}", "✗ Failed to write fixed script:".red(), e);
+                                        let written = executor.rewrite_script(&script_path, &render_script(&fixed_code, Some(&provenance), config));
+                                        if written.is_ok() {
+                                            record_script(&executor, &logger, &script_path, &provenance, &request_prompt);
+                                        }
+                                        if let Err(e) = written {
+                                            println!("{} {}", "✗ Failed to write fixed script:".error_color(), e);
                                         } else if let Err(syn_err) = executor.syntax_check(&script_path) {
-                                            println!("{} {}", "✗ Fixed code has syntax errors:".red(), syn_err);
-                                        } else if confirm("Execute the fixed script?") {
+                                            println!("{} {}", "✗ Fixed code has syntax errors:".error_color(), syn_err);
+                                        } else if pre_run_gate(
+                                            &executor,
+                                            &fixed_code,
+                                            Some(&script_path),
+                                            config,
+                                            cli.allow_dangerous,
+                                            &logger,
+                                            Some(tr(Msg::ConfirmExecuteFixed)),
+                                        ) {
                                             match executor.execute_script(&script_path, mode, config.execution_timeout_secs) {
                                                 Ok(retry_result) => {
-                                                    let retry_success = retry_result.is_success();
+                                                    clean_after_run(&executor, config, mode, &logger);
+                                                    let retry_success = execution_succeeded(&retry_result, mode);
+                                                    if retry_success {
+                                                        pin_after_run(&executor, config, mode, &script_path, &logger);
+                                                    }
                                                     if retry_success {
                                                         metrics.successful_executions += 1;
                                                     } else {
@@ -475,22 +3615,16 @@ pub async fn start_repl(config: &AppConfig) {
                                                     }
                                                     let _ = logger.log_execution(retry_success, &retry_result.stdout);
 
-                                                    println!("\n{}", "━━━━━━━━━━━ Execution Result ━━━━━━━━━━━".bright_blue().bold());
-                                                    println!("{} {:?}", "Script saved at:".dimmed(), retry_result.script_path);
-                                                    if !retry_result.stdout.is_empty() {
-                                                        println!("\n{}:", "STDOUT".green().bold());
-                                                        println!("{}", retry_result.stdout);
-                                                    }
-                                                    if !retry_result.stderr.is_empty() {
-                                                        println!("\n{}:", "STDERR".red().bold());
-                                                        println!("{}", retry_result.stderr);
+                                                    print_execution_result(&retry_result, mode, true);
+                                                    if !retry_success {
+                                                        triage_failure(&retry_result, mode, &mut metrics, &logger);
                                                     }
-                                                    println!("{}", "━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━".bright_blue());
+                                                    check_golden(&mut golden, &retry_result, retry_success, &logger);
                                                 }
                                                 Err(e) => {
                                                     metrics.failed_executions += 1;
                                                     let _ = logger.log_error(&format!("Execution error: {}", e));
-                                                    println!("{} {}", "✗ Execution error:".red(), e);
+                                                    println!("{} {}", "✗ Execution error:".error_color(), e);
                                                 }
                                             }
                                         }
@@ -498,7 +3632,7 @@ pub async fn start_repl(config: &AppConfig) {
                                     Err(e) => {
                                         metrics.api_errors += 1;
                                         let _ = logger.log_error(&format!("API error during auto-refine: {}", e));
-                                        println!("{} {}", "✗ API error during auto-refine:".red(), e);
+                                        println!("{} {}", "✗ API error during auto-refine:".error_color(), e);
                                         conversation_history.pop();
                                     }
                                 }
@@ -507,7 +3641,7 @@ pub async fn start_repl(config: &AppConfig) {
                         Err(e) => {
                             metrics.failed_executions += 1;
                             let _ = logger.log_error(&format!("Execution error: {}", e));
-                            println!("{} {}", "✗ Execution error:".red(), e);
+                            println!("{} {}", "✗ Execution error:".error_color(), e);
                         }
                     }
                 }
@@ -515,7 +3649,7 @@ pub async fn start_repl(config: &AppConfig) {
             Err(e) => {
                 metrics.api_errors += 1;
                 let _ = logger.log_error(&format!("API error: {}", e));
-                println!("{} {}", "✗ API error:".red(), e);
+                println!("{} {}", "✗ API error:".error_color(), e);
                 // Remove the last user message if API call failed
                 conversation_history.pop();
             }
@@ -523,6 +3657,6 @@ pub async fn start_repl(config: &AppConfig) {
     }
 
     // Display session statistics on exit
-    println!("\n{}", "Session ended.".bright_cyan());
+    println!("\n{}", "Session ended.".heading_color());
     metrics.display();
 }
@@ -1,12 +1,21 @@
+use crate::diagnostics::FailureKind;
 use crate::utils::find_char_boundary;
-use anyhow::Result;
-use chrono::Local;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
 use std::fs::{self, OpenOptions};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
 pub struct Logger {
-    log_file: PathBuf,
+    /// `None` when logging is disabled (`--no-log` or unwritable log directory).
+    log_file: Option<PathBuf>,
+    /// First write failure seen mid-session, waiting to be reported to the user.
+    pending_failure: RefCell<Option<String>>,
+    failure_reported: Cell<bool>,
 }
 
 #[derive(Debug)]
@@ -15,6 +24,68 @@ pub struct SessionMetrics {
     pub successful_executions: usize,
     pub failed_executions: usize,
     pub api_errors: usize,
+    /// Latency of every successful API call, in order.
+    pub api_latencies: Vec<Duration>,
+    /// Latency and prompt preview of the slowest API call so far.
+    pub slowest_request: Option<(Duration, String)>,
+    pub api_retries: usize,
+    /// Script descriptions reused from the session cache instead of asked again.
+    pub cache_hits: usize,
+    /// Requests answered by a fallback model after the configured one failed.
+    pub fallback_activations: usize,
+    /// Automatic repair requests sent after a syntax check failure.
+    pub repair_attempts: usize,
+    /// Generations that compiled after automatic repair.
+    pub successful_repairs: usize,
+    /// Replies classified as refusals, and how many a simplified-prompt retry recovered.
+    pub refusals: usize,
+    pub refusal_recoveries: usize,
+    /// `/py` evaluations; not requests, so they don't count toward the success rate.
+    pub quick_evals: usize,
+    /// Failed runs by what their error output showed.
+    pub failures_by_kind: BTreeMap<FailureKind, usize>,
+    /// Requests, estimated tokens and latencies per model.
+    pub by_model: BTreeMap<String, ModelUsage>,
+    pub started_at: DateTime<Local>,
+}
+
+/// What one model was asked this session. Token counts are estimates (see
+/// `api::estimate_tokens`): the API doesn't report usage.
+#[derive(Debug, Default, Clone)]
+pub struct ModelUsage {
+    pub requests: usize,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub latencies: Vec<Duration>,
+}
+
+/// Summary of API latencies over a session.
+#[derive(Debug, PartialEq)]
+pub struct LatencyStats {
+    pub count: usize,
+    pub mean: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+impl LatencyStats {
+    pub fn of(latencies: &[Duration]) -> Option<Self> {
+        if latencies.is_empty() {
+            return None;
+        }
+        let mut sorted = latencies.to_vec();
+        sorted.sort();
+        let count = sorted.len();
+        let total: Duration = sorted.iter().sum();
+        // Nearest-rank percentile
+        let p95_index = ((count as f64 * 0.95).ceil() as usize).saturating_sub(1);
+        Some(LatencyStats {
+            count,
+            mean: total / count as u32,
+            p95: sorted[p95_index],
+            max: sorted[count - 1],
+        })
+    }
 }
 
 impl SessionMetrics {
@@ -24,9 +95,49 @@ impl SessionMetrics {
             successful_executions: 0,
             failed_executions: 0,
             api_errors: 0,
+            api_latencies: Vec::new(),
+            slowest_request: None,
+            api_retries: 0,
+            cache_hits: 0,
+            fallback_activations: 0,
+            repair_attempts: 0,
+            successful_repairs: 0,
+            refusals: 0,
+            refusal_recoveries: 0,
+            quick_evals: 0,
+            failures_by_kind: BTreeMap::new(),
+            by_model: BTreeMap::new(),
+            started_at: Local::now(),
+        }
+    }
+
+    /// Count one answered request to `model` in the per-model breakdown.
+    pub fn record_model_call(&mut self, model: &str, latency: Duration, prompt_tokens: usize, completion_tokens: usize) {
+        let usage = self.by_model.entry(model.to_string()).or_default();
+        usage.requests += 1;
+        usage.prompt_tokens += prompt_tokens;
+        usage.completion_tokens += completion_tokens;
+        usage.latencies.push(latency);
+    }
+
+    /// Record the latency of one successful API call for `prompt`.
+    pub fn record_api_latency(&mut self, latency: Duration, prompt: &str) {
+        self.api_latencies.push(latency);
+        if self.slowest_request.as_ref().is_none_or(|(slowest, _)| latency > *slowest) {
+            let end = find_char_boundary(prompt, 60);
+            let preview = if end < prompt.len() {
+                format!("{}...", &prompt[..end])
+            } else {
+                prompt.to_string()
+            };
+            self.slowest_request = Some((latency, preview));
         }
     }
 
+    pub fn latency_stats(&self) -> Option<LatencyStats> {
+        LatencyStats::of(&self.api_latencies)
+    }
+
     pub fn success_rate(&self) -> f64 {
         if self.total_requests == 0 {
             return 0.0;
@@ -35,17 +146,347 @@ impl SessionMetrics {
     }
 
     pub fn display(&self) {
+        use crate::theme::Themed;
         use colored::Colorize;
-        println!("\n{}", "━━━━━━━━━ Session Statistics ━━━━━━━━━".bright_cyan().bold());
+        println!("\n{}", "━━━━━━━━━ Session Statistics ━━━━━━━━━".heading_color().bold());
         println!("Total requests: {}", self.total_requests);
-        println!("Successful executions: {}", self.successful_executions.to_string().green());
-        println!("Failed executions: {}", self.failed_executions.to_string().red());
-        println!("API errors: {}", self.api_errors.to_string().yellow());
+        println!("Successful executions: {}", self.successful_executions.to_string().success_color());
+        println!("Failed executions: {}", self.failed_executions.to_string().error_color());
+        println!("API errors: {}", self.api_errors.to_string().warning_color());
         println!("Success rate: {:.1}%", self.success_rate());
-        println!("{}", "━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━".bright_cyan());
+        println!("Retries: {}  Cache hits: {}  Fallback model: {}",
+            self.api_retries, self.cache_hits, self.fallback_activations);
+        println!("Repairs: {} attempted, {} succeeded", self.repair_attempts, self.successful_repairs);
+        if !self.failures_by_kind.is_empty() {
+            let kinds: Vec<String> = self.failures_by_kind.iter().map(|(kind, n)| format!("{n} {}", kind.label())).collect();
+            println!("Failures by kind: {}", kinds.join(", "));
+        }
+        if self.refusals > 0 {
+            println!("Refusals: {} ({} recovered with the simplified prompt)", self.refusals, self.refusal_recoveries);
+        }
+        if self.quick_evals > 0 {
+            println!("Quick evaluations (/py): {}", self.quick_evals);
+        }
+
+        if let Some(stats) = self.latency_stats() {
+            println!("\n{} ({} calls)", "API latency".heading_color(), stats.count);
+            let max_ms = stats.max.as_millis().max(1);
+            for (label, value) in [("mean", stats.mean), ("p95", stats.p95), ("max", stats.max)] {
+                let width = (value.as_millis() * 20 / max_ms) as usize;
+                let bar = "█".repeat(width.max(1));
+                let bar = if value.as_secs() >= 20 {
+                    bar.error_color()
+                } else if value.as_secs() >= 5 {
+                    bar.warning_color()
+                } else {
+                    bar.success_color()
+                };
+                println!("  {:<4} {:>7} ms {}", label, value.as_millis(), bar);
+            }
+            if let Some((latency, prompt)) = &self.slowest_request {
+                println!("  Slowest: {} ms for \"{}\"", latency.as_millis(), prompt.dimmed());
+            }
+        }
+        if self.by_model.len() > 1 {
+            let width = self.by_model.keys().map(|m| m.chars().count()).max().unwrap_or(0).max(5);
+            println!("\n{}", "Per model".heading_color());
+            println!("  {:<width$} {:>8} {:>10} {:>10} {:>9} {:>9}", "model", "requests", "~tok in", "~tok out", "mean ms", "p95 ms");
+            for row in self.export().models {
+                println!(
+                    "  {:<width$} {:>8} {:>10} {:>10} {:>9} {:>9}",
+                    row.model, row.requests, row.estimated_prompt_tokens, row.estimated_completion_tokens,
+                    row.latency_mean_ms, row.latency_p95_ms
+                );
+            }
+        }
+        println!("{}", "━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━".heading_color());
+    }
+}
+
+/// Version of the `/stats export` schema, bumped when fields change meaning or go away.
+pub const STATS_SCHEMA_VERSION: u32 = 1;
+
+/// `/stats export` document. Latencies are in milliseconds (0 when there were no
+/// calls), timestamps are RFC 3339 and token counts are estimates.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatsExport {
+    pub schema_version: u32,
+    pub session_started_at: String,
+    pub exported_at: String,
+    pub totals: StatsTotals,
+    pub models: Vec<ModelStats>,
+    /// Failed runs by kind label (`missing module`, `timeout`, ...).
+    pub failures_by_kind: BTreeMap<String, usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatsTotals {
+    pub requests: usize,
+    pub successful_executions: usize,
+    pub failed_executions: usize,
+    pub api_errors: usize,
+    pub success_rate: f64,
+    pub api_retries: usize,
+    pub cache_hits: usize,
+    pub fallback_activations: usize,
+    pub repair_attempts: usize,
+    pub successful_repairs: usize,
+    pub refusals: usize,
+    pub refusal_recoveries: usize,
+    pub quick_evals: usize,
+    pub latency_mean_ms: u64,
+    pub latency_p95_ms: u64,
+    pub latency_max_ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelStats {
+    pub model: String,
+    pub requests: usize,
+    pub estimated_prompt_tokens: usize,
+    pub estimated_completion_tokens: usize,
+    pub latency_mean_ms: u64,
+    pub latency_p95_ms: u64,
+    pub latency_max_ms: u64,
+}
+
+/// Mean, p95 and max in milliseconds.
+fn latency_ms(latencies: &[Duration]) -> (u64, u64, u64) {
+    LatencyStats::of(latencies).map_or((0, 0, 0), |s| {
+        (s.mean.as_millis() as u64, s.p95.as_millis() as u64, s.max.as_millis() as u64)
+    })
+}
+
+/// Header of the CSV export: one row per model, then a `TOTAL` row.
+pub const STATS_CSV_HEADER: &str = "model,requests,estimated_prompt_tokens,estimated_completion_tokens,latency_mean_ms,latency_p95_ms,latency_max_ms,successful_executions,failed_executions,api_errors";
+
+/// A CSV field, quoted when it holds a separator, quote or newline.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
     }
 }
 
+impl SessionMetrics {
+    pub fn export(&self) -> StatsExport {
+        let (mean, p95, max) = latency_ms(&self.api_latencies);
+        StatsExport {
+            schema_version: STATS_SCHEMA_VERSION,
+            session_started_at: self.started_at.to_rfc3339(),
+            exported_at: Local::now().to_rfc3339(),
+            totals: StatsTotals {
+                requests: self.total_requests,
+                successful_executions: self.successful_executions,
+                failed_executions: self.failed_executions,
+                api_errors: self.api_errors,
+                success_rate: self.success_rate(),
+                api_retries: self.api_retries,
+                cache_hits: self.cache_hits,
+                fallback_activations: self.fallback_activations,
+                repair_attempts: self.repair_attempts,
+                successful_repairs: self.successful_repairs,
+                refusals: self.refusals,
+                refusal_recoveries: self.refusal_recoveries,
+                quick_evals: self.quick_evals,
+                latency_mean_ms: mean,
+                latency_p95_ms: p95,
+                latency_max_ms: max,
+            },
+            models: self
+                .by_model
+                .iter()
+                .map(|(model, usage)| {
+                    let (mean, p95, max) = latency_ms(&usage.latencies);
+                    ModelStats {
+                        model: model.clone(),
+                        requests: usage.requests,
+                        estimated_prompt_tokens: usage.prompt_tokens,
+                        estimated_completion_tokens: usage.completion_tokens,
+                        latency_mean_ms: mean,
+                        latency_p95_ms: p95,
+                        latency_max_ms: max,
+                    }
+                })
+                .collect(),
+            failures_by_kind: self.failures_by_kind.iter().map(|(kind, n)| (kind.label().to_string(), *n)).collect(),
+        }
+    }
+}
+
+impl StatsExport {
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// One row per model plus a `TOTAL` row; execution outcomes only apply to the total.
+    /// `requests` counts API calls in every row, so the total is the sum of the models'.
+    pub fn to_csv(&self) -> String {
+        let mut csv = format!("{STATS_CSV_HEADER}\n");
+        for m in &self.models {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{},{},,,\n",
+                csv_field(&m.model), m.requests, m.estimated_prompt_tokens, m.estimated_completion_tokens,
+                m.latency_mean_ms, m.latency_p95_ms, m.latency_max_ms
+            ));
+        }
+        let t = &self.totals;
+        let api_calls: usize = self.models.iter().map(|m| m.requests).sum();
+        let prompt_tokens: usize = self.models.iter().map(|m| m.estimated_prompt_tokens).sum();
+        let completion_tokens: usize = self.models.iter().map(|m| m.estimated_completion_tokens).sum();
+        csv.push_str(&format!(
+            "TOTAL,{},{},{},{},{},{},{},{},{}\n",
+            api_calls, prompt_tokens, completion_tokens, t.latency_mean_ms, t.latency_p95_ms, t.latency_max_ms,
+            t.successful_executions, t.failed_executions, t.api_errors
+        ));
+        csv
+    }
+}
+
+/// Limits on the log directory; 0 disables a limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetentionPolicy {
+    pub max_age_days: u64,
+    pub max_total_bytes: u64,
+    pub max_files: usize,
+}
+
+impl RetentionPolicy {
+    pub fn from_config(config: &crate::config::AppConfig) -> Self {
+        Self {
+            max_age_days: config.log_max_age_days,
+            max_total_bytes: config.log_max_total_mb * 1024 * 1024,
+            max_files: config.log_max_files,
+        }
+    }
+}
+
+/// Pick the log files to delete: newest are kept first, and anything past the age,
+/// count or total-size limit goes. `keep` (the current session's file) is never selected.
+/// Returns the selected files, oldest first.
+pub fn select_for_deletion(
+    files: &[(PathBuf, u64, SystemTime)],
+    now: SystemTime,
+    policy: &RetentionPolicy,
+    keep: Option<&Path>,
+) -> Vec<PathBuf> {
+    // The current file counts against the limits before anything else
+    let (current, mut sorted): (Vec<_>, Vec<_>) = files.iter().partition(|f| keep == Some(f.0.as_path()));
+    sorted.sort_by_key(|f| std::cmp::Reverse(f.2));
+
+    let max_age = Duration::from_secs(policy.max_age_days * 24 * 3600);
+    let mut kept_files = current.len();
+    let mut kept_bytes: u64 = current.iter().map(|f| f.1).sum();
+    let mut selected = Vec::new();
+    for (path, size, modified) in sorted {
+        let age = now.duration_since(*modified).unwrap_or_default();
+        let too_old = policy.max_age_days > 0 && age > max_age;
+        let too_many = policy.max_files > 0 && kept_files >= policy.max_files;
+        let too_big = policy.max_total_bytes > 0 && kept_bytes + size > policy.max_total_bytes;
+        if too_old || too_many || too_big {
+            selected.push(path.clone());
+        } else {
+            kept_files += 1;
+            kept_bytes += size;
+        }
+    }
+    selected.reverse();
+    selected
+}
+
+/// Apply `policy` to the `.log` files in `log_dir`, sparing `current`.
+/// Returns what was deleted, with sizes.
+pub fn apply_retention(log_dir: &Path, policy: &RetentionPolicy, current: Option<&Path>) -> Result<Vec<(PathBuf, u64)>> {
+    let entries = match fs::read_dir(log_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Cannot read log directory {}", log_dir.display())),
+    };
+
+    let mut files = Vec::new();
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        // Session transcripts (`session_*.log`) and rotated logs (`*.log.1`, ...)
+        if !entry.file_name().to_string_lossy().contains(".log") {
+            continue;
+        }
+        let Ok(meta) = entry.metadata() else { continue };
+        if meta.is_file() {
+            files.push((path, meta.len(), meta.modified().unwrap_or(SystemTime::UNIX_EPOCH)));
+        }
+    }
+
+    let mut removed = Vec::new();
+    for path in select_for_deletion(&files, SystemTime::now(), policy, current) {
+        let size = files.iter().find(|f| f.0 == path).map_or(0, |f| f.1);
+        fs::remove_file(&path).with_context(|| format!("Cannot delete {}", path.display()))?;
+        removed.push((path, size));
+    }
+    Ok(removed)
+}
+
+/// Session logs in `log_dir`, newest first.
+pub fn list_sessions(log_dir: &Path) -> Vec<PathBuf> {
+    let mut sessions: Vec<PathBuf> = fs::read_dir(log_dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| p.file_name().is_some_and(|n| {
+            let n = n.to_string_lossy();
+            n.starts_with("session_") && n.ends_with(".log")
+        }))
+        .collect();
+    // The timestamp in the name sorts chronologically
+    sessions.sort();
+    sessions.reverse();
+    sessions
+}
+
+/// The session log `name` designates: a path, a file name, or its timestamp with or
+/// without the `session_` prefix and `.log` suffix (`20240501_101500`). A timestamp
+/// alone also finds logs named with the instance's PID (`session_20240501_101500_4242.log`).
+pub fn find_session(log_dir: &Path, name: &str) -> Option<PathBuf> {
+    if Path::new(name).is_file() {
+        return Some(PathBuf::from(name));
+    }
+    let stem = name.trim_end_matches(".log");
+    let stem = if stem.starts_with("session_") { stem.to_string() } else { format!("session_{stem}") };
+    let path = log_dir.join(format!("{stem}.log"));
+    if path.is_file() {
+        return Some(path);
+    }
+    let with_pid = format!("{stem}_");
+    list_sessions(log_dir).into_iter().find(|session| {
+        session.file_name().is_some_and(|n| {
+            n.to_string_lossy()
+                .strip_prefix(&with_pid)
+                .and_then(|rest| rest.strip_suffix(".log"))
+                .is_some_and(|pid| !pid.is_empty() && pid.bytes().all(|b| b.is_ascii_digit()))
+        })
+    })
+}
+
+/// The script each turn of a session log ended with, in turn order: `TURN` entries start
+/// turns and the last `SCRIPT` entry before the next one is its code, while `TURN DROPPED`
+/// takes back the latest turn. `None` for a turn that wrote no script.
+pub fn session_turn_scripts(log: &str) -> Vec<Option<PathBuf>> {
+    let mut turns: Vec<Option<PathBuf>> = Vec::new();
+    for line in log.lines() {
+        // Entries are `[timestamp] MESSAGE`; continuation lines of a message don't start with `[`
+        let Some((_, entry)) = line.strip_prefix('[').and_then(|rest| rest.split_once("] ")) else { continue };
+        if entry.starts_with("TURN DROPPED") {
+            turns.pop();
+        } else if entry.starts_with("TURN ") {
+            turns.push(None);
+        } else if let (Some(path), Some(turn)) = (entry.strip_prefix("SCRIPT: "), turns.last_mut()) {
+            *turn = Some(PathBuf::from(path));
+        }
+    }
+    turns
+}
+
 impl Logger {
     pub fn new(log_dir: &str) -> Result<Self> {
         let dir = PathBuf::from(log_dir);
@@ -53,35 +494,101 @@ impl Logger {
             fs::create_dir_all(&dir)?;
         }
 
+        // The PID keeps two instances started in the same second apart
         let timestamp = Local::now().format("%Y%m%d_%H%M%S");
-        let log_file = dir.join(format!("session_{}.log", timestamp));
+        let log_file = dir.join(format!("session_{}_{}.log", timestamp, std::process::id()));
+
+        // Probe once so an unwritable directory is reported at startup, not on first use
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_file)
+            .with_context(|| format!("Cannot write to log file {}", log_file.display()))?;
 
-        Ok(Self { log_file })
+        Ok(Self {
+            log_file: Some(log_file),
+            pending_failure: RefCell::new(None),
+            failure_reported: Cell::new(false),
+        })
+    }
+
+    /// A logger that silently drops every message.
+    pub fn disabled() -> Self {
+        Self {
+            log_file: None,
+            pending_failure: RefCell::new(None),
+            failure_reported: Cell::new(false),
+        }
+    }
+
+    /// This session's log file, if logging is enabled.
+    pub fn current_file(&self) -> Option<&Path> {
+        self.log_file.as_deref()
     }
 
     pub fn log(&self, message: &str) -> Result<()> {
+        let Some(log_file) = &self.log_file else {
+            return Ok(());
+        };
+
+        let result = Self::append(log_file, message);
+        if let Err(e) = &result {
+            if !self.failure_reported.get() && self.pending_failure.borrow().is_none() {
+                *self.pending_failure.borrow_mut() = Some(e.to_string());
+            }
+        }
+        result
+    }
+
+    /// Returns the first logging failure of the session, exactly once.
+    pub fn take_failure(&self) -> Option<String> {
+        let failure = self.pending_failure.borrow_mut().take();
+        if failure.is_some() {
+            self.failure_reported.set(true);
+        }
+        failure
+    }
+
+    fn append(log_file: &PathBuf, message: &str) -> Result<()> {
         let mut file = OpenOptions::new()
             .create(true)
             .append(true)
-            .open(&self.log_file)?;
+            .open(log_file)
+            .with_context(|| format!("Cannot open log file {}", log_file.display()))?;
 
         let timestamp = Local::now().format("%Y-%m-%d %H:%M:%S");
         writeln!(file, "[{}] {}", timestamp, message)?;
         Ok(())
     }
 
+    /// Start a new turn in the log (`/replay` counts these).
+    pub fn log_turn(&self, number: usize, prompt: &str) -> Result<()> {
+        self.log(&format!("TURN {}: {}", number, prompt.lines().next().unwrap_or_default()))
+    }
+
+    /// Take back turn `number`, started but dropped before it produced anything.
+    pub fn log_turn_dropped(&self, number: usize) -> Result<()> {
+        self.log(&format!("TURN DROPPED: {}", number))
+    }
+
+    /// Note a script written for the current turn, by absolute path.
+    pub fn log_script(&self, path: &Path) -> Result<()> {
+        let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+        self.log(&format!("SCRIPT: {}", path.display()))
+    }
+
     pub fn log_api_request(&self, prompt: &str) -> Result<()> {
         self.log(&format!("API REQUEST: {}", prompt))
     }
 
-    pub fn log_api_response(&self, response: &str) -> Result<()> {
+    pub fn log_api_response(&self, response: &str, latency: Duration) -> Result<()> {
         let preview = if response.len() > 200 {
             let end = find_char_boundary(response, 200);
             format!("{}...", &response[..end])
         } else {
             response.to_string()
         };
-        self.log(&format!("API RESPONSE: {}", preview))
+        self.log(&format!("API RESPONSE [latency_ms={}]: {}", latency.as_millis(), preview))
     }
 
     pub fn log_execution(&self, success: bool, output: &str) -> Result<()> {
@@ -99,6 +606,63 @@ mod tests {
     use super::*;
     use std::fs;
 
+    #[test]
+    fn test_session_turn_scripts() {
+        let log = "\
+[2024-05-01 10:00:00] SCRIPT: /tmp/before_any_turn.py
+[2024-05-01 10:00:01] TURN 1: plot a sine wave
+[2024-05-01 10:00:02] API REQUEST: plot a sine wave
+[2024-05-01 10:00:05] SCRIPT: /g/script_1.py
+[2024-05-01 10:00:06] SCRIPT: /g/script_1_repaired.py
+[2024-05-01 10:00:07] EXECUTION SUCCESS: done
+SCRIPT: /not/an/entry.py
+[2024-05-01 10:01:00] TURN 2: explain recursion
+[2024-05-01 10:02:00] TURN 3: now save it
+[2024-05-01 10:02:05] SCRIPT: /g/script_3.py
+";
+        assert_eq!(
+            session_turn_scripts(log),
+            vec![Some(PathBuf::from("/g/script_1_repaired.py")), None, Some(PathBuf::from("/g/script_3.py"))]
+        );
+        assert!(session_turn_scripts("[2024-05-01 10:00:02] API REQUEST: old log").is_empty());
+
+        // A turn a hook rejected leaves no entry behind
+        let dropped = "\
+[2024-05-01 10:00:01] TURN 1: plot a sine wave
+[2024-05-01 10:00:02] SCRIPT: /g/script_1.py
+[2024-05-01 10:01:00] TURN 2: use eval
+[2024-05-01 10:01:05] TURN DROPPED: 2
+[2024-05-01 10:02:00] TURN 2: use ast.literal_eval
+[2024-05-01 10:02:05] SCRIPT: /g/script_2.py
+";
+        assert_eq!(
+            session_turn_scripts(dropped),
+            vec![Some(PathBuf::from("/g/script_1.py")), Some(PathBuf::from("/g/script_2.py"))]
+        );
+    }
+
+    #[test]
+    fn test_find_and_list_sessions() {
+        let dir = Path::new("test_find_sessions_dir");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        for name in ["session_20240501_101500.log", "session_20240502_090000.log", "other.log"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let expected = dir.join("session_20240501_101500.log");
+        for name in ["20240501_101500", "session_20240501_101500", "session_20240501_101500.log"] {
+            assert_eq!(find_session(dir, name), Some(expected.clone()), "{name}");
+        }
+        assert_eq!(find_session(dir, expected.to_str().unwrap()), Some(expected.clone()));
+        assert_eq!(find_session(dir, "20990101_000000"), None);
+        fs::write(dir.join("session_20240503_120000_4242.log"), "").unwrap();
+        assert_eq!(find_session(dir, "20240503_120000"), Some(dir.join("session_20240503_120000_4242.log")));
+        assert_eq!(find_session(dir, "20240503_12"), None);
+        fs::remove_file(dir.join("session_20240503_120000_4242.log")).unwrap();
+        assert_eq!(list_sessions(dir), vec![dir.join("session_20240502_090000.log"), expected]);
+        let _ = fs::remove_dir_all(dir);
+    }
+
     #[test]
     fn test_session_metrics_new() {
         let metrics = SessionMetrics::new();
@@ -130,6 +694,137 @@ mod tests {
         assert_eq!(metrics.success_rate(), 100.0);
     }
 
+    #[test]
+    fn test_latency_stats_empty() {
+        let metrics = SessionMetrics::new();
+        assert!(metrics.latency_stats().is_none());
+    }
+
+    #[test]
+    fn test_latency_stats_calculation() {
+        let mut metrics = SessionMetrics::new();
+        for ms in 1..=20u64 {
+            metrics.record_api_latency(Duration::from_millis(ms * 100), &format!("prompt {ms}"));
+        }
+        let stats = metrics.latency_stats().unwrap();
+        assert_eq!(stats.count, 20);
+        assert_eq!(stats.mean, Duration::from_millis(1050));
+        assert_eq!(stats.p95, Duration::from_millis(1900));
+        assert_eq!(stats.max, Duration::from_millis(2000));
+        assert_eq!(metrics.slowest_request.unwrap().1, "prompt 20");
+    }
+
+    fn metrics_with_two_models() -> SessionMetrics {
+        let mut metrics = SessionMetrics::new();
+        // Two prompts, one of which needed a repair call
+        metrics.total_requests = 2;
+        metrics.successful_executions = 2;
+        metrics.failed_executions = 1;
+        metrics.failures_by_kind.insert(FailureKind::Timeout, 1);
+        metrics.cache_hits = 1;
+        for ms in [100, 300] {
+            metrics.record_api_latency(Duration::from_millis(ms), "p");
+            metrics.record_model_call("Qwen/Qwen2.5-Coder-32B-Instruct", Duration::from_millis(ms), 50, 200);
+        }
+        metrics.record_api_latency(Duration::from_millis(800), "p");
+        metrics.record_model_call("org/model,with-comma", Duration::from_millis(800), 60, 10);
+        metrics
+    }
+
+    #[test]
+    fn test_stats_export_json_round_trip() {
+        let export = metrics_with_two_models().export();
+        assert_eq!(export.schema_version, STATS_SCHEMA_VERSION);
+        assert_eq!(export.models.len(), 2);
+        let qwen = &export.models[0];
+        assert_eq!((qwen.requests, qwen.estimated_prompt_tokens, qwen.estimated_completion_tokens), (2, 100, 400));
+        assert_eq!((qwen.latency_mean_ms, qwen.latency_max_ms), (200, 300));
+        assert_eq!(export.totals.latency_max_ms, 800);
+        assert_eq!((export.totals.cache_hits, export.totals.fallback_activations), (1, 0));
+        assert_eq!(export.failures_by_kind.get("timeout"), Some(&1));
+        assert!(DateTime::parse_from_rfc3339(&export.session_started_at).is_ok());
+
+        let json = export.to_json().unwrap();
+        let parsed: StatsExport = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, export);
+    }
+
+    #[test]
+    fn test_stats_export_csv() {
+        let csv = metrics_with_two_models().export().to_csv();
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!(rows[0], STATS_CSV_HEADER);
+        assert_eq!(rows[1], "Qwen/Qwen2.5-Coder-32B-Instruct,2,100,400,200,300,300,,,");
+        assert_eq!(rows[2], "\"org/model,with-comma\",1,60,10,800,800,800,,,");
+        assert_eq!(rows[3], "TOTAL,3,160,410,400,800,800,2,1,0");
+        assert_eq!(rows.len(), 4);
+        // Every row has as many fields as the header (the quoted comma excepted)
+        let columns = STATS_CSV_HEADER.split(',').count();
+        assert!(rows.iter().filter(|r| !r.starts_with('"')).all(|r| r.split(',').count() == columns));
+    }
+
+    #[test]
+    fn test_slowest_prompt_is_truncated() {
+        let mut metrics = SessionMetrics::new();
+        metrics.record_api_latency(Duration::from_millis(10), &"x".repeat(200));
+        let (_, preview) = metrics.slowest_request.unwrap();
+        assert!(preview.ends_with("..."));
+        assert!(preview.len() < 100);
+    }
+
+    fn file(name: &str, size: u64, days_old: u64, now: SystemTime) -> (PathBuf, u64, SystemTime) {
+        (PathBuf::from(name), size, now - Duration::from_secs(days_old * 24 * 3600))
+    }
+
+    #[test]
+    fn test_select_for_deletion_by_age() {
+        let now = SystemTime::now();
+        let files = vec![file("a.log", 10, 40, now), file("b.log", 10, 5, now), file("c.log", 10, 31, now)];
+        let policy = RetentionPolicy { max_age_days: 30, max_total_bytes: 0, max_files: 0 };
+        assert_eq!(select_for_deletion(&files, now, &policy, None), vec![PathBuf::from("a.log"), PathBuf::from("c.log")]);
+    }
+
+    #[test]
+    fn test_select_for_deletion_by_count_and_size() {
+        let now = SystemTime::now();
+        let files = vec![file("old.log", 10, 3, now), file("mid.log", 10, 2, now), file("new.log", 10, 1, now)];
+        let by_count = RetentionPolicy { max_age_days: 0, max_total_bytes: 0, max_files: 2 };
+        assert_eq!(select_for_deletion(&files, now, &by_count, None), vec![PathBuf::from("old.log")]);
+
+        let by_size = RetentionPolicy { max_age_days: 0, max_total_bytes: 15, max_files: 0 };
+        assert_eq!(
+            select_for_deletion(&files, now, &by_size, None),
+            vec![PathBuf::from("old.log"), PathBuf::from("mid.log")]
+        );
+    }
+
+    #[test]
+    fn test_select_for_deletion_spares_current_session() {
+        let now = SystemTime::now();
+        let files = vec![file("current.log", 100, 90, now), file("other.log", 10, 90, now)];
+        let policy = RetentionPolicy { max_age_days: 30, max_total_bytes: 1, max_files: 1 };
+        let selected = select_for_deletion(&files, now, &policy, Some(Path::new("current.log")));
+        assert_eq!(selected, vec![PathBuf::from("other.log")]);
+    }
+
+    #[test]
+    fn test_apply_retention_deletes_only_logs() {
+        let dir = Path::new("test_logs_retention");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("session_a.log"), "aaaa").unwrap();
+        fs::write(dir.join("session_b.log"), "bbbb").unwrap();
+        fs::write(dir.join("notes.txt"), "keep").unwrap();
+
+        let policy = RetentionPolicy { max_age_days: 0, max_total_bytes: 0, max_files: 1 };
+        let removed = apply_retention(dir, &policy, Some(&dir.join("session_b.log"))).unwrap();
+        assert_eq!(removed, vec![(dir.join("session_a.log"), 4)]);
+        assert!(dir.join("session_b.log").exists());
+        assert!(dir.join("notes.txt").exists());
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
     #[test]
     fn test_logger_creation() {
         let test_log_dir = "test_logs_temp";
@@ -138,7 +833,7 @@ mod tests {
         
         let logger = logger.unwrap();
         // Check that the parent directory exists
-        assert!(logger.log_file.parent().unwrap().exists());
+        assert!(logger.log_file.as_ref().unwrap().parent().unwrap().exists());
         
         // Clean up
         let _ = fs::remove_dir_all(test_log_dir);
@@ -153,7 +848,7 @@ mod tests {
         assert!(result.is_ok());
         
         // Verify log file has content
-        let content = fs::read_to_string(&logger.log_file).unwrap();
+        let content = fs::read_to_string(logger.log_file.as_ref().unwrap()).unwrap();
         assert!(content.contains("Test message"));
         
         // Clean up
@@ -168,7 +863,7 @@ mod tests {
         let result = logger.log_api_request("Create a hello world script");
         assert!(result.is_ok());
         
-        let content = fs::read_to_string(&logger.log_file).unwrap();
+        let content = fs::read_to_string(logger.log_file.as_ref().unwrap()).unwrap();
         assert!(content.contains("API REQUEST"));
         assert!(content.contains("hello world"));
         
@@ -185,7 +880,7 @@ mod tests {
         let _ = logger.log("Entry 2");
         let _ = logger.log("Entry 3");
         
-        let content = fs::read_to_string(&logger.log_file).unwrap();
+        let content = fs::read_to_string(logger.log_file.as_ref().unwrap()).unwrap();
         assert!(content.contains("Entry 1"));
         assert!(content.contains("Entry 2"));
         assert!(content.contains("Entry 3"));
@@ -193,4 +888,51 @@ mod tests {
         // Clean up
         let _ = fs::remove_dir_all(test_log_dir);
     }
+
+    #[test]
+    fn test_disabled_logger_is_noop() {
+        let logger = Logger::disabled();
+        assert!(logger.log_file.is_none());
+        assert!(logger.log("ignored").is_ok());
+        assert!(logger.take_failure().is_none());
+    }
+
+    #[test]
+    fn test_logger_reports_failure_once() {
+        let test_log_dir = "test_logs_temp5";
+        let logger = Logger::new(test_log_dir).unwrap();
+
+        // Pull the directory out from under the logger
+        let _ = fs::remove_dir_all(test_log_dir);
+
+        assert!(logger.log("lost").is_err());
+        assert!(logger.log("lost again").is_err());
+        assert!(logger.take_failure().is_some());
+        assert!(logger.take_failure().is_none());
+
+        let _ = logger.log("still lost");
+        assert!(logger.take_failure().is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_logger_unwritable_directory() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let test_log_dir = "test_logs_readonly";
+        let _ = fs::remove_dir_all(test_log_dir);
+        fs::create_dir_all(test_log_dir).unwrap();
+        fs::set_permissions(test_log_dir, fs::Permissions::from_mode(0o555)).unwrap();
+
+        // Privileged users (e.g. root in containers) bypass permission bits
+        let probe = PathBuf::from(test_log_dir).join("probe");
+        let bypassed = fs::write(&probe, "").is_ok();
+
+        if !bypassed {
+            assert!(Logger::new(test_log_dir).is_err());
+        }
+
+        fs::set_permissions(test_log_dir, fs::Permissions::from_mode(0o755)).unwrap();
+        let _ = fs::remove_dir_all(test_log_dir);
+    }
 }
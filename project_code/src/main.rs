@@ -1,23 +1,97 @@
 use anyhow::Result;
+use colored::*;
 use dotenvy::dotenv;
+use theme::Themed;
 
 mod api;
+mod auth;
+mod branch;
+mod cli;
+mod commands;
 mod config;
-mod python_exec;
+mod diagnostics;
+mod env;
+mod export;
+mod hooks;
+mod index;
+mod input;
 mod interface;
-mod utils;
 mod logger;
+mod messages;
+mod progress;
+mod provenance;
+#[cfg(unix)]
+mod pty;
+mod python_exec;
+mod recipe;
+mod requirements;
+mod run;
+mod setup;
+mod spec;
+mod theme;
+mod utils;
 
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Charge .env (HF_TOKEN)
     dotenv().ok();
+    messages::init_from_env();
+    theme::init_from_env();
+
+    let cli = cli::CliArgs::parse(std::env::args().skip(1))?;
+    cli.apply_color_choice();
+    let mut config = config::AppConfig::load();
+    if let Err(e) = index::layout_dir(&config.generated_layout, chrono::Local::now()) {
+        let default = config::AppConfig::default().generated_layout;
+        eprintln!("{} {:#}", format!("⚠️  Using the default layout {default:?}:").warning_color(), e);
+        config.generated_layout = default;
+    }
+    if let Some(secs) = cli.timeout_secs {
+        config.request_timeout_secs = secs;
+    }
+    // No API involved: runs before anything token- or network-related
+    if let Some(command) = &cli.run {
+        std::process::exit(run::run(command, &config));
+    }
+    if config.danger_accept_invalid_certs {
+        eprintln!(
+            "{}",
+            "⚠️  WARNING: danger_accept_invalid_certs is on. TLS certificates are NOT verified; \
+             anyone on the network path can read your HF token. Use ca_cert instead if you can."
+                .error_color()
+                .bold()
+        );
+    }
+
+    if let Some(token) = &cli.token {
+        auth::set_cli_token(token.clone());
+    }
+    if let Some(command) = cli.auth {
+        let ok = auth::run(command, &config).await;
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
+    if let Some(run) = &cli.recipe {
+        let ok = recipe::run_cli(run, &config, &cli).await;
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
+    if cli.check {
+        let ok = interface::check_api(&config).await;
+        std::process::exit(if ok { 0 } else { 1 });
+    }
 
-    let config = config::AppConfig::load();
+    if !cli.no_wizard && setup::is_first_run() {
+        // The answers are set on top of `config`: command-line overrides still hold
+        match setup::run(&config).await {
+            Ok(configured) => config = configured,
+            Err(e) => eprintln!("{} {:#}", "✗ Setup failed:".error_color(), e),
+        }
+    }
 
     // Lance ton interface CLI (boucle REPL)
-    interface::start_repl(&config).await;
+    interface::start_repl(&config, &cli).await;
 
     Ok(())
 }
@@ -0,0 +1,182 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Language of the user-facing strings. Set from `BOT_LANG` at startup and by `/lang`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Lang {
+    #[default]
+    En,
+    Fr,
+}
+
+impl Lang {
+    /// Accepts "en"/"fr" as well as locale-style values such as "fr_FR.UTF-8".
+    pub fn parse(value: &str) -> Option<Lang> {
+        let code = value.trim().to_lowercase();
+        match code.get(..2) {
+            Some("en") => Some(Lang::En),
+            Some("fr") => Some(Lang::Fr),
+            _ => None,
+        }
+    }
+
+    pub fn code(self) -> &'static str {
+        match self {
+            Lang::En => "en",
+            Lang::Fr => "fr",
+        }
+    }
+}
+
+static CURRENT: AtomicU8 = AtomicU8::new(0);
+
+/// Language used by `tr` from now on.
+pub fn set_lang(lang: Lang) {
+    CURRENT.store(lang as u8, Ordering::Relaxed);
+}
+
+pub fn lang() -> Lang {
+    match CURRENT.load(Ordering::Relaxed) {
+        1 => Lang::Fr,
+        _ => Lang::En,
+    }
+}
+
+/// Pick the startup language from `BOT_LANG` (English when unset or unknown).
+pub fn init_from_env() {
+    if let Some(lang) = std::env::var("BOT_LANG").ok().as_deref().and_then(Lang::parse) {
+        set_lang(lang);
+    }
+}
+
+/// User-facing strings that exist in every language.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Msg {
+    BannerTagline,
+    BannerHint,
+    HelpTitle,
+    /// Appended to every yes/no question.
+    YesNo,
+    Goodbye,
+    LangSwitched,
+    LangUsage,
+    AskFilename,
+    AskScriptFilename,
+    AskRefinement,
+    AskPrompt,
+    AskSendEditCancel,
+    /// Shown again when the answer to `AskSendEditCancel` is none of the three.
+    SendEditCancelHint,
+    PromptToSend,
+    PromptCancelled,
+    AskKeepChoice,
+    AskFixFindings,
+    AskDependencies,
+    ConfirmXvfb,
+    ConfirmSendRefinement,
+    ConfirmRetry,
+    ConfirmAutoRefineSyntax,
+    ConfirmExecute,
+    ConfirmAutoRefineRuntime,
+    ConfirmExecuteFixed,
+}
+
+/// The text of `msg` in the current language.
+pub fn tr(msg: Msg) -> &'static str {
+    text(msg, lang())
+}
+
+/// The text of `msg` in `lang`.
+fn text(msg: Msg, lang: Lang) -> &'static str {
+    let (en, fr) = match msg {
+        Msg::BannerTagline => (" AI-Powered Python Code Generator", " Générateur de code Python assisté par IA"),
+        Msg::BannerHint => (
+            " Type /help for commands or /quit to exit",
+            " Tapez /help pour les commandes ou /quit pour quitter",
+        ),
+        Msg::HelpTitle => ("Available Commands:", "Commandes disponibles :"),
+        Msg::YesNo => ("(y/n): ", "(o/n) : "),
+        Msg::Goodbye => ("Goodbye!", "Au revoir !"),
+        Msg::LangSwitched => ("Language set to English.", "Langue : français."),
+        Msg::LangUsage => ("Usage: /lang en|fr", "Utilisation : /lang en|fr"),
+        Msg::AskFilename => ("Enter filename (e.g., script.py): ", "Nom du fichier (ex. script.py) : "),
+        Msg::AskScriptFilename => (
+            "Enter script filename (e.g., script_20251209_152023.py): ",
+            "Nom du script (ex. script_20251209_152023.py) : ",
+        ),
+        Msg::AskRefinement => ("What would you like to change or add? ", "Que voulez-vous modifier ou ajouter ? "),
+        Msg::AskKeepChoice => (
+            "Keep which choice? (Enter keeps the current code)",
+            "Quel choix garder ? (Entrée garde le code actuel)",
+        ),
+        Msg::AskFixFindings => (
+            "Fix which findings? (numbers, a for all, Enter to skip) ",
+            "Corriger quels points ? (numéros, a pour tous, Entrée pour passer) ",
+        ),
+        Msg::AskDependencies => (
+            "Enter to install [x], numbers to toggle (e.g. 1 3), n to skip all: ",
+            "Entrée pour installer [x], numéros pour basculer (ex. 1 3), n pour tout ignorer : ",
+        ),
+        Msg::ConfirmXvfb => (
+            "No display found. Run under a virtual display (Xvfb) to check it starts?",
+            "Aucun écran détecté. Lancer sous un écran virtuel (Xvfb) pour vérifier le démarrage ?",
+        ),
+        Msg::AskPrompt => ("Prompt: ", "Requête : "),
+        Msg::AskSendEditCancel => (
+            "Enter to send, e to edit, c to cancel: ",
+            "Entrée pour envoyer, e pour modifier, c pour annuler : ",
+        ),
+        Msg::SendEditCancelHint => ("Enter, e or c.", "Entrée, e ou c."),
+        Msg::PromptToSend => ("Prompt to send:", "Requête à envoyer :"),
+        Msg::PromptCancelled => ("Prompt cancelled.", "Requête annulée."),
+        Msg::ConfirmSendRefinement => ("Send this refinement?", "Envoyer cette modification ?"),
+        Msg::ConfirmRetry => ("Retry?", "Réessayer ?"),
+        Msg::ConfirmAutoRefineSyntax => ("Auto-refine to fix this error?", "Corriger automatiquement cette erreur ?"),
+        Msg::ConfirmExecute => ("Execute this script?", "Exécuter ce script ?"),
+        Msg::ConfirmAutoRefineRuntime => (
+            "Auto-refine to fix this runtime error?",
+            "Corriger automatiquement cette erreur d'exécution ?",
+        ),
+        Msg::ConfirmExecuteFixed => ("Execute the fixed script?", "Exécuter le script corrigé ?"),
+    };
+    match lang {
+        Lang::En => en,
+        Lang::Fr => fr,
+    }
+}
+
+/// Whether a yes/no answer means yes. "y" and "o" are accepted in both languages.
+pub fn is_yes(answer: &str) -> bool {
+    let answer = answer.trim().to_lowercase();
+    answer.starts_with('y') || answer.starts_with('o')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lang_parse() {
+        assert_eq!(Lang::parse("fr"), Some(Lang::Fr));
+        assert_eq!(Lang::parse("FR_fr.UTF-8"), Some(Lang::Fr));
+        assert_eq!(Lang::parse("en"), Some(Lang::En));
+        assert_eq!(Lang::parse("de"), None);
+        assert_eq!(Lang::parse(""), None);
+    }
+
+    #[test]
+    fn test_text_per_lang() {
+        // The current language is global: other tests run alongside, so it is left alone
+        assert_eq!(text(Msg::YesNo, Lang::Fr), "(o/n) : ");
+        assert_eq!(text(Msg::YesNo, Lang::En), "(y/n): ");
+        assert_eq!(tr(Msg::YesNo), text(Msg::YesNo, lang()));
+    }
+
+    #[test]
+    fn test_is_yes() {
+        assert!(is_yes("o"));
+        assert!(is_yes("Yes"));
+        assert!(is_yes(" oui"));
+        assert!(!is_yes("n"));
+        assert!(!is_yes(""));
+    }
+}
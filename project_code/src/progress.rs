@@ -0,0 +1,356 @@
+//! Progress bars in script output: tqdm-style lines redrawn with `\r`, and runs of lines
+//! that only differ by an `n/total` counter or a percentage that keeps rising. They are
+//! collapsed to their latest frame so the capture stays readable, and drawn in place on
+//! the live line. The result a script prints between `RESULT_MARKER` lines is left alone.
+
+use crate::python_exec::RESULT_MARKER;
+use regex::Regex;
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+
+/// An `n/total` counter or a percentage.
+static COUNTER_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\b\d+\s*/\s*\d+\b|\b\d{1,3}(?:\.\d+)?\s*%").unwrap());
+static DIGITS_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\d+").unwrap());
+
+/// While a bar keeps updating, one frame per interval is kept in the capture besides the last.
+pub const SNAPSHOT_EVERY: Duration = Duration::from_secs(10);
+
+/// The progress a line reports.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Counter {
+    Fraction(u64, u64),
+    Percent(f64),
+}
+
+impl Counter {
+    /// The first counter in `text`. An `n/m` within a longer run of slashes (a date such
+    /// as 2024/05/01, a path) is not one.
+    fn find(text: &str) -> Option<Self> {
+        COUNTER_RE.find_iter(text).find_map(|m| {
+            let found = m.as_str();
+            if let Some(percent) = found.strip_suffix('%') {
+                return percent.trim().parse().ok().map(Counter::Percent);
+            }
+            if text[..m.start()].ends_with('/') || text[m.end()..].starts_with('/') {
+                return None;
+            }
+            let (done, total) = found.split_once('/')?;
+            Some(Counter::Fraction(done.trim().parse().ok()?, total.trim().parse().ok()?))
+        })
+    }
+
+    /// Whether `next` is a later frame of the same bar: the count goes up, toward the same total.
+    fn rises_to(self, next: Counter) -> bool {
+        match (self, next) {
+            (Counter::Fraction(done, total), Counter::Fraction(next_done, next_total)) => total == next_total && next_done > done,
+            (Counter::Percent(done), Counter::Percent(next_done)) => next_done > done,
+            _ => false,
+        }
+    }
+}
+
+/// What a line looks like once its numbers are blanked out, and its counter, if it
+/// reports progress.
+fn progress_shape(text: &str) -> Option<(String, Counter)> {
+    Counter::find(text).map(|counter| (DIGITS_RE.replace_all(text.trim(), "#").into_owned(), counter))
+}
+
+/// Elapsed time as shown on the live line: `42s`, `3m07s`.
+pub fn format_elapsed(elapsed: Duration) -> String {
+    let secs = elapsed.as_secs();
+    if secs < 60 {
+        format!("{secs}s")
+    } else {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    }
+}
+
+/// What to show live for one piece of output.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Live {
+    /// An ordinary line, printed as is (terminator included).
+    Line(Vec<u8>),
+    /// The current frame of a progress bar, drawn over the previous one.
+    Frame(String),
+    /// The last frame of a progress bar; the live line is finished after it.
+    EndFrame(String),
+}
+
+struct Bar {
+    frame: Vec<u8>,
+    /// `None` for a bar redrawn with `\r`; for a counter run, the shape of its lines and
+    /// the latest counter.
+    shape: Option<(String, Counter)>,
+    snapshot_at: Instant,
+    /// The current frame is already in the capture as a snapshot.
+    stored: bool,
+}
+
+/// Collapses progress output piece by piece (a piece ends at `\r` or `\n`).
+pub struct ProgressCollapser {
+    bar: Option<Bar>,
+    stored: Vec<u8>,
+    /// Between `RESULT_MARKER` lines: output is kept as it is.
+    verbatim: bool,
+    /// Frames replaced by a later one and left out of the capture.
+    pub collapsed_frames: usize,
+}
+
+impl Default for ProgressCollapser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressCollapser {
+    pub fn new() -> Self {
+        Self { bar: None, stored: Vec::new(), verbatim: false, collapsed_frames: 0 }
+    }
+
+    /// Feed one piece of output, ending with `\r`, `\n` (or nothing at the end of the
+    /// stream), and get what to show live.
+    pub fn push(&mut self, piece: &[u8], now: Instant) -> Vec<Live> {
+        let (text, terminator) = match piece.last() {
+            Some(&t @ (b'\r' | b'\n')) => (&piece[..piece.len() - 1], Some(t)),
+            _ => (piece, None),
+        };
+        let shown = String::from_utf8_lossy(text).into_owned();
+        let mut live = Vec::new();
+        let marker = shown.trim() == RESULT_MARKER;
+        if self.verbatim || marker {
+            self.verbatim ^= marker;
+            live.extend(self.finish_bar());
+            self.stored.extend_from_slice(piece);
+            live.push(Live::Line(piece.to_vec()));
+            return live;
+        }
+        match terminator {
+            Some(b'\r') => {
+                if text.iter().all(u8::is_ascii_whitespace) {
+                    return live;
+                }
+                if self.bar.as_ref().is_some_and(|bar| bar.shape.is_some()) {
+                    live.extend(self.finish_bar());
+                }
+                self.update(text, None, now);
+                live.push(Live::Frame(shown));
+            }
+            _ => {
+                let redrawn = self.bar.as_ref().is_some_and(|bar| bar.shape.is_none());
+                let shape = progress_shape(&shown);
+                let continues = match (self.bar.as_ref().and_then(|bar| bar.shape.as_ref()), shape.as_ref()) {
+                    (Some((prev, prev_counter)), Some((next, counter))) => prev == next && prev_counter.rises_to(*counter),
+                    _ => false,
+                };
+                if redrawn && text.is_empty() {
+                    // `\r\n`, or a newline after the last redraw: that frame stays
+                    live.extend(self.finish_bar());
+                } else if redrawn {
+                    // The line that ends a redrawn bar is its last frame
+                    self.update(text, None, now);
+                    live.extend(self.finish_bar());
+                } else if continues {
+                    self.update(text, shape, now);
+                    live.push(Live::Frame(shown));
+                } else {
+                    live.extend(self.finish_bar());
+                    if shape.is_some() {
+                        self.update(text, shape, now);
+                        live.push(Live::Frame(shown));
+                    } else {
+                        self.stored.extend_from_slice(piece);
+                        live.push(Live::Line(piece.to_vec()));
+                    }
+                }
+            }
+        }
+        live
+    }
+
+    /// A new frame for the current bar, or the first of a new one.
+    fn update(&mut self, frame: &[u8], shape: Option<(String, Counter)>, now: Instant) {
+        match &mut self.bar {
+            Some(bar) => {
+                if !bar.stored {
+                    self.collapsed_frames += 1;
+                }
+                bar.frame = frame.to_vec();
+                if shape.is_some() {
+                    bar.shape = shape;
+                }
+                bar.stored = false;
+                if now.duration_since(bar.snapshot_at) >= SNAPSHOT_EVERY {
+                    self.stored.extend_from_slice(frame);
+                    self.stored.push(b'\n');
+                    bar.snapshot_at = now;
+                    bar.stored = true;
+                }
+            }
+            None => self.bar = Some(Bar { frame: frame.to_vec(), shape, snapshot_at: now, stored: false }),
+        }
+    }
+
+    /// Store the last frame of the current bar, if any.
+    fn finish_bar(&mut self) -> Option<Live> {
+        let bar = self.bar.take()?;
+        if !bar.stored {
+            self.stored.extend_from_slice(&bar.frame);
+            self.stored.push(b'\n');
+        }
+        Some(Live::EndFrame(String::from_utf8_lossy(&bar.frame).into_owned()))
+    }
+
+    /// What the capture gained since the last call.
+    pub fn take_stored(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.stored)
+    }
+
+    /// End of the stream: the bar still being drawn is kept as is.
+    pub fn finish(&mut self) -> Vec<Live> {
+        self.finish_bar().into_iter().collect()
+    }
+}
+
+/// Collapse the progress bars of output read in one go (captured runs).
+pub fn collapse(output: &[u8]) -> Vec<u8> {
+    let now = Instant::now();
+    let mut collapser = ProgressCollapser::new();
+    for piece in output.split_inclusive(|&b| b == b'\r' || b == b'\n') {
+        collapser.push(piece, now);
+    }
+    collapser.finish();
+    collapser.take_stored()
+}
+
+/// A `⏱ 12s` line on stderr while a captured run goes on, when stderr is a terminal;
+/// cleared when dropped. Short runs finish before it shows.
+pub struct Stopwatch {
+    stop: Arc<AtomicBool>,
+    ticker: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Stopwatch {
+    const SHOW_AFTER: Duration = Duration::from_secs(2);
+
+    pub fn start() -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        if !std::io::stderr().is_terminal() {
+            return Self { stop, ticker: None };
+        }
+        let flag = Arc::clone(&stop);
+        let ticker = std::thread::spawn(move || {
+            let start = Instant::now();
+            let mut shown = false;
+            while !flag.load(Ordering::Relaxed) {
+                std::thread::sleep(Duration::from_millis(100));
+                let elapsed = start.elapsed();
+                if elapsed >= Self::SHOW_AFTER && elapsed.subsec_millis() < 100 {
+                    let _ = write!(std::io::stderr(), "\r⏱ running… {}\x1b[K", format_elapsed(elapsed));
+                    shown = true;
+                }
+            }
+            if shown {
+                let _ = write!(std::io::stderr(), "\r\x1b[K");
+            }
+        });
+        Self { stop, ticker: Some(ticker) }
+    }
+}
+
+impl Drop for Stopwatch {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(ticker) = self.ticker.take() {
+            let _ = ticker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// tqdm writes `\r` before each frame and `\n` after the last one.
+    fn tqdm(total: usize, label: &str) -> String {
+        let mut out = String::new();
+        for i in 0..=total {
+            let pct = i * 100 / total;
+            out.push_str(&format!("\r{label}: {pct:3}%|{:<10}| {i}/{total}", "█".repeat(pct / 10)));
+        }
+        out.push('\n');
+        out
+    }
+
+    #[test]
+    fn test_collapse_keeps_the_final_frame_of_each_bar() {
+        let output = format!("Loading data\n{}{}done\n", tqdm(200, "train"), tqdm(50, "eval"));
+        let collapsed = String::from_utf8(collapse(output.as_bytes())).unwrap();
+        assert_eq!(
+            collapsed,
+            "Loading data\ntrain: 100%|██████████| 200/200\neval: 100%|██████████| 50/50\ndone\n"
+        );
+    }
+
+    #[test]
+    fn test_collapse_counter_lines_and_crlf() {
+        let mut output = String::from("start\n");
+        for i in 1..=500 {
+            output.push_str(&format!("Processed {i}/500 files\n"));
+        }
+        output.push_str("Epoch 1/2 loss 0.5\r\nsummary: ok\n");
+        let collapsed = String::from_utf8(collapse(output.as_bytes())).unwrap();
+        assert_eq!(collapsed, "start\nProcessed 500/500 files\nEpoch 1/2 loss 0.5\nsummary: ok\n");
+        // Nothing to collapse
+        assert_eq!(collapse(b"a\nb\n"), b"a\nb\n");
+        // A bar cut off by the end of the stream keeps its last frame
+        assert_eq!(collapse(b"\r1/3\r2/3\r"), b"2/3\n");
+    }
+
+    #[test]
+    fn test_collapse_needs_a_rising_counter() {
+        // Dates and paths with slashes are not counters
+        let log = "2024/05/01 backup ok\n2024/05/02 backup ok\n2024/05/03 backup ok\n";
+        assert_eq!(collapse(log.as_bytes()), log.as_bytes());
+        // Same shape, but the count goes down or the total changes: separate lines
+        let table = "row 3/10 ok\nrow 1/10 ok\nrow 2/20 ok\n";
+        assert_eq!(collapse(table.as_bytes()), table.as_bytes());
+        let percentages = "cpu 40%\ncpu 12%\n";
+        assert_eq!(collapse(percentages.as_bytes()), percentages.as_bytes());
+        assert_eq!(collapse(b"done 10%\ndone 55%\ndone 100%\n"), b"done 100%\n");
+        assert_eq!(Counter::find("at 2024/05/01, step 3/9"), Some(Counter::Fraction(3, 9)));
+    }
+
+    #[test]
+    fn test_collapse_leaves_the_result_alone() {
+        let output = format!(
+            "step 1/3\nstep 2/3\nstep 3/3\n{RESULT_MARKER}\n[\"1/3\",\n\"2/3\"]\n{RESULT_MARKER}\n"
+        );
+        let collapsed = String::from_utf8(collapse(output.as_bytes())).unwrap();
+        assert_eq!(collapsed, format!("step 3/3\n{RESULT_MARKER}\n[\"1/3\",\n\"2/3\"]\n{RESULT_MARKER}\n"));
+    }
+
+    #[test]
+    fn test_live_frames_and_snapshots() {
+        let start = Instant::now();
+        let mut collapser = ProgressCollapser::new();
+        assert_eq!(collapser.push(b"header\n", start), vec![Live::Line(b"header\n".to_vec())]);
+        assert_eq!(collapser.push(b"\r", start), vec![]);
+        assert_eq!(collapser.push(b"1/100\r", start), vec![Live::Frame("1/100".into())]);
+        collapser.push(b"2/100\r", start + Duration::from_secs(1));
+        // Past the snapshot interval the frame is kept in the capture too
+        collapser.push(b"50/100\r", start + SNAPSHOT_EVERY);
+        collapser.push(b"51/100\r", start + SNAPSHOT_EVERY + Duration::from_secs(1));
+        assert_eq!(collapser.push(b"100/100\n", start + SNAPSHOT_EVERY * 2), vec![Live::EndFrame("100/100".into())]);
+        assert_eq!(collapser.take_stored(), b"header\n50/100\n100/100\n");
+        assert_eq!(collapser.collapsed_frames, 3);
+        assert!(collapser.finish().is_empty());
+    }
+
+    #[test]
+    fn test_format_elapsed() {
+        assert_eq!(format_elapsed(Duration::from_millis(4500)), "4s");
+        assert_eq!(format_elapsed(Duration::from_secs(187)), "3m07s");
+    }
+}
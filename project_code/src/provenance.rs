@@ -0,0 +1,183 @@
+use crate::api::Message;
+use crate::config::AppConfig;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Everything needed to reproduce a generation: model, sampling parameters and
+/// a fingerprint of the exact messages sent.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Provenance {
+    pub model: String,
+    pub temperature: f32,
+    pub max_tokens: u32,
+    pub seed: Option<u64>,
+    pub api_url: String,
+    pub messages_sha256: String,
+    pub tool_version: String,
+    pub created_at: String,
+    /// One line on what the script does (`describe`); absent from older index entries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+impl Provenance {
+    /// `messages` must be the full array sent to the API, system prompt included.
+    pub fn new(config: &AppConfig, messages: &[Message]) -> Self {
+        Self {
+            model: config.model.clone(),
+            temperature: config.temperature,
+            max_tokens: config.max_tokens,
+            seed: config.seed,
+            api_url: config.api_url.clone(),
+            messages_sha256: fingerprint(messages),
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            created_at: Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+            description: None,
+        }
+    }
+
+    /// Python comment block describing this provenance.
+    pub fn header(&self) -> String {
+        let seed = self.seed.map_or("none".to_string(), |s| s.to_string());
+        let description = self.description.as_ref().map(|d| format!("# Description: {d}"));
+        [
+            "# ------------------------------------------------------------".to_string(),
+            format!("# Generated by python-maker-bot {}", self.tool_version),
+        ]
+        .into_iter()
+        .chain(description)
+        .chain([
+            format!("# Date: {}", self.created_at),
+            format!("# Model: {}", self.model),
+            format!("# Endpoint: {}", self.api_url),
+            format!(
+                "# Temperature: {} | max_tokens: {} | seed: {}",
+                self.temperature, self.max_tokens, seed
+            ),
+            format!("# Messages SHA-256: {}", self.messages_sha256),
+            "# ------------------------------------------------------------".to_string(),
+        ])
+        .collect::<Vec<_>>()
+        .join("\n")
+    }
+}
+
+/// SHA-256 (hex) of the JSON-serialized message array.
+pub fn fingerprint(messages: &[Message]) -> String {
+    let json = serde_json::to_string(messages).unwrap_or_default();
+    let digest = Sha256::digest(json.as_bytes());
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// SHA-256 (hex) of a piece of code, to recognize it again (`describe` cache).
+pub fn code_sha256(code: &str) -> String {
+    Sha256::digest(code.trim().as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// The single place where scripts get their header; every writer goes through here.
+/// The configured `code_prologue`/`code_epilogue` go around the model's code.
+pub fn render_script(code: &str, provenance: Option<&Provenance>, config: &AppConfig) -> String {
+    let boilerplate = |text: &Option<String>| text.as_deref().map(str::trim_end).filter(|t| !t.trim().is_empty()).map(String::from);
+    let mut parts: Vec<String> = provenance.map(Provenance::header).into_iter().collect();
+    parts.extend(boilerplate(&config.code_prologue));
+    parts.push(code.to_string());
+    parts.extend(boilerplate(&config.code_epilogue));
+    parts.join("\n\n")
+}
+
+/// How many script lines come before the model's code in `script` (header, prologue):
+/// script line `n` is line `n - offset` of the code. `None` when the code isn't in it.
+pub fn code_line_offset(script: &str, code: &str) -> Option<usize> {
+    let start = script.find(code.trim_end())?;
+    Some(script[..start].matches('\n').count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_messages() -> Vec<Message> {
+        vec![
+            Message {
+                role: "system".to_string(),
+                content: "sys".to_string(),
+            },
+            Message {
+                role: "user".to_string(),
+                content: "hello".to_string(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_fingerprint_is_stable_and_sensitive() {
+        let a = fingerprint(&sample_messages());
+        assert_eq!(a, fingerprint(&sample_messages()));
+        assert_eq!(a.len(), 64);
+
+        let mut changed = sample_messages();
+        changed[1].content.push('!');
+        assert_ne!(a, fingerprint(&changed));
+    }
+
+    #[test]
+    fn test_header_contains_fields() {
+        let config = AppConfig {
+            seed: Some(42),
+            ..AppConfig::default()
+        };
+        let header = Provenance::new(&config, &sample_messages()).header();
+        assert!(header.contains(&config.model));
+        assert!(header.contains(&config.api_url));
+        assert!(header.contains("seed: 42"));
+        assert!(header.contains(env!("CARGO_PKG_VERSION")));
+        assert!(header.lines().all(|l| l.starts_with('#')));
+        assert!(!header.contains("Description"));
+
+        let described = Provenance { description: Some("Sums a CSV column".to_string()), ..Provenance::new(&config, &sample_messages()) };
+        assert_eq!(described.header().lines().nth(2), Some("# Description: Sums a CSV column"));
+        // Entries written before descriptions existed still load
+        let json = serde_json::to_value(Provenance::new(&config, &sample_messages())).unwrap();
+        assert!(json.get("description").is_none());
+        assert_eq!(serde_json::from_value::<Provenance>(json).unwrap().description, None);
+    }
+
+    #[test]
+    fn test_code_sha256_ignores_surrounding_whitespace() {
+        assert_eq!(code_sha256("print(1)\n"), code_sha256("print(1)"));
+        assert_ne!(code_sha256("print(1)"), code_sha256("print(2)"));
+    }
+
+    #[test]
+    fn test_render_script() {
+        let p = Provenance::new(&AppConfig::default(), &sample_messages());
+        let config = AppConfig::default();
+        let rendered = render_script("print('x')", Some(&p), &config);
+        assert!(rendered.starts_with("# ---"));
+        assert!(rendered.ends_with("print('x')"));
+        assert_eq!(render_script("print('x')", None, &config), "print('x')");
+    }
+
+    #[test]
+    fn test_render_script_with_prologue_and_epilogue() {
+        let config = AppConfig {
+            code_prologue: Some("import logging\nlogging.basicConfig(level=logging.INFO)\n".to_string()),
+            code_epilogue: Some("if __name__ == \"__main__\":\n    main()".to_string()),
+            ..AppConfig::default()
+        };
+        let code = "def main():\n    print('x')";
+        let rendered = render_script(code, None, &config);
+        assert_eq!(
+            rendered,
+            "import logging\nlogging.basicConfig(level=logging.INFO)\n\ndef main():\n    print('x')\n\nif __name__ == \"__main__\":\n    main()"
+        );
+        assert_eq!(code_line_offset(&rendered, code), Some(3));
+
+        let p = Provenance::new(&config, &sample_messages());
+        let rendered = render_script(code, Some(&p), &config);
+        let offset = code_line_offset(&rendered, code).unwrap();
+        assert_eq!(rendered.lines().nth(offset), Some("def main():"));
+        assert_eq!(code_line_offset(&rendered, "print('missing')"), None);
+    }
+}
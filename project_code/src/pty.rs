@@ -0,0 +1,170 @@
+//! Interactive runs under a pseudo-terminal (Unix only): the child sees a real terminal,
+//! the user types to it live, and everything it prints is also captured.
+
+use crate::python_exec::MAX_CAPTURE_BYTES;
+use nix::libc;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::pty::{openpty, Winsize};
+use nix::sys::termios::{self, SetArg, Termios};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// Outcome of a pty run: exit status and what the child printed (up to the cap).
+pub struct PtyRun {
+    pub status: ExitStatus,
+    pub output: Vec<u8>,
+    pub truncated: bool,
+}
+
+/// Puts our own terminal in raw mode so keys reach the child unprocessed (the child's
+/// pty does the echoing and line editing); restores it on drop.
+struct RawMode(Termios);
+
+impl RawMode {
+    fn enable() -> Option<RawMode> {
+        let stdin = io::stdin();
+        let saved = termios::tcgetattr(stdin.as_fd()).ok()?;
+        let mut raw = saved.clone();
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(stdin.as_fd(), SetArg::TCSANOW, &raw).ok()?;
+        Some(RawMode(saved))
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(io::stdin().as_fd(), SetArg::TCSANOW, &self.0);
+    }
+}
+
+/// Size of the terminal we run in, so full-screen programs lay out correctly.
+fn terminal_size() -> Option<Winsize> {
+    let mut size: Winsize = unsafe { std::mem::zeroed() };
+    let ok = unsafe { libc::ioctl(io::stdout().as_raw_fd(), libc::TIOCGWINSZ, &mut size) } == 0;
+    (ok && size.ws_col > 0).then_some(size)
+}
+
+/// Run `command` attached to a new pty, relaying the user's keystrokes and teeing the
+/// child's output to our stdout and a capture buffer.
+/// `Ok(None)`: no pty could be opened, the caller should fall back to inherited stdio.
+/// `Err`: the command itself could not be started.
+pub fn run(mut command: Command) -> io::Result<Option<PtyRun>> {
+    let size = terminal_size();
+    let Ok(pty) = openpty(size.as_ref(), None) else {
+        return Ok(None);
+    };
+    let (Ok(stdin_end), Ok(stdout_end)) = (pty.slave.try_clone(), pty.slave.try_clone()) else {
+        return Ok(None);
+    };
+    command
+        .stdin(Stdio::from(stdin_end))
+        .stdout(Stdio::from(stdout_end))
+        .stderr(Stdio::from(pty.slave));
+    // Make the pty the child's controlling terminal (job control, Ctrl+C, curses)
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+    // Our copies of the slave side must be closed, or reading the master never ends
+    drop(command);
+
+    let master = File::from(pty.master);
+    let mut reader = master.try_clone()?;
+    let mut writer = master;
+    let raw_mode = RawMode::enable();
+
+    let output = thread::spawn(move || {
+        let mut captured = Vec::new();
+        let mut truncated = false;
+        let mut buf = [0u8; 4096];
+        let mut stdout = io::stdout();
+        // Ends with EIO once the child and its descendants have closed the terminal
+        while let Ok(n) = reader.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            let _ = stdout.write_all(&buf[..n]);
+            let _ = stdout.flush();
+            let room = MAX_CAPTURE_BYTES - captured.len();
+            captured.extend_from_slice(&buf[..n.min(room)]);
+            truncated |= n > room;
+        }
+        (captured, truncated)
+    });
+
+    let done = Arc::new(AtomicBool::new(false));
+    let input = {
+        let done = Arc::clone(&done);
+        // Polls instead of blocking on stdin, so no keystroke meant for the REPL is
+        // swallowed after the child exits
+        thread::spawn(move || {
+            let stdin_fd = unsafe { BorrowedFd::borrow_raw(0) };
+            let mut buf = [0u8; 1024];
+            while !done.load(Ordering::Relaxed) {
+                let mut fds = [PollFd::new(stdin_fd, PollFlags::POLLIN)];
+                if !matches!(poll(&mut fds, 100u16), Ok(n) if n > 0) {
+                    continue;
+                }
+                match nix::unistd::read(stdin_fd.as_raw_fd(), &mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if writer.write_all(&buf[..n]).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        })
+    };
+
+    let status = child.wait();
+    done.store(true, Ordering::Relaxed);
+    let _ = input.join();
+    drop(raw_mode);
+    let (output, truncated) = output.join().unwrap_or_default();
+    Ok(Some(PtyRun { status: status?, output, truncated }))
+}
+
+/// Captured pty output as plain text: terminal line endings become `\n`.
+pub fn output_text(run: &PtyRun) -> String {
+    let mut text = String::from_utf8_lossy(&run.output).replace("\r\n", "\n");
+    if run.truncated {
+        text.push_str(&format!("\n[output truncated after {} KB]", MAX_CAPTURE_BYTES / 1024));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_child_sees_a_terminal_and_output_is_captured() {
+        let mut command = Command::new("python3");
+        command.args(["-c", "import sys; print('hi', sys.stdout.isatty())"]);
+        let Some(run) = run(command).unwrap() else {
+            return; // no pty available in this environment
+        };
+        assert!(run.status.success());
+        assert!(!run.truncated);
+        assert!(output_text(&run).contains("hi True\n"), "{:?}", output_text(&run));
+    }
+
+    #[test]
+    fn test_output_text_marks_truncation() {
+        let status = Command::new("true").status().unwrap();
+        let run = PtyRun { status, output: b"a\r\nb".to_vec(), truncated: true };
+        assert!(output_text(&run).starts_with("a\nb\n[output truncated"));
+    }
+}
@@ -1,10 +1,22 @@
-use crate::utils::{ensure_dir, extract_imports, is_stdlib};
+use crate::env::TaskEnv;
+use crate::index::{self, IndexEntry};
+use crate::progress::{self, format_elapsed, Live, ProgressCollapser};
+use crate::provenance::Provenance;
+use crate::requirements;
+use crate::utils::{ensure_dir, write_file, CodeFile, extract_imports, find_in_path, is_stdlib, normalize_python, parse_mypy_errors, parse_python_version, parse_unused_imports, pip_name};
+use std::cell::RefCell;
 use anyhow::{Context, Result};
-use chrono::Utc;
+use chrono::{Local, Utc};
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs;
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
-use std::time::Duration;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::LazyLock;
+#[cfg(unix)]
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::{Duration, Instant};
 use wait_timeout::ChildExt;
 
 /// Mode d'exécution pour les scripts Python
@@ -14,14 +26,414 @@ pub enum ExecutionMode {
     Captured,
     /// Mode interactif: hérite stdio (pour jeux, input utilisateur)
     Interactive,
+    /// Captured, but each line is also printed as soon as the script writes it
+    /// (for long-running scripts that report progress).
+    Streamed,
+    /// GUI code on a headless Linux box: captured, under a throwaway Xvfb display,
+    /// stopped after `HEADLESS_GUI_TIMEOUT_SECS` to prove it gets past initialization.
+    HeadlessGui,
+}
+
+/// Runs `/py` input: statements are executed and a final expression's value is printed
+/// with `repr`, like the interactive interpreter.
+const QUICK_EVAL_DRIVER: &str = "import ast, sys\n\
+tree = ast.parse(sys.argv[1], '<py>')\n\
+last = tree.body.pop() if tree.body and isinstance(tree.body[-1], ast.Expr) else None\n\
+ns = {'__name__': '__main__'}\n\
+exec(compile(tree, '<py>', 'exec'), ns)\n\
+if last is not None:\n    \
+    value = eval(compile(ast.Expression(last.value), '<py>', 'eval'), ns)\n    \
+    if value is not None:\n        \
+        print(repr(value))\n";
+
+/// Constructs that can run arbitrary shell commands or code: `(blocked_patterns name, why)`.
+pub const DANGEROUS_PATTERNS: &[(&str, &str)] = &[
+    ("os.system", "runs a shell command"),
+    ("subprocess-shell", "runs a subprocess through the shell (shell=True)"),
+    ("eval", "evaluates a string as Python"),
+    ("exec", "executes a string as Python"),
+    ("__import__", "imports a module chosen at run time"),
+];
+
+/// One regex per `DANGEROUS_PATTERNS` entry, in the same order. `eval`/`exec` must not be
+/// attribute calls (`model.eval()`) or part of a longer name (`literal_eval`).
+static DANGEROUS_RES: LazyLock<Vec<Regex>> = LazyLock::new(|| {
+    [
+        r"\bos\.system\s*\(",
+        r"\bshell\s*=\s*True\b",
+        r"(?:^|[^\w.])eval\s*\(",
+        r"(?:^|[^\w.])exec\s*\(",
+        r"\b__import__\s*\(",
+    ]
+    .iter()
+    .map(|re| Regex::new(re).unwrap())
+    .collect()
+});
+
+/// A blocked construct found in generated code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DangerousPattern {
+    /// Its `DANGEROUS_PATTERNS` name, as listed in `blocked_patterns`.
+    pub name: &'static str,
+    pub line: usize,
+    /// The offending line, trimmed.
+    pub snippet: String,
+}
+
+impl DangerousPattern {
+    pub fn reason(&self) -> &'static str {
+        DANGEROUS_PATTERNS.iter().find(|(name, _)| *name == self.name).map_or("", |(_, why)| why)
+    }
+}
+
+/// Process group of the script running now (0 when none), for the Ctrl+C handler.
+#[cfg(unix)]
+static RUNNING_GROUP: AtomicI32 = AtomicI32::new(0);
+
+#[cfg(unix)]
+extern "C" fn on_interrupt(_: nix::libc::c_int) {
+    let group = RUNNING_GROUP.load(Ordering::SeqCst);
+    // Only async-signal-safe calls in here
+    unsafe {
+        if group > 0 {
+            // Cancel the script and whatever it started; the REPL goes on
+            nix::libc::killpg(group, nix::libc::SIGKILL);
+        } else {
+            // Outside a run, Ctrl+C quits as it always did
+            nix::libc::signal(nix::libc::SIGINT, nix::libc::SIG_DFL);
+            nix::libc::raise(nix::libc::SIGINT);
+        }
+    }
+}
+
+/// Start `command` in a process group of its own (Unix), so a timeout or Ctrl+C stops
+/// everything the script started and not only the interpreter. Its stdin is closed: a
+/// background group reading the terminal would just be stopped (SIGTTIN).
+pub(crate) fn in_own_group(command: &mut Command) -> &mut Command {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0).stdin(Stdio::null());
+    }
+    command
+}
+
+/// While alive, Ctrl+C is delivered to the group of the script being run.
+struct GroupWatch;
+
+impl GroupWatch {
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    fn new(process: &Child) -> Self {
+        #[cfg(unix)]
+        {
+            static INSTALL: std::sync::Once = std::sync::Once::new();
+            INSTALL.call_once(|| {
+                use nix::sys::signal::{signal, SigHandler, Signal};
+                // Safety: the handler only makes async-signal-safe calls
+                let _ = unsafe { signal(Signal::SIGINT, SigHandler::Handler(on_interrupt)) };
+            });
+            RUNNING_GROUP.store(process.id() as i32, Ordering::SeqCst);
+        }
+        GroupWatch
+    }
+}
+
+impl Drop for GroupWatch {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        RUNNING_GROUP.store(0, Ordering::SeqCst);
+    }
+}
+
+/// Kill a script started with `in_own_group` and everything it started. Returns true when
+/// processes other than the interpreter were still running and had to be killed.
+pub(crate) fn kill_group(process: &mut Child) -> bool {
+    #[cfg(unix)]
+    {
+        use nix::sys::signal::{killpg, Signal};
+        let group = nix::unistd::Pid::from_raw(process.id() as i32);
+        let _ = process.kill();
+        let _ = process.wait();
+        // The group outlives its leader as long as one of its members is left
+        if killpg(group, None).is_ok() {
+            let _ = killpg(group, Signal::SIGKILL);
+            return true;
+        }
+        false
+    }
+    #[cfg(windows)]
+    {
+        // /T ends the whole tree; taskkill prints one line per process it ended
+        let ended = Command::new("taskkill")
+            .args(["/T", "/F", "/PID", &process.id().to_string()])
+            .output()
+            .map_or(0, |o| String::from_utf8_lossy(&o.stdout).lines().filter(|l| !l.trim().is_empty()).count());
+        let _ = process.kill();
+        let _ = process.wait();
+        ended > 1
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = process.kill();
+        let _ = process.wait();
+        false
+    }
+}
+
+/// How long a GUI script runs under the virtual display before it is stopped.
+pub const HEADLESS_GUI_TIMEOUT_SECS: u64 = 5;
+
+/// True on Linux when neither an X11 nor a Wayland display is available.
+pub fn is_headless() -> bool {
+    let unset = |var: &str| std::env::var_os(var).is_none_or(|v| v.is_empty());
+    cfg!(target_os = "linux") && unset("DISPLAY") && unset("WAYLAND_DISPLAY")
+}
+
+/// True when an `Xvfb` binary is available to back `ExecutionMode::HeadlessGui`.
+pub fn has_virtual_display() -> bool {
+    find_in_path("Xvfb").is_some()
+}
+
+/// An Xvfb server started for a single run; killed when dropped.
+struct VirtualDisplay {
+    server: Child,
+    display: String,
+}
+
+impl VirtualDisplay {
+    fn start() -> Result<Self> {
+        let xvfb = find_in_path("Xvfb")
+            .ok_or_else(|| anyhow::anyhow!("Xvfb is not installed"))?;
+        let number = (99..120)
+            .find(|n| {
+                !Path::new(&format!("/tmp/.X11-unix/X{n}")).exists()
+                    && !Path::new(&format!("/tmp/.X{n}-lock")).exists()
+            })
+            .ok_or_else(|| anyhow::anyhow!("No free X display number between :99 and :119"))?;
+        let display = format!(":{number}");
+
+        let server = Command::new(xvfb)
+            .args([display.as_str(), "-screen", "0", "1024x768x24", "-nolisten", "tcp"])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .context("Failed to start Xvfb")?;
+        let mut vd = Self { server, display };
+
+        // Wait for the X socket so the script doesn't race the server
+        let socket = PathBuf::from(format!("/tmp/.X11-unix/X{number}"));
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !socket.exists() {
+            if let Some(status) = vd.server.try_wait()? {
+                anyhow::bail!("Xvfb exited during startup ({status})");
+            }
+            if Instant::now() >= deadline {
+                anyhow::bail!("Xvfb did not open display {} in time", vd.display);
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        Ok(vd)
+    }
+}
+
+impl Drop for VirtualDisplay {
+    fn drop(&mut self) {
+        let _ = self.server.kill();
+        let _ = self.server.wait();
+    }
+}
+
+/// Whether executed scripts are wrapped in an isolation tool.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Sandbox {
+    /// Run the interpreter directly.
+    #[default]
+    Off,
+    /// Sandbox when a tool is installed, otherwise run directly.
+    Preferred,
+    /// Refuse to run scripts when no tool is installed.
+    Required,
+}
+
+/// Sandboxing tools we know how to drive, in order of preference.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SandboxTool {
+    Firejail,
+    Bubblewrap,
+}
+
+impl SandboxTool {
+    pub fn binary(self) -> &'static str {
+        match self {
+            SandboxTool::Firejail => "firejail",
+            SandboxTool::Bubblewrap => "bwrap",
+        }
+    }
+
+    /// First installed tool, if any. Sandboxing is only supported on unix.
+    pub fn detect() -> Option<Self> {
+        #[cfg(unix)]
+        {
+            [SandboxTool::Firejail, SandboxTool::Bubblewrap]
+                .into_iter()
+                .find(|tool| find_in_path(tool.binary()).is_some())
+        }
+        #[cfg(not(unix))]
+        {
+            None
+        }
+    }
+
+    /// Arguments placed before the interpreter: no network, and only `work_dir`
+    /// (plus a private /tmp) writable.
+    #[cfg(unix)]
+    fn prefix_args(self, work_dir: &Path) -> Vec<std::ffi::OsString> {
+        let work_dir = std::path::absolute(work_dir).unwrap_or_else(|_| work_dir.to_path_buf());
+        let mut args: Vec<std::ffi::OsString> = Vec::new();
+        match self {
+            SandboxTool::Firejail => {
+                for a in ["--quiet", "--net=none", "--private-tmp", "--read-only=/"] {
+                    args.push(a.into());
+                }
+                let mut rw = std::ffi::OsString::from("--read-write=");
+                rw.push(&work_dir);
+                args.push(rw);
+                args.push("--".into());
+            }
+            SandboxTool::Bubblewrap => {
+                for a in [
+                    "--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc",
+                    "--tmpfs", "/tmp", "--unshare-net", "--die-with-parent",
+                ] {
+                    args.push(a.into());
+                }
+                // The tmpfs hides the X socket a HeadlessGui run needs
+                if Path::new("/tmp/.X11-unix").exists() {
+                    for a in ["--ro-bind", "/tmp/.X11-unix", "/tmp/.X11-unix"] {
+                        args.push(a.into());
+                    }
+                }
+                args.push("--bind".into());
+                args.push(work_dir.clone().into());
+                args.push(work_dir.into());
+                args.push("--".into());
+            }
+        }
+        args
+    }
+}
+
+/// What `CodeExecutor` will actually do with its sandbox setting.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SandboxStatus {
+    Disabled,
+    Active(SandboxTool),
+    /// Requested but no tool is installed.
+    Unavailable { required: bool },
+}
+
+/// How the captured output bytes were turned into `stdout`/`stderr`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum OutputEncoding {
+    /// Valid UTF-8, shown as is.
+    #[default]
+    Utf8,
+    /// Not UTF-8 but mostly text; decoded with replacement characters (cp1252 on Windows).
+    Lossy,
+    /// Binary or undecodable: the fields hold a one-line summary and the raw bytes were
+    /// written next to the script.
+    Binary { saved_to: Vec<PathBuf> },
+}
+
+/// At most this much of a run's output is kept per stream (streamed and interactive runs).
+pub const MAX_CAPTURE_BYTES: usize = 1024 * 1024;
+/// Streamed lines are printed live at most `LIVE_LINES_PER_WINDOW` per `LIVE_WINDOW`;
+/// the rest are only captured, so a tight `print` loop can't flood the terminal.
+const LIVE_WINDOW: Duration = Duration::from_millis(100);
+const LIVE_LINES_PER_WINDOW: usize = 25;
+
+/// How a streamed run's output was limited.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct StreamLimits {
+    /// Lines captured but not printed live because they arrived too fast.
+    pub coalesced_lines: usize,
+    /// The capture stopped at `MAX_CAPTURE_BYTES`; later output was read and dropped.
+    pub truncated: bool,
+    /// Progress bar frames drawn in place and left out of the capture.
+    pub collapsed_frames: usize,
+}
+
+impl StreamLimits {
+    fn merge(self, other: StreamLimits) -> StreamLimits {
+        StreamLimits {
+            coalesced_lines: self.coalesced_lines + other.coalesced_lines,
+            truncated: self.truncated || other.truncated,
+            collapsed_frames: self.collapsed_frames + other.collapsed_frames,
+        }
+    }
+}
+
+/// How script output is handled (`CodeExecutor::with_run_options`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RunOptions {
+    /// Keep only the latest frame of progress bars (`\r` redraws, runs of `n/total`
+    /// lines) in the captured output, with a snapshot every `progress::SNAPSHOT_EVERY`
+    /// while streaming; streamed runs draw them in place with the elapsed time.
+    pub collapse_progress: bool,
+    /// Parse the JSON a script prints between `RESULT_MARKER` lines into
+    /// `CodeExecutionResult::result`.
+    pub capture_result: bool,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self { collapse_progress: true, capture_result: false }
+    }
+}
+
+/// Delimits the JSON a script hands back (`capture_result`):
+/// `===PMB_RESULT===\n{...}\n===PMB_RESULT===`.
+pub const RESULT_MARKER: &str = "===PMB_RESULT===";
+
+/// The JSON between the last pair of `RESULT_MARKER`s in `stdout`: `None` without a
+/// marker, `Err` when the JSON is invalid or the last marker is never closed.
+pub fn parse_result(stdout: &str) -> Option<Result<serde_json::Value, String>> {
+    let parts: Vec<&str> = stdout.split(RESULT_MARKER).collect();
+    let markers = parts.len() - 1;
+    if markers == 0 {
+        return None;
+    }
+    if markers == 1 {
+        return Some(Err(format!("{RESULT_MARKER} printed once, without a closing marker")));
+    }
+    // parts[1], parts[3]... sit between an opening and a closing marker
+    let last = parts[markers / 2 * 2 - 1];
+    Some(serde_json::from_str(last.trim()).map_err(|e| format!("invalid JSON between the {RESULT_MARKER} markers: {e}")))
 }
 
 /// Résultat de l'exécution d'un script Python.
+#[derive(Debug, Default)]
 pub struct CodeExecutionResult {
     pub script_path: PathBuf,
     pub stdout: String,
     pub stderr: String,
     pub exit_code: Option<i32>,
+    /// Signal that terminated the process (Unix only), when `exit_code` is `None` because
+    /// it was killed rather than exiting: SIGSEGV, SIGKILL...
+    pub signal: Option<i32>,
+    /// The process was killed because it hit the time limit.
+    pub timed_out: bool,
+    pub output_encoding: OutputEncoding,
+    /// What `Streamed` mode held back; always empty in the other modes.
+    pub stream_limits: StreamLimits,
+    /// Processes the script had started were still running when it was stopped (timeout
+    /// or Ctrl+C) and were killed with it.
+    pub reaped_descendants: bool,
+    /// The JSON the script printed between `RESULT_MARKER`s, with `capture_result`.
+    pub result: Option<serde_json::Value>,
+    /// Why the markers were there but gave no `result`.
+    pub result_error: Option<String>,
 }
 
 impl CodeExecutionResult {
@@ -29,21 +441,302 @@ impl CodeExecutionResult {
     pub fn is_success(&self) -> bool {
         self.exit_code == Some(0)
     }
+
+    /// Store what `parse_result` found in `result` or `result_error`.
+    fn set_result(&mut self, parsed: Option<Result<serde_json::Value, String>>) {
+        match parsed {
+            Some(Ok(value)) => self.result = Some(value),
+            Some(Err(e)) => self.result_error = Some(e),
+            None => {}
+        }
+    }
 }
 
 /// Responsable de l'écriture des scripts Python sur le disque et de leur exécution.
 pub struct CodeExecutor {
     base_dir: PathBuf,
+    /// strftime template of the subdirectory scripts are written to (`generated_layout`);
+    /// empty writes them straight into `base_dir`.
+    layout: String,
+    /// Current directory for executed scripts; `None` means `base_dir`.
+    work_dir: Option<PathBuf>,
+    sandbox: Sandbox,
+    run_options: RunOptions,
+    /// Most recent script written by `write_script`, for `/deps --pin`.
+    last_script: RefCell<Option<PathBuf>>,
+    /// Task environment scripts run in and packages are installed into (`/env`);
+    /// `None` uses the system interpreter.
+    env: Option<TaskEnv>,
+    /// Version of the interpreter scripts run with, probed on first use (`None` inside
+    /// when no interpreter answered).
+    python_version: RefCell<Option<Option<(u32, u32)>>>,
 }
 
 impl CodeExecutor {
     /// Crée un exécuteur de code.
     ///
     /// `base_dir` : répertoire où seront stockés les scripts générés.
-    pub fn new(base_dir: &str) -> Result<Self> {
-        let dir = PathBuf::from(base_dir);
+    pub fn new(base_dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = base_dir.as_ref().to_path_buf();
         ensure_dir(&dir)?;
-        Ok(Self { base_dir: dir })
+        Ok(Self {
+            base_dir: dir,
+            layout: String::new(),
+            work_dir: None,
+            sandbox: Sandbox::Off,
+            run_options: RunOptions::default(),
+            last_script: RefCell::new(None),
+            env: None,
+            python_version: RefCell::new(None),
+        })
+    }
+
+    /// Switch to a task environment (which must exist), or back to the system interpreter.
+    pub fn set_env(&mut self, env: Option<TaskEnv>) {
+        self.env = env;
+        self.python_version.replace(None);
+    }
+
+    pub fn env(&self) -> Option<&TaskEnv> {
+        self.env.as_ref()
+    }
+
+    /// Interpreters to try, in order: the task environment's, or `python3` then `python`.
+    fn pythons(&self) -> Vec<PathBuf> {
+        match &self.env {
+            // Absolute: scripts run from work_dir, where a relative path would not resolve
+            Some(env) => vec![std::path::absolute(env.python()).unwrap_or_else(|_| env.python())],
+            None => vec![PathBuf::from("python3"), PathBuf::from("python")],
+        }
+    }
+
+    /// `(major, minor)` of the interpreter scripts run with: the first of `pythons()` that answers.
+    pub fn python_version(&self) -> Option<(u32, u32)> {
+        if let Some(version) = *self.python_version.borrow() {
+            return version;
+        }
+        let version = self.pythons().iter().find_map(|cmd| probe_python_version(cmd));
+        self.python_version.replace(Some(version));
+        version
+    }
+
+    /// Directory where generated scripts are written.
+    pub fn base_dir(&self) -> &Path {
+        &self.base_dir
+    }
+
+    /// Write scripts under `base_dir/<layout>`, e.g. `%Y/%m/%d` for one directory per day.
+    pub fn with_layout(mut self, layout: &str) -> Self {
+        self.layout = layout.to_string();
+        self
+    }
+
+    pub fn with_run_options(mut self, options: RunOptions) -> Self {
+        self.run_options = options;
+        self
+    }
+
+    pub fn run_options(&self) -> RunOptions {
+        self.run_options
+    }
+
+    /// Wrap executed scripts in firejail/bwrap according to `sandbox`.
+    pub fn with_sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    pub fn sandbox_status(&self) -> SandboxStatus {
+        match (self.sandbox, SandboxTool::detect()) {
+            (Sandbox::Off, _) => SandboxStatus::Disabled,
+            (_, Some(tool)) => SandboxStatus::Active(tool),
+            (sandbox, None) => SandboxStatus::Unavailable { required: sandbox == Sandbox::Required },
+        }
+    }
+
+    /// The sandbox tool to wrap scripts in, or an error when one is required but missing.
+    fn active_sandbox(&self) -> Result<Option<SandboxTool>> {
+        match self.sandbox_status() {
+            SandboxStatus::Active(tool) => Ok(Some(tool)),
+            SandboxStatus::Unavailable { required: true } => {
+                anyhow::bail!("Sandboxing is required but neither firejail nor bwrap is installed");
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Command running `script` with `python`, sandboxed and from `work_dir` like every
+    /// other execution, for callers that manage the process themselves (`run`).
+    pub fn script_command(&self, python: &str, script: &Path) -> Result<Command> {
+        let script = std::path::absolute(script)
+            .with_context(|| format!("Could not resolve script path {}", script.display()))?;
+        Ok(self.interpreter_command(Path::new(python), &script, self.active_sandbox()?))
+    }
+
+    /// Build the interpreter command, behind the sandbox prefix when one is active.
+    fn interpreter_command(&self, python: &Path, script: &Path, sandbox: Option<SandboxTool>) -> Command {
+        self.python_command(python, &[script.as_os_str()], sandbox)
+    }
+
+    /// `python <args>` from `work_dir`, behind the sandbox prefix when one is active.
+    fn python_command(&self, python: &Path, args: &[&std::ffi::OsStr], sandbox: Option<SandboxTool>) -> Command {
+        self.python_command_in(self.work_dir(), python, args, sandbox)
+    }
+
+    /// `python <args>` from `dir`, behind the sandbox prefix when one is active.
+    fn python_command_in(&self, dir: &Path, python: &Path, args: &[&std::ffi::OsStr], sandbox: Option<SandboxTool>) -> Command {
+        #[cfg(unix)]
+        if let Some(tool) = sandbox {
+            let mut command = Command::new(tool.binary());
+            command
+                .args(tool.prefix_args(dir))
+                .arg(python)
+                .args(args)
+                .current_dir(dir);
+            return command;
+        }
+        #[cfg(not(unix))]
+        let _ = sandbox;
+        let mut command = Command::new(python);
+        command.args(args).current_dir(dir);
+        command
+    }
+
+    /// Run a snippet with `python -c` (`/py`), captured: no script file is written.
+    /// `script_path` of the result is empty. `timeout_secs == 0` means no timeout.
+    pub fn quick_eval(&self, code: &str, timeout_secs: u64) -> Result<CodeExecutionResult> {
+        let args = ["-c".as_ref(), QUICK_EVAL_DRIVER.as_ref(), code.as_ref()];
+        self.run_captured(self.work_dir(), &args, timeout_secs)
+    }
+
+    /// Run `python -m <module>` from `project_dir` (`/init`), captured, so the package's
+    /// absolute imports resolve. `script_path` of the result is the project directory.
+    pub fn run_module(&self, project_dir: &Path, module: &str, timeout_secs: u64) -> Result<CodeExecutionResult> {
+        let dir = std::path::absolute(project_dir)
+            .with_context(|| format!("Could not resolve project path {}", project_dir.display()))?;
+        let args = ["-m".as_ref(), module.as_ref()];
+        let result = self.run_captured(&dir, &args, timeout_secs)?;
+        Ok(self.capture_result(CodeExecutionResult { script_path: dir, ..result }))
+    }
+
+    /// Run a copy of `script_path` captured in a temporary copy of `work_dir` and report
+    /// which files it created, modified or deleted there. It always runs sandboxed with the
+    /// copy as the only writable directory, whatever the `sandbox` setting, so it is refused
+    /// when neither firejail nor bwrap is installed, or when `work_dir` holds more than
+    /// `max_bytes`. When scripts run from the generated directory, its own files (scripts,
+    /// index, task environments) are not copied.
+    pub fn trial_run(&self, script_path: &Path, timeout_secs: u64, max_bytes: u64) -> Result<TrialRun> {
+        let Some(tool) = SandboxTool::detect() else {
+            anyhow::bail!("A trial run needs firejail or bwrap to keep the script inside the copy; neither is installed");
+        };
+        let script = std::path::absolute(script_path)
+            .with_context(|| format!("Could not resolve script path {}", script_path.display()))?;
+        let generated: Option<BTreeSet<PathBuf>> = if same_dir(self.work_dir(), &self.base_dir) {
+            Some(index::list_scripts(&self.base_dir)?.into_iter().collect())
+        } else {
+            None
+        };
+        let skip = |relative: &Path| {
+            relative.starts_with(TRIAL_SCRIPT_DIR) || generated.as_ref().is_some_and(|scripts| is_generated_file(relative, scripts))
+        };
+        let before = snapshot(self.work_dir(), max_bytes, &skip)?;
+        let copy = std::env::temp_dir().join(format!("pmb_trial_{}_{}", std::process::id(), Utc::now().timestamp_nanos_opt().unwrap_or_default()));
+        let outcome = (|| {
+            ensure_dir(&copy)?;
+            for relative in before.keys() {
+                let target = copy.join(relative);
+                if let Some(parent) = target.parent() {
+                    ensure_dir(parent)?;
+                }
+                fs::copy(self.work_dir().join(relative), &target)
+                    .with_context(|| format!("Could not copy {} for the trial run", relative.display()))?;
+            }
+            let trial_script = copy.join(TRIAL_SCRIPT_DIR).join(script.file_name().unwrap_or("script.py".as_ref()));
+            ensure_dir(&copy.join(TRIAL_SCRIPT_DIR))?;
+            fs::copy(&script, &trial_script).with_context(|| format!("Could not copy {} for the trial run", script.display()))?;
+            let result = self.run_captured_in(&copy, &[trial_script.as_os_str()], timeout_secs, Some(tool))?;
+            let after = snapshot(&copy, u64::MAX, &skip)?;
+            Ok(TrialRun { result: CodeExecutionResult { script_path: script.clone(), ..result }, changes: FileChanges::between(&before, &after) })
+        })();
+        let _ = fs::remove_dir_all(&copy);
+        outcome
+    }
+
+    /// `python <args>` from `dir` with both streams captured and no stdin.
+    fn run_captured(&self, dir: &Path, args: &[&std::ffi::OsStr], timeout_secs: u64) -> Result<CodeExecutionResult> {
+        self.run_captured_in(dir, args, timeout_secs, self.active_sandbox()?)
+    }
+
+    /// `run_captured` behind the given sandbox rather than the configured one.
+    fn run_captured_in(&self, dir: &Path, args: &[&std::ffi::OsStr], timeout_secs: u64, sandbox: Option<SandboxTool>) -> Result<CodeExecutionResult> {
+        let mut process = self.pythons()
+            .iter()
+            .find_map(|cmd| {
+                in_own_group(&mut self.python_command_in(dir, cmd, args, sandbox))
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .spawn()
+                    .ok()
+            })
+            .ok_or_else(|| anyhow::anyhow!("Could not run python/python3"))?;
+        let watch = GroupWatch::new(&process);
+        let stdout = std::thread::spawn({
+            let pipe = process.stdout.take();
+            move || read_pipe(pipe)
+        });
+        let stderr = std::thread::spawn({
+            let pipe = process.stderr.take();
+            move || read_pipe(pipe)
+        });
+        let status = if timeout_secs > 0 {
+            process.wait_timeout(Duration::from_secs(timeout_secs))?
+        } else {
+            Some(process.wait()?)
+        };
+        let reaped_descendants = status.is_none() && kill_group(&mut process);
+        drop(watch);
+        Ok(CodeExecutionResult {
+            stdout: String::from_utf8_lossy(&stdout.join().unwrap_or_default()).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr.join().unwrap_or_default()).into_owned(),
+            exit_code: status.and_then(|s| s.code()),
+            signal: status.and_then(exit_signal),
+            timed_out: status.is_none(),
+            reaped_descendants,
+            ..CodeExecutionResult::default()
+        })
+    }
+
+    /// Run scripts from `dir` instead of `base_dir` (e.g. `"."` for the launch directory).
+    pub fn with_work_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.work_dir = Some(dir.into());
+        self
+    }
+
+    /// Directory executed scripts run in, so relative files they create land there.
+    pub fn work_dir(&self) -> &Path {
+        self.work_dir.as_deref().unwrap_or(&self.base_dir)
+    }
+
+    /// Find the `blocked` constructs (names from `DANGEROUS_PATTERNS`) in `code`, line by
+    /// line; comment lines are skipped. Best effort: strings and docstrings are scanned too.
+    pub fn scan_dangerous(&self, code: &str, blocked: &[String]) -> Vec<DangerousPattern> {
+        let uses_subprocess = code.contains("subprocess");
+        let mut findings = Vec::new();
+        for (i, line) in code.lines().enumerate() {
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            for ((name, _), re) in DANGEROUS_PATTERNS.iter().zip(DANGEROUS_RES.iter()) {
+                if !blocked.iter().any(|b| b == name) || (*name == "subprocess-shell" && !uses_subprocess) {
+                    continue;
+                }
+                if re.is_match(line) {
+                    findings.push(DangerousPattern { name, line: i + 1, snippet: line.trim().to_string() });
+                }
+            }
+        }
+        findings
     }
 
     /// Detect non-standard library dependencies in Python code
@@ -55,49 +748,133 @@ impl CodeExecutor {
             .collect()
     }
 
-    /// Install Python packages using pip
-    pub fn install_packages(&self, packages: &[String]) -> Result<()> {
-        if packages.is_empty() {
-            return Ok(());
-        }
-
-        println!("Installing dependencies: {}", packages.join(", "));
+    /// Every import of `code` with its pip package and status, without running anything:
+    /// the third-party ones (`detect_dependencies`) are checked in a single import probe.
+    pub fn dependency_report(&self, code: &str) -> Vec<DepRow> {
+        let third_party = self.detect_dependencies(code);
+        let installed = if third_party.is_empty() { Some(HashMap::new()) } else { self.installed_modules(&third_party) };
+        extract_imports(code)
+            .into_iter()
+            .map(|module| {
+                let status = if !third_party.contains(&module) {
+                    DepStatus::Stdlib
+                } else {
+                    match installed.as_ref().and_then(|found| found.get(&module)) {
+                        Some(true) => DepStatus::Installed,
+                        Some(false) => DepStatus::Missing,
+                        None => DepStatus::Unknown,
+                    }
+                };
+                let pip = (status != DepStatus::Stdlib).then(|| pip_name(&module).to_string());
+                DepRow { module, pip, status }
+            })
+            .collect()
+    }
 
-        let python_cmds = ["python3", "python"];
-        let mut last_err: Option<anyhow::Error> = None;
+    /// Whether `module` can already be imported by the interpreter.
+    pub fn is_installed(&self, module: &str) -> bool {
+        let probe = "import importlib.util, sys; sys.exit(0 if importlib.util.find_spec(sys.argv[1]) else 1)";
+        self.pythons().iter().find_map(|cmd| {
+            Command::new(cmd)
+                .args(["-c", probe, module])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .ok()
+        })
+        .is_some_and(|status| status.success())
+    }
 
-        for cmd in python_cmds {
-            let mut args = vec!["-m", "pip", "install", "--quiet"];
-            args.extend(packages.iter().map(|s| s.as_str()));
+    /// Importability of every module in one interpreter call; `None` when no
+    /// interpreter could be run or its answer could not be read.
+    pub fn installed_modules(&self, modules: &[String]) -> Option<HashMap<String, bool>> {
+        let probe = "import importlib.util, json, sys\n\
+                     def found(m):\n    \
+                         try:\n        \
+                             return importlib.util.find_spec(m) is not None\n    \
+                         except (ImportError, ValueError):\n        \
+                             return False\n\
+                     print(json.dumps({m: found(m) for m in sys.argv[1:]}))";
+        let output = self.pythons().iter().find_map(|cmd| {
+            Command::new(cmd)
+                .arg("-c")
+                .arg(probe)
+                .args(modules)
+                .stderr(Stdio::null())
+                .output()
+                .ok()
+        })?;
+        if !output.status.success() {
+            return None;
+        }
+        serde_json::from_slice(&output.stdout).ok()
+    }
 
-            let output = Command::new(cmd).args(&args).output();
+    /// Unused imports reported by pyflakes, or `None` when pyflakes isn't installed.
+    pub fn unused_imports(&self, code: &str) -> Option<Vec<(usize, String)>> {
+        let mut child = ["python3", "python"].iter().find_map(|cmd| {
+            Command::new(cmd)
+                .args(["-m", "pyflakes"])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .ok()
+        })?;
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(code.as_bytes());
+        }
+        let output = child.wait_with_output().ok()?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        // pyflakes exits 1 when it reports anything; a missing module is reported on stderr
+        if stderr.contains("No module named pyflakes") {
+            return None;
+        }
+        Some(parse_unused_imports(&String::from_utf8_lossy(&output.stdout)))
+    }
 
-            match output {
-                Ok(out) => {
-                    if out.status.success() {
-                        println!("✓ Dependencies installed successfully");
-                        return Ok(());
-                    } else {
-                        let stderr = String::from_utf8_lossy(&out.stderr);
-                        last_err = Some(anyhow::anyhow!(
-                            "pip install failed: {}",
-                            stderr
-                        ));
-                    }
-                }
-                Err(e) => {
-                    last_err = Some(anyhow::anyhow!(
-                        "Failed to run pip with {}: {}",
-                        cmd,
-                        e
-                    ));
-                }
-            }
+    /// Type errors mypy reports for `code`, or `None` when mypy isn't installed.
+    pub fn type_check(&self, code: &str) -> Option<Vec<(usize, String)>> {
+        let output = self.pythons().iter().find_map(|cmd| {
+            Command::new(cmd)
+                .args(["-m", "mypy", "--ignore-missing-imports", "--no-error-summary", "-c", code])
+                .output()
+                .ok()
+        })?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if stderr.contains("No module named mypy") {
+            return None;
         }
+        Some(parse_mypy_errors(&String::from_utf8_lossy(&output.stdout)))
+    }
 
-        Err(last_err.unwrap_or_else(|| {
-            anyhow::anyhow!("Could not install packages with python/python3")
-        }))
+    /// Whether `import <module>` actually succeeds (stricter than `is_installed`, which
+    /// only finds the module: a broken install fails here).
+    pub fn imports_ok(&self, module: &str) -> bool {
+        let probe = "import importlib, sys; importlib.import_module(sys.argv[1])";
+        self.pythons().iter().find_map(|cmd| {
+            Command::new(cmd)
+                .args(["-c", probe, module])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .ok()
+        })
+        .is_some_and(|status| status.success())
+    }
+
+    /// Install the pip packages for `modules` (import names), reporting each package's
+    /// outcome. Modules that already import are reported as already satisfied and never
+    /// reach pip. Everything else is tried in one pip call first; if that fails, packages
+    /// are retried one by one to find which ones are at fault. With a task environment,
+    /// packages go into its venv and are recorded in its manifest.
+    pub fn install_packages(&self, modules: &[String]) -> InstallReport {
+        let pythons = self.pythons();
+        let report = InstallReport::build(modules, |m| self.imports_ok(m), |packages| pip_install(&pythons, packages));
+        if let Some(env) = &self.env {
+            let _ = env.record_installed(&report.installed);
+        }
+        report
     }
 
     /// Détecte si le code nécessite une exécution interactive (pygame, input(), etc.)
@@ -117,19 +894,167 @@ impl CodeExecutor {
         interactive_keywords.iter().any(|keyword| code.contains(keyword))
     }
 
-    /// Write a Python script to disk, returning the path.
+    /// Write a Python script to disk after `normalize_python`, returning the path. The
+    /// layout's directory for today is created on demand. Scripts written in the same
+    /// second, by this or another instance, get `_2`, `_3`... suffixes.
     pub fn write_script(&self, code: &str) -> Result<PathBuf> {
         let ts = Utc::now().format("%Y%m%d_%H%M%S");
-        let filename = format!("script_{ts}.py");
-        let script_path = self.base_dir.join(filename);
-        fs::write(&script_path, code)
-            .with_context(|| format!("Could not write the script {:?}", script_path))?;
+        let dir = self.base_dir.join(index::layout_dir(&self.layout, Local::now())?);
+        ensure_dir(&dir)?;
+        let script_path = index::create_unique_file(&self.base_dir, &dir, &format!("script_{ts}"), "py")?;
+        write_file(&script_path, normalize_python(code))?;
+        self.last_script.replace(Some(script_path.clone()));
         Ok(script_path)
     }
 
+    /// Overwrite an existing script (a repaired or fixed version) the way `write_script`
+    /// writes new ones, after `normalize_python`.
+    pub fn rewrite_script(&self, script_path: &Path, code: &str) -> Result<()> {
+        write_file(script_path, normalize_python(code))?;
+        Ok(())
+    }
+
+    /// The script most recently written by `write_script`.
+    pub fn last_script(&self) -> Option<PathBuf> {
+        self.last_script.borrow().clone()
+    }
+
+    /// Write `<script>.requirements.txt` pinning the script's third-party imports (and
+    /// their direct dependencies) to the versions installed in the interpreter's
+    /// environment. Returns the file and the number of pins, or `None` when the
+    /// script only uses the standard library.
+    pub fn pin_requirements(&self, script_path: &Path) -> Result<Option<(PathBuf, usize)>> {
+        let code = fs::read_to_string(script_path)
+            .with_context(|| format!("Could not read {}", script_path.display()))?;
+        let mut packages: Vec<String> = Vec::new();
+        for module in self.detect_dependencies(&code) {
+            let package = pip_name(&module).to_string();
+            if !packages.contains(&package) {
+                packages.push(package);
+            }
+        }
+        if packages.is_empty() {
+            return Ok(None);
+        }
+
+        let pythons = self.pythons();
+        let mut listers: Vec<(&Path, &[&str])> =
+            pythons.iter().map(|p| (p.as_path(), &["-m", "pip", "list", "--format", "json"][..])).collect();
+        // uv lists the system environment, not the task's venv
+        if self.env.is_none() {
+            listers.push((Path::new("uv"), &["pip", "list", "--format", "json"][..]));
+        }
+        let listing = listers
+        .iter()
+        .find_map(|(cmd, args)| {
+            let output = Command::new(cmd).args(*args).stderr(Stdio::null()).output().ok()?;
+            output.status.success().then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+        })
+        .ok_or_else(|| anyhow::anyhow!("Could not list installed packages (pip and uv both failed)"))?;
+        let installed = requirements::parse_pip_list(&listing)?;
+
+        // `pip show` prints what it found even when some packages are missing
+        let show = pythons.iter().find_map(|cmd| {
+            Command::new(cmd).args(["-m", "pip", "show"]).args(&packages).stderr(Stdio::null()).output().ok()
+        });
+        let requires = show
+            .map(|o| requirements::parse_pip_show_requires(&String::from_utf8_lossy(&o.stdout)))
+            .unwrap_or_default();
+        let python_version = pythons
+            .iter()
+            .find_map(|cmd| Command::new(cmd).args(["-c", "import platform; print(platform.python_version())"]).output().ok())
+            .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| "unknown".to_string());
+
+        let (pins, missing) = requirements::pin(&packages, &installed, &requires);
+        let script_name = script_path.file_name().unwrap_or_default().to_string_lossy();
+        let path = script_path.with_extension("requirements.txt");
+        fs::write(&path, requirements::render(&script_name, &python_version, &pins, &missing))
+            .with_context(|| format!("Could not write {}", path.display()))?;
+        if let Some(env) = &self.env {
+            env.adopt_requirements(&path)?;
+        }
+        Ok(Some((path, pins.len())))
+    }
+
+    /// Record a written script in the generated index, with its provenance.
+    pub fn record_script(&self, script_path: &Path, provenance: &Provenance, prompt: &str) -> Result<()> {
+        let script = script_path
+            .strip_prefix(&self.base_dir)
+            .unwrap_or(script_path)
+            .to_string_lossy()
+            .to_string();
+        index::upsert_entry(
+            &self.base_dir,
+            IndexEntry {
+                script,
+                prompt: prompt.to_string(),
+                provenance: provenance.clone(),
+            },
+        )
+    }
+
+    /// Remove every `__pycache__` directory under `base_dir` (left by `py_compile` and by
+    /// scripts importing each other) and return how many were removed. Symlinks are not
+    /// followed and nothing outside `base_dir` is touched.
+    pub fn clean_pycache(&self) -> Result<usize> {
+        let root = fs::canonicalize(&self.base_dir)
+            .with_context(|| format!("Failed to resolve {}", self.base_dir.display()))?;
+        let mut removed = 0;
+        let mut pending = vec![root.clone()];
+        while let Some(dir) = pending.pop() {
+            for entry in fs::read_dir(&dir)?.flatten() {
+                // file_type() does not follow symlinks, so a link to a directory is skipped
+                if !entry.file_type().is_ok_and(|t| t.is_dir()) {
+                    continue;
+                }
+                let path = entry.path();
+                if !path.starts_with(&root) {
+                    continue;
+                }
+                if entry.file_name() == "__pycache__" {
+                    fs::remove_dir_all(&path)
+                        .with_context(|| format!("Failed to remove {}", path.display()))?;
+                    removed += 1;
+                } else {
+                    pending.push(path);
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    /// `py_compile` every `.py` file of a multi-file reply on its own, so a broken module
+    /// is reported even when the main one compiles; other files (`pyproject.toml`...) are
+    /// left out. The files are written under a scratch directory of `base_dir` (keeping
+    /// their relative paths), removed afterwards.
+    pub fn compile_files(&self, files: &[CodeFile]) -> Result<Vec<(String, Result<(), String>)>> {
+        let dir = self.base_dir.join(format!(".compile_check_{}", std::process::id()));
+        let result = files
+            .iter()
+            .filter(|file| file.name.ends_with(".py"))
+            .map(|file| {
+                let relative = Path::new(&file.name);
+                if !relative.components().all(|c| matches!(c, std::path::Component::Normal(_))) {
+                    return Ok((file.name.clone(), Err(format!("refusing to write outside the project: {}", file.name))));
+                }
+                let path = dir.join(relative);
+                if let Some(parent) = path.parent() {
+                    ensure_dir(parent)?;
+                }
+                fs::write(&path, normalize_python(&file.code))
+                    .with_context(|| format!("Could not write {}", path.display()))?;
+                Ok((file.name.clone(), self.syntax_check(&path)))
+            })
+            .collect();
+        let _ = fs::remove_dir_all(&dir);
+        result
+    }
+
     /// Run `python3 -m py_compile <path>` and return Ok(()) on success or
     /// Err(message) with the compiler output on failure.
-    pub fn syntax_check(&self, path: &PathBuf) -> Result<(), String> {
+    pub fn syntax_check(&self, path: &Path) -> Result<(), String> {
         let python_cmds = ["python3", "python"];
         for cmd in python_cmds {
             let output = Command::new(cmd)
@@ -168,29 +1093,85 @@ impl CodeExecutor {
     }
 
     /// Exécute un script Python existant avec le mode d'exécution spécifié.
-    pub fn run_existing_script(&self, script_path: &str, mode: ExecutionMode, timeout_secs: u64) -> Result<CodeExecutionResult> {
-        let path = PathBuf::from(script_path);
+    pub fn run_existing_script(&self, script_path: impl AsRef<Path>, mode: ExecutionMode, timeout_secs: u64) -> Result<CodeExecutionResult> {
+        let path = script_path.as_ref();
         if !path.exists() {
-            return Err(anyhow::anyhow!("Script not found: {}", script_path));
+            return Err(anyhow::anyhow!("Script not found: {}", path.display()));
         }
-        self.execute_script(&path, mode, timeout_secs)
+        self.execute_script(path, mode, timeout_secs)
     }
 
     /// Execute a Python script. `timeout_secs == 0` means no timeout.
-    /// Timeout only applies to `Captured` mode.
-    pub fn execute_script(&self, script_path: &PathBuf, mode: ExecutionMode, timeout_secs: u64) -> Result<CodeExecutionResult> {
-        // On essaie d'abord `python3`, puis `python` si besoin.
-        let python_cmds = ["python3", "python"];
+    /// Timeout only applies to `Captured` and `Streamed` modes; `HeadlessGui` always stops
+    /// after `HEADLESS_GUI_TIMEOUT_SECS` (or `timeout_secs` if shorter).
+    pub fn execute_script(&self, script_path: &Path, mode: ExecutionMode, timeout_secs: u64) -> Result<CodeExecutionResult> {
+        self.run_script(script_path, mode, timeout_secs).map(|result| self.capture_result(result))
+    }
+
+    /// Fill `result` from the markers in stdout, with `capture_result`, unless the
+    /// captured run already read them from the raw output (`decode_output`).
+    fn capture_result(&self, mut result: CodeExecutionResult) -> CodeExecutionResult {
+        if self.run_options.capture_result && result.result.is_none() && result.result_error.is_none() {
+            result.set_result(parse_result(&result.stdout));
+        }
+        result
+    }
+
+    fn run_script(&self, script_path: &Path, mode: ExecutionMode, timeout_secs: u64) -> Result<CodeExecutionResult> {
+        // On essaie d'abord `python3`, puis `python` si besoin (ou l'interpréteur de la tâche).
+        let python_cmds = self.pythons();
+        // The child runs from work_dir, so a relative script path would no longer resolve
+        let script_arg = std::path::absolute(script_path)
+            .with_context(|| format!("Could not resolve script path {}", script_path.display()))?;
+
+        let sandbox = self.active_sandbox()?;
+
+        // Kept alive until the function returns, then torn down by Drop
+        let display = match mode {
+            ExecutionMode::HeadlessGui => Some(VirtualDisplay::start()?),
+            _ => None,
+        };
+        let timeout_secs = match mode {
+            ExecutionMode::HeadlessGui if timeout_secs > 0 => timeout_secs.min(HEADLESS_GUI_TIMEOUT_SECS),
+            ExecutionMode::HeadlessGui => HEADLESS_GUI_TIMEOUT_SECS,
+            _ => timeout_secs,
+        };
 
         let mut last_err: Option<anyhow::Error> = None;
 
-        for cmd in python_cmds {
+        for cmd in &python_cmds {
+            let cmd = cmd.as_path();
+            let name = cmd.display();
             match mode {
                 ExecutionMode::Interactive => {
+                    // Under a pty the program still talks to the user live, but its output
+                    // is captured too (and kept next to the script as `<script>.out`)
+                    #[cfg(unix)]
+                    match crate::pty::run(self.interpreter_command(cmd, &script_arg, sandbox)) {
+                        Ok(Some(run)) => {
+                            let stdout = crate::pty::output_text(&run);
+                            let _ = fs::write(script_path.with_extension("out"), &stdout);
+                            return Ok(CodeExecutionResult {
+                                script_path: script_path.to_path_buf(),
+                                stdout,
+                                stderr: String::new(),
+                                exit_code: run.status.code(),
+                                signal: exit_signal(run.status),
+                                ..CodeExecutionResult::default()
+                            });
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            last_err = Some(anyhow::anyhow!(
+                                "Failed to spawn interactive process with `{name}`: {e}"
+                            ));
+                            continue;
+                        }
+                    }
+
                     // Mode interactif: hérite stdin/stdout/stderr pour l'interaction utilisateur
                     // No timeout for interactive mode
-                    let child = Command::new(cmd)
-                        .arg(script_path)
+                    let child = self.interpreter_command(cmd, &script_arg, sandbox)
                         .stdin(Stdio::inherit())
                         .stdout(Stdio::inherit())
                         .stderr(Stdio::inherit())
@@ -199,53 +1180,86 @@ impl CodeExecutor {
                     match child {
                         Ok(mut process) => {
                             let status = process.wait()
-                                .with_context(|| format!("Failed to wait for process with {}", cmd))?;
+                                .with_context(|| format!("Failed to wait for process with {}", name))?;
 
+                            // Nothing was captured: both streams stay empty, so there is
+                            // no `.out` to mention and no output to send the model
                             return Ok(CodeExecutionResult {
-                                script_path: script_path.clone(),
-                                stdout: String::from("[Interactive mode - output displayed directly]"),
-                                stderr: String::new(),
+                                script_path: script_path.to_path_buf(),
                                 exit_code: status.code(),
+                                signal: exit_signal(status),
+                                ..CodeExecutionResult::default()
                             });
                         }
                         Err(e) => {
                             last_err = Some(anyhow::anyhow!(
-                                "Failed to spawn interactive process with `{cmd}`: {e}"
+                                "Failed to spawn interactive process with `{name}`: {e}"
                             ));
                         }
                     }
                 }
-                ExecutionMode::Captured => {
-                    // Mode capturé: spawn + optional timeout
-                    let child = Command::new(cmd)
-                        .arg(script_path)
+                ExecutionMode::Streamed => {
+                    // Python block-buffers a piped stdout; unbuffered, lines arrive as printed
+                    let child = in_own_group(&mut self.interpreter_command(cmd, &script_arg, sandbox))
                         .stdout(Stdio::piped())
                         .stderr(Stdio::piped())
+                        .env("PYTHONUNBUFFERED", "1")
                         .spawn();
+                    match child {
+                        Ok(process) => return Self::stream_process(process, script_path, timeout_secs, self.run_options),
+                        Err(e) => {
+                            last_err = Some(anyhow::anyhow!("Failed with command `{name}`: {e}"));
+                        }
+                    }
+                }
+                ExecutionMode::Captured | ExecutionMode::HeadlessGui => {
+                    // Mode capturé: spawn + optional timeout
+                    let mut command = self.interpreter_command(cmd, &script_arg, sandbox);
+                    in_own_group(&mut command)
+                        .stdout(Stdio::piped())
+                        .stderr(Stdio::piped());
+                    if let Some(vd) = &display {
+                        command.env("DISPLAY", &vd.display);
+                    }
+                    let child = command.spawn();
 
                     match child {
                         Ok(mut process) => {
+                            let _watch = GroupWatch::new(&process);
+                            let _stopwatch = progress::Stopwatch::start();
                             if timeout_secs > 0 {
                                 let timeout = Duration::from_secs(timeout_secs);
                                 match process.wait_timeout(timeout)
-                                    .with_context(|| format!("Failed to wait for process with {}", cmd))?
+                                    .with_context(|| format!("Failed to wait for process with {}", name))?
                                 {
                                     Some(status) => {
-                                        let stdout = read_pipe(process.stdout.take());
-                                        let stderr = read_pipe(process.stderr.take());
+                                        let decoded = decode_output(
+                                            script_path,
+                                            read_pipe(process.stdout.take()),
+                                            read_pipe(process.stderr.take()),
+                                            self.run_options,
+                                        );
                                         return Ok(CodeExecutionResult {
-                                            script_path: script_path.clone(),
-                                            stdout,
-                                            stderr,
                                             exit_code: status.code(),
+                                            signal: exit_signal(status),
+                                            ..decoded
                                         });
                                     }
                                     None => {
-                                        // Timed out — kill the process
-                                        let _ = process.kill();
-                                        let _ = process.wait();
+                                        // Timed out — kill the process and whatever it started
+                                        let reaped_descendants = kill_group(&mut process);
+                                        if display.is_some() {
+                                            // Still running after the grace period is the expected outcome
+                                            let decoded = decode_output(
+                                                script_path,
+                                                read_pipe(process.stdout.take()),
+                                                read_pipe(process.stderr.take()),
+                                                self.run_options,
+                                            );
+                                            return Ok(CodeExecutionResult { timed_out: true, reaped_descendants, ..decoded });
+                                        }
                                         return Ok(CodeExecutionResult {
-                                            script_path: script_path.clone(),
+                                            script_path: script_path.to_path_buf(),
                                             stdout: String::new(),
                                             stderr: format!(
                                                 "Process timed out after {} seconds. \
@@ -253,26 +1267,27 @@ impl CodeExecutor {
                                                 timeout_secs
                                             ),
                                             exit_code: None,
+                                            timed_out: true,
+                                            reaped_descendants,
+                                            ..CodeExecutionResult::default()
                                         });
                                     }
                                 }
                             } else {
                                 // No timeout — blocking wait
                                 let output = process.wait_with_output()
-                                    .with_context(|| format!("Failed to wait for process with {}", cmd))?;
-                                let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-                                let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+                                    .with_context(|| format!("Failed to wait for process with {}", name))?;
+                                let decoded = decode_output(script_path, output.stdout, output.stderr, self.run_options);
                                 return Ok(CodeExecutionResult {
-                                    script_path: script_path.clone(),
-                                    stdout,
-                                    stderr,
                                     exit_code: output.status.code(),
+                                    signal: exit_signal(output.status),
+                                    ..decoded
                                 });
                             }
                         }
                         Err(e) => {
                             last_err = Some(anyhow::anyhow!(
-                                "Failed with command `{cmd}`: {e}"
+                                "Failed with command `{name}`: {e}"
                             ));
                         }
                     }
@@ -280,24 +1295,632 @@ impl CodeExecutor {
             }
         }
 
-        Err(last_err.unwrap_or_else(|| anyhow::anyhow!(
-            "Could not execute the script with python/python3"
-        )))
+        Err(last_err.unwrap_or_else(|| anyhow::anyhow!(
+            "Could not execute the script with python/python3"
+        )))
+    }
+
+    /// Streamed mode: both pipes are relayed line by line while being captured.
+    fn stream_process(mut process: Child, script_path: &Path, timeout_secs: u64, options: RunOptions) -> Result<CodeExecutionResult> {
+        let watch = GroupWatch::new(&process);
+        let stdout = tee_lines(process.stdout.take(), std::io::stdout, options.collapse_progress);
+        let stderr = tee_lines(process.stderr.take(), std::io::stderr, options.collapse_progress);
+
+        let status = if timeout_secs > 0 {
+            process.wait_timeout(Duration::from_secs(timeout_secs))
+                .context("Failed to wait for the script")?
+        } else {
+            Some(process.wait().context("Failed to wait for the script")?)
+        };
+        let reaped_descendants = status.is_none() && kill_group(&mut process);
+        drop(watch);
+
+        let (stdout, stdout_limits) = stdout.join().unwrap_or_default();
+        let (stderr, stderr_limits) = stderr.join().unwrap_or_default();
+        let stream_limits = stdout_limits.merge(stderr_limits);
+        // Already collapsed while relayed, which leaves the result markers as printed
+        let mut decoded = decode_output(script_path, stdout, stderr, RunOptions { collapse_progress: false, ..options });
+        if status.is_none() {
+            decoded.stderr.push_str(&format!(
+                "\nProcess timed out after {} seconds. \
+                 You can increase this with execution_timeout_secs in pymakebot.toml",
+                timeout_secs
+            ));
+        }
+        Ok(CodeExecutionResult {
+            exit_code: status.and_then(|s| s.code()),
+            signal: status.and_then(exit_signal),
+            timed_out: status.is_none(),
+            stream_limits,
+            reaped_descendants,
+            ..decoded
+        })
+    }
+
+    /// Run a script in captured mode with `input` piped to its stdin (used by `/spec`).
+    pub fn execute_with_input(&self, script_path: &Path, input: &str, timeout_secs: u64) -> Result<CodeExecutionResult> {
+        let script_arg = std::path::absolute(script_path)
+            .with_context(|| format!("Could not resolve script path {}", script_path.display()))?;
+        let sandbox = self.active_sandbox()?;
+
+        let mut process = self.pythons()
+            .iter()
+            .find_map(|cmd| {
+                in_own_group(&mut self.interpreter_command(cmd, &script_arg, sandbox))
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .spawn()
+                    .ok()
+            })
+            .ok_or_else(|| anyhow::anyhow!("Could not execute the script with python/python3"))?;
+        let watch = GroupWatch::new(&process);
+
+        // Written from a thread so a script that doesn't read stdin can't block us
+        let stdin = process.stdin.take();
+        let input = input.to_string();
+        let writer = std::thread::spawn(move || {
+            if let Some(mut stdin) = stdin {
+                let _ = stdin.write_all(input.as_bytes());
+                if !input.ends_with('\n') {
+                    let _ = stdin.write_all(b"\n");
+                }
+            }
+        });
+
+        let status = if timeout_secs > 0 {
+            process.wait_timeout(Duration::from_secs(timeout_secs))?
+        } else {
+            Some(process.wait()?)
+        };
+        let timed_out = status.is_none();
+        let reaped_descendants = timed_out && kill_group(&mut process);
+        drop(watch);
+        let _ = writer.join();
+
+        // Spec output is compared line by line: never collapsed
+        let raw = RunOptions { collapse_progress: false, capture_result: false };
+        let decoded = decode_output(script_path, read_pipe(process.stdout.take()), read_pipe(process.stderr.take()), raw);
+        Ok(CodeExecutionResult {
+            exit_code: status.and_then(|s| s.code()),
+            signal: status.and_then(exit_signal),
+            timed_out,
+            reaped_descendants,
+            ..decoded
+        })
+    }
+}
+
+/// Where one import of the code stands before a run (`/deps`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DepStatus {
+    Stdlib,
+    /// Third-party and importable by the interpreter scripts run with.
+    Installed,
+    /// Third-party and not importable: the install step will offer it.
+    Missing,
+    /// Third-party, but no interpreter answered the import probe.
+    Unknown,
+}
+
+impl DepStatus {
+    pub fn label(self) -> &'static str {
+        match self {
+            DepStatus::Stdlib => "stdlib",
+            DepStatus::Installed => "installed",
+            DepStatus::Missing => "missing",
+            DepStatus::Unknown => "?",
+        }
+    }
+}
+
+/// One import of the code: module, pip package (`None` for the standard library), status.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DepRow {
+    pub module: String,
+    pub pip: Option<String>,
+    pub status: DepStatus,
+}
+
+/// Size and content hash of a file, to tell whether a trial run changed it.
+type FileStamp = (u64, u64);
+
+/// Where a trial run's copy of the script goes, inside the copy of the run directory.
+const TRIAL_SCRIPT_DIR: &str = ".pmb_trial";
+
+/// Whether two paths name the same directory, once made absolute.
+fn same_dir(a: &Path, b: &Path) -> bool {
+    matches!((std::path::absolute(a), std::path::absolute(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+/// Whether `relative` (to the generated directory) is one of the bot's own files there:
+/// a generated script or a file named after one (`script_x.requirements.txt`), the
+/// index, dotfiles such as the lock, caches and task environments.
+fn is_generated_file(relative: &Path, scripts: &BTreeSet<PathBuf>) -> bool {
+    let first = relative.components().next().map(|c| c.as_os_str().to_string_lossy().into_owned()).unwrap_or_default();
+    if first.starts_with('.') || first == "envs" || first == "__pycache__" || relative == index::index_path(Path::new("")) {
+        return true;
+    }
+    let name = relative.file_name().unwrap_or_default().to_string_lossy();
+    let stem = name.split('.').next().unwrap_or_default();
+    scripts.contains(&relative.with_file_name(format!("{stem}.py")))
+}
+
+/// Files under `dir` by relative path (symlinks, and paths `skip` returns true for, are
+/// left out). Fails once their total size passes `max_bytes`.
+fn snapshot(dir: &Path, max_bytes: u64, skip: &dyn Fn(&Path) -> bool) -> Result<BTreeMap<PathBuf, FileStamp>> {
+    use std::hash::{DefaultHasher, Hasher};
+    let mut files = BTreeMap::new();
+    let mut total = 0u64;
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in fs::read_dir(&current).with_context(|| format!("Could not list {}", current.display()))? {
+            let entry = entry?;
+            let kind = entry.file_type()?;
+            let path = entry.path();
+            let relative = path.strip_prefix(dir).unwrap_or(&path).to_path_buf();
+            if skip(&relative) {
+                continue;
+            }
+            if kind.is_dir() {
+                pending.push(path);
+            } else if kind.is_file() {
+                let contents = fs::read(entry.path()).with_context(|| format!("Could not read {}", entry.path().display()))?;
+                total += contents.len() as u64;
+                if total > max_bytes {
+                    anyhow::bail!("{} holds more than {} MB", dir.display(), max_bytes / (1024 * 1024));
+                }
+                let mut hasher = DefaultHasher::new();
+                hasher.write(&contents);
+                files.insert(relative, (contents.len() as u64, hasher.finish()));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// What a trial run did to the files of its copy of the run directory, by relative path.
+#[derive(Debug, Default, PartialEq)]
+pub struct FileChanges {
+    pub created: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+    pub deleted: Vec<PathBuf>,
+}
+
+impl FileChanges {
+    fn between(before: &BTreeMap<PathBuf, FileStamp>, after: &BTreeMap<PathBuf, FileStamp>) -> Self {
+        let mut changes = Self::default();
+        for (path, stamp) in after {
+            match before.get(path) {
+                None => changes.created.push(path.clone()),
+                Some(old) if old != stamp => changes.modified.push(path.clone()),
+                Some(_) => {}
+            }
+        }
+        changes.deleted = before.keys().filter(|p| !after.contains_key(*p)).cloned().collect();
+        changes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.created.is_empty() && self.modified.is_empty() && self.deleted.is_empty()
+    }
+
+    /// `+ out.csv`, `~ data.json`, `– old.log`: one line per file.
+    pub fn lines(&self) -> Vec<String> {
+        let created = self.created.iter().map(|p| format!("+ {}", p.display()));
+        let modified = self.modified.iter().map(|p| format!("~ {}", p.display()));
+        let deleted = self.deleted.iter().map(|p| format!("– {}", p.display()));
+        created.chain(modified).chain(deleted).collect()
+    }
+}
+
+/// A rehearsal of a script in a throwaway copy of the run directory (`trial_run`).
+#[derive(Debug)]
+pub struct TrialRun {
+    pub result: CodeExecutionResult,
+    pub changes: FileChanges,
+}
+
+/// The signal that terminated a process; always `None` off Unix.
+fn exit_signal(status: std::process::ExitStatus) -> Option<i32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        status.signal()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = status;
+        None
+    }
+}
+
+/// `SIGSEGV` for 11, or `signal 11` when the number isn't a known signal.
+pub fn signal_name(signal: i32) -> String {
+    #[cfg(unix)]
+    if let Ok(known) = nix::sys::signal::Signal::try_from(signal) {
+        return known.as_str().to_string();
+    }
+    format!("signal {signal}")
+}
+
+/// How long an interpreter found on `PATH` gets to report its version.
+const PYTHON_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `(major, minor)` of the interpreter `cmd`, if it runs and answers in time.
+fn probe_python_version(cmd: &Path) -> Option<(u32, u32)> {
+    use std::io::Read;
+    let mut child = Command::new(cmd)
+        .args(["-c", "import platform; print(platform.python_version())"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    if child.wait_timeout(PYTHON_PROBE_TIMEOUT).ok()?.is_none() {
+        let _ = child.kill();
+        let _ = child.wait();
+        return None;
+    }
+    let mut stdout = String::new();
+    child.stdout.take()?.read_to_string(&mut stdout).ok()?;
+    parse_python_version(&stdout)
+}
+
+/// Versioned interpreters to look for (`python3.14` down to `python3.8`).
+const VERSIONED_PYTHONS: std::ops::RangeInclusive<u32> = 8..=14;
+
+/// The newest `python3.x` on `PATH` that is at least `min`, with its version, for
+/// code the default interpreter is too old to run.
+pub fn find_python_at_least(min: (u32, u32)) -> Option<(PathBuf, (u32, u32))> {
+    VERSIONED_PYTHONS
+        .rev()
+        .map(|minor| (3, minor))
+        .filter(|&version| version >= min)
+        .find_map(|(major, minor)| {
+            let path = find_in_path(&format!("python{major}.{minor}"))?;
+            // The name can lie (a link to another version): ask the interpreter
+            let version = probe_python_version(&path)?;
+            (version >= min).then_some((path, version))
+        })
+}
+
+/// Outcome of `CodeExecutor::install_packages`.
+#[derive(Debug, Default, PartialEq)]
+pub struct InstallReport {
+    /// Pip names of packages whose module already imported; pip was not run for them.
+    pub already_satisfied: Vec<String>,
+    pub installed: Vec<String>,
+    /// Package and pip's error output.
+    pub failed: Vec<(String, String)>,
+}
+
+impl InstallReport {
+    /// Skip the modules `imports` accepts, then batch install the pip packages of the
+    /// others with `install`, falling back to one package at a time on failure.
+    fn build(
+        modules: &[String],
+        imports: impl Fn(&str) -> bool,
+        mut install: impl FnMut(&[String]) -> Result<(), String>,
+    ) -> Self {
+        let mut report = Self::default();
+        let mut packages: Vec<String> = Vec::new();
+        for module in modules {
+            let package = pip_name(module).to_string();
+            if report.already_satisfied.contains(&package) || packages.contains(&package) {
+                continue;
+            }
+            if imports(module) {
+                report.already_satisfied.push(package);
+            } else {
+                packages.push(package);
+            }
+        }
+        let packages = &packages[..];
+        if packages.is_empty() {
+            return report;
+        }
+        if install(packages).is_ok() {
+            report.installed = packages.to_vec();
+            return report;
+        }
+        for package in packages {
+            match install(std::slice::from_ref(package)) {
+                Ok(()) => report.installed.push(package.clone()),
+                Err(e) => report.failed.push((package.clone(), e)),
+            }
+        }
+        report
+    }
+
+    /// Error only when nothing could be installed; partial success is still `Ok`.
+    pub fn into_result(self) -> Result<Self> {
+        if self.installed.is_empty() && !self.failed.is_empty() {
+            let details: Vec<String> = self
+                .failed
+                .iter()
+                .map(|(package, e)| format!("{package}: {}", e.trim()))
+                .collect();
+            return Err(anyhow::anyhow!("pip install failed:\n{}", details.join("\n")));
+        }
+        Ok(self)
+    }
+}
+
+/// Run `pip install` for `packages` with each of `pythons` until one works.
+fn pip_install(pythons: &[PathBuf], packages: &[String]) -> Result<(), String> {
+    let mut last_err = String::from("Could not install packages with python/python3");
+    for cmd in pythons {
+        let output = Command::new(cmd)
+            .args(["-m", "pip", "install", "--quiet"])
+            .args(packages)
+            .output();
+        match output {
+            Ok(out) if out.status.success() => return Ok(()),
+            Ok(out) => last_err = String::from_utf8_lossy(&out.stderr).trim().to_string(),
+            Err(e) => last_err = format!("Failed to run pip with {}: {}", cmd.display(), e),
+        }
+    }
+    Err(last_err)
+}
+
+/// Live printing budget of a streamed pipe: `LIVE_LINES_PER_WINDOW` lines per `LIVE_WINDOW`.
+struct LiveRate {
+    window_start: Instant,
+    shown: usize,
+    held: usize,
+}
+
+impl LiveRate {
+    fn new(now: Instant) -> Self {
+        Self { window_start: now, shown: 0, held: 0 }
+    }
+
+    /// Whether a line arriving at `now` is printed, and how many lines the previous
+    /// window held back when this line opens a new one (to be reported first).
+    fn admit(&mut self, now: Instant) -> (bool, usize) {
+        let mut held_back = 0;
+        if now.duration_since(self.window_start) >= LIVE_WINDOW {
+            held_back = std::mem::take(&mut self.held);
+            self.window_start = now;
+            self.shown = 0;
+        }
+        if self.shown < LIVE_LINES_PER_WINDOW {
+            self.shown += 1;
+            (true, held_back)
+        } else {
+            self.held += 1;
+            (false, held_back)
+        }
+    }
+}
+
+/// Read up to and including the next `\n` (or `\r` too with `split_cr`) into `piece`.
+fn read_piece<R: std::io::BufRead>(reader: &mut R, split_cr: bool, piece: &mut Vec<u8>) -> std::io::Result<usize> {
+    if !split_cr {
+        return reader.read_until(b'\n', piece);
+    }
+    let mut read = 0;
+    loop {
+        let buf = match reader.fill_buf() {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if buf.is_empty() {
+            return Ok(read);
+        }
+        match buf.iter().position(|&b| b == b'\n' || b == b'\r') {
+            Some(i) => {
+                piece.extend_from_slice(&buf[..=i]);
+                reader.consume(i + 1);
+                return Ok(read + i + 1);
+            }
+            None => {
+                let n = buf.len();
+                piece.extend_from_slice(buf);
+                reader.consume(n);
+                read += n;
+            }
+        }
+    }
+}
+
+/// Copy `pipe` to `echo` one line at a time from a thread, rate-limited, returning what
+/// was read up to `MAX_CAPTURE_BYTES` and how the output was limited. With `collapse`,
+/// progress bars are drawn in place with the elapsed time and only their latest frame
+/// (plus periodic snapshots) is captured.
+fn tee_lines<R, W>(pipe: Option<R>, echo: fn() -> W, collapse: bool) -> std::thread::JoinHandle<(Vec<u8>, StreamLimits)>
+where
+    R: std::io::Read + Send + 'static,
+    W: Write + 'static,
+{
+    std::thread::spawn(move || {
+        let mut captured = Vec::new();
+        let mut limits = StreamLimits::default();
+        let Some(pipe) = pipe else {
+            return (captured, limits);
+        };
+        let start = Instant::now();
+        let held_note = |n: usize| format!("[… {n} line(s) not shown live]\n");
+        let mut rate = LiveRate::new(start);
+        let mut collapser = collapse.then(ProgressCollapser::new);
+        // When the progress line was last redrawn; redraws share the live line budget window
+        let mut last_frame: Option<Instant> = None;
+        let mut show = |event: Live, now: Instant, limits: &mut StreamLimits| {
+            let mut out = echo();
+            match event {
+                Live::Line(line) => {
+                    let (show, held_back) = rate.admit(now);
+                    if held_back > 0 {
+                        let _ = out.write_all(held_note(held_back).as_bytes());
+                    }
+                    if show {
+                        let _ = out.write_all(&line);
+                    } else {
+                        limits.coalesced_lines += 1;
+                    }
+                }
+                Live::Frame(frame) => {
+                    if last_frame.is_none_or(|t| now.duration_since(t) >= LIVE_WINDOW) {
+                        let _ = write!(out, "\r{frame}  ⏱ {}\x1b[K", format_elapsed(now - start));
+                        last_frame = Some(now);
+                    }
+                }
+                Live::EndFrame(frame) => {
+                    let _ = writeln!(out, "\r{frame}  ⏱ {}\x1b[K", format_elapsed(now - start));
+                    last_frame = None;
+                }
+            }
+            let _ = out.flush();
+        };
+        // Keep draining after the cap so the script never blocks on a full pipe
+        let mut capture = |bytes: &[u8], limits: &mut StreamLimits| {
+            let room = MAX_CAPTURE_BYTES - captured.len();
+            if bytes.len() > room {
+                limits.truncated = true;
+            }
+            captured.extend_from_slice(&bytes[..bytes.len().min(room)]);
+        };
+        let mut reader = std::io::BufReader::new(pipe);
+        let mut line = Vec::new();
+        // Bytes, not String: a non-UTF-8 line must not end the stream
+        while read_piece(&mut reader, collapser.is_some(), &mut line).is_ok_and(|n| n > 0) {
+            let now = Instant::now();
+            match collapser.as_mut() {
+                Some(collapser) => {
+                    for event in collapser.push(&line, now) {
+                        show(event, now, &mut limits);
+                    }
+                    capture(&collapser.take_stored(), &mut limits);
+                }
+                None => {
+                    capture(&line, &mut limits);
+                    show(Live::Line(std::mem::take(&mut line)), now, &mut limits);
+                }
+            }
+            line.clear();
+        }
+        if let Some(collapser) = collapser.as_mut() {
+            for event in collapser.finish() {
+                show(event, Instant::now(), &mut limits);
+            }
+            capture(&collapser.take_stored(), &mut limits);
+            limits.collapsed_frames = collapser.collapsed_frames;
+        }
+        if rate.held > 0 {
+            let _ = echo().write_all(held_note(rate.held).as_bytes());
+        }
+        (captured, limits)
+    })
+}
+
+/// Read a piped child stdio handle to the end, as raw bytes.
+fn read_pipe<R: std::io::Read>(pipe: Option<R>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    if let Some(mut r) = pipe {
+        let _ = std::io::Read::read_to_end(&mut r, &mut buf);
+    }
+    buf
+}
+
+/// What captured bytes look like.
+#[derive(Debug, PartialEq)]
+enum OutputKind {
+    Utf8,
+    Text,
+    Binary,
+}
+
+/// Binary when NUL bytes or undecodable sequences make up more than a few percent.
+fn classify_output(bytes: &[u8]) -> OutputKind {
+    if std::str::from_utf8(bytes).is_ok() && !bytes.contains(&0) {
+        return OutputKind::Utf8;
+    }
+    let nuls = bytes.iter().filter(|b| **b == 0).count();
+    let replaced = String::from_utf8_lossy(bytes).matches('\u{FFFD}').count();
+    if nuls * 100 > bytes.len() || replaced * 10 > bytes.len() {
+        OutputKind::Binary
+    } else {
+        OutputKind::Text
+    }
+}
+
+/// Windows-1252: Latin-1 plus printable characters in 0x80..0x9F.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn decode_cp1252(bytes: &[u8]) -> String {
+    const HIGH: [char; 32] = [
+        '€', '\u{FFFD}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{FFFD}', 'Ž', '\u{FFFD}',
+        '\u{FFFD}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{FFFD}', 'ž', 'Ÿ',
+    ];
+    bytes
+        .iter()
+        .map(|&b| match b {
+            0x80..=0x9F => HIGH[(b - 0x80) as usize],
+            _ => b as char,
+        })
+        .collect()
+}
+
+/// Human-readable size, e.g. "2.3 MB".
+fn format_size(bytes: usize) -> String {
+    match bytes {
+        b if b >= 1024 * 1024 => format!("{:.1} MB", b as f64 / (1024.0 * 1024.0)),
+        b if b >= 1024 => format!("{:.1} KB", b as f64 / 1024.0),
+        b => format!("{} B", b),
     }
 }
 
-/// Helper to read a piped child stdio handle into a String.
-fn read_pipe<R: std::io::Read>(pipe: Option<R>) -> String {
-    match pipe {
-        Some(mut r) => {
-            let mut buf = Vec::new();
-            let _ = std::io::Read::read_to_end(&mut r, &mut buf);
-            String::from_utf8_lossy(&buf).to_string()
+/// Decode one captured stream. Binary output is saved to `<script>.<stream>.bin` and
+/// replaced by a summary line.
+fn decode_stream(script_path: &Path, stream: &str, bytes: Vec<u8>, encoding: &mut OutputEncoding) -> String {
+    match classify_output(&bytes) {
+        OutputKind::Utf8 => String::from_utf8(bytes).unwrap_or_default(),
+        OutputKind::Text => {
+            if *encoding == OutputEncoding::Utf8 {
+                *encoding = OutputEncoding::Lossy;
+            }
+            if cfg!(windows) {
+                decode_cp1252(&bytes)
+            } else {
+                String::from_utf8_lossy(&bytes).into_owned()
+            }
+        }
+        OutputKind::Binary => {
+            let companion = script_path.with_extension(format!("{stream}.bin"));
+            let saved = fs::write(&companion, &bytes).is_ok();
+            let summary = if saved {
+                format!("[binary or non-UTF-8 output, {} — saved to {}]", format_size(bytes.len()), companion.display())
+            } else {
+                format!("[binary or non-UTF-8 output, {} — could not be saved]", format_size(bytes.len()))
+            };
+            match encoding {
+                OutputEncoding::Binary { saved_to } if saved => saved_to.push(companion),
+                OutputEncoding::Binary { .. } => {}
+                _ => *encoding = OutputEncoding::Binary { saved_to: if saved { vec![companion] } else { Vec::new() } },
+            }
+            summary
         }
-        None => String::new(),
     }
 }
 
+/// Decode both captured streams of a run, first collapsing their progress bars if asked.
+/// The result markers are read from the raw bytes, before any progress collapsing.
+fn decode_output(script_path: &Path, stdout: Vec<u8>, stderr: Vec<u8>, options: RunOptions) -> CodeExecutionResult {
+    let mut decoded = CodeExecutionResult { script_path: script_path.to_path_buf(), ..CodeExecutionResult::default() };
+    if options.capture_result {
+        decoded.set_result(parse_result(&String::from_utf8_lossy(&stdout)));
+    }
+    // Binary output is saved byte for byte
+    let collapse = |bytes: Vec<u8>| {
+        if options.collapse_progress && classify_output(&bytes) != OutputKind::Binary { progress::collapse(&bytes) } else { bytes }
+    };
+    let (stdout, stderr) = (collapse(stdout), collapse(stderr));
+    decoded.stdout = decode_stream(script_path, "out", stdout, &mut decoded.output_encoding);
+    decoded.stderr = decode_stream(script_path, "err", stderr, &mut decoded.output_encoding);
+    decoded
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -313,6 +1936,47 @@ mod tests {
         let _ = fs::remove_dir_all(temp_dir);
     }
 
+    fn all_blocked() -> Vec<String> {
+        DANGEROUS_PATTERNS.iter().map(|(name, _)| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_scan_dangerous_detects_each_pattern() {
+        let executor = CodeExecutor::new("test_scan_dangerous").unwrap();
+        let cases = [
+            ("import os\nos.system('rm -rf build')", "os.system", 2),
+            ("import subprocess\nsubprocess.run(\n    cmd,\n    shell=True,\n)", "subprocess-shell", 4),
+            ("x = eval(input())", "eval", 1),
+            ("exec(open('plugin.py').read())", "exec", 1),
+            ("mod = __import__(name)", "__import__", 1),
+        ];
+        for (code, name, line) in cases {
+            let findings = executor.scan_dangerous(code, &all_blocked());
+            assert_eq!(findings.len(), 1, "{code}");
+            assert_eq!((findings[0].name, findings[0].line), (name, line));
+            assert!(!findings[0].reason().is_empty());
+        }
+        let only_eval = vec!["eval".to_string()];
+        assert!(executor.scan_dangerous("import os\nos.system('ls')", &only_eval).is_empty());
+        let _ = fs::remove_dir_all("test_scan_dangerous");
+    }
+
+    #[test]
+    fn test_scan_dangerous_passes_clean_code() {
+        let executor = CodeExecutor::new("test_scan_dangerous_clean").unwrap();
+        let code = "import ast, subprocess\n\
+                    # os.system('ls') would be simpler\n\
+                    value = ast.literal_eval(text)\n\
+                    model.eval()\n\
+                    cursor.executemany(sql, rows)\n\
+                    subprocess.run(['ls', '-l'], check=True)\n\
+                    print(evaluate(3))";
+        assert!(executor.scan_dangerous(code, &all_blocked()).is_empty());
+        // shell=True outside subprocess (e.g. a custom function's keyword) is not a finding
+        assert!(executor.scan_dangerous("launch(shell=True)", &all_blocked()).is_empty());
+        let _ = fs::remove_dir_all("test_scan_dangerous_clean");
+    }
+
     #[test]
     fn test_detect_dependencies_stdlib_only() {
         let executor = CodeExecutor::new("test_temp").unwrap();
@@ -380,6 +2044,29 @@ mod tests {
         let _ = fs::remove_dir_all("test_generated_calc");
     }
 
+    // macOS and Windows file systems reject names that aren't valid Unicode
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_non_utf8_generated_dir() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = Path::new(OsStr::from_bytes(b"test_generated_\xffbytes"));
+        assert!(dir.to_str().is_none());
+        let executor = CodeExecutor::new(dir).unwrap();
+        let script = executor.write_script("print('ok')").unwrap();
+        assert!(script.starts_with(dir));
+        assert!(executor.syntax_check(&script).is_ok());
+
+        let result = executor.run_existing_script(&script, ExecutionMode::Captured, 30).unwrap();
+        assert!(result.is_success(), "{}", result.stderr);
+        assert_eq!(result.stdout.trim(), "ok");
+        assert_eq!(result.script_path, script);
+        assert_eq!(crate::index::list_scripts(dir).unwrap().len(), 1);
+
+        let _ = fs::remove_dir_all(dir);
+    }
+
     #[test]
     fn test_write_and_run_error_script() {
         let executor = CodeExecutor::new("test_generated_error").unwrap();
@@ -397,14 +2084,100 @@ mod tests {
         let _ = fs::remove_dir_all("test_generated_error");
     }
 
+    #[test]
+    fn test_indented_fence_extraction_runs() {
+        use crate::utils::extract_python_code;
+
+        let executor = CodeExecutor::new("test_generated_indented").unwrap();
+        let response = "Steps:\n    ```python\n    import sys\n    if True:\n        print('dedented ok')\n    sys.exit(0)\n    ```";
+        let code = extract_python_code(response);
+
+        let result = executor.write_and_run(&code).unwrap();
+        assert!(result.is_success(), "stderr: {}", result.stderr);
+        assert!(result.stdout.contains("dedented ok"));
+
+        let _ = fs::remove_dir_all("test_generated_indented");
+    }
+
+    #[test]
+    fn test_is_installed() {
+        let executor = CodeExecutor::new("test_is_installed").unwrap();
+        assert!(executor.is_installed("json"));
+        assert!(!executor.is_installed("definitely_not_a_module_xyz"));
+        let _ = fs::remove_dir_all("test_is_installed");
+    }
+
     #[test]
     fn test_install_packages_empty_list() {
         let executor = CodeExecutor::new("test_temp").unwrap();
-        let result = executor.install_packages(&[]);
-        assert!(result.is_ok());
+        let report = executor.install_packages(&[]);
+        assert_eq!(report, InstallReport::default());
+        assert!(report.into_result().is_ok());
         let _ = fs::remove_dir_all("test_temp");
     }
 
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_install_report_skips_importable_modules() {
+        let mut installed_with: Vec<Vec<String>> = Vec::new();
+        let report = InstallReport::build(&names(&["yaml", "requests", "yaml"]), |m| m == "yaml", |pkgs| {
+            installed_with.push(pkgs.to_vec());
+            Ok(())
+        });
+        assert_eq!(installed_with, vec![names(&["requests"])]);
+        assert_eq!(report.already_satisfied, names(&["PyYAML"]));
+        assert_eq!(report.installed, names(&["requests"]));
+    }
+
+    #[test]
+    fn test_install_packages_skips_pip_when_already_importable() {
+        // pip itself ships with every interpreter we support
+        let executor = CodeExecutor::new("test_install_present").unwrap();
+        let report = executor.install_packages(&names(&["pip"]));
+        assert_eq!(report.already_satisfied, names(&["pip"]));
+        assert!(report.installed.is_empty() && report.failed.is_empty());
+        let _ = fs::remove_dir_all("test_install_present");
+    }
+
+    #[test]
+    fn test_install_report_batch_success() {
+        let mut calls = 0;
+        let report = InstallReport::build(&names(&["numpy", "requests"]), |_| false, |_| {
+            calls += 1;
+            Ok(())
+        });
+        assert_eq!(calls, 1);
+        assert_eq!(report.installed, names(&["numpy", "requests"]));
+        assert!(report.failed.is_empty());
+    }
+
+    #[test]
+    fn test_install_report_isolates_failures() {
+        let report = InstallReport::build(&names(&["numpy", "sklearnx", "requests"]), |_| false, |pkgs| {
+            if pkgs.iter().any(|p| p == "sklearnx") {
+                Err("No matching distribution found for sklearnx".to_string())
+            } else {
+                Ok(())
+            }
+        });
+        assert_eq!(report.installed, names(&["numpy", "requests"]));
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, "sklearnx");
+        // Partial success is not an error
+        assert!(report.into_result().is_ok());
+    }
+
+    #[test]
+    fn test_install_report_all_failed_is_error() {
+        let report = InstallReport::build(&names(&["a", "b"]), |_| false, |_| Err("offline".to_string()));
+        assert_eq!(report.failed.len(), 2);
+        let err = report.into_result().unwrap_err().to_string();
+        assert!(err.contains("a: offline") && err.contains("b: offline"));
+    }
+
     #[test]
     fn test_needs_interactive_mode_pygame() {
         let executor = CodeExecutor::new("test_temp").unwrap();
@@ -444,6 +2217,19 @@ mod tests {
         assert_ne!(ExecutionMode::Captured, ExecutionMode::Interactive);
     }
 
+    #[test]
+    fn test_headless_gui_without_xvfb_errors() {
+        if has_virtual_display() {
+            return;
+        }
+        let dir = "test_headless_gui";
+        let executor = CodeExecutor::new(dir).unwrap();
+        let path = executor.write_script("print('hi')").unwrap();
+        let result = executor.execute_script(&path, ExecutionMode::HeadlessGui, 30);
+        let _ = fs::remove_dir_all(dir);
+        assert!(result.unwrap_err().to_string().contains("Xvfb"));
+    }
+
     #[test]
     fn test_is_success_true() {
         let result = CodeExecutionResult {
@@ -451,6 +2237,7 @@ mod tests {
             stdout: "ok".to_string(),
             stderr: String::new(),
             exit_code: Some(0),
+            ..CodeExecutionResult::default()
         };
         assert!(result.is_success());
     }
@@ -462,6 +2249,7 @@ mod tests {
             stdout: String::new(),
             stderr: "error".to_string(),
             exit_code: Some(1),
+            ..CodeExecutionResult::default()
         };
         assert!(!result.is_success());
     }
@@ -473,6 +2261,7 @@ mod tests {
             stdout: String::new(),
             stderr: String::new(),
             exit_code: None,
+            ..CodeExecutionResult::default()
         };
         assert!(!result.is_success());
     }
@@ -483,10 +2272,519 @@ mod tests {
         let path = executor.write_script("print('hi')").unwrap();
         assert!(path.exists());
         let content = fs::read_to_string(&path).unwrap();
-        assert_eq!(content, "print('hi')");
+        assert_eq!(content, "print('hi')\n");
         let _ = fs::remove_dir_all("test_write_script_dir");
     }
 
+    #[test]
+    fn test_compile_files_reports_each_file() {
+        let dir = "test_compile_files_dir";
+        let executor = CodeExecutor::new(dir).unwrap();
+        let files = vec![
+            CodeFile { name: "main.py".to_string(), code: "from pkg.util import f\nprint(f())".to_string() },
+            CodeFile { name: "pkg/util.py".to_string(), code: "def f(:\n    return 1".to_string() },
+            CodeFile { name: "../escape.py".to_string(), code: "x = 1".to_string() },
+            CodeFile { name: "pyproject.toml".to_string(), code: "[project]\nname = \"demo\"".to_string() },
+        ];
+        let results = executor.compile_files(&files).unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0], ("main.py".to_string(), Ok(())));
+        assert!(results[1].1.as_ref().is_err_and(|e| e.contains("SyntaxError")), "{:?}", results[1]);
+        assert!(results[2].1.is_err());
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 0, "scratch directory left behind");
+        assert!(!Path::new("escape.py").exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_record_script_in_index() {
+        use crate::config::AppConfig;
+
+        let executor = CodeExecutor::new("test_record_script_dir").unwrap();
+        let provenance = Provenance::new(&AppConfig::default(), &[]);
+        let path = executor.write_script("print('hi')").unwrap();
+        executor.record_script(&path, &provenance, "say hi").unwrap();
+
+        let entries = index::load_index(Path::new("test_record_script_dir")).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].prompt, "say hi");
+        assert!(entries[0].script.starts_with("script_"));
+        let _ = fs::remove_dir_all("test_record_script_dir");
+    }
+
+    #[test]
+    fn test_concurrent_instances_share_a_generated_dir() {
+        use crate::config::AppConfig;
+        use std::collections::HashSet;
+
+        const INSTANCES: usize = 8;
+        const SCRIPTS: usize = 10;
+        let dir = "test_concurrent_generated_dir";
+        let _ = fs::remove_dir_all(dir);
+        let written: Vec<PathBuf> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..INSTANCES)
+                .map(|i| {
+                    scope.spawn(move || {
+                        // One executor per thread, as each terminal has its own
+                        let executor = CodeExecutor::new(dir).unwrap();
+                        let provenance = Provenance::new(&AppConfig::default(), &[]);
+                        (0..SCRIPTS)
+                            .map(|j| {
+                                let code = format!("print('{i}-{j}')");
+                                let path = executor.write_script(&code).unwrap();
+                                executor.record_script(&path, &provenance, &code).unwrap();
+                                path
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            workers.into_iter().flat_map(|w| w.join().unwrap()).collect()
+        });
+
+        let unique: HashSet<&PathBuf> = written.iter().collect();
+        assert_eq!(unique.len(), INSTANCES * SCRIPTS, "file names collided");
+        let entries = index::load_index(Path::new(dir)).unwrap();
+        assert_eq!(entries.len(), INSTANCES * SCRIPTS, "index entries were lost");
+        for entry in &entries {
+            // Each script still holds the code its entry was recorded with
+            let code = fs::read_to_string(Path::new(dir).join(&entry.script)).unwrap();
+            assert_eq!(code.trim(), entry.prompt);
+        }
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_dated_layout_is_recorded_relative_to_base_dir() {
+        use crate::config::AppConfig;
+
+        let dir = "test_dated_layout_dir";
+        let executor = CodeExecutor::new(dir).unwrap().with_layout("%Y/%m/%d/");
+        let path = executor.write_script("print('hi')").unwrap();
+        let day = Local::now().format("%Y/%m/%d").to_string();
+        assert_eq!(path.parent().unwrap(), Path::new(dir).join(&day));
+        executor.record_script(&path, &Provenance::new(&AppConfig::default(), &[]), "say hi").unwrap();
+
+        let entries = index::load_index(Path::new(dir)).unwrap();
+        let relative = PathBuf::from(&entries[0].script);
+        assert!(relative.starts_with(&day), "{}", entries[0].script);
+        assert_eq!(index::list_scripts(Path::new(dir)).unwrap(), vec![relative]);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_pin_requirements_stdlib_only() {
+        let dir = "test_pin_requirements_dir";
+        let executor = CodeExecutor::new(dir).unwrap();
+        assert!(executor.last_script().is_none());
+        let path = executor.write_script("import json\nprint(json.dumps(1))").unwrap();
+        assert_eq!(executor.last_script(), Some(path.clone()));
+        assert!(executor.pin_requirements(&path).unwrap().is_none());
+        assert!(!path.with_extension("requirements.txt").exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_clean_pycache() {
+        let dir = "test_clean_pycache_dir";
+        let _ = fs::remove_dir_all(dir);
+        let executor = CodeExecutor::new(dir).unwrap();
+        fs::create_dir_all(Path::new(dir).join("__pycache__")).unwrap();
+        fs::write(Path::new(dir).join("__pycache__/script.cpython-312.pyc"), b"\0").unwrap();
+        fs::create_dir_all(Path::new(dir).join("pkg/__pycache__")).unwrap();
+        fs::write(Path::new(dir).join("keep.py"), "print(1)").unwrap();
+
+        assert_eq!(executor.clean_pycache().unwrap(), 2);
+        assert!(!Path::new(dir).join("__pycache__").exists());
+        assert!(!Path::new(dir).join("pkg/__pycache__").exists());
+        assert!(Path::new(dir).join("pkg").exists());
+        assert!(Path::new(dir).join("keep.py").exists());
+        assert_eq!(executor.clean_pycache().unwrap(), 0);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_scripts_run_in_base_dir() {
+        let dir = "test_work_dir_default";
+        let _ = fs::remove_dir_all(dir);
+        let executor = CodeExecutor::new(dir).unwrap();
+        let result = executor
+            .write_and_run("open('output.txt', 'w').write('hello')")
+            .unwrap();
+        assert!(result.is_success(), "stderr: {}", result.stderr);
+        assert!(Path::new(dir).join("output.txt").exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_task_env_runs_its_own_interpreter() {
+        let dir = "test_task_env_exec";
+        let _ = fs::remove_dir_all(dir);
+        let mut executor = CodeExecutor::new(dir).unwrap();
+        let env = TaskEnv::new(Path::new(dir), "demo").unwrap();
+        if env.ensure().is_err() {
+            let _ = fs::remove_dir_all(dir);
+            return; // venv module not available here
+        }
+        executor.set_env(Some(env.clone()));
+        let result = executor.write_and_run("import sys; print(sys.prefix)").unwrap();
+        assert!(result.is_success(), "stderr: {}", result.stderr);
+        assert!(result.stdout.trim().ends_with("venv"), "{}", result.stdout);
+        assert!(env.manifest().unwrap().packages.is_empty());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_type_check() {
+        let executor = CodeExecutor::new("test_type_check").unwrap();
+        // None when mypy isn't installed
+        if let Some(errors) = executor.type_check("def f(x: int) -> int:\n    return 'a'\n") {
+            assert_eq!(errors.len(), 1, "{errors:?}");
+            assert_eq!(errors[0].0, 2);
+        }
+        let _ = fs::remove_dir_all("test_type_check");
+    }
+
+    #[test]
+    fn test_quick_eval() {
+        let dir = "test_quick_eval_dir";
+        let executor = CodeExecutor::new(dir).unwrap();
+        let result = executor.quick_eval("from datetime import date; date(2024, 1, 1).isocalendar()[:2]", 10).unwrap();
+        assert!(result.is_success(), "stderr: {}", result.stderr);
+        assert_eq!(result.stdout, "(2024, 1)\n");
+        // Statements only: nothing is echoed
+        let result = executor.quick_eval("x = 2; print(x * 3)", 10).unwrap();
+        assert_eq!(result.stdout, "6\n");
+        let result = executor.quick_eval("1 / 0", 10).unwrap();
+        assert_eq!(result.exit_code, Some(1));
+        assert!(result.stderr.trim_end().ends_with("ZeroDivisionError: division by zero"));
+        // Nothing written to the generated directory
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 0);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_signal_of_a_killed_script_is_recorded() {
+        let dir = "test_signal_killed_dir";
+        let _ = fs::remove_dir_all(dir);
+        let executor = CodeExecutor::new(dir).unwrap();
+        let pid_file = Path::new(dir).join("sleeper.pid");
+        let killer = std::thread::spawn({
+            let pid_file = pid_file.clone();
+            move || {
+                for _ in 0..200 {
+                    if let Some(pid) = fs::read_to_string(&pid_file).ok().and_then(|p| p.trim().parse().ok()) {
+                        let _ = nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid), nix::sys::signal::Signal::SIGKILL);
+                        return;
+                    }
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+            }
+        });
+        let code = "import os, time\nwith open('sleeper.pid.tmp', 'w') as f:\n    f.write(str(os.getpid()))\n\
+                    os.replace('sleeper.pid.tmp', 'sleeper.pid')\ntime.sleep(30)\n";
+        let result = executor.write_and_run(code).unwrap();
+        killer.join().unwrap();
+        assert_eq!(result.exit_code, None);
+        assert_eq!(result.signal, Some(nix::libc::SIGKILL));
+        assert_eq!(signal_name(nix::libc::SIGKILL), "SIGKILL");
+        assert_eq!(signal_name(11), "SIGSEGV");
+        assert_eq!(signal_name(999), "signal 999");
+
+        // A clean non-zero exit has a code and no signal
+        let result = executor.write_and_run("raise SystemExit(3)").unwrap();
+        assert_eq!((result.exit_code, result.signal), (Some(3), None));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_trial_run_reports_changes_and_leaves_the_directory_alone() {
+        let dir = "test_trial_run_dir";
+        let run_dir = Path::new("test_trial_run_work");
+        let _ = fs::remove_dir_all(dir);
+        let _ = fs::remove_dir_all(run_dir);
+        fs::create_dir_all(run_dir.join("data")).unwrap();
+        fs::write(run_dir.join("data/keep.txt"), "same").unwrap();
+        fs::write(run_dir.join("edit.txt"), "before").unwrap();
+        fs::write(run_dir.join("old.log"), "x").unwrap();
+
+        let executor = CodeExecutor::new(dir).unwrap().with_work_dir(run_dir);
+        let script = executor
+            .write_script("import os\nopen('edit.txt', 'w').write('after')\nos.remove('old.log')\nopen('data/new.csv', 'w').write('a,b')\n")
+            .unwrap();
+        if SandboxTool::detect().is_none() {
+            // Without a sandbox the script could write outside the copy: nothing runs
+            let err = executor.trial_run(&script, 10, 1024 * 1024).unwrap_err();
+            assert!(err.to_string().contains("needs firejail or bwrap"), "{err}");
+        } else {
+            let trial = executor.trial_run(&script, 10, 1024 * 1024).unwrap();
+            assert!(trial.result.is_success(), "stderr: {}", trial.result.stderr);
+            assert_eq!(trial.changes, FileChanges {
+                created: vec![PathBuf::from("data/new.csv")],
+                modified: vec![PathBuf::from("edit.txt")],
+                deleted: vec![PathBuf::from("old.log")],
+            });
+
+            // Over the size cap, nothing runs
+            let err = executor.trial_run(&script, 10, 4).unwrap_err();
+            assert!(err.to_string().contains("holds more than"), "{err}");
+        }
+        // The real directory is as it was
+        assert_eq!(fs::read_to_string(run_dir.join("edit.txt")).unwrap(), "before");
+        assert!(run_dir.join("old.log").exists() && !run_dir.join("data/new.csv").exists());
+        let _ = fs::remove_dir_all(dir);
+        let _ = fs::remove_dir_all(run_dir);
+    }
+
+    #[test]
+    fn test_file_changes_between_snapshots() {
+        let dir = Path::new("test_file_changes_dir");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir.join("data")).unwrap();
+        fs::write(dir.join("data/keep.txt"), "same").unwrap();
+        fs::write(dir.join("edit.txt"), "before").unwrap();
+        fs::write(dir.join("old.log"), "x").unwrap();
+        let before = snapshot(dir, u64::MAX, &|_| false).unwrap();
+        fs::write(dir.join("edit.txt"), "after").unwrap();
+        fs::remove_file(dir.join("old.log")).unwrap();
+        fs::write(dir.join("data/new.csv"), "a,b").unwrap();
+        let changes = FileChanges::between(&before, &snapshot(dir, u64::MAX, &|_| false).unwrap());
+        assert_eq!(changes.lines(), vec!["+ data/new.csv", "~ edit.txt", "– old.log"]);
+        let err = snapshot(dir, 4, &|_| false).unwrap_err();
+        assert!(err.to_string().contains("holds more than"), "{err}");
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_snapshot_leaves_out_generated_files() {
+        let dir = Path::new("test_snapshot_generated_dir");
+        let _ = fs::remove_dir_all(dir);
+        for sub in ["2024/05/01", "envs/game/venv", "results"] {
+            fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        for file in [
+            "2024/05/01/script_1.py",
+            "2024/05/01/script_1.requirements.txt",
+            "envs/game/venv/big.bin",
+            "index.json",
+            ".index.lock",
+            "results/out.csv",
+            "notes.txt",
+        ] {
+            fs::write(dir.join(file), "x").unwrap();
+        }
+        let scripts: BTreeSet<PathBuf> = index::list_scripts(dir).unwrap().into_iter().collect();
+        let files = snapshot(dir, u64::MAX, &|relative: &Path| is_generated_file(relative, &scripts)).unwrap();
+        assert_eq!(files.keys().cloned().collect::<Vec<_>>(), vec![PathBuf::from("notes.txt"), PathBuf::from("results/out.csv")]);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_parse_result() {
+        assert_eq!(parse_result("no markers\n"), None);
+        let stdout = "working...\n===PMB_RESULT===\n{\"total\": 3}\n===PMB_RESULT===\ndone\n";
+        assert_eq!(parse_result(stdout), Some(Ok(serde_json::json!({"total": 3}))));
+        // Several pairs: the last one wins, even with an unclosed marker after it
+        let stdout = "===PMB_RESULT===\n1\n===PMB_RESULT===\n===PMB_RESULT===\n[2]\n===PMB_RESULT===\n===PMB_RESULT===\n";
+        assert_eq!(parse_result(stdout), Some(Ok(serde_json::json!([2]))));
+        let err = parse_result("===PMB_RESULT===\n{'a': 1}\n===PMB_RESULT===\n").unwrap().unwrap_err();
+        assert!(err.starts_with("invalid JSON"), "{err}");
+        let err = parse_result("===PMB_RESULT===\n{}\n").unwrap().unwrap_err();
+        assert!(err.contains("without a closing marker"), "{err}");
+    }
+
+    #[test]
+    fn test_capture_result_only_when_enabled() {
+        let dir = "test_capture_result_dir";
+        let code = "import json\nprint('===PMB_RESULT===')\nprint(json.dumps({'rows': [1, 2]}))\nprint('===PMB_RESULT===')\n";
+        let plain = CodeExecutor::new(dir).unwrap();
+        let result = plain.write_and_run(code).unwrap();
+        assert!(result.result.is_none() && result.result_error.is_none());
+
+        let capturing = CodeExecutor::new(dir).unwrap().with_run_options(RunOptions { capture_result: true, ..RunOptions::default() });
+        let result = capturing.write_and_run(code).unwrap();
+        assert!(result.is_success(), "stderr: {}", result.stderr);
+        assert_eq!(result.result, Some(serde_json::json!({"rows": [1, 2]})));
+        let result = capturing.write_and_run("print('===PMB_RESULT===')\nprint('nope')\nprint('===PMB_RESULT===')\n").unwrap();
+        assert!(result.result.is_none());
+        assert!(result.result_error.is_some());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_capture_result_reads_the_output_before_collapsing() {
+        let dir = "test_capture_result_raw_dir";
+        let code = "import json\nfor i in range(1, 21):\n    print(f'step {i}/20')\nprint('===PMB_RESULT===')\nprint(json.dumps({'steps': 20}))\nprint('===PMB_RESULT===')\n";
+        let executor = CodeExecutor::new(dir).unwrap().with_run_options(RunOptions { capture_result: true, ..RunOptions::default() });
+        let result = executor.write_and_run(code).unwrap();
+        assert!(result.is_success(), "stderr: {}", result.stderr);
+        assert_eq!(result.result, Some(serde_json::json!({"steps": 20})));
+        assert!(result.stdout.starts_with("step 20/20\n"), "stdout: {}", result.stdout);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_run_module_resolves_package_imports() {
+        let dir = "test_run_module_dir";
+        let project = Path::new("test_run_module_project");
+        let _ = fs::remove_dir_all(project);
+        fs::create_dir_all(project.join("demo")).unwrap();
+        fs::write(project.join("demo/__init__.py"), "").unwrap();
+        fs::write(project.join("demo/util.py"), "def greet():\n    return 'hi'\n").unwrap();
+        fs::write(project.join("demo/main.py"), "from demo.util import greet\nprint(greet())\n").unwrap();
+
+        let executor = CodeExecutor::new(dir).unwrap();
+        let result = executor.run_module(project, "demo.main", 10).unwrap();
+        assert!(result.is_success(), "stderr: {}", result.stderr);
+        assert_eq!(result.stdout, "hi\n");
+        assert!(result.script_path.is_absolute() && result.script_path.ends_with(project));
+        let _ = fs::remove_dir_all(project);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_with_work_dir_overrides_base_dir() {
+        let dir = "test_work_dir_override";
+        let run_dir = "test_work_dir_override_run";
+        let _ = fs::remove_dir_all(dir);
+        let _ = fs::remove_dir_all(run_dir);
+        fs::create_dir_all(run_dir).unwrap();
+
+        let executor = CodeExecutor::new(dir).unwrap().with_work_dir(run_dir);
+        assert_eq!(executor.work_dir(), Path::new(run_dir));
+        let result = executor
+            .write_and_run("open('output.txt', 'w').write('hello')")
+            .unwrap();
+        assert!(result.is_success(), "stderr: {}", result.stderr);
+        assert!(Path::new(run_dir).join("output.txt").exists());
+        assert!(!Path::new(dir).join("output.txt").exists());
+
+        let _ = fs::remove_dir_all(dir);
+        let _ = fs::remove_dir_all(run_dir);
+    }
+
+    #[test]
+    fn test_work_dir_reads_user_files() {
+        let dir = "test_work_dir_user_files";
+        let run_dir = "test_work_dir_user_files_data";
+        let _ = fs::remove_dir_all(dir);
+        let _ = fs::remove_dir_all(run_dir);
+        fs::create_dir_all(run_dir).unwrap();
+        fs::write(Path::new(run_dir).join("data.csv"), "a,b\n1,2\n").unwrap();
+
+        let executor = CodeExecutor::new(dir).unwrap().with_work_dir(run_dir);
+        let result = executor
+            .write_and_run("print(len(open('data.csv').readlines()))")
+            .unwrap();
+        assert!(result.is_success(), "stderr: {}", result.stderr);
+        assert_eq!(result.stdout.trim(), "2");
+        // The script itself still lives (and is reported) under the generated dir
+        assert!(result.script_path.starts_with(dir));
+        assert!(result.script_path.exists());
+
+        let _ = fs::remove_dir_all(dir);
+        let _ = fs::remove_dir_all(run_dir);
+    }
+
+    #[test]
+    fn test_installed_modules_batch() {
+        let executor = CodeExecutor::new("test_installed_modules_batch").unwrap();
+        let modules = vec!["json".to_string(), "surely_not_a_real_module_xyz".to_string(), "os.path".to_string()];
+        let found = executor.installed_modules(&modules).unwrap();
+        assert_eq!(found.get("json"), Some(&true));
+        assert_eq!(found.get("surely_not_a_real_module_xyz"), Some(&false));
+        assert_eq!(found.get("os.path"), Some(&true));
+        let _ = fs::remove_dir_all("test_installed_modules_batch");
+    }
+
+    #[test]
+    fn test_dependency_report() {
+        let dir = "test_dependency_report";
+        let executor = CodeExecutor::new(dir).unwrap();
+        let rows = executor.dependency_report("import os\nimport surely_not_a_real_module_xyz\nimport yaml\n");
+        let modules: Vec<&str> = rows.iter().map(|r| r.module.as_str()).collect();
+        assert_eq!(modules, vec!["os", "surely_not_a_real_module_xyz", "yaml"]);
+        assert_eq!((rows[0].status, rows[0].pip.as_deref()), (DepStatus::Stdlib, None));
+        assert_eq!(rows[1].status, DepStatus::Missing);
+        assert_eq!(rows[1].pip.as_deref(), Some("surely_not_a_real_module_xyz"));
+        // Installed or not depends on the machine; the PyPI name doesn't
+        assert!(matches!(rows[2].status, DepStatus::Installed | DepStatus::Missing));
+        assert_eq!(rows[2].pip.as_deref(), Some("PyYAML"));
+        assert!(executor.dependency_report("x = 1").is_empty());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_execute_with_input() {
+        let dir = "test_execute_with_input";
+        let executor = CodeExecutor::new(dir).unwrap();
+        let path = executor.write_script("a = int(input())\nb = int(input())\nprint(a + b)").unwrap();
+        let result = executor.execute_with_input(&path, "3\n4", 10).unwrap();
+        assert!(result.is_success(), "stderr: {}", result.stderr);
+        assert_eq!(result.stdout.trim(), "7");
+
+        // A script that ignores stdin still finishes
+        let path = executor.write_script("print('hi')").unwrap();
+        assert_eq!(executor.execute_with_input(&path, "unused", 10).unwrap().stdout.trim(), "hi");
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_classify_output() {
+        assert_eq!(classify_output(b"hello\n"), OutputKind::Utf8);
+        assert_eq!(classify_output("héllo".as_bytes()), OutputKind::Utf8);
+        // Latin-1 "café" in a longer line: mostly text
+        let mut latin1 = b"the price at the caf".to_vec();
+        latin1.extend_from_slice(&[0xE9, b'\n']);
+        assert_eq!(classify_output(&latin1), OutputKind::Text);
+        assert_eq!(classify_output(&[0u8; 64]), OutputKind::Binary);
+        let noise: Vec<u8> = (0..=255u8).cycle().take(2048).collect();
+        assert_eq!(classify_output(&noise), OutputKind::Binary);
+    }
+
+    #[test]
+    fn test_decode_cp1252() {
+        assert_eq!(decode_cp1252(&[0x80, b' ', 0xE9, 0x93, b'x', 0x94]), "€ é“x”");
+    }
+
+    #[test]
+    fn test_binary_output_saved_to_companion_file() {
+        let dir = "test_binary_output";
+        let executor = CodeExecutor::new(dir).unwrap();
+        let result = executor
+            .write_and_run("import sys\nsys.stdout.buffer.write(bytes(range(256)) * 64)")
+            .unwrap();
+        assert!(result.is_success(), "stderr: {}", result.stderr);
+        let OutputEncoding::Binary { saved_to } = &result.output_encoding else {
+            panic!("expected binary output, got {:?}", result.output_encoding);
+        };
+        assert_eq!(saved_to.len(), 1);
+        assert_eq!(fs::read(&saved_to[0]).unwrap().len(), 256 * 64);
+        assert!(result.stdout.starts_with("[binary or non-UTF-8 output, 16.0 KB"));
+        assert!(result.stderr.is_empty());
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_sandbox_status() {
+        let dir = "test_sandbox_status";
+        let executor = CodeExecutor::new(dir).unwrap();
+        assert_eq!(executor.sandbox_status(), SandboxStatus::Disabled);
+
+        let executor = executor.with_sandbox(Sandbox::Required);
+        match SandboxTool::detect() {
+            Some(tool) => assert_eq!(executor.sandbox_status(), SandboxStatus::Active(tool)),
+            None => {
+                assert_eq!(executor.sandbox_status(), SandboxStatus::Unavailable { required: true });
+                // Refuses rather than silently running unsandboxed
+                let path = executor.write_script("print('hi')").unwrap();
+                assert!(executor.execute_script(&path, ExecutionMode::Captured, 5).is_err());
+            }
+        }
+        let _ = fs::remove_dir_all(dir);
+    }
+
     #[test]
     fn test_syntax_check_valid() {
         let executor = CodeExecutor::new("test_syntax_valid").unwrap();
@@ -503,6 +2801,75 @@ mod tests {
         let _ = fs::remove_dir_all("test_syntax_invalid");
     }
 
+    #[test]
+    fn test_streamed_mode_captures_both_streams() {
+        let executor = CodeExecutor::new("test_streamed_dir").unwrap();
+        let path = executor
+            .write_script("import sys\nfor i in range(3):\n    print('step', i)\nprint('oops', file=sys.stderr)\nsys.exit(2)")
+            .unwrap();
+        let result = executor.execute_script(&path, ExecutionMode::Streamed, 10).unwrap();
+        assert_eq!(result.stdout, "step 0\nstep 1\nstep 2\n");
+        assert_eq!(result.stderr, "oops\n");
+        assert_eq!(result.exit_code, Some(2));
+        let _ = fs::remove_dir_all("test_streamed_dir");
+    }
+
+    #[test]
+    fn test_live_rate_coalesces_bursts() {
+        let start = Instant::now();
+        let mut rate = LiveRate::new(start);
+        let shown = (0..LIVE_LINES_PER_WINDOW + 10).filter(|_| rate.admit(start).0).count();
+        assert_eq!(shown, LIVE_LINES_PER_WINDOW);
+        // The next window reports the 10 held-back lines before printing again
+        assert_eq!(rate.admit(start + LIVE_WINDOW), (true, 10));
+        assert_eq!(rate.admit(start + LIVE_WINDOW), (true, 0));
+    }
+
+    #[test]
+    fn test_streamed_flood_is_coalesced_and_capped() {
+        let executor = CodeExecutor::new("test_streamed_flood_dir").unwrap();
+        let path = executor
+            .write_script("for i in range(200_000):\n    print(f'{i:09d}')")
+            .unwrap();
+        let result = executor.execute_script(&path, ExecutionMode::Streamed, 30).unwrap();
+        assert!(result.is_success(), "stderr: {}", result.stderr);
+        assert!(result.stream_limits.coalesced_lines > 0);
+        assert!(result.stream_limits.truncated);
+        assert!(result.stdout.len() <= MAX_CAPTURE_BYTES);
+        assert!(result.stdout.starts_with("000000000\n000000001\n"));
+        let _ = fs::remove_dir_all("test_streamed_flood_dir");
+    }
+
+    #[test]
+    fn test_progress_bars_collapse_in_captured_and_streamed_runs() {
+        let code = "import sys\nprint('start')\nfor i in range(301):\n    sys.stderr.write(f'\\r{i * 100 // 300:3d}%| {i}/300')\nsys.stderr.write('\\n')\nfor i in range(1, 51):\n    print(f'batch {i}/50')\nprint('done')";
+        let executor = CodeExecutor::new("test_progress_dir").unwrap();
+        let path = executor.write_script(code).unwrap();
+        for mode in [ExecutionMode::Captured, ExecutionMode::Streamed] {
+            let result = executor.execute_script(&path, mode, 30).unwrap();
+            assert!(result.is_success(), "stderr: {}", result.stderr);
+            assert_eq!(result.stdout, "start\nbatch 50/50\ndone\n");
+            assert_eq!(result.stderr, "100%| 300/300\n");
+        }
+
+        let raw = CodeExecutor::new("test_progress_dir").unwrap().with_run_options(RunOptions { collapse_progress: false, ..RunOptions::default() });
+        let result = raw.execute_script(&path, ExecutionMode::Captured, 30).unwrap();
+        assert_eq!(result.stdout.lines().count(), 52);
+        assert_eq!(result.stderr.matches('\r').count(), 301);
+        let _ = fs::remove_dir_all("test_progress_dir");
+    }
+
+    #[test]
+    fn test_streamed_mode_timeout_keeps_partial_output() {
+        let executor = CodeExecutor::new("test_streamed_timeout_dir").unwrap();
+        let path = executor.write_script("import time\nprint('started')\ntime.sleep(10)").unwrap();
+        let result = executor.execute_script(&path, ExecutionMode::Streamed, 1).unwrap();
+        assert!(result.timed_out);
+        assert_eq!(result.stdout, "started\n");
+        assert!(result.stderr.contains("timed out after 1 seconds"));
+        let _ = fs::remove_dir_all("test_streamed_timeout_dir");
+    }
+
     #[test]
     fn test_execution_timeout() {
         let executor = CodeExecutor::new("test_timeout_dir").unwrap();
@@ -510,6 +2877,63 @@ mod tests {
         let result = executor.execute_script(&path, ExecutionMode::Captured, 2).unwrap();
         assert!(!result.is_success());
         assert!(result.stderr.contains("timed out"));
+        assert!(result.timed_out);
         let _ = fs::remove_dir_all("test_timeout_dir");
     }
+
+    /// Processes of `group` that are still running (zombies waiting for init don't count).
+    #[cfg(target_os = "linux")]
+    fn live_group_members(group: i32) -> Vec<i32> {
+        fs::read_dir("/proc")
+            .unwrap()
+            .flatten()
+            .filter_map(|entry| {
+                let pid: i32 = entry.file_name().to_str()?.parse().ok()?;
+                let stat = fs::read_to_string(entry.path().join("stat")).ok()?;
+                // pid (comm) state ppid pgrp ...; comm may contain spaces
+                let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+                (fields.first() != Some(&"Z") && fields.get(2)?.parse::<i32>().ok()? == group).then_some(pid)
+            })
+            .collect()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_timeout_kills_the_whole_process_group() {
+        let executor = CodeExecutor::new("test_group_kill_dir").unwrap();
+        let path = executor
+            .write_script(
+                "import os, subprocess, sys, time\n\
+                 child = subprocess.Popen([sys.executable, '-c', 'import time; time.sleep(60)'])\n\
+                 with open('group.txt', 'w') as f:\n    \
+                     f.write(f'{os.getpgid(0)} {child.pid}')\n\
+                 time.sleep(60)\n",
+            )
+            .unwrap();
+        let result = executor.execute_script(&path, ExecutionMode::Captured, 2).unwrap();
+        assert!(result.timed_out);
+        assert!(result.reaped_descendants);
+
+        let ids = fs::read_to_string(executor.work_dir().join("group.txt")).unwrap();
+        let (group, grandchild) = ids.split_once(' ').unwrap();
+        let (group, grandchild): (i32, i32) = (group.parse().unwrap(), grandchild.parse().unwrap());
+        assert_ne!(group, std::process::id() as i32, "the script must not share the bot's group");
+        // SIGKILL is delivered asynchronously: give the kernel a moment
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !live_group_members(group).is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        assert!(live_group_members(group).is_empty(), "grandchild {grandchild} survived the timeout");
+        let _ = fs::remove_dir_all("test_group_kill_dir");
+    }
+
+    #[test]
+    fn test_timeout_without_descendants_reaps_nothing_extra() {
+        let executor = CodeExecutor::new("test_group_kill_alone_dir").unwrap();
+        let path = executor.write_script("import time\ntime.sleep(10)").unwrap();
+        let result = executor.execute_script(&path, ExecutionMode::Streamed, 1).unwrap();
+        assert!(result.timed_out);
+        assert!(!result.reaped_descendants);
+        let _ = fs::remove_dir_all("test_group_kill_alone_dir");
+    }
 }
@@ -0,0 +1,288 @@
+//! Shareable session "recipes": the ordered user prompts of a session plus the generation
+//! settings, so someone else can replay them (`/recipe`, `recipe run <file>`).
+
+use crate::api::{self, Message};
+use crate::cli::CliArgs;
+use crate::config::AppConfig;
+use crate::interface::{apply_hooks, configure_executor, open_logger, pre_run_gate};
+use crate::logger::Logger;
+use crate::provenance::{render_script, Provenance};
+use crate::python_exec::{CodeExecutor, ExecutionMode};
+use crate::theme::Themed;
+use crate::utils::extract_python_code;
+use anyhow::{anyhow, Context, Result};
+use colored::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Execution limit for replayed steps when `execution_timeout_secs` is 0 (no limit).
+const REPLAY_TIMEOUT_SECS: u64 = 60;
+
+/// One request of the recorded session.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecipeStep {
+    pub prompt: String,
+    /// Exit code of the step's code in the original session; unset when it wasn't run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+}
+
+/// The YAML file written by `/recipe export`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Recipe {
+    pub model: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    pub temperature: f64,
+    pub max_tokens: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub steps: Vec<RecipeStep>,
+}
+
+/// `recipe run <file> [--keep-going] [--yes]`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecipeRun {
+    pub path: PathBuf,
+    /// Continue with the next step after a failure instead of stopping.
+    pub keep_going: bool,
+    /// Run each step's code without asking first.
+    pub yes: bool,
+}
+
+impl Recipe {
+    pub fn from_session(config: &AppConfig, steps: &[RecipeStep]) -> Self {
+        Self {
+            model: config.model.clone(),
+            profile: config.profile.clone(),
+            // Through the decimal form, so 0.2 is written as 0.2 and not 0.20000000298
+            temperature: config.temperature.to_string().parse().unwrap_or_default(),
+            max_tokens: config.max_tokens,
+            seed: config.seed,
+            steps: steps.to_vec(),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = serde_yaml::to_string(self).context("Could not serialize the recipe")?;
+        fs::write(path, text).with_context(|| format!("Could not write {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+        serde_yaml::from_str(&text).with_context(|| format!("Could not parse recipe {}", path.display()))
+    }
+
+    /// The current configuration with the recipe's generation settings. The model stays
+    /// the current one: replaying against another model is the point.
+    pub fn apply(&self, config: &AppConfig) -> AppConfig {
+        AppConfig {
+            profile: self.profile.clone(),
+            temperature: self.temperature as f32,
+            max_tokens: self.max_tokens,
+            seed: self.seed,
+            ..config.clone()
+        }
+    }
+}
+
+/// What happened to one step during a replay.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepOutcome {
+    pub prompt: String,
+    pub original: Option<i32>,
+    pub replayed: Option<i32>,
+    /// Generation or execution failure, when there is no exit code to compare.
+    pub error: Option<String>,
+}
+
+impl StepOutcome {
+    /// Same exit code as the original session (steps that never ran there always match).
+    pub fn matches(&self) -> bool {
+        self.error.is_none() && (self.original.is_none() || self.original == self.replayed)
+    }
+}
+
+/// The final replay report: one line per step, then a count of matching steps.
+pub fn report(outcomes: &[StepOutcome], total_steps: usize) -> String {
+    let code = |c: Option<i32>| c.map_or("-".to_string(), |c| c.to_string());
+    let mut out = String::new();
+    for (i, o) in outcomes.iter().enumerate() {
+        let mark = if o.matches() { "✓" } else { "✗" };
+        let result = match &o.error {
+            Some(e) => format!("error: {e}"),
+            None => format!("exit {} (original {})", code(o.replayed), code(o.original)),
+        };
+        let prompt: String = o.prompt.split_whitespace().collect::<Vec<_>>().join(" ");
+        let prompt = match prompt.char_indices().nth(50) {
+            Some((cut, _)) => format!("{}…", &prompt[..cut]),
+            None => prompt,
+        };
+        out.push_str(&format!("{mark} {}. {prompt} — {result}\n", i + 1));
+    }
+    let matching = outcomes.iter().filter(|o| o.matches()).count();
+    out.push_str(&format!("{matching}/{total_steps} step(s) matched the original session"));
+    if outcomes.len() < total_steps {
+        out.push_str(&format!(", {} not run", total_steps - outcomes.len()));
+    }
+    out
+}
+
+/// Replay every prompt against the current model, running each step's code captured
+/// through the same hooks and pre-run gate as the REPL. Each run is asked for unless
+/// `run.yes`. Dependencies are not installed: the replay environment is used as it is.
+pub async fn replay(
+    recipe: &Recipe,
+    config: &AppConfig,
+    executor: &CodeExecutor,
+    logger: &Logger,
+    run: &RecipeRun,
+    allow_dangerous: bool,
+) -> Result<Vec<StepOutcome>> {
+    let config = recipe.apply(config);
+    let timeout = match config.execution_timeout_secs {
+        0 => REPLAY_TIMEOUT_SECS,
+        secs => secs,
+    };
+    let mut history: Vec<Message> = Vec::new();
+    let mut outcomes = Vec::new();
+    for (i, step) in recipe.steps.iter().enumerate() {
+        println!("{}", format!("▶ Step {}/{}: {}", i + 1, recipe.steps.len(), step.prompt.lines().next().unwrap_or_default()).heading_color());
+        history.push(Message { role: "user".to_string(), content: step.prompt.clone() });
+        let mut outcome = StepOutcome { prompt: step.prompt.clone(), original: step.exit_code, replayed: None, error: None };
+        let step_run = StepRun { logger, allow_dangerous, yes: run.yes, timeout };
+        match run_step(executor, &config, &history, &step_run).await {
+            Ok((code, exit_code)) => {
+                history.push(Message { role: "assistant".to_string(), content: code });
+                outcome.replayed = exit_code;
+                if exit_code.is_none() {
+                    outcome.error = Some(format!("timed out after {timeout}s"));
+                }
+            }
+            Err(e) => outcome.error = Some(format!("{e:#}")),
+        }
+        let failed = outcome.error.is_some() || outcome.replayed.is_some_and(|c| c != 0);
+        outcomes.push(outcome);
+        if failed && !run.keep_going {
+            break;
+        }
+    }
+    Ok(outcomes)
+}
+
+/// How `run_step` runs a step's code.
+struct StepRun<'a> {
+    logger: &'a Logger,
+    allow_dangerous: bool,
+    yes: bool,
+    timeout: u64,
+}
+
+/// Generate one step's code and run it; returns the code and its exit code (`None` on timeout).
+async fn run_step(executor: &CodeExecutor, config: &AppConfig, history: &[Message], run: &StepRun<'_>) -> Result<(String, Option<i32>)> {
+    let generation = api::generate_code_with_history(history.to_vec(), config).await?;
+    let code = extract_python_code(&generation.content);
+    if code.trim().is_empty() {
+        return Err(anyhow!("the model returned no code"));
+    }
+    let code = apply_hooks(&code, config, run.logger).ok_or_else(|| anyhow!("rejected by a post-generation hook"))?;
+    let provenance = Provenance::new(config, &api::full_messages(history, config.profile.as_deref(), &config.style));
+    let script = executor.write_script(&render_script(&code, Some(&provenance), config))?;
+    let question = (!run.yes).then_some("Run this step's code?");
+    if !pre_run_gate(executor, &code, Some(&script), config, run.allow_dangerous, run.logger, question) {
+        return Err(anyhow!("not run"));
+    }
+    let result = executor.execute_script(&script, ExecutionMode::Captured, run.timeout)?;
+    Ok((code, result.exit_code))
+}
+
+/// `recipe run <file>`: replay and print the report; true when every step matched.
+pub async fn run_cli(run: &RecipeRun, config: &AppConfig, cli: &CliArgs) -> bool {
+    let recipe = match Recipe::load(&run.path) {
+        Ok(recipe) => recipe,
+        Err(e) => {
+            eprintln!("{} {:#}", "✗".error_color(), e);
+            return false;
+        }
+    };
+    println!(
+        "{}",
+        format!("Replaying {} step(s) recorded with {} against {}", recipe.steps.len(), recipe.model, config.model).dimmed()
+    );
+    let executor = match CodeExecutor::new(&config.generated_dir) {
+        Ok(executor) => configure_executor(executor, config),
+        Err(e) => {
+            eprintln!("{} {:#}", "✗".error_color(), e);
+            return false;
+        }
+    };
+    let logger = open_logger(config, cli);
+    match replay(&recipe, config, &executor, &logger, run, cli.allow_dangerous).await {
+        Ok(outcomes) => {
+            println!("\n{}", report(&outcomes, recipe.steps.len()));
+            outcomes.len() == recipe.steps.len() && outcomes.iter().all(StepOutcome::matches)
+        }
+        Err(e) => {
+            eprintln!("{} {:#}", "✗".error_color(), e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Recipe {
+        Recipe::from_session(
+            &AppConfig { seed: Some(7), profile: Some("data".to_string()), ..AppConfig::default() },
+            &[
+                RecipeStep { prompt: "write a csv summarizer".to_string(), exit_code: Some(0) },
+                RecipeStep { prompt: "Please refine the previous code: add a --top flag".to_string(), exit_code: None },
+            ],
+        )
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let recipe = sample();
+        let text = serde_yaml::to_string(&recipe).unwrap();
+        assert!(text.contains("temperature: 0.2\n"), "{text}");
+        assert!(text.contains("steps:\n- prompt: write a csv summarizer\n"), "{text}");
+        let back: Recipe = serde_yaml::from_str(&text).unwrap();
+        assert_eq!(back, recipe);
+
+        let path = PathBuf::from("test_recipe_round_trip.yaml");
+        recipe.save(&path).unwrap();
+        assert_eq!(Recipe::load(&path).unwrap(), recipe);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_apply_keeps_current_model() {
+        let current = AppConfig { model: "other/model".to_string(), ..AppConfig::default() };
+        let config = sample().apply(&current);
+        assert_eq!(config.model, "other/model");
+        assert_eq!(config.seed, Some(7));
+        assert_eq!(config.profile.as_deref(), Some("data"));
+    }
+
+    #[test]
+    fn test_report() {
+        let outcome = |original, replayed, error: Option<&str>| StepOutcome {
+            prompt: "step".to_string(),
+            original,
+            replayed,
+            error: error.map(String::from),
+        };
+        let outcomes = vec![outcome(Some(0), Some(0), None), outcome(None, Some(1), None), outcome(Some(0), Some(2), None)];
+        let text = report(&outcomes, 4);
+        assert!(text.contains("✓ 1. step — exit 0 (original 0)"), "{text}");
+        assert!(text.contains("✓ 2. step — exit 1 (original -)"), "{text}");
+        assert!(text.contains("✗ 3. step — exit 2 (original 0)"), "{text}");
+        assert!(text.ends_with("2/4 step(s) matched the original session, 1 not run"), "{text}");
+        assert!(!outcome(None, None, Some("no code")).matches());
+    }
+}
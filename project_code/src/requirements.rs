@@ -0,0 +1,140 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// One entry of `pip list --format json` (extra fields such as
+/// `editable_project_location` are ignored).
+#[derive(Debug, Deserialize)]
+struct PipListEntry {
+    name: String,
+    version: String,
+}
+
+/// Package names compare case-insensitively with `-`, `_` and `.` equivalent (PEP 503).
+pub fn normalize_package(name: &str) -> String {
+    name.trim().to_lowercase().replace(['_', '.'], "-")
+}
+
+/// Installed versions from `pip list --format json` (or `uv pip list --format json`),
+/// keyed by normalized package name.
+pub fn parse_pip_list(json: &str) -> Result<BTreeMap<String, String>> {
+    let entries: Vec<PipListEntry> = serde_json::from_str(json).context("Unexpected `pip list` output")?;
+    Ok(entries
+        .into_iter()
+        .map(|e| (normalize_package(&e.name), e.version))
+        .collect())
+}
+
+/// Direct dependencies of each package from `pip show a b ...`, keyed by normalized name.
+pub fn parse_pip_show_requires(output: &str) -> BTreeMap<String, Vec<String>> {
+    let mut requires = BTreeMap::new();
+    for block in output.split("\n---") {
+        let field = |key: &str| {
+            block
+                .lines()
+                .find_map(|line| line.strip_prefix(key))
+                .map(str::trim)
+        };
+        if let Some(name) = field("Name:") {
+            let deps = field("Requires:")
+                .unwrap_or_default()
+                .split(',')
+                .map(normalize_package)
+                .filter(|d| !d.is_empty())
+                .collect();
+            requires.insert(normalize_package(name), deps);
+        }
+    }
+    requires
+}
+
+/// Pins for `packages` and their direct dependencies, in order, without duplicates.
+/// Packages missing from `installed` are returned separately.
+pub fn pin(
+    packages: &[String],
+    installed: &BTreeMap<String, String>,
+    requires: &BTreeMap<String, Vec<String>>,
+) -> (Vec<(String, String)>, Vec<String>) {
+    let mut pins: Vec<(String, String)> = Vec::new();
+    let mut missing = Vec::new();
+    let add = |name: &str, pins: &mut Vec<(String, String)>| -> bool {
+        let name = normalize_package(name);
+        if pins.iter().any(|(n, _)| *n == name) {
+            return true;
+        }
+        match installed.get(&name) {
+            Some(version) => {
+                pins.push((name, version.clone()));
+                true
+            }
+            None => false,
+        }
+    };
+    for package in packages {
+        if !add(package, &mut pins) {
+            missing.push(package.clone());
+            continue;
+        }
+        for dep in requires.get(&normalize_package(package)).into_iter().flatten() {
+            add(dep, &mut pins);
+        }
+    }
+    (pins, missing)
+}
+
+/// The pinned requirements file: a header with the script and Python version, then
+/// `package==version` lines; packages that weren't installed are listed as comments.
+pub fn render(script: &str, python_version: &str, pins: &[(String, String)], missing: &[String]) -> String {
+    let mut out = format!(
+        "# Pinned from the environment that ran {script}\n# Python {python_version}\n"
+    );
+    for (name, version) in pins {
+        out.push_str(&format!("{name}=={version}\n"));
+    }
+    for name in missing {
+        out.push_str(&format!("# {name}: not installed, left unpinned\n{name}\n"));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIP_LIST: &str = include_str!("../tests/fixtures/pip_list.json");
+    const PIP_SHOW: &str = include_str!("../tests/fixtures/pip_show.txt");
+
+    #[test]
+    fn test_parse_pip_list_fixture() {
+        let installed = parse_pip_list(PIP_LIST).unwrap();
+        assert_eq!(installed.get("requests").map(String::as_str), Some("2.32.3"));
+        assert_eq!(installed.get("pillow").map(String::as_str), Some("11.0.0"));
+        assert_eq!(installed.get("typing-extensions").map(String::as_str), Some("4.12.2"));
+        assert_eq!(installed.get("mylib").map(String::as_str), Some("0.1.0"));
+        assert!(parse_pip_list("not json").is_err());
+        assert!(parse_pip_list("[]").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_pip_show_fixture() {
+        let requires = parse_pip_show_requires(PIP_SHOW);
+        assert_eq!(requires["requests"], vec!["certifi", "charset-normalizer", "idna", "urllib3"]);
+        assert!(requires["pillow"].is_empty());
+    }
+
+    #[test]
+    fn test_pin_and_render() {
+        let installed = parse_pip_list(PIP_LIST).unwrap();
+        let requires = parse_pip_show_requires(PIP_SHOW);
+        let packages = vec!["requests".to_string(), "Pillow".to_string(), "pandas".to_string()];
+        let (pins, missing) = pin(&packages, &installed, &requires);
+        let names: Vec<&str> = pins.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, vec!["requests", "certifi", "charset-normalizer", "idna", "urllib3", "pillow"]);
+        assert_eq!(missing, vec!["pandas"]);
+
+        let file = render("script_1.py", "3.12.1", &pins, &missing);
+        assert!(file.starts_with("# Pinned from the environment that ran script_1.py\n# Python 3.12.1\n"));
+        assert!(file.contains("requests==2.32.3\n"));
+        assert!(file.contains("# pandas: not installed, left unpinned\npandas\n"));
+    }
+}
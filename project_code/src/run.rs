@@ -0,0 +1,170 @@
+use crate::config::AppConfig;
+use crate::python_exec::{CodeExecutor, Sandbox};
+use crate::theme::Themed;
+use std::io::{ErrorKind, Read};
+use std::path::PathBuf;
+use std::process::{Child, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+use wait_timeout::ChildExt;
+
+/// Exit code when `--timeout` stopped the script (same as coreutils `timeout`).
+pub const EXIT_TIMEOUT: i32 = 124;
+/// Exit code when neither `python3` nor `python` could be started.
+pub const EXIT_NO_INTERPRETER: i32 = 127;
+/// Exit code for problems before the script ran (missing file, sandbox unavailable...).
+pub const EXIT_SETUP: i32 = 2;
+
+/// `run <script> [--quiet] [--json] [--timeout <secs>]`: execute a script without the REPL.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunCommand {
+    pub script: PathBuf,
+    /// Only the script's own stdout/stderr, no banner or summary.
+    pub quiet: bool,
+    /// Capture the output and print one JSON object instead.
+    pub json: bool,
+    /// Kill the script after this many seconds; unset means no limit.
+    pub timeout_secs: Option<u64>,
+}
+
+/// The process exit code for a finished script: its own code, or 128 + the signal
+/// number when a signal ended it (the shell convention).
+pub fn exit_code(status: ExitStatus) -> i32 {
+    if let Some(code) = status.code() {
+        return code;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    1
+}
+
+/// Run the script and return the exit code for this process.
+pub fn run(command: &RunCommand, config: &AppConfig) -> i32 {
+    if !command.script.is_file() {
+        eprintln!("{} {}", "✗ Script not found:".error_color(), command.script.display());
+        return EXIT_SETUP;
+    }
+    // Scripts run from the current directory, as they would from a shell
+    let mut executor = match CodeExecutor::new(".") {
+        Ok(executor) => executor,
+        Err(e) => {
+            eprintln!("{} {:#}", "✗".error_color(), e);
+            return EXIT_SETUP;
+        }
+    };
+    if config.sandbox {
+        executor = executor.with_sandbox(if config.sandbox_required { Sandbox::Required } else { Sandbox::Preferred });
+    }
+
+    let mut child = None;
+    for python in ["python3", "python"] {
+        let mut process = match executor.script_command(python, &command.script) {
+            Ok(process) => process,
+            Err(e) => {
+                eprintln!("{} {:#}", "✗".error_color(), e);
+                return EXIT_SETUP;
+            }
+        };
+        if command.json {
+            process.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+        match process.spawn() {
+            Ok(process) => {
+                child = Some(process);
+                break;
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => {
+                eprintln!("{} {}", format!("✗ Could not start {python}:").error_color(), e);
+                return EXIT_SETUP;
+            }
+        }
+    }
+    let Some(mut child) = child else {
+        eprintln!("{}", "✗ No Python interpreter found (tried python3 and python)".error_color());
+        return EXIT_NO_INTERPRETER;
+    };
+
+    if !command.quiet && !command.json {
+        eprintln!("{} {}", "▶ Running".heading_color(), command.script.display());
+    }
+    let start = Instant::now();
+    let stdout = capture(child.stdout.take());
+    let stderr = capture(child.stderr.take());
+    let status = wait(&mut child, command.timeout_secs);
+    let elapsed = start.elapsed();
+    let code = match status {
+        Ok(Some(status)) => exit_code(status),
+        Ok(None) => EXIT_TIMEOUT,
+        Err(e) => {
+            eprintln!("{} {}", "✗ Failed to wait for the script:".error_color(), e);
+            return EXIT_SETUP;
+        }
+    };
+    let timed_out = matches!(status, Ok(None));
+
+    if command.json {
+        let report = serde_json::json!({
+            "script": command.script,
+            "exit_code": code,
+            "timed_out": timed_out,
+            "duration_ms": elapsed.as_millis() as u64,
+            "stdout": String::from_utf8_lossy(&stdout.join().unwrap_or_default()),
+            "stderr": String::from_utf8_lossy(&stderr.join().unwrap_or_default()),
+        });
+        println!("{}", report);
+    } else if !command.quiet {
+        let secs = elapsed.as_secs_f64();
+        if timed_out {
+            eprintln!("{}", format!("⏱ Timed out after {}s", command.timeout_secs.unwrap_or_default()).error_color());
+        } else if code == 0 {
+            eprintln!("{}", format!("✓ Exited 0 in {secs:.2}s").success_color());
+        } else {
+            eprintln!("{}", format!("✗ Exited {code} in {secs:.2}s").error_color());
+        }
+    }
+    code
+}
+
+/// Read a piped stream from a thread (nothing to read when the stream is inherited).
+fn capture<R: Read + Send + 'static>(pipe: Option<R>) -> std::thread::JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+/// Wait for the child; `Ok(None)` when it had to be killed at the time limit.
+fn wait(child: &mut Child, timeout_secs: Option<u64>) -> std::io::Result<Option<ExitStatus>> {
+    let Some(secs) = timeout_secs.filter(|&s| s > 0) else {
+        return child.wait().map(Some);
+    };
+    let status = child.wait_timeout(Duration::from_secs(secs))?;
+    if status.is_none() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+    Ok(status)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    fn test_exit_code() {
+        // Raw wait statuses: exit code in the high byte, signal number in the low bits
+        assert_eq!(exit_code(ExitStatus::from_raw(0)), 0);
+        assert_eq!(exit_code(ExitStatus::from_raw(3 << 8)), 3);
+        assert_eq!(exit_code(ExitStatus::from_raw(9)), 137);
+        assert_eq!(exit_code(ExitStatus::from_raw(15)), 143);
+    }
+}
@@ -0,0 +1,356 @@
+//! First-run setup wizard, also available as `/setup` (`--no-wizard` skips it): backend,
+//! token, model and directories, written to `pymakebot.toml`. Every question has a
+//! default, so pressing Enter throughout gives a working setup.
+
+use crate::api;
+use crate::auth::{self, Secret};
+use crate::config::{AppConfig, Backend};
+use crate::interface::ask_user;
+use crate::messages;
+use crate::theme::Themed;
+use anyhow::{Context, Result};
+use colored::*;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Where a local Ollama server listens.
+const OLLAMA_URL: &str = "http://localhost:11434";
+const TOKENS_PAGE: &str = "https://huggingface.co/settings/tokens";
+/// Tokens tried before the token step gives up (the setup goes on without one).
+const TOKEN_ATTEMPTS: usize = 3;
+
+/// Models offered for the Hugging Face router; the first one is the default.
+const CURATED_MODELS: &[(&str, &str)] = &[
+    ("Qwen/Qwen2.5-Coder-32B-Instruct", "strong at Python, the default"),
+    ("Qwen/Qwen3-Coder-30B-A3B-Instruct", "newer, faster, good at code"),
+    ("deepseek-ai/DeepSeek-V3-0324", "large general model, slower"),
+    ("meta-llama/Llama-3.3-70B-Instruct", "general purpose"),
+];
+/// Suggested when Ollama has no model pulled yet.
+const OLLAMA_DEFAULT_MODEL: &str = "qwen2.5-coder:7b";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BackendChoice {
+    HfRouter,
+    Ollama,
+}
+
+/// What the wizard writes to the configuration file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SetupAnswers {
+    pub backend: BackendChoice,
+    pub model: String,
+    pub generated_dir: String,
+    pub log_dir: String,
+}
+
+impl SetupAnswers {
+    pub fn api_url(&self) -> String {
+        match self.backend {
+            BackendChoice::HfRouter => AppConfig::default().api_url,
+            BackendChoice::Ollama => format!("{OLLAMA_URL}/v1/chat/completions"),
+        }
+    }
+
+    /// `existing` (a config file's contents, possibly empty) with the answered keys set;
+    /// every other setting is kept.
+    pub fn merge_into(&self, existing: &str) -> Result<String> {
+        let mut table: toml::Table = toml::from_str(existing).context("The existing configuration is not valid TOML")?;
+        table.insert("model".to_string(), self.model.clone().into());
+        table.insert("api_url".to_string(), self.api_url().into());
+        table.insert("backend".to_string(), "chat".into());
+        table.insert("generated_dir".to_string(), self.generated_dir.clone().into());
+        table.insert("log_dir".to_string(), self.log_dir.clone().into());
+        toml::to_string(&table).context("Could not serialize the configuration")
+    }
+
+    /// Set the answered keys on `config`, as `merge_into` does in the file; the rest of
+    /// `config` (command-line overrides, session changes) is kept.
+    pub fn apply_to(&self, config: &mut AppConfig) {
+        config.model = self.model.clone();
+        config.api_url = self.api_url();
+        config.backend = Backend::Chat;
+        config.generated_dir = self.generated_dir.clone();
+        config.log_dir = self.log_dir.clone();
+    }
+}
+
+/// First launch: no configuration file and no token anywhere, on an interactive terminal.
+pub fn is_first_run() -> bool {
+    AppConfig::config_file().is_none() && auth::resolve_token().is_none() && std::io::stdin().is_terminal()
+}
+
+/// The file the wizard writes: the configuration in use, else `~/pymakebot.toml`.
+fn target_path() -> PathBuf {
+    AppConfig::config_file()
+        .or_else(|| dirs::home_dir().map(|home| home.join("pymakebot.toml")))
+        .unwrap_or_else(|| PathBuf::from("pymakebot.toml"))
+}
+
+fn ask_default(question: &str, default: &str) -> String {
+    let answer = ask_user(&format!("{question} [{default}]: "));
+    if answer.is_empty() { default.to_string() } else { answer }
+}
+
+fn ask_yes(question: &str, default: bool) -> bool {
+    let answer = ask_user(&format!("{question} [{}] ", if default { "Y/n" } else { "y/N" }));
+    if answer.is_empty() { default } else { messages::is_yes(&answer) }
+}
+
+/// The option numbered by `answer` (1-based), the default on an empty answer, or the
+/// answer itself (a model name that isn't listed).
+fn pick(answer: &str, options: &[String], default: usize) -> String {
+    let answer = answer.trim();
+    if answer.is_empty() {
+        return options.get(default).cloned().unwrap_or_default();
+    }
+    match answer.parse::<usize>() {
+        Ok(n) if (1..=options.len()).contains(&n) => options[n - 1].clone(),
+        _ => answer.to_string(),
+    }
+}
+
+/// Model names from Ollama's `/api/tags` response.
+fn parse_ollama_tags(body: &serde_json::Value) -> Vec<String> {
+    body["models"]
+        .as_array()
+        .map(|models| models.iter().filter_map(|m| m["name"].as_str().map(String::from)).collect())
+        .unwrap_or_default()
+}
+
+/// The models of a running local Ollama, `None` when it doesn't answer.
+async fn probe_ollama() -> Option<Vec<String>> {
+    let client = reqwest::Client::builder().timeout(Duration::from_secs(2)).build().ok()?;
+    let response = client.get(format!("{OLLAMA_URL}/api/tags")).send().await.ok()?;
+    let body: serde_json::Value = response.json().await.ok()?;
+    Some(parse_ollama_tags(&body))
+}
+
+/// Read a token with the input hidden (rpassword comes with the `keyring` feature).
+#[cfg(feature = "keyring")]
+fn read_token() -> std::io::Result<String> {
+    rpassword::prompt_password("Token (input hidden, Enter to skip): ")
+}
+
+/// Read a token; without the `keyring` feature the input is echoed.
+#[cfg(not(feature = "keyring"))]
+fn read_token() -> std::io::Result<String> {
+    Ok(ask_user("Token (input visible, Enter to skip): "))
+}
+
+/// Read tokens until one is accepted by Hugging Face; `None` when skipped.
+async fn ask_token(config: &AppConfig) -> Option<Secret> {
+    println!("\n{}", "The Hugging Face router needs an access token.".heading_color());
+    println!("  Create one at {TOKENS_PAGE}: a \"Read\" token works, or a fine-grained one");
+    println!("  with the \"Make calls to Inference Providers\" permission.");
+    for _ in 0..TOKEN_ATTEMPTS {
+        let token = match read_token() {
+            Ok(token) => token.trim().to_string(),
+            Err(e) => {
+                println!("{} {}", "✗ Could not read the token:".error_color(), e);
+                return None;
+            }
+        };
+        if token.is_empty() {
+            break;
+        }
+        let token = Secret::new(token);
+        match api::whoami(config, &token).await {
+            Ok(name) => {
+                println!("{} {}", "✓ Token accepted for".success_color(), name.emphasis_color());
+                return Some(token);
+            }
+            Err(e) => println!("{} {}", "✗".error_color(), e),
+        }
+    }
+    println!("{}", format!("Continuing without a token. {}", auth::missing_token_hint()).warning_color());
+    None
+}
+
+/// `contents` of a `.env` file with `HF_TOKEN` set to `token` (replacing any previous value).
+fn with_env_token(contents: &str, token: &Secret) -> String {
+    let mut out: String = contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("HF_TOKEN="))
+        .map(|line| format!("{line}\n"))
+        .collect();
+    out.push_str(&format!("HF_TOKEN={}\n", token.expose()));
+    out
+}
+
+fn save_env_token(path: &Path, token: &Secret) -> Result<()> {
+    let contents = fs::read_to_string(path).unwrap_or_default();
+    fs::write(path, with_env_token(&contents, token)).with_context(|| format!("Could not write {}", path.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let _ = fs::set_permissions(path, fs::Permissions::from_mode(0o600));
+    }
+    Ok(())
+}
+
+/// Keep the token for later sessions (keyring or `.env`) and use it for this one.
+fn store_token(token: &Secret) {
+    std::env::set_var("HF_TOKEN", token.expose());
+    #[cfg(feature = "keyring")]
+    if ask_yes("Store the token in the OS keyring?", true) {
+        match auth::store_token(token) {
+            Ok(()) => {
+                println!("{}", "✓ Token stored in the OS keyring.".success_color());
+                return;
+            }
+            Err(e) => println!("{} {}", "✗ Could not store the token:".error_color(), e),
+        }
+    }
+    if ask_yes("Save the token as HF_TOKEN in ./.env?", true) {
+        match save_env_token(Path::new(".env"), token) {
+            Ok(()) => println!("{}", "✓ Token saved in .env".success_color()),
+            Err(e) => println!("{} {:#}", "✗".error_color(), e),
+        }
+    }
+}
+
+/// Run the wizard and write the configuration file; returns the configuration to use.
+pub async fn run(config: &AppConfig) -> Result<AppConfig> {
+    println!("\n{}", "Python Maker Bot setup".heading_color().bold());
+    println!("{}", "Press Enter to accept the [default] of each question.".dimmed());
+
+    // Backend
+    let ollama = probe_ollama().await;
+    let ollama_note = match &ollama {
+        Some(models) => format!("running, {} model(s)", models.len()),
+        None => format!("not detected at {OLLAMA_URL}"),
+    };
+    println!("\n{}", "Where should code be generated?".heading_color());
+    println!("  1) Hugging Face router (needs a token)");
+    println!("  2) Local Ollama ({ollama_note})");
+    let default = if config.api_url.starts_with(OLLAMA_URL) { "2" } else { "1" };
+    let backend = match ask_default("Backend", default).to_lowercase().as_str() {
+        "2" | "ollama" => BackendChoice::Ollama,
+        _ => BackendChoice::HfRouter,
+    };
+
+    // Token
+    if backend == BackendChoice::HfRouter {
+        let keep = match auth::resolve_token() {
+            Some((_, source)) => ask_yes(&format!("\nKeep the token from the {source}?"), true),
+            None => false,
+        };
+        if !keep {
+            if let Some(token) = ask_token(config).await {
+                store_token(&token);
+            }
+        }
+    }
+
+    // Model
+    let (options, notes): (Vec<String>, Vec<String>) = match backend {
+        BackendChoice::HfRouter => CURATED_MODELS.iter().map(|(m, note)| (m.to_string(), note.to_string())).unzip(),
+        BackendChoice::Ollama => match ollama.filter(|models| !models.is_empty()) {
+            Some(models) => models.into_iter().map(|m| (m, String::new())).unzip(),
+            None => (
+                vec![OLLAMA_DEFAULT_MODEL.to_string()],
+                vec![format!("run `ollama pull {OLLAMA_DEFAULT_MODEL}` first")],
+            ),
+        },
+    };
+    println!("\n{}", "Which model? (a number, or any model name)".heading_color());
+    for (i, (model, note)) in options.iter().zip(&notes).enumerate() {
+        match note.is_empty() {
+            true => println!("  {}) {model}", i + 1),
+            false => println!("  {}) {model} — {}", i + 1, note.dimmed()),
+        }
+    }
+    let default = options.iter().position(|m| *m == config.model).unwrap_or(0);
+    let model = pick(&ask_default("Model", &(default + 1).to_string()), &options, default);
+
+    // Directories
+    println!();
+    let generated_dir = ask_default("Directory for generated scripts", &config.generated_dir);
+    let log_dir = ask_default("Directory for session logs", &config.log_dir);
+
+    let answers = SetupAnswers { backend, model, generated_dir, log_dir };
+    let path = target_path();
+    let existing = fs::read_to_string(&path).unwrap_or_default();
+    let text = answers.merge_into(&existing)?;
+    fs::write(&path, &text).with_context(|| format!("Could not write {}", path.display()))?;
+    println!("\n{} {}", "✓ Configuration written to".success_color(), path.display());
+    toml::from_str::<AppConfig>(&text).with_context(|| format!("Could not load {}", path.display()))?;
+    let mut configured = config.clone();
+    answers.apply_to(&mut configured);
+    Ok(configured)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick() {
+        let options = vec!["a/one".to_string(), "b/two".to_string()];
+        assert_eq!(pick("", &options, 1), "b/two");
+        assert_eq!(pick("1", &options, 1), "a/one");
+        assert_eq!(pick(" org/custom ", &options, 0), "org/custom");
+        assert_eq!(pick("3", &options, 0), "3");
+    }
+
+    #[test]
+    fn test_parse_ollama_tags() {
+        let body = serde_json::json!({"models": [{"name": "qwen2.5-coder:7b", "size": 1}, {"name": "llama3.2:latest"}]});
+        assert_eq!(parse_ollama_tags(&body), vec!["qwen2.5-coder:7b", "llama3.2:latest"]);
+        assert!(parse_ollama_tags(&serde_json::json!({})).is_empty());
+    }
+
+    #[test]
+    fn test_merge_into_keeps_other_settings() {
+        let answers = SetupAnswers {
+            backend: BackendChoice::Ollama,
+            model: "qwen2.5-coder:7b".to_string(),
+            generated_dir: "generated".to_string(),
+            log_dir: "logs".to_string(),
+        };
+        let text = answers.merge_into("model = \"old\"\nsandbox = true\n").unwrap();
+        let config: AppConfig = toml::from_str(&text).unwrap();
+        assert_eq!(config.model, "qwen2.5-coder:7b");
+        assert_eq!(config.api_url, "http://localhost:11434/v1/chat/completions");
+        assert!(config.sandbox);
+
+        // All defaults: a config equivalent to having no file
+        let defaults = AppConfig::default();
+        let answers = SetupAnswers {
+            backend: BackendChoice::HfRouter,
+            model: CURATED_MODELS[0].0.to_string(),
+            generated_dir: defaults.generated_dir.clone(),
+            log_dir: defaults.log_dir.clone(),
+        };
+        let config: AppConfig = toml::from_str(&answers.merge_into("").unwrap()).unwrap();
+        assert_eq!((config.model, config.api_url), (defaults.model, defaults.api_url));
+        assert!(answers.merge_into("not = [toml").is_err());
+    }
+
+    #[test]
+    fn test_apply_to_matches_the_file_and_keeps_the_rest() {
+        let answers = SetupAnswers {
+            backend: BackendChoice::Ollama,
+            model: "qwen2.5-coder:7b".to_string(),
+            generated_dir: "out".to_string(),
+            log_dir: "logs".to_string(),
+        };
+        // e.g. `--timeout 600`
+        let mut config = AppConfig { request_timeout_secs: 600, backend: Backend::HfTextgen, ..AppConfig::default() };
+        answers.apply_to(&mut config);
+        let written: AppConfig = toml::from_str(&answers.merge_into("").unwrap()).unwrap();
+        assert_eq!((&config.model, &config.api_url, config.backend), (&written.model, &written.api_url, written.backend));
+        assert_eq!((&config.generated_dir, &config.log_dir), (&written.generated_dir, &written.log_dir));
+        assert_eq!(config.request_timeout_secs, 600);
+    }
+
+    #[test]
+    fn test_with_env_token_replaces_previous_value() {
+        let token = Secret::new("hf_new");
+        assert_eq!(with_env_token("", &token), "HF_TOKEN=hf_new\n");
+        assert_eq!(with_env_token("HF_TOKEN=hf_old\nOTHER=1", &token), "OTHER=1\nHF_TOKEN=hf_new\n");
+    }
+}
@@ -0,0 +1,94 @@
+use crate::api::Message;
+
+/// One `/spec` case: text fed on stdin and the stdout the program must produce.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpecCase {
+    pub input: String,
+    pub expected: String,
+}
+
+impl SpecCase {
+    /// Parse `input => expected`, where `\n` and `\t` in either side are escapes.
+    pub fn parse(text: &str) -> Option<SpecCase> {
+        let (input, expected) = text.split_once("=>")?;
+        Some(SpecCase {
+            input: unescape(input.trim()),
+            expected: unescape(expected.trim()),
+        })
+    }
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("\\n", "\n").replace("\\t", "\t")
+}
+
+/// Extra user message sent with every request while specs are set.
+pub fn spec_message(cases: &[SpecCase]) -> Option<Message> {
+    if cases.is_empty() {
+        return None;
+    }
+    let mut content = String::from(
+        "The program must satisfy these examples. It reads its input from stdin and prints \
+         exactly the expected output to stdout, with no prompts or extra text:\n",
+    );
+    for (i, case) in cases.iter().enumerate() {
+        content.push_str(&format!(
+            "\nExample {}\nstdin:\n```\n{}\n```\nexpected stdout:\n```\n{}\n```\n",
+            i + 1,
+            case.input,
+            case.expected
+        ));
+    }
+    Some(Message {
+        role: "user".to_string(),
+        content,
+    })
+}
+
+/// Program output without trailing whitespace on each line nor trailing blank lines.
+pub fn normalize_output(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().map(str::trim_end).collect();
+    lines.join("\n").trim_end_matches('\n').to_string()
+}
+
+/// Compare program output with the expected text, ignoring trailing whitespace on each
+/// line and trailing blank lines.
+pub fn outputs_match(actual: &str, expected: &str) -> bool {
+    normalize_output(actual) == normalize_output(expected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_case() {
+        let case = SpecCase::parse("3\\n4 => 7").unwrap();
+        assert_eq!(case.input, "3\n4");
+        assert_eq!(case.expected, "7");
+        assert!(SpecCase::parse("no arrow here").is_none());
+    }
+
+    #[test]
+    fn test_spec_message() {
+        assert!(spec_message(&[]).is_none());
+        let message = spec_message(&[SpecCase { input: "2".into(), expected: "4".into() }]).unwrap();
+        assert_eq!(message.role, "user");
+        assert!(message.content.contains("Example 1"));
+        assert!(message.content.contains("stdin:\n```\n2\n```"));
+    }
+
+    #[test]
+    fn test_outputs_match() {
+        assert!(outputs_match("7\n", "7"));
+        assert!(outputs_match("a  \nb\n\n", "a\nb"));
+        assert!(!outputs_match("7", "8"));
+        assert!(!outputs_match(" 7", "7"));
+    }
+
+    #[test]
+    fn test_normalize_output() {
+        assert_eq!(normalize_output("a \t\r\nb\n\n  \n"), "a\nb");
+        assert_eq!(normalize_output(""), "");
+    }
+}
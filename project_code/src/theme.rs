@@ -0,0 +1,224 @@
+use colored::{Color, ColoredString, Colorize};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Colors used by the banner, the code block, the execution result block and every
+/// message of the REPL. `None` leaves the text in the terminal's default color.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Theme {
+    pub name: &'static str,
+    pub banner: Option<Color>,
+    pub tagline: Option<Color>,
+    pub code_frame: Option<Color>,
+    pub comment: Option<Color>,
+    pub definition: Option<Color>,
+    pub import: Option<Color>,
+    pub result_frame: Option<Color>,
+    pub success: Option<Color>,
+    pub error: Option<Color>,
+    pub warning: Option<Color>,
+    /// Section titles and labels (`Current model:`, `Conversation History:`).
+    pub heading: Option<Color>,
+    /// Names and values shown next to a label: models, files, the prompt being sent.
+    pub emphasis: Option<Color>,
+    /// Items picked out of a list: code snippets, modules, review findings.
+    pub highlight: Option<Color>,
+}
+
+/// The original palette, meant for dark backgrounds.
+pub const DARK: Theme = Theme {
+    name: "dark",
+    banner: Some(Color::BrightCyan),
+    tagline: Some(Color::BrightWhite),
+    code_frame: Some(Color::BrightGreen),
+    comment: Some(Color::BrightBlack),
+    definition: Some(Color::BrightYellow),
+    import: Some(Color::BrightMagenta),
+    result_frame: Some(Color::BrightBlue),
+    success: Some(Color::Green),
+    error: Some(Color::Red),
+    warning: Some(Color::Yellow),
+    heading: Some(Color::BrightCyan),
+    emphasis: Some(Color::BrightWhite),
+    highlight: Some(Color::BrightYellow),
+};
+
+/// Darker, non-bright colors that stay readable on white backgrounds (no yellow).
+pub const LIGHT: Theme = Theme {
+    name: "light",
+    banner: Some(Color::Blue),
+    tagline: None,
+    code_frame: Some(Color::Green),
+    comment: Some(Color::BrightBlack),
+    definition: Some(Color::Blue),
+    import: Some(Color::Magenta),
+    result_frame: Some(Color::Blue),
+    success: Some(Color::Green),
+    error: Some(Color::Red),
+    warning: Some(Color::Magenta),
+    heading: Some(Color::Blue),
+    emphasis: None,
+    highlight: Some(Color::Magenta),
+};
+
+/// No colors at all; bold and dimmed text still apply.
+pub const MONO: Theme = Theme {
+    name: "mono",
+    banner: None,
+    tagline: None,
+    code_frame: None,
+    comment: None,
+    definition: None,
+    import: None,
+    result_frame: None,
+    success: None,
+    error: None,
+    warning: None,
+    heading: None,
+    emphasis: None,
+    highlight: None,
+};
+
+pub const THEMES: [&Theme; 3] = [&DARK, &LIGHT, &MONO];
+
+impl Theme {
+    pub fn by_name(name: &str) -> Option<&'static Theme> {
+        let name = name.trim().to_lowercase();
+        THEMES.into_iter().find(|t| t.name == name)
+    }
+}
+
+/// Color `text` with one of the theme's colors.
+pub fn paint(text: &str, color: Option<Color>) -> ColoredString {
+    match color {
+        Some(color) => text.color(color),
+        None => text.normal(),
+    }
+}
+
+/// Colors from the current theme, used instead of `Colorize::green`, `bright_cyan`...
+/// so `/theme` applies to every message.
+pub trait Themed: Sized {
+    /// Color with `color`, or leave the text as it is (styles included) for `None`.
+    fn tint(self, color: Option<Color>) -> ColoredString;
+
+    fn success_color(self) -> ColoredString {
+        self.tint(current().success)
+    }
+
+    fn error_color(self) -> ColoredString {
+        self.tint(current().error)
+    }
+
+    fn warning_color(self) -> ColoredString {
+        self.tint(current().warning)
+    }
+
+    fn heading_color(self) -> ColoredString {
+        self.tint(current().heading)
+    }
+
+    fn emphasis_color(self) -> ColoredString {
+        self.tint(current().emphasis)
+    }
+
+    fn highlight_color(self) -> ColoredString {
+        self.tint(current().highlight)
+    }
+}
+
+impl Themed for &str {
+    fn tint(self, color: Option<Color>) -> ColoredString {
+        paint(self, color)
+    }
+}
+
+impl Themed for ColoredString {
+    fn tint(self, color: Option<Color>) -> ColoredString {
+        match color {
+            Some(color) => self.color(color),
+            None => self,
+        }
+    }
+}
+
+static CURRENT: AtomicU8 = AtomicU8::new(0);
+
+/// Theme used from now on. Set from `BOT_THEME` at startup and by `/theme`.
+pub fn set_theme(theme: &Theme) {
+    let index = THEMES.iter().position(|t| t.name == theme.name).unwrap_or(0);
+    CURRENT.store(index as u8, Ordering::Relaxed);
+}
+
+pub fn current() -> &'static Theme {
+    THEMES.get(CURRENT.load(Ordering::Relaxed) as usize).copied().unwrap_or(&DARK)
+}
+
+/// Pick the startup theme from `BOT_THEME` (dark when unset or unknown).
+pub fn init_from_env() {
+    if let Some(theme) = std::env::var("BOT_THEME").ok().as_deref().and_then(Theme::by_name) {
+        set_theme(theme);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_by_name() {
+        assert_eq!(Theme::by_name("Light").map(|t| t.name), Some("light"));
+        assert_eq!(Theme::by_name(" mono ").map(|t| t.name), Some("mono"));
+        assert!(Theme::by_name("solarized").is_none());
+    }
+
+    #[test]
+    fn test_mono_paints_nothing() {
+        for color in [MONO.banner, MONO.code_frame, MONO.error, MONO.warning] {
+            assert_eq!(paint("x", color).to_string(), "x");
+        }
+    }
+
+    #[test]
+    fn test_tint_keeps_styles_without_a_color() {
+        assert_eq!("x".tint(MONO.warning), "x".normal());
+        assert_eq!("x".bold().tint(MONO.error), "x".bold());
+        assert_eq!("x".tint(DARK.success), "x".green());
+        assert_eq!("x".bold().tint(DARK.error), "x".bold().red());
+    }
+
+    /// Pieces of a few REPL lines (`/model`, `/models`, a failed install, `/review`) in `theme`.
+    fn repl_lines(theme: &Theme) -> Vec<ColoredString> {
+        vec![
+            "Current model:".tint(theme.heading),
+            "Qwen/Qwen2.5-Coder-32B-Instruct".tint(theme.emphasis),
+            "Available models (2 of 9):".tint(theme.heading).bold(),
+            " (current)".tint(theme.success),
+            "✗ Could not install".tint(theme.error),
+            "numpy".tint(theme.highlight),
+            "1".tint(theme.highlight),
+            "⚠️  Could not list models:".tint(theme.warning),
+            "Prompt to send:".tint(theme.heading).bold(),
+        ]
+    }
+
+    #[test]
+    fn test_mono_repl_lines_have_no_color() {
+        for piece in repl_lines(&MONO) {
+            assert_eq!((piece.fgcolor, piece.bgcolor), (None, None), "{piece:?}");
+            assert!(!format!("{piece}").contains("\x1b[3"), "{piece:?}");
+        }
+        assert!(repl_lines(&DARK).iter().all(|piece| piece.fgcolor.is_some()));
+    }
+
+    #[test]
+    fn test_light_avoids_yellow_and_white() {
+        let colors = [
+            LIGHT.banner, LIGHT.tagline, LIGHT.code_frame, LIGHT.definition, LIGHT.import, LIGHT.warning,
+            LIGHT.heading, LIGHT.emphasis, LIGHT.highlight,
+        ];
+        assert!(colors.iter().all(|c| !matches!(
+            c,
+            Some(Color::Yellow | Color::BrightYellow | Color::White | Color::BrightWhite)
+        )));
+    }
+}
@@ -0,0 +1,4 @@
+import sys
+print('partial')
+print('failure detail', file=sys.stderr)
+sys.exit(3)
@@ -0,0 +1 @@
+print('report ok')
@@ -0,0 +1,81 @@
+// `run <script>` subcommand: exit codes and output modes, against the compiled binary
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+/// Run the binary from an empty directory so no local pymakebot.toml or .env applies.
+fn run_bot(dir: &str, args: &[&str], path: Option<&str>) -> Output {
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir).unwrap();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_project_code"));
+    command.args(args).current_dir(dir).env("NO_COLOR", "1");
+    if let Some(path) = path {
+        command.env("PATH", path);
+    }
+    let output = command.output().unwrap();
+    let _ = fs::remove_dir_all(dir);
+    output
+}
+
+#[test]
+fn test_run_success_exits_zero() {
+    let script = fixture("ok.py");
+    let output = run_bot("test_run_ok", &["run", script.to_str().unwrap()], None);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "report ok\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Exited 0"));
+}
+
+#[test]
+fn test_run_propagates_exit_code_and_quiet_forwards_only_script_output() {
+    let script = fixture("fail.py");
+    let output = run_bot("test_run_fail", &["run", script.to_str().unwrap(), "--quiet"], None);
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "partial\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "failure detail\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_run_signal_uses_128_plus_signal() {
+    let script = fixture("killed.py");
+    let output = run_bot("test_run_signal", &["run", script.to_str().unwrap(), "-q"], None);
+    assert_eq!(output.status.code(), Some(128 + 15));
+}
+
+#[test]
+fn test_run_timeout() {
+    let script = fixture("sleep.py");
+    let output = run_bot("test_run_timeout", &["run", script.to_str().unwrap(), "-q", "--timeout", "1"], None);
+    assert_eq!(output.status.code(), Some(124));
+}
+
+#[test]
+fn test_run_json_report() {
+    let script = fixture("fail.py");
+    let output = run_bot("test_run_json", &["run", script.to_str().unwrap(), "--json"], None);
+    assert_eq!(output.status.code(), Some(3));
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["exit_code"], 3);
+    assert_eq!(report["timed_out"], false);
+    assert_eq!(report["stdout"], "partial\n");
+    assert_eq!(report["stderr"], "failure detail\n");
+}
+
+#[test]
+fn test_run_missing_interpreter_exits_127() {
+    let script = fixture("ok.py");
+    let output = run_bot("test_run_no_python", &["run", script.to_str().unwrap()], Some("/nonexistent"));
+    assert_eq!(output.status.code(), Some(127));
+}
+
+#[test]
+fn test_run_missing_script_is_a_setup_error() {
+    let output = run_bot("test_run_missing", &["run", "does_not_exist.py"], None);
+    assert_eq!(output.status.code(), Some(2));
+}